@@ -0,0 +1,304 @@
+use std::{collections::HashMap, sync::OnceLock, time::Instant};
+
+use axum::{http::StatusCode, Json};
+use serde_json::{json, Value};
+
+use crate::{
+    circuit::{Circuit, Instruction, Op},
+    emulate::{self, EmulateInfo, EmulateMessage, EmulateMode},
+    pool::{statevector_bytes, ComputePool},
+    qubits::{parse_cregs, parse_qregs},
+    simulator::{self, backend::Backend, external, statevector::StateVector},
+    thread, SharedState,
+};
+
+/// Amplitudes updated per second by one thread, measured once by applying
+/// gates to a small statevector. It blocks the first time, see
+/// `measured_rate`.
+fn amplitudes_per_second() -> f64 {
+    static RATE: OnceLock<f64> = OnceLock::new();
+    *RATE.get_or_init(|| {
+        let (num_qubits, gates) = (14, 32);
+        let mut state = StateVector::new(num_qubits).unwrap();
+        let start = Instant::now();
+        for i in 0..gates {
+            let _ = state.apply_gate("h", &[], &[i % num_qubits]);
+        }
+        let seconds = start.elapsed().as_secs_f64().max(1e-9);
+        (gates << num_qubits) as f64 / seconds
+    })
+}
+
+/// the rate of `amplitudes_per_second`, measured on a blocking thread
+async fn measured_rate() -> Result<f64, String> {
+    tokio::task::spawn_blocking(amplitudes_per_second)
+        .await
+        .map_err(|err| err.to_string())
+}
+
+/// the bytes, or null if they do not fit in a u64
+fn exact_bytes(bytes: u64) -> Option<u64> {
+    (bytes < u64::MAX).then_some(bytes)
+}
+
+/// Estimate the runtime in milliseconds from the work of each instruction on
+/// the state of the backend, e.g. the whole statevector, or for the mps
+/// backend two sites of the largest bond. With terminal measurements the
+/// circuit is simulated once, otherwise each shot is simulated after the
+/// gates shared by all shots, with the shots split across the `parallel`
+/// chunks running at once.
+fn estimate_runtime_ms(
+    circuit: &Circuit,
+    info: &EmulateInfo,
+    shots: usize,
+    parallel: usize,
+    backend: &dyn Backend,
+    rate: f64,
+) -> f64 {
+    let (size, sample) = backend.state_cost(circuit, info);
+    let cost = |insts: &[Instruction]| {
+        insts
+            .iter()
+            .map(|inst| backend.instruction_cost(inst, size, circuit, info))
+            .sum::<f64>()
+    };
+    let amplitudes = if backend.simulation_path(circuit) != "per_shot" {
+        cost(&circuit.instructions) + size + shots as f64 * sample
+    } else {
+        let prefix = circuit
+            .instructions
+            .iter()
+            .position(|inst| inst.condition.is_some() || !matches!(inst.op, Op::Gate { .. }))
+            .unwrap_or(circuit.instructions.len());
+        let per_shot = size + cost(&circuit.instructions[prefix..]);
+        cost(&circuit.instructions[..prefix])
+            + per_shot * shots as f64 / parallel.clamp(1, shots.max(1)) as f64
+    };
+    amplitudes / rate * 1000.0
+}
+
+/// Parse and compile the program of a task like `/submit` does, and report
+/// the properties of the circuit which would be simulated, without running
+/// it. The circuit is compiled for the device, so the counts are those after
+/// the optimization, routing and basis gates. A program the agent cannot
+/// parse is reported as qasmsim would run it, with the registers it
+/// declares.
+pub async fn analyze_task(
+    state: SharedState,
+    mut message: EmulateMessage,
+) -> (StatusCode, Json<Value>) {
+    if let Err(err) = emulate::convert_input(&mut message) {
+        return (StatusCode::BAD_REQUEST, Json(json!({ "Error": err })));
+    }
+    // the variables of vqe are ranges, they are left unbound
+    if let Some(EmulateMode::Vqe) = message.mode {
+        message.vars = None;
+    }
+    if serde_json::from_str::<HashMap<String, f64>>(message.vars.as_deref().unwrap_or("{}"))
+        .is_err()
+    {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"Error": "Invalid vars"})),
+        );
+    }
+    let shots = message.shots.max(1);
+    let every = message.progress_every;
+
+    let mut info = emulate::pre_process_msg(message);
+    let (cache, pool, backends) = {
+        let state_r = state.read().await;
+        info.basis_gates = state_r.qreg.basis_gates.clone();
+        info.coupling_map = state_r.qreg.coupling_map.clone();
+        info.noise = state_r.qreg.noise;
+        (
+            state_r.cache.clone(),
+            state_r.pool.clone(),
+            state_r.backends.clone(),
+        )
+    };
+
+    let mut metadata = serde_json::Map::new();
+    let parsed = match cache.get_or_parse(&info.template) {
+        Ok(parsed) => parsed,
+        Err(err) => {
+            return match thread::fallback_to_qasmsim(&info, err.clone()) {
+                Ok(()) => analyze_qasmsim(&info, &pool, err),
+                Err(err) => (
+                    StatusCode::BAD_REQUEST,
+                    Json(json!({"Error": format!("Cannot analyze the program: {}", err)})),
+                ),
+            }
+        }
+    };
+    let compiled =
+        match thread::compile_circuit(parsed, &mut info, &mut metadata).and_then(|circuit| {
+            let backend = backends.select(&info, &circuit)?;
+            Ok((backend.prepare(circuit, &info)?, backend))
+        }) {
+            Ok(compiled) => compiled,
+            Err(err) => {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    Json(json!({"Error": format!("Cannot analyze the program: {}", err)})),
+                )
+            }
+        };
+    let (compiled, backend) = compiled;
+    let rate = match measured_rate().await {
+        Ok(rate) => rate,
+        Err(err) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({ "Error": err })),
+            )
+        }
+    };
+    let circuit = compiled.bind(&info.vars);
+
+    let two_qubit_gates = circuit
+        .instructions
+        .iter()
+        .filter(|inst| matches!(&inst.op, Op::Gate { qubits, .. } if qubits.len() == 2))
+        .count();
+    let state = backend.state_bytes(&circuit, &info);
+    let parallel = simulator::parallel_chunks(state, pool.size, shots, every, pool.memory.budget);
+    let memory_bytes = backend.memory_bytes(&circuit, &info, parallel);
+    let terminal = circuit.measurements_are_terminal();
+
+    (
+        StatusCode::OK,
+        Json(json!({
+            "Result": {
+                "backend": backend.name(),
+                "qubits": circuit.num_qubits(),
+                "clbits": circuit.num_clbits(),
+                "depth": circuit.depth(),
+                "gate_counts": circuit.gate_counts(),
+                "two_qubit_gates": two_qubit_gates,
+                "measurements_are_terminal": terminal,
+                "simulation_path": backend.simulation_path(&circuit),
+                "unbound_vars": circuit.free_vars(),
+                "statevector_bytes": exact_bytes(statevector_bytes(circuit.num_qubits())),
+                "memory_bytes": exact_bytes(memory_bytes),
+                "fits_memory_budget": memory_bytes <= pool.memory.budget,
+                "estimated_runtime_ms": estimate_runtime_ms(
+                    &circuit,
+                    &info,
+                    shots,
+                    parallel,
+                    backend.as_ref(),
+                    rate,
+                ),
+            },
+            "metadata": metadata,
+        })),
+    )
+}
+
+/// The report of a program which only qasmsim runs, from the registers it
+/// declares. Its gates are unknown, so are its depth and runtime.
+fn analyze_qasmsim(
+    info: &EmulateInfo,
+    pool: &ComputePool,
+    err: String,
+) -> (StatusCode, Json<Value>) {
+    let qubits: usize = parse_qregs(&info.qasm).iter().map(|reg| reg.size).sum();
+    let clbits: usize = parse_cregs(&info.qasm).iter().map(|reg| reg.size).sum();
+    // qasmsim simulates one shot at a time
+    let memory_bytes = statevector_bytes(qubits);
+    (
+        StatusCode::OK,
+        Json(json!({
+            "Result": {
+                "backend": external::QASMSIM,
+                "qubits": qubits,
+                "clbits": clbits,
+                "depth": null,
+                "gate_counts": null,
+                "two_qubit_gates": null,
+                "measurements_are_terminal": null,
+                "simulation_path": "qasmsim",
+                "unbound_vars": null,
+                "statevector_bytes": exact_bytes(memory_bytes),
+                "memory_bytes": exact_bytes(memory_bytes),
+                "fits_memory_budget": memory_bytes <= pool.memory.budget,
+                "estimated_runtime_ms": null,
+            },
+            "metadata": {"parse_error": err},
+        })),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{pool::MemoryBudget, tests::state};
+    use std::sync::Arc;
+
+    const GHZ: &str = "OPENQASM 2.0;\ninclude \"qelib1.inc\";\nqreg q[3];\ncreg c[3];\n\
+                       h q[0];\ncx q[0],q[1];\ncx q[1],q[2];\nt q[2];\n";
+
+    async fn analyze(state: SharedState, qasm: &str) -> Value {
+        let message = serde_json::from_value(json!({"qasm": qasm, "qubits": 3, "shots": 100}));
+        let (status, json) = analyze_task(state, message.unwrap()).await;
+        assert_eq!(status, StatusCode::OK, "{}", json.0);
+        json.0["Result"].clone()
+    }
+
+    #[tokio::test]
+    async fn circuit_is_analyzed() {
+        let result = analyze(state(4), &format!("{}measure q -> c;\n", GHZ)).await;
+        assert_eq!(result["backend"], "statevector");
+        assert_eq!(
+            (&result["qubits"], &result["clbits"]),
+            (&json!(3), &json!(3))
+        );
+        assert_eq!(result["depth"], 5);
+        assert_eq!(
+            result["gate_counts"],
+            json!({"cx": 2, "h": 1, "measure": 3, "t": 1})
+        );
+        assert_eq!(result["two_qubit_gates"], 2);
+        assert_eq!(result["measurements_are_terminal"], true);
+        assert_eq!(result["simulation_path"], "sampling");
+        assert_eq!(result["statevector_bytes"], 128);
+        // the state and its probabilities
+        assert_eq!(result["memory_bytes"], 192);
+        assert_eq!(result["fits_memory_budget"], true);
+    }
+
+    #[tokio::test]
+    async fn mid_circuit_measurements_are_analyzed() {
+        let state = state(4);
+        state.write().await.pool = Arc::new(ComputePool::new(1, 1, MemoryBudget::new(200)));
+        let result = analyze(state, &format!("{}measure q[0] -> c[0];\nx q[0];\n", GHZ)).await;
+        assert_eq!(result["measurements_are_terminal"], false);
+        assert_eq!(result["simulation_path"], "per_shot");
+        // the initial state and the state of the chunk
+        assert_eq!(result["memory_bytes"], 256);
+        assert_eq!(result["fits_memory_budget"], false);
+    }
+
+    #[tokio::test]
+    async fn program_only_qasmsim_runs_has_no_gates() {
+        let result = analyze(state(4), crate::tests::OPAQUE).await;
+        assert_eq!(result["backend"], "qasmsim");
+        assert_eq!(
+            (&result["qubits"], &result["clbits"]),
+            (&json!(1), &json!(0))
+        );
+        for key in [
+            "depth",
+            "gate_counts",
+            "two_qubit_gates",
+            "measurements_are_terminal",
+            "unbound_vars",
+            "estimated_runtime_ms",
+        ] {
+            assert!(result[key].is_null(), "{}", key);
+        }
+        assert_eq!(result["simulation_path"], "qasmsim");
+        assert_eq!(result["memory_bytes"], 32);
+    }
+}
@@ -0,0 +1,293 @@
+use std::{collections::HashMap, sync::Arc};
+
+use axum::{http::StatusCode, Json};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::sync::{oneshot, Semaphore};
+
+use crate::{
+    emulate::{convert_input, EmulateMessage, EmulateMode},
+    jobs::JobStore,
+    progress::Progress,
+    thread, SharedState,
+};
+
+/// Body of `/submit_batch`, either a list of tasks, or one task run once for
+/// each of the variable bindings. The tasks of a list are parsed one by one,
+/// so that a malformed task only fails itself.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum BatchMessage {
+    Tasks(Vec<Value>),
+    Bindings {
+        #[serde(flatten)]
+        message: Box<EmulateMessage>,
+        bindings: Vec<HashMap<String, f64>>,
+    },
+}
+
+impl BatchMessage {
+    pub fn into_tasks(self) -> Result<Vec<Result<EmulateMessage, String>>, String> {
+        match self {
+            BatchMessage::Tasks(tasks) => Ok(tasks
+                .into_iter()
+                .map(|task| {
+                    serde_json::from_value(task).map_err(|err| format!("Invalid task: {}", err))
+                })
+                .collect()),
+            BatchMessage::Bindings { message, bindings } => {
+                // the bindings extend the variables shared by all tasks
+                let vars = serde_json::from_str::<HashMap<String, f64>>(
+                    message.vars.as_deref().unwrap_or("{}"),
+                )
+                .map_err(|_| "Invalid vars".to_string())?;
+
+                Ok(bindings
+                    .into_iter()
+                    .enumerate()
+                    .map(|(index, binding)| {
+                        let mut vars = vars.clone();
+                        vars.extend(binding);
+                        let mut task = EmulateMessage::clone(&message);
+                        task.vars = Some(serde_json::to_string(&vars).unwrap());
+                        task.job_id = point_job_id(&message.job_id, index);
+                        Ok(task)
+                    })
+                    .collect())
+            }
+        }
+    }
+}
+
+/// `{job_id}-{index}` for each of the tasks run from one message with a job id
+pub fn point_job_id(job_id: &Option<String>, index: usize) -> Option<String> {
+    job_id
+        .as_ref()
+        .map(|job_id| format!("{}-{}", job_id, index))
+}
+
+/// Run the tasks of a batch together on the compute pool.
+pub async fn consume_batch(state: SharedState, batch: BatchMessage) -> (StatusCode, Json<Value>) {
+    let tasks = match batch.into_tasks() {
+        Ok(tasks) => tasks,
+        Err(err) => return (StatusCode::BAD_REQUEST, Json(json!({ "Error": err }))),
+    };
+
+    match run_tasks(state, tasks).await {
+        Ok(results) => (StatusCode::OK, Json(json!({ "Results": results }))),
+        Err(err) => err,
+    }
+}
+
+/// How many of the tasks of `widths` qubits, sorted from the widest, run at
+/// once on `threads` threads with `idle` qubits, and the qubits they hold
+fn running_tasks(widths: &[usize], threads: usize, idle: usize) -> (usize, usize) {
+    let (mut qubits, mut running) = (0, 0);
+    for width in widths.iter().take(threads) {
+        if qubits + width > idle {
+            break;
+        }
+        qubits += width;
+        running += 1;
+    }
+    (qubits, running)
+}
+
+/// Run the tasks together, the results are in the order of the tasks with
+/// their `status`. A failed task, or one which could not be parsed, only
+/// reports its own error. At most one task per thread of the pool runs at
+/// once, and only as many as the qubits of the widest ones are idle, those
+/// qubits are allocated for the whole batch so that each running task holds
+/// its own.
+pub async fn run_tasks(
+    state: SharedState,
+    tasks: Vec<Result<EmulateMessage, String>>,
+) -> Result<Vec<Value>, (StatusCode, Json<Value>)> {
+    let mut widths: Vec<usize> = tasks
+        .iter()
+        .filter_map(|task| task.as_ref().ok())
+        .map(|task| task.qubits)
+        .collect();
+    widths.sort_by(|a, b| b.cmp(a));
+
+    let (qubits, running, pool, cache, backends, hub) = {
+        let mut state_w = state.write().await;
+        let (qubits, running) = running_tasks(&widths, state_w.pool.size, state_w.qreg.idle);
+        if state_w.qreg.idle < 1 || (running == 0 && !widths.is_empty()) {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(json!({"Error": "No enough qubits"})),
+            ));
+        }
+        state_w.qreg.idle -= qubits;
+        (
+            qubits,
+            running,
+            state_w.pool.clone(),
+            state_w.cache.clone(),
+            state_w.backends.clone(),
+            state_w.progress.clone(),
+        )
+    };
+
+    let permits = Arc::new(Semaphore::new(running.max(1)));
+    let mut handles = Vec::new();
+    for task in tasks {
+        let mut message = match task {
+            Ok(message) => message,
+            Err(err) => {
+                handles.push(tokio::spawn(async move {
+                    (StatusCode::BAD_REQUEST, Json(json!({ "Error": err })))
+                }));
+                continue;
+            }
+        };
+        message.mode = Some(message.mode.unwrap_or(EmulateMode::Aggregation));
+        message.fix_seed();
+        let (state, pool, cache, backends, permits) = (
+            state.clone(),
+            pool.clone(),
+            cache.clone(),
+            backends.clone(),
+            permits.clone(),
+        );
+        let progress = Progress::new(
+            hub.clone(),
+            message.job_id.clone().unwrap_or_else(JobStore::new_job_id),
+            message.progress_every,
+        );
+
+        handles.push(tokio::spawn(async move {
+            if let Err(err) = convert_input(&mut message) {
+                return (StatusCode::BAD_REQUEST, Json(json!({ "Error": err })));
+            }
+            if let Some(EmulateMode::Vqe | EmulateMode::Sweep) = message.mode {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    Json(json!({
+                        "Error": format!("{} tasks are not supported in a batch", message.mode.unwrap())
+                    })),
+                );
+            }
+
+            {
+                let state_r = state.read().await;
+                if state_r.jobs.jobs.contains_key(&progress.job_id) {
+                    return (
+                        StatusCode::CONFLICT,
+                        Json(json!({"Error": format!("Job {} already exists", progress.job_id)})),
+                    );
+                }
+                if let Err(err) = state_r.backends.check(&message) {
+                    return (StatusCode::BAD_REQUEST, Json(json!({ "Error": err })));
+                }
+                if let Err(err) = state_r.progress.start(&progress.job_id) {
+                    return (StatusCode::CONFLICT, Json(json!({ "Error": err })));
+                }
+            }
+
+            let _permit = permits.acquire_owned().await.unwrap();
+            let (msg_tx, msg_rx) = oneshot::channel();
+            let (res_tx, res_rx) = oneshot::channel();
+            tokio::spawn(thread::quantum_thread(
+                pool,
+                cache,
+                backends,
+                progress.clone(),
+                msg_rx,
+                res_tx,
+            ));
+            thread::classical_thread_allocated(state, message, progress, msg_tx, res_rx).await
+        }));
+    }
+
+    let mut results = Vec::with_capacity(handles.len());
+    for handle in handles {
+        let (status, Json(mut result)) = match handle.await {
+            Ok(result) => result,
+            Err(err) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"Error": format!("{}", err)})),
+            ),
+        };
+        result["status"] = json!(status.as_u16());
+        results.push(result);
+    }
+
+    state.write().await.qreg.release(qubits);
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn running_tasks_hold_their_own_qubits() {
+        // one task per thread
+        assert_eq!(running_tasks(&[2, 1, 1], 2, 8), (3, 2));
+        // only the widest tasks which fit in the idle qubits
+        assert_eq!(running_tasks(&[3, 2, 2], 4, 4), (3, 1));
+        assert_eq!(running_tasks(&[2, 2, 1], 4, 5), (5, 3));
+        assert_eq!(running_tasks(&[5, 1], 4, 4), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn qubits_are_released_after_the_batch() {
+        let state = crate::tests::state(4);
+        let task = |qubits: usize| {
+            Ok(serde_json::from_value(json!({"qasm": "", "qubits": qubits, "shots": 1})).unwrap())
+        };
+        let (status, json) = run_tasks(state.clone(), vec![task(5), task(1)])
+            .await
+            .unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(json["Error"], "No enough qubits");
+
+        let results = run_tasks(state.clone(), vec![task(3), task(2), task(2)])
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 3);
+        assert_eq!(state.read().await.qreg.idle, 4);
+    }
+
+    #[test]
+    fn malformed_task_fails_alone() {
+        let batch: BatchMessage = serde_json::from_value(json!([
+            {"qasm": "", "qubits": 1, "shots": 1},
+            {"qasm": "", "qubits": "one", "shots": 1},
+            {"qasm": "", "qubits": 2, "shots": 1, "seed": 3},
+        ]))
+        .unwrap();
+        let tasks = batch.into_tasks().unwrap();
+        assert_eq!(tasks.len(), 3);
+        assert!(tasks[0].is_ok());
+        assert!(tasks[1].as_ref().unwrap_err().starts_with("Invalid task"));
+        assert_eq!(tasks[2].as_ref().unwrap().seed, Some(3));
+    }
+
+    #[test]
+    fn bindings_extend_the_shared_variables() {
+        let batch: BatchMessage = serde_json::from_value(json!({
+            "qasm": "", "qubits": 1, "shots": 1, "job_id": "sweep",
+            "vars": "{\"a\": 1.0}",
+            "bindings": [{"b": 2.0}, {"a": 3.0}],
+        }))
+        .unwrap();
+        let tasks: Vec<EmulateMessage> = batch
+            .into_tasks()
+            .unwrap()
+            .into_iter()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let vars = |task: &EmulateMessage| {
+            serde_json::from_str::<HashMap<String, f64>>(task.vars.as_deref().unwrap()).unwrap()
+        };
+        assert_eq!(
+            vars(&tasks[0]),
+            HashMap::from([("a".into(), 1.0), ("b".into(), 2.0)])
+        );
+        assert_eq!(vars(&tasks[1]), HashMap::from([("a".into(), 3.0)]));
+        assert_eq!(tasks[1].job_id.as_deref(), Some("sweep-1"));
+    }
+}
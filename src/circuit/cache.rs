@@ -0,0 +1,210 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    sync::{Arc, Mutex},
+};
+
+use serde_json::{json, Value};
+
+use super::Circuit;
+
+struct CacheEntry {
+    source: String,
+    circuit: Arc<Circuit>,
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct CacheInner {
+    entries: HashMap<u64, CacheEntry>,
+    tick: u64,
+    hits: u64,
+    misses: u64,
+}
+
+/// LRU cache of parsed circuits keyed by the hash of the normalized QASM. The
+/// variables stay free in the cached circuit and are bound when it is run, so
+/// a parameter sweep parses the program only once.
+pub struct CircuitCache {
+    pub capacity: usize,
+    inner: Mutex<CacheInner>,
+}
+
+impl std::fmt::Debug for CircuitCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "CircuitCache {}", self.info())
+    }
+}
+
+/// drop the comments and the layout, so that the same program written
+/// differently hits the same entry
+pub fn normalize(qasm: &str) -> String {
+    qasm.lines()
+        .map(|line| line.split("//").next().unwrap_or(""))
+        .flat_map(|line| line.split_whitespace())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+impl CircuitCache {
+    pub fn new(capacity: usize) -> Self {
+        CircuitCache {
+            capacity,
+            inner: Mutex::new(CacheInner::default()),
+        }
+    }
+
+    /// read the capacity from `CIRCUIT_CACHE_SIZE`, 128 by default and 0
+    /// disables the cache
+    pub fn from_env() -> Self {
+        CircuitCache::new(
+            std::env::var("CIRCUIT_CACHE_SIZE")
+                .ok()
+                .and_then(|size| size.parse().ok())
+                .unwrap_or(128),
+        )
+    }
+
+    /// the parsed circuit of `qasm`, parsed only if it is not cached
+    pub fn get_or_parse(&self, qasm: &str) -> Result<Arc<Circuit>, String> {
+        let source = normalize(qasm);
+        let mut hasher = DefaultHasher::new();
+        source.hash(&mut hasher);
+        let key = hasher.finish();
+
+        {
+            let mut inner = self.inner.lock().unwrap();
+            inner.tick += 1;
+            let tick = inner.tick;
+            let hit = match inner.entries.get_mut(&key) {
+                Some(entry) if entry.source == source => {
+                    entry.last_used = tick;
+                    Some(entry.circuit.clone())
+                }
+                _ => None,
+            };
+            match hit {
+                Some(circuit) => {
+                    inner.hits += 1;
+                    return Ok(circuit);
+                }
+                None => inner.misses += 1,
+            }
+        }
+
+        // parse without holding the lock
+        let circuit = Arc::new(super::parse(qasm)?);
+        if self.capacity == 0 {
+            return Ok(circuit);
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        if inner.entries.len() >= self.capacity && !inner.entries.contains_key(&key) {
+            let oldest = inner
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| *key);
+            if let Some(oldest) = oldest {
+                inner.entries.remove(&oldest);
+            }
+        }
+        let last_used = inner.tick;
+        inner.entries.insert(
+            key,
+            CacheEntry {
+                source,
+                circuit: circuit.clone(),
+                last_used,
+            },
+        );
+        Ok(circuit)
+    }
+
+    pub fn info(&self) -> Value {
+        let inner = self.inner.lock().unwrap();
+        json!({
+            "capacity": self.capacity,
+            "size": inner.entries.len(),
+            "hits": inner.hits,
+            "misses": inner.misses,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn program(gate: &str) -> String {
+        format!(
+            "OPENQASM 2.0;\ninclude \"qelib1.inc\";\nqreg q[1];\n{} q[0];\n",
+            gate
+        )
+    }
+
+    fn counters(cache: &CircuitCache) -> (Value, Value, Value) {
+        let info = cache.info();
+        (
+            info["size"].clone(),
+            info["hits"].clone(),
+            info["misses"].clone(),
+        )
+    }
+
+    #[test]
+    fn hits_and_misses_are_counted() {
+        let cache = CircuitCache::new(4);
+        let first = cache.get_or_parse(&program("x")).unwrap();
+        let second = cache.get_or_parse(&program("x")).unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+        cache.get_or_parse(&program("h")).unwrap();
+        assert_eq!(counters(&cache), (json!(2), json!(1), json!(2)));
+
+        // a program which does not parse is a miss and is not cached
+        assert!(cache
+            .get_or_parse("OPENQASM 2.0;\nqreg q[1];\nfoo q[0];\n")
+            .is_err());
+        assert_eq!(counters(&cache), (json!(2), json!(1), json!(3)));
+    }
+
+    #[test]
+    fn layout_and_comments_share_an_entry() {
+        let cache = CircuitCache::new(4);
+        cache.get_or_parse(&program("rx(theta)")).unwrap();
+        let rewritten = "OPENQASM 2.0;  // header\n\ninclude \"qelib1.inc\";\n\
+                         qreg  q[1];\n  rx(theta)   q[0]; // the variable stays free\n";
+        assert_eq!(normalize(rewritten), normalize(&program("rx(theta)")));
+        let circuit = cache.get_or_parse(rewritten).unwrap();
+        assert_eq!(circuit.free_vars(), ["theta"]);
+        assert_eq!(counters(&cache), (json!(1), json!(1), json!(1)));
+    }
+
+    #[test]
+    fn least_recently_used_entry_is_evicted() {
+        let cache = CircuitCache::new(2);
+        let x = cache.get_or_parse(&program("x")).unwrap();
+        cache.get_or_parse(&program("h")).unwrap();
+        // x is used again, so h is the least recently used
+        cache.get_or_parse(&program("x")).unwrap();
+        cache.get_or_parse(&program("z")).unwrap();
+        assert_eq!(counters(&cache), (json!(2), json!(1), json!(3)));
+
+        assert!(Arc::ptr_eq(&x, &cache.get_or_parse(&program("x")).unwrap()));
+        cache.get_or_parse(&program("h")).unwrap();
+        assert_eq!(counters(&cache), (json!(2), json!(2), json!(4)));
+    }
+
+    #[test]
+    fn zero_capacity_disables_the_cache() {
+        std::env::set_var("CIRCUIT_CACHE_SIZE", "0");
+        let cache = CircuitCache::from_env();
+        std::env::remove_var("CIRCUIT_CACHE_SIZE");
+        assert_eq!(cache.capacity, 0);
+        let first = cache.get_or_parse(&program("x")).unwrap();
+        let second = cache.get_or_parse(&program("x")).unwrap();
+        assert!(!Arc::ptr_eq(&first, &second));
+        assert_eq!(counters(&cache), (json!(0), json!(0), json!(2)));
+        assert_eq!(CircuitCache::from_env().capacity, 128);
+    }
+}
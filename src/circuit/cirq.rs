@@ -0,0 +1,558 @@
+use std::collections::HashMap;
+
+use serde_json::{json, Map, Value};
+
+use super::{Circuit, Expr, Instruction, Op, Register};
+
+/// a qubit of Cirq, ordered as Cirq orders them
+#[derive(Debug, Clone, PartialEq, PartialOrd)]
+enum CirqQubit {
+    Line(i64),
+    Grid(i64, i64),
+    Named(String),
+}
+
+fn qubit(path: &str, value: &Value) -> Result<CirqQubit, String> {
+    let int = |key: &str| {
+        value[key]
+            .as_i64()
+            .ok_or_else(|| format!("{}.{}: expected an integer", path, key))
+    };
+    match value["cirq_type"].as_str() {
+        Some("LineQubit") => Ok(CirqQubit::Line(int("x")?)),
+        Some("GridQubit") => Ok(CirqQubit::Grid(int("row")?, int("col")?)),
+        Some("NamedQubit") => match value["name"].as_str() {
+            Some(name) => Ok(CirqQubit::Named(name.to_string())),
+            None => Err(format!("{}.name: expected a string", path)),
+        },
+        other => Err(format!("{}: unsupported qubit {:?}", path, other)),
+    }
+}
+
+/// a number or a sympy expression of the variables
+fn expr(path: &str, value: &Value) -> Result<Expr, String> {
+    if let Some(x) = value.as_f64() {
+        return Ok(Expr::Num(x));
+    }
+    let args = || -> Result<Vec<Expr>, String> {
+        value["args"]
+            .as_array()
+            .ok_or_else(|| format!("{}.args: expected a list", path))?
+            .iter()
+            .enumerate()
+            .map(|(i, arg)| expr(&format!("{}.args[{}]", path, i), arg))
+            .collect()
+    };
+    let fold = |args: Vec<Expr>, op: fn(Box<Expr>, Box<Expr>) -> Expr| {
+        args.into_iter()
+            .reduce(|a, b| op(Box::new(a), Box::new(b)))
+            .ok_or_else(|| format!("{}.args: expected at least one argument", path))
+    };
+    match value["cirq_type"].as_str() {
+        Some("sympy.Symbol") => match value["name"].as_str() {
+            Some(name) => Ok(Expr::Var(name.to_string())),
+            None => Err(format!("{}.name: expected a string", path)),
+        },
+        Some("sympy.Integer") => Ok(Expr::Num(value["i"].as_f64().unwrap_or(0.0))),
+        Some("sympy.Float") => Ok(Expr::Num(value["approx"].as_f64().unwrap_or(0.0))),
+        Some("sympy.Rational") => Ok(Expr::Div(
+            Box::new(Expr::Num(value["p"].as_f64().unwrap_or(0.0))),
+            Box::new(Expr::Num(value["q"].as_f64().unwrap_or(1.0))),
+        )),
+        Some("sympy.Add") => fold(args()?, Expr::Add),
+        Some("sympy.Mul") => fold(args()?, Expr::Mul),
+        Some("sympy.Pow") => fold(args()?, Expr::Pow),
+        other => Err(format!("{}: unsupported value {:?}", path, other)),
+    }
+}
+
+/// a value in Cirq JSON, a number if it has no variables
+fn cirq_value(expr: &Expr) -> Value {
+    if let Ok(x) = expr.eval(&HashMap::new()) {
+        return json!(x);
+    }
+    let sympy = |ty: &str, args: Vec<Value>| json!({"cirq_type": ty, "args": args});
+    match expr {
+        Expr::Var(name) => json!({"cirq_type": "sympy.Symbol", "name": name}),
+        Expr::Neg(x) => sympy("sympy.Mul", vec![json!(-1), cirq_value(x)]),
+        Expr::Add(a, b) => sympy("sympy.Add", vec![cirq_value(a), cirq_value(b)]),
+        Expr::Sub(a, b) => sympy(
+            "sympy.Add",
+            vec![
+                cirq_value(a),
+                sympy("sympy.Mul", vec![json!(-1), cirq_value(b)]),
+            ],
+        ),
+        Expr::Mul(a, b) => sympy("sympy.Mul", vec![cirq_value(a), cirq_value(b)]),
+        Expr::Div(a, b) => sympy(
+            "sympy.Mul",
+            vec![
+                cirq_value(a),
+                sympy("sympy.Pow", vec![cirq_value(b), json!(-1)]),
+            ],
+        ),
+        Expr::Pow(a, b) => sympy("sympy.Pow", vec![cirq_value(a), cirq_value(b)]),
+        // functions of variables have no Cirq form, they are kept as a symbol
+        _ => json!({"cirq_type": "sympy.Symbol", "name": expr.to_string()}),
+    }
+}
+
+/// `exponent * pi`, the angle of a `*PowGate`
+fn exponent_angle(exponent: Expr) -> Expr {
+    match exponent {
+        Expr::Num(x) => Expr::Num(x * std::f64::consts::PI),
+        exponent => Expr::Mul(Box::new(exponent), Box::new(Expr::Pi)),
+    }
+}
+
+/// `angle / pi`, the exponent of a `*PowGate`
+fn angle_exponent(angle: &Expr) -> Expr {
+    Expr::Div(Box::new(angle.clone()), Box::new(Expr::Pi))
+}
+
+/// the gates of an operation, the `*PowGate`s are equal to the gates of the
+/// agent up to a global phase
+fn gate_ops(path: &str, gate: &Value, qubits: &[usize]) -> Result<Vec<Op>, String> {
+    let exponent = match gate.get("exponent") {
+        Some(exponent) => expr(&format!("{}.exponent", path), exponent)?,
+        None => Expr::Num(1.0),
+    };
+    let is_one = exponent == Expr::Num(1.0);
+    let rads = || expr(&format!("{}.rads", path), &gate["rads"]);
+    let op = |name: &str, params: Vec<Expr>| Op::Gate {
+        name: name.to_string(),
+        params,
+        qubits: qubits.to_vec(),
+    };
+
+    let ty = gate["cirq_type"].as_str().unwrap_or("");
+    let ops = match ty {
+        "ResetChannel" => qubits.iter().map(|&qubit| Op::Reset { qubit }).collect(),
+        "IdentityGate" => qubits
+            .iter()
+            .map(|&qubit| Op::Gate {
+                name: "id".to_string(),
+                params: Vec::new(),
+                qubits: vec![qubit],
+            })
+            .collect(),
+        "XPowGate" if is_one => vec![op("x", vec![])],
+        "YPowGate" if is_one => vec![op("y", vec![])],
+        "ZPowGate" if is_one => vec![op("z", vec![])],
+        "HPowGate" if is_one => vec![op("h", vec![])],
+        "CXPowGate" | "CNotPowGate" if is_one => vec![op("cx", vec![])],
+        "CZPowGate" if is_one => vec![op("cz", vec![])],
+        "SwapPowGate" if is_one => vec![op("swap", vec![])],
+        "CCXPowGate" | "CCNotPowGate" if is_one => vec![op("ccx", vec![])],
+        "CSwapGate" => vec![op("cswap", vec![])],
+        "XPowGate" => vec![op("rx", vec![exponent_angle(exponent)])],
+        "YPowGate" => vec![op("ry", vec![exponent_angle(exponent)])],
+        "ZPowGate" => vec![op("p", vec![exponent_angle(exponent)])],
+        "CZPowGate" => vec![op("cp", vec![exponent_angle(exponent)])],
+        "Rx" => vec![op("rx", vec![rads()?])],
+        "Ry" => vec![op("ry", vec![rads()?])],
+        "Rz" => vec![op("rz", vec![rads()?])],
+        "" => return Err(format!("{}.cirq_type: expected a gate", path)),
+        _ if gate.get("exponent").is_some() => {
+            return Err(format!(
+                "{}: {} with exponent {} is not supported",
+                path, ty, exponent
+            ))
+        }
+        _ => return Err(format!("{}: unsupported gate {}", path, ty)),
+    };
+    Ok(ops)
+}
+
+/// the classical bit of a measurement key, `c[1]` is the bit 1 of `c`
+fn measurement_key(key: &str) -> (String, Option<usize>) {
+    if let Some((name, index)) = key.strip_suffix(']').and_then(|key| key.split_once('[')) {
+        if let Ok(index) = index.parse() {
+            return (name.to_string(), Some(index));
+        }
+    }
+    (key.to_string(), None)
+}
+
+/// Parse a Cirq circuit serialized by `cirq.to_json`. The qubits are one
+/// register `q` in Cirq order, each measurement key is a classical register,
+/// a key `c[1]` is the bit 1 of the register `c`. Sympy symbols in the
+/// exponents are kept as variables.
+pub fn parse(source: &str) -> Result<Circuit, String> {
+    let value: Value = serde_json::from_str(source).map_err(|err| format!("cirq_json: {}", err))?;
+    if value["cirq_type"] != "Circuit" {
+        return Err("cirq_type: expected a Circuit".to_string());
+    }
+    let moments = value["moments"]
+        .as_array()
+        .ok_or("moments: expected a list")?;
+
+    // collect the qubits and the registers first, in Cirq order
+    let mut operations = Vec::new();
+    let mut qubits = Vec::new();
+    let mut cregs: Vec<(String, usize)> = Vec::new();
+    for (i, moment) in moments.iter().enumerate() {
+        let ops = moment["operations"]
+            .as_array()
+            .ok_or(format!("moments[{}].operations: expected a list", i))?;
+        for (j, operation) in ops.iter().enumerate() {
+            let path = format!("moments[{}].operations[{}]", i, j);
+            if operation["cirq_type"] != "GateOperation" {
+                return Err(format!(
+                    "{}: unsupported operation {}",
+                    path, operation["cirq_type"]
+                ));
+            }
+            let mut targets = Vec::new();
+            for (k, q) in operation["qubits"]
+                .as_array()
+                .ok_or(format!("{}.qubits: expected a list", path))?
+                .iter()
+                .enumerate()
+            {
+                let q = qubit(&format!("{}.qubits[{}]", path, k), q)?;
+                if !qubits.contains(&q) {
+                    qubits.push(q.clone());
+                }
+                targets.push(q);
+            }
+
+            let gate = &operation["gate"];
+            if gate["cirq_type"] == "MeasurementGate" {
+                let key = gate["key"]
+                    .as_str()
+                    .ok_or(format!("{}.gate.key: expected a string", path))?;
+                let (name, index) = measurement_key(key);
+                let size = index.map_or(targets.len(), |index| index + 1);
+                match cregs.iter_mut().find(|(creg, _)| *creg == name) {
+                    Some((_, creg_size)) if index.is_some() => *creg_size = size.max(*creg_size),
+                    Some(_) => return Err(format!("{}.gate.key: {} is measured twice", path, key)),
+                    None => cregs.push((name, size)),
+                }
+            }
+            operations.push((path, targets, gate));
+        }
+    }
+    let mut sorted = qubits.clone();
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+
+    let mut circuit = Circuit {
+        qregs: vec![Register {
+            name: "q".to_string(),
+            size: sorted.len(),
+            offset: 0,
+        }],
+        cregs: Vec::new(),
+        instructions: Vec::new(),
+    };
+    for (name, size) in cregs {
+        let offset = circuit.num_clbits();
+        circuit.cregs.push(Register { name, size, offset });
+    }
+
+    for (path, targets, gate) in operations {
+        let targets: Vec<usize> = targets
+            .iter()
+            .map(|q| sorted.iter().position(|s| s == q).unwrap())
+            .collect();
+        let ops = if gate["cirq_type"] == "MeasurementGate" {
+            let (name, index) = measurement_key(gate["key"].as_str().unwrap());
+            let creg = circuit.creg(&name).unwrap();
+            if index.is_some() && targets.len() != 1 {
+                return Err(format!("{}.gate.key: {} measures one qubit", path, name));
+            }
+            let invert: Vec<bool> = gate["invert_mask"]
+                .as_array()
+                .map(|mask| mask.iter().map(|bit| bit.as_bool() == Some(true)).collect())
+                .unwrap_or_default();
+
+            let mut ops = Vec::new();
+            for (i, &qubit) in targets.iter().enumerate() {
+                // the bits of the key are reg[0] first
+                let clbit = creg.offset + index.unwrap_or(i);
+                let flip = invert.get(i).copied().unwrap_or(false);
+                let x = Op::Gate {
+                    name: "x".to_string(),
+                    params: Vec::new(),
+                    qubits: vec![qubit],
+                };
+                if flip {
+                    ops.push(x.clone());
+                }
+                ops.push(Op::Measure { qubit, clbit });
+                if flip {
+                    ops.push(x);
+                }
+            }
+            ops
+        } else {
+            gate_ops(&format!("{}.gate", path), gate, &targets)?
+        };
+        circuit
+            .instructions
+            .extend(ops.into_iter().map(|op| Instruction {
+                op,
+                condition: None,
+            }));
+    }
+    Ok(circuit)
+}
+
+/// the Cirq gate of a gate of the agent
+fn cirq_gate(name: &str, params: &[Expr]) -> Result<Vec<Value>, String> {
+    let pow = |ty: &str, exponent: Value| json!({"cirq_type": ty, "exponent": exponent, "global_shift": 0.0});
+    let rot = |ty: &str, rads: &Expr| json!({"cirq_type": ty, "rads": cirq_value(rads)});
+    Ok(match name {
+        "id" => vec![json!({"cirq_type": "IdentityGate", "qid_shape": [2]})],
+        "x" => vec![pow("XPowGate", json!(1.0))],
+        "y" => vec![pow("YPowGate", json!(1.0))],
+        "z" => vec![pow("ZPowGate", json!(1.0))],
+        "h" => vec![pow("HPowGate", json!(1.0))],
+        "s" => vec![pow("ZPowGate", json!(0.5))],
+        "sdg" => vec![pow("ZPowGate", json!(-0.5))],
+        "t" => vec![pow("ZPowGate", json!(0.25))],
+        "tdg" => vec![pow("ZPowGate", json!(-0.25))],
+        "sx" => vec![pow("XPowGate", json!(0.5))],
+        "sxdg" => vec![pow("XPowGate", json!(-0.5))],
+        "rx" => vec![rot("Rx", &params[0])],
+        "ry" => vec![rot("Ry", &params[0])],
+        "rz" => vec![rot("Rz", &params[0])],
+        "p" | "u1" => vec![pow("ZPowGate", cirq_value(&angle_exponent(&params[0])))],
+        // u3(theta, phi, lambda) = rz(phi) ry(theta) rz(lambda)
+        "u3" => vec![
+            rot("Rz", &params[2]),
+            rot("Ry", &params[0]),
+            rot("Rz", &params[1]),
+        ],
+        "u2" => vec![
+            rot("Rz", &params[1]),
+            rot(
+                "Ry",
+                &Expr::Div(Box::new(Expr::Pi), Box::new(Expr::Num(2.0))),
+            ),
+            rot("Rz", &params[0]),
+        ],
+        "cx" => vec![pow("CXPowGate", json!(1.0))],
+        "cz" => vec![pow("CZPowGate", json!(1.0))],
+        "cp" | "cu1" => vec![pow("CZPowGate", cirq_value(&angle_exponent(&params[0])))],
+        "swap" => vec![pow("SwapPowGate", json!(1.0))],
+        "ccx" => vec![pow("CCXPowGate", json!(1.0))],
+        "cswap" => vec![json!({"cirq_type": "CSwapGate"})],
+        _ => return Err(format!("gate {} cannot be written in Cirq JSON", name)),
+    })
+}
+
+/// Write the circuit as Cirq JSON with `LineQubit`s. The operations are put
+/// in the earliest moment where their qubits are free, and each measurement
+/// has the key of its classical bit, e.g. `c[1]`. Conditions are not
+/// supported.
+pub fn to_cirq_json(circuit: &Circuit) -> Result<String, String> {
+    let line_qubit = |qubit: usize| json!({"cirq_type": "LineQubit", "x": qubit});
+    let mut moments: Vec<Vec<Value>> = Vec::new();
+    // the first free moment of each qubit
+    let mut free = vec![0; circuit.num_qubits()];
+
+    for inst in circuit.instructions.iter() {
+        if inst.condition.is_some() {
+            return Err("conditions cannot be written in Cirq JSON".to_string());
+        }
+        let (gates, qubits) = match &inst.op {
+            Op::Gate {
+                name,
+                params,
+                qubits,
+            } => (cirq_gate(name, params)?, qubits.clone()),
+            Op::Measure { qubit, clbit } => {
+                let mut gate = Map::new();
+                gate.insert("cirq_type".to_string(), json!("MeasurementGate"));
+                gate.insert("num_qubits".to_string(), json!(1));
+                gate.insert("key".to_string(), json!(circuit.clbit_name(*clbit)));
+                gate.insert("invert_mask".to_string(), json!([]));
+                (vec![Value::Object(gate)], vec![*qubit])
+            }
+            Op::Reset { qubit } => (
+                vec![json!({"cirq_type": "ResetChannel", "dimension": 2})],
+                vec![*qubit],
+            ),
+            Op::Barrier { qubits } => {
+                // later operations on these qubits start after all of them
+                let moment = qubits.iter().map(|&qubit| free[qubit]).max().unwrap_or(0);
+                qubits.iter().for_each(|&qubit| free[qubit] = moment);
+                continue;
+            }
+        };
+
+        for gate in gates {
+            let moment = qubits.iter().map(|&qubit| free[qubit]).max().unwrap_or(0);
+            if moment == moments.len() {
+                moments.push(Vec::new());
+            }
+            moments[moment].push(json!({
+                "cirq_type": "GateOperation",
+                "gate": gate,
+                "qubits": qubits.iter().map(|&qubit| line_qubit(qubit)).collect::<Vec<_>>(),
+            }));
+            qubits.iter().for_each(|&qubit| free[qubit] = moment + 1);
+        }
+    }
+
+    let moments: Vec<Value> = moments
+        .into_iter()
+        .map(|operations| json!({"cirq_type": "Moment", "operations": operations}))
+        .collect();
+    serde_json::to_string_pretty(&json!({"cirq_type": "Circuit", "moments": moments}))
+        .map_err(|err| err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{circuit::qasm2, simulator::statevector::StateVector};
+    use num::complex::Complex64;
+
+    /// a state where no amplitude is 0, so that the gates are told apart
+    const PREPARE: &str = "qreg q[3];\ncreg c[3];\nh q[0];\nry(0.3) q[1];\nrx(0.7) q[2];\n";
+
+    fn circuit(body: &str) -> Circuit {
+        qasm2::parse(&format!(
+            "OPENQASM 2.0;\ninclude \"qelib1.inc\";\n{}{}",
+            PREPARE, body
+        ))
+        .unwrap()
+    }
+
+    fn amplitudes(circuit: &Circuit) -> Vec<Complex64> {
+        let mut state = StateVector::new(circuit.num_qubits()).unwrap();
+        for inst in circuit.instructions.iter() {
+            if let Op::Gate {
+                name,
+                params,
+                qubits,
+            } = &inst.op
+            {
+                let params: Vec<f64> = params
+                    .iter()
+                    .map(|param| param.eval(&Default::default()).unwrap())
+                    .collect();
+                state.apply_gate(name, &params, qubits).unwrap();
+            }
+        }
+        state.amplitudes
+    }
+
+    /// the states are equal up to a global phase
+    fn assert_same_state(a: &Circuit, b: &Circuit) {
+        let (a, b) = (amplitudes(a), amplitudes(b));
+        let phase = a[0] / b[0];
+        for (x, y) in a.iter().zip(b.iter()) {
+            assert!((x - y * phase).norm() < 1e-9, "{:?} != {:?}", a, b);
+        }
+    }
+
+    #[test]
+    fn gates_are_kept_by_a_round_trip() {
+        for gate in [
+            "id q[0];",
+            "x q[0];",
+            "y q[0];",
+            "z q[0];",
+            "h q[0];",
+            "s q[0];",
+            "sdg q[0];",
+            "t q[0];",
+            "tdg q[0];",
+            "sx q[0];",
+            "sxdg q[0];",
+            "rx(0.4) q[0];",
+            "ry(0.4) q[0];",
+            "rz(0.4) q[0];",
+            "p(0.4) q[0];",
+            "u1(0.4) q[0];",
+            "u2(0.4,1.1) q[0];",
+            "u3(0.4,1.1,-0.6) q[0];",
+            "cx q[0],q[1];",
+            "cz q[0],q[1];",
+            "cp(0.4) q[0],q[1];",
+            "cu1(0.4) q[0],q[1];",
+            "swap q[0],q[1];",
+            "ccx q[0],q[1],q[2];",
+            "cswap q[0],q[1],q[2];",
+        ] {
+            let original = circuit(gate);
+            let json = to_cirq_json(&original).unwrap();
+            let parsed = parse(&json).unwrap_or_else(|err| panic!("{}: {}\n{}", gate, err, json));
+            assert_same_state(&original, &parsed);
+        }
+    }
+
+    #[test]
+    fn measurements_and_variables_are_kept_by_a_round_trip() {
+        let original = circuit("rx(theta) q[0];\nmeasure q[0] -> c[2];\nmeasure q[1] -> c[0];\n");
+        let parsed = parse(&to_cirq_json(&original).unwrap()).unwrap();
+        assert_eq!(parsed.cregs, original.cregs);
+        assert_eq!(parsed.free_vars(), ["theta"]);
+        let measures: Vec<&Op> = parsed
+            .instructions
+            .iter()
+            .map(|inst| &inst.op)
+            .filter(|op| matches!(op, Op::Measure { .. }))
+            .collect();
+        // q[1] is free first, so it is measured in an earlier moment
+        assert_eq!(
+            measures,
+            [
+                &Op::Measure { qubit: 1, clbit: 0 },
+                &Op::Measure { qubit: 0, clbit: 2 }
+            ]
+        );
+    }
+
+    #[test]
+    fn gates_without_cirq_json_are_errors() {
+        for (gate, name) in [
+            ("cy q[0],q[1];", "cy"),
+            ("ch q[0],q[1];", "ch"),
+            ("csx q[0],q[1];", "csx"),
+            ("crx(0.4) q[0],q[1];", "crx"),
+            ("cry(0.4) q[0],q[1];", "cry"),
+            ("crz(0.4) q[0],q[1];", "crz"),
+            ("cu3(0.1,0.2,0.3) q[0],q[1];", "cu3"),
+            ("cu(0.1,0.2,0.3,0.4) q[0],q[1];", "cu"),
+            ("rxx(0.4) q[0],q[1];", "rxx"),
+            ("rzz(0.4) q[0],q[1];", "rzz"),
+        ] {
+            assert_eq!(
+                to_cirq_json(&circuit(gate)).unwrap_err(),
+                format!("gate {} cannot be written in Cirq JSON", name)
+            );
+        }
+        assert_eq!(
+            to_cirq_json(&circuit("measure q[0] -> c[0];\nif(c==1) x q[1];\n")).unwrap_err(),
+            "conditions cannot be written in Cirq JSON"
+        );
+    }
+
+    #[test]
+    fn unsupported_cirq_json_is_an_error() {
+        let moment = |gate: Value| {
+            json!({"cirq_type": "Circuit", "moments": [{"cirq_type": "Moment", "operations": [{
+                "cirq_type": "GateOperation",
+                "gate": gate,
+                "qubits": [{"cirq_type": "LineQubit", "x": 0}],
+            }]}]})
+            .to_string()
+        };
+        assert_eq!(
+            parse(&moment(json!({"cirq_type": "PhasedXPowGate"}))).unwrap_err(),
+            "moments[0].operations[0].gate: unsupported gate PhasedXPowGate"
+        );
+        assert_eq!(
+            parse(&moment(json!({"cirq_type": "HPowGate", "exponent": 0.5}))).unwrap_err(),
+            "moments[0].operations[0].gate: HPowGate with exponent 0.5 is not supported"
+        );
+        assert_eq!(
+            parse("{\"cirq_type\": \"Moment\"}").unwrap_err(),
+            "cirq_type: expected a Circuit"
+        );
+    }
+}
@@ -0,0 +1,300 @@
+use num::complex::Complex64;
+
+pub type Matrix2 = [[Complex64; 2]; 2];
+
+/// Gates understood by the agent as `(name, parameters, qubits)`, the names
+/// and definitions follow `qelib1.inc`
+pub const GATES: &[(&str, usize, usize)] = &[
+    ("id", 0, 1),
+    ("x", 0, 1),
+    ("y", 0, 1),
+    ("z", 0, 1),
+    ("h", 0, 1),
+    ("s", 0, 1),
+    ("sdg", 0, 1),
+    ("t", 0, 1),
+    ("tdg", 0, 1),
+    ("sx", 0, 1),
+    ("sxdg", 0, 1),
+    ("rx", 1, 1),
+    ("ry", 1, 1),
+    ("rz", 1, 1),
+    ("p", 1, 1),
+    ("u1", 1, 1),
+    ("u2", 2, 1),
+    ("u3", 3, 1),
+    ("cx", 0, 2),
+    ("cy", 0, 2),
+    ("cz", 0, 2),
+    ("ch", 0, 2),
+    ("csx", 0, 2),
+    ("crx", 1, 2),
+    ("cry", 1, 2),
+    ("crz", 1, 2),
+    ("cp", 1, 2),
+    ("cu1", 1, 2),
+    ("cu3", 3, 2),
+    ("cu", 4, 2),
+    ("swap", 0, 2),
+    ("rxx", 1, 2),
+    ("rzz", 1, 2),
+    ("ccx", 0, 3),
+    ("cswap", 0, 3),
+];
+
+/// number of parameters and qubits of a gate
+pub fn gate_info(name: &str) -> Option<(usize, usize)> {
+    GATES
+        .iter()
+        .find(|(gate, _, _)| *gate == name)
+        .map(|&(_, params, qubits)| (params, qubits))
+}
+
+/// map the builtin and alias names to the names used in `GATES`
+pub fn canonical_name(name: &str) -> &str {
+    match name {
+        "U" | "u" => "u3",
+        "CX" => "cx",
+        "phase" => "p",
+        "cphase" => "cp",
+        "toffoli" => "ccx",
+        "fredkin" => "cswap",
+        _ => name,
+    }
+}
+
+/// Definitions of the gates which are not in the original `qelib1.inc`, so
+/// that circuits using them can still be run by `qasmsim`
+pub const QELIB1_EXTENSIONS: &[(&str, &str)] = &[
+    ("p", "gate p(lambda) a { u1(lambda) a; }"),
+    ("sx", "gate sx a { sdg a; h a; sdg a; }"),
+    ("sxdg", "gate sxdg a { s a; h a; s a; }"),
+    ("swap", "gate swap a,b { cx a,b; cx b,a; cx a,b; }"),
+    ("cswap", "gate cswap a,b,c { cx c,b; ccx a,b,c; cx c,b; }"),
+    (
+        "crx",
+        "gate crx(lambda) a,b { u1(pi/2) b; cx a,b; u3(-lambda/2,0,0) b; cx a,b; u3(lambda/2,-pi/2,0) b; }",
+    ),
+    (
+        "cry",
+        "gate cry(lambda) a,b { ry(lambda/2) b; cx a,b; ry(-lambda/2) b; cx a,b; }",
+    ),
+    (
+        "cp",
+        "gate cp(lambda) a,b { u1(lambda/2) a; cx a,b; u1(-lambda/2) b; cx a,b; u1(lambda/2) b; }",
+    ),
+    ("csx", "gate csx a,b { h b; cu1(pi/2) a,b; h b; }"),
+    (
+        "cu",
+        "gate cu(theta,phi,lambda,gamma) c,t { u1(gamma) c; u1((lambda+phi)/2) c; u1((lambda-phi)/2) t; cx c,t; u3(-theta/2,0,-(phi+lambda)/2) t; cx c,t; u3(theta/2,phi,0) t; }",
+    ),
+    (
+        "rxx",
+        "gate rxx(theta) a,b { u3(pi/2,theta,0) a; h b; cx a,b; u1(-theta) b; cx a,b; h b; u2(-pi,pi-theta) a; }",
+    ),
+    ("rzz", "gate rzz(theta) a,b { cx a,b; u1(theta) b; cx a,b; }"),
+];
+
+/// the definition of a gate which `qasmsim` does not know
+pub fn qelib1_extension(name: &str) -> Option<&'static str> {
+    QELIB1_EXTENSIONS
+        .iter()
+        .find(|(gate, _)| *gate == name)
+        .map(|(_, definition)| *definition)
+}
+
+fn c(re: f64, im: f64) -> Complex64 {
+    Complex64::new(re, im)
+}
+
+fn u3(theta: f64, phi: f64, lambda: f64) -> Matrix2 {
+    let (cos, sin) = ((theta / 2.0).cos(), (theta / 2.0).sin());
+    [
+        [c(cos, 0.0), -Complex64::from_polar(sin, lambda)],
+        [
+            Complex64::from_polar(sin, phi),
+            Complex64::from_polar(cos, phi + lambda),
+        ],
+    ]
+}
+
+/// matrix of a single qubit gate
+pub fn matrix_1q(name: &str, params: &[f64]) -> Option<Matrix2> {
+    let sqrt_half = std::f64::consts::FRAC_1_SQRT_2;
+    let matrix = match (name, params) {
+        ("id", []) => [[c(1.0, 0.0), c(0.0, 0.0)], [c(0.0, 0.0), c(1.0, 0.0)]],
+        ("x", []) => [[c(0.0, 0.0), c(1.0, 0.0)], [c(1.0, 0.0), c(0.0, 0.0)]],
+        ("y", []) => [[c(0.0, 0.0), c(0.0, -1.0)], [c(0.0, 1.0), c(0.0, 0.0)]],
+        ("z", []) => [[c(1.0, 0.0), c(0.0, 0.0)], [c(0.0, 0.0), c(-1.0, 0.0)]],
+        ("h", []) => [
+            [c(sqrt_half, 0.0), c(sqrt_half, 0.0)],
+            [c(sqrt_half, 0.0), c(-sqrt_half, 0.0)],
+        ],
+        ("s", []) => [[c(1.0, 0.0), c(0.0, 0.0)], [c(0.0, 0.0), c(0.0, 1.0)]],
+        ("sdg", []) => [[c(1.0, 0.0), c(0.0, 0.0)], [c(0.0, 0.0), c(0.0, -1.0)]],
+        ("t", []) => [
+            [c(1.0, 0.0), c(0.0, 0.0)],
+            [c(0.0, 0.0), c(sqrt_half, sqrt_half)],
+        ],
+        ("tdg", []) => [
+            [c(1.0, 0.0), c(0.0, 0.0)],
+            [c(0.0, 0.0), c(sqrt_half, -sqrt_half)],
+        ],
+        ("sx", []) => [[c(0.5, 0.5), c(0.5, -0.5)], [c(0.5, -0.5), c(0.5, 0.5)]],
+        ("sxdg", []) => [[c(0.5, -0.5), c(0.5, 0.5)], [c(0.5, 0.5), c(0.5, -0.5)]],
+        ("rx", [theta]) => {
+            let (cos, sin) = ((theta / 2.0).cos(), (theta / 2.0).sin());
+            [[c(cos, 0.0), c(0.0, -sin)], [c(0.0, -sin), c(cos, 0.0)]]
+        }
+        ("ry", [theta]) => {
+            let (cos, sin) = ((theta / 2.0).cos(), (theta / 2.0).sin());
+            [[c(cos, 0.0), c(-sin, 0.0)], [c(sin, 0.0), c(cos, 0.0)]]
+        }
+        ("rz", [theta]) => [
+            [Complex64::from_polar(1.0, -theta / 2.0), c(0.0, 0.0)],
+            [c(0.0, 0.0), Complex64::from_polar(1.0, theta / 2.0)],
+        ],
+        ("p", [lambda]) | ("u1", [lambda]) => [
+            [c(1.0, 0.0), c(0.0, 0.0)],
+            [c(0.0, 0.0), Complex64::from_polar(1.0, *lambda)],
+        ],
+        ("u2", [phi, lambda]) => u3(std::f64::consts::FRAC_PI_2, *phi, *lambda),
+        ("u3", [theta, phi, lambda]) => u3(*theta, *phi, *lambda),
+        _ => return None,
+    };
+    Some(matrix)
+}
+
+/// A gate which is a single qubit gate on the last qubit, controlled by all
+/// the other qubits
+pub fn controlled_1q(name: &str, params: &[f64]) -> Option<(usize, Matrix2)> {
+    match (name, params) {
+        ("ccx", []) => Some((2, matrix_1q("x", &[])?)),
+        ("cu1", _) => Some((1, matrix_1q("u1", params)?)),
+        ("cu3", _) => Some((1, matrix_1q("u3", params)?)),
+        ("cu", [theta, phi, lambda, gamma]) => {
+            let phase = Complex64::from_polar(1.0, *gamma);
+            let mut matrix = u3(*theta, *phi, *lambda);
+            matrix.iter_mut().flatten().for_each(|x| *x *= phase);
+            Some((1, matrix))
+        }
+        ("cx" | "cy" | "cz" | "ch" | "csx" | "crx" | "cry" | "crz" | "cp", _) => {
+            Some((1, matrix_1q(&name[1..], params)?))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::{FRAC_PI_2, FRAC_PI_4, PI};
+
+    use super::*;
+
+    fn mul(a: &Matrix2, b: &Matrix2) -> Matrix2 {
+        let mut product = [[c(0.0, 0.0); 2]; 2];
+        for (i, row) in product.iter_mut().enumerate() {
+            for (j, entry) in row.iter_mut().enumerate() {
+                *entry = a[i][0] * b[0][j] + a[i][1] * b[1][j];
+            }
+        }
+        product
+    }
+
+    fn dagger(a: &Matrix2) -> Matrix2 {
+        [
+            [a[0][0].conj(), a[1][0].conj()],
+            [a[0][1].conj(), a[1][1].conj()],
+        ]
+    }
+
+    fn assert_close(a: &Matrix2, b: &Matrix2) {
+        for (x, y) in a.iter().flatten().zip(b.iter().flatten()) {
+            assert!((x - y).norm() < 1e-12, "{:?} != {:?}", a, b);
+        }
+    }
+
+    /// equal up to a global phase
+    fn assert_equivalent(a: &Matrix2, b: &Matrix2) {
+        let (i, j) = if a[0][0].norm() > 1e-6 {
+            (0, 0)
+        } else {
+            (0, 1)
+        };
+        let phase = b[i][j] / a[i][j];
+        let mut scaled = *a;
+        scaled.iter_mut().flatten().for_each(|x| *x *= phase);
+        assert_close(&scaled, b);
+    }
+
+    fn gate(name: &str, params: &[f64]) -> Matrix2 {
+        matrix_1q(name, params).unwrap()
+    }
+
+    #[test]
+    fn single_qubit_gates_are_unitary() {
+        let identity = gate("id", &[]);
+        for &(name, params, qubits) in GATES {
+            if qubits != 1 {
+                continue;
+            }
+            let params: Vec<f64> = [0.3, 1.1, -2.0][..params].to_vec();
+            let matrix = gate(name, &params);
+            assert_close(&mul(&matrix, &dagger(&matrix)), &identity);
+        }
+    }
+
+    #[test]
+    fn gates_follow_qelib1() {
+        let identity = gate("id", &[]);
+        assert_close(&mul(&gate("h", &[]), &gate("h", &[])), &identity);
+        assert_close(&mul(&gate("s", &[]), &gate("s", &[])), &gate("z", &[]));
+        assert_close(&mul(&gate("t", &[]), &gate("t", &[])), &gate("s", &[]));
+        assert_close(&mul(&gate("sx", &[]), &gate("sx", &[])), &gate("x", &[]));
+        assert_close(&gate("sdg", &[]), &dagger(&gate("s", &[])));
+        assert_close(&gate("tdg", &[]), &dagger(&gate("t", &[])));
+        assert_close(&gate("sxdg", &[]), &dagger(&gate("sx", &[])));
+        // u3(pi,0,pi) is x and u2(0,pi) is h exactly, as in qelib1.inc
+        assert_close(&gate("u3", &[PI, 0.0, PI]), &gate("x", &[]));
+        assert_close(&gate("u2", &[0.0, PI]), &gate("h", &[]));
+        assert_close(&gate("p", &[FRAC_PI_4]), &gate("t", &[]));
+        assert_close(&gate("u1", &[0.7]), &gate("p", &[0.7]));
+        // rotations equal the gates up to a global phase
+        assert_equivalent(&gate("rx", &[PI]), &gate("x", &[]));
+        assert_equivalent(&gate("ry", &[PI]), &gate("y", &[]));
+        assert_equivalent(&gate("rz", &[FRAC_PI_2]), &gate("s", &[]));
+        assert_equivalent(
+            &gate("u3", &[0.4, -FRAC_PI_2, FRAC_PI_2]),
+            &gate("rx", &[0.4]),
+        );
+        assert_equivalent(&gate("u3", &[0.4, 0.0, 0.0]), &gate("ry", &[0.4]));
+    }
+
+    #[test]
+    fn controlled_gates_control_the_last_qubit() {
+        assert_eq!(controlled_1q("ccx", &[]).map(|(n, _)| n), Some(2));
+        let (controls, matrix) = controlled_1q("crz", &[0.5]).unwrap();
+        assert_eq!(controls, 1);
+        assert_close(&matrix, &gate("rz", &[0.5]));
+        let (_, matrix) = controlled_1q("cu", &[0.1, 0.2, 0.3, 0.4]).unwrap();
+        let mut expected = gate("u3", &[0.1, 0.2, 0.3]);
+        expected
+            .iter_mut()
+            .flatten()
+            .for_each(|x| *x *= Complex64::from_polar(1.0, 0.4));
+        assert_close(&matrix, &expected);
+        assert!(controlled_1q("swap", &[]).is_none());
+        assert!(controlled_1q("crx", &[]).is_none());
+    }
+
+    #[test]
+    fn aliases_and_extensions_are_known() {
+        assert_eq!(canonical_name("U"), "u3");
+        assert_eq!(canonical_name("toffoli"), "ccx");
+        assert_eq!(gate_info("cu"), Some((4, 2)));
+        assert_eq!(gate_info("foo"), None);
+        for &(name, _) in QELIB1_EXTENSIONS {
+            assert!(gate_info(name).is_some(), "{} is not a gate", name);
+        }
+    }
+}
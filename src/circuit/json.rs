@@ -0,0 +1,530 @@
+use std::collections::HashMap;
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use super::{
+    gates::{canonical_name, gate_info},
+    qasm2, Circuit, Condition, Expr, Instruction, Op, Register,
+};
+
+/// A gate parameter, a number or an expression of the variables
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum Param {
+    Num(f64),
+    Expr(String),
+}
+
+/// An instruction of a Qiskit `QasmQobj` experiment
+#[derive(Deserialize, Debug, Clone)]
+pub struct QobjInstruction {
+    pub name: String,
+    #[serde(default)]
+    pub params: Vec<Param>,
+    #[serde(default)]
+    pub qubits: Vec<usize>,
+    #[serde(default)]
+    pub memory: Vec<usize>,
+    pub conditional: Option<usize>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct QobjHeader {
+    pub qreg_sizes: Option<Vec<(String, usize)>>,
+    pub creg_sizes: Option<Vec<(String, usize)>>,
+    pub n_qubits: Option<usize>,
+    pub memory_slots: Option<usize>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct QobjExperiment {
+    #[serde(default)]
+    pub header: QobjHeader,
+    pub instructions: Vec<QobjInstruction>,
+}
+
+/// A Qiskit `QasmQobj`, only its experiments are used
+#[derive(Deserialize, Debug, Clone)]
+pub struct Qobj {
+    pub experiments: Vec<QobjExperiment>,
+}
+
+/// A register of the JSON circuit IR
+#[derive(Deserialize, Debug, Clone)]
+pub struct IrRegister {
+    pub name: String,
+    pub size: usize,
+}
+
+/// `if (creg == value)` of the JSON circuit IR
+#[derive(Deserialize, Debug, Clone)]
+pub struct IrCondition {
+    pub creg: String,
+    pub value: u64,
+}
+
+/// An instruction of the JSON circuit IR, a gate, `measure` (with `clbits`),
+/// `reset` or `barrier`
+#[derive(Deserialize, Debug, Clone)]
+pub struct IrInstruction {
+    pub gate: String,
+    #[serde(default)]
+    pub params: Vec<Param>,
+    pub qubits: Vec<usize>,
+    #[serde(default)]
+    pub clbits: Vec<usize>,
+    pub condition: Option<IrCondition>,
+}
+
+/// The JSON circuit IR, the registers are `q[qubits]` and `c[clbits]` unless
+/// `qregs` and `cregs` are given
+#[derive(Deserialize, Debug, Clone)]
+pub struct JsonIr {
+    pub qubits: Option<usize>,
+    pub clbits: Option<usize>,
+    pub qregs: Option<Vec<IrRegister>>,
+    pub cregs: Option<Vec<IrRegister>>,
+    pub gates: Vec<IrInstruction>,
+}
+
+fn registers(regs: Vec<(String, usize)>) -> Vec<Register> {
+    let mut offset = 0;
+    regs.into_iter()
+        .map(|(name, size)| {
+            offset += size;
+            Register {
+                name,
+                size,
+                offset: offset - size,
+            }
+        })
+        .collect()
+}
+
+/// the number of bits needed for the used indices
+fn width(bits: impl Iterator<Item = usize>) -> usize {
+    bits.max().map_or(0, |bit| bit + 1)
+}
+
+fn param(path: &str, param: &Param) -> Result<Expr, String> {
+    match param {
+        Param::Num(x) => Ok(Expr::Num(*x)),
+        Param::Expr(expr) => qasm2::parse_expr(expr).map_err(|err| format!("{}: {}", path, err)),
+    }
+}
+
+fn check_bits(path: &str, bits: &[usize], size: usize, kind: &str) -> Result<(), String> {
+    for (i, bit) in bits.iter().enumerate() {
+        if *bit >= size {
+            return Err(format!(
+                "{}[{}]: {} {} is out of range, the circuit has {}",
+                path, i, kind, bit, size
+            ));
+        }
+        if bits[..i].contains(bit) {
+            return Err(format!("{}[{}]: {} {} is used twice", path, i, kind, bit));
+        }
+    }
+    Ok(())
+}
+
+/// Lower an instruction of the JSON formats, `path` is the position of the
+/// instruction in the input and prefixes the errors
+fn instruction(
+    circuit: &Circuit,
+    path: &str,
+    name: &str,
+    params: &[Param],
+    qubits: &[usize],
+    clbits: &[usize],
+) -> Result<Vec<Op>, String> {
+    check_bits(
+        &format!("{}.qubits", path),
+        qubits,
+        circuit.num_qubits(),
+        "qubit",
+    )?;
+    let ops = match name {
+        "measure" => {
+            if clbits.len() != qubits.len() {
+                return Err(format!(
+                    "{}: measure has {} qubits and {} clbits",
+                    path,
+                    qubits.len(),
+                    clbits.len()
+                ));
+            }
+            check_bits(
+                &format!("{}.clbits", path),
+                clbits,
+                circuit.num_clbits(),
+                "clbit",
+            )?;
+            qubits
+                .iter()
+                .zip(clbits.iter())
+                .map(|(&qubit, &clbit)| Op::Measure { qubit, clbit })
+                .collect()
+        }
+        "reset" => qubits.iter().map(|&qubit| Op::Reset { qubit }).collect(),
+        "barrier" => vec![Op::Barrier {
+            qubits: qubits.to_vec(),
+        }],
+        _ => {
+            let gate = canonical_name(name);
+            let (num_params, num_qubits) =
+                gate_info(gate).ok_or_else(|| format!("{}.name: unknown gate {}", path, name))?;
+            if params.len() != num_params || qubits.len() != num_qubits {
+                return Err(format!(
+                    "{}: gate {} takes {} parameters and {} qubits, found {} and {}",
+                    path,
+                    name,
+                    num_params,
+                    num_qubits,
+                    params.len(),
+                    qubits.len()
+                ));
+            }
+            vec![Op::Gate {
+                name: gate.to_string(),
+                params: params
+                    .iter()
+                    .enumerate()
+                    .map(|(i, p)| param(&format!("{}.params[{}]", path, i), p))
+                    .collect::<Result<_, _>>()?,
+                qubits: qubits.to_vec(),
+            }]
+        }
+    };
+    Ok(ops)
+}
+
+/// Parse a Qiskit `QasmQobj` with one experiment. The registers are taken from
+/// `qreg_sizes` and `creg_sizes` of the header, or `n_qubits` and
+/// `memory_slots`. Conditional instructions are not supported.
+pub fn parse_qobj(source: &str) -> Result<Circuit, String> {
+    let qobj: Qobj = serde_json::from_str(source).map_err(|err| format!("qobj: {}", err))?;
+    if qobj.experiments.len() != 1 {
+        return Err(format!(
+            "experiments: expected one experiment, found {}, submit them with /submit_batch",
+            qobj.experiments.len()
+        ));
+    }
+    let experiment = &qobj.experiments[0];
+    let header = &experiment.header;
+
+    let mut circuit = Circuit {
+        qregs: registers(header.qreg_sizes.clone().unwrap_or_else(|| {
+            let size = header.n_qubits.unwrap_or_else(|| {
+                width(
+                    experiment
+                        .instructions
+                        .iter()
+                        .flat_map(|inst| inst.qubits.clone()),
+                )
+            });
+            vec![("q".to_string(), size)]
+        })),
+        cregs: registers(header.creg_sizes.clone().unwrap_or_else(|| {
+            let size = header.memory_slots.unwrap_or_else(|| {
+                width(
+                    experiment
+                        .instructions
+                        .iter()
+                        .flat_map(|inst| inst.memory.clone()),
+                )
+            });
+            vec![("c".to_string(), size)]
+        })),
+        instructions: Vec::new(),
+    };
+
+    for (i, inst) in experiment.instructions.iter().enumerate() {
+        let path = format!("experiments[0].instructions[{}]", i);
+        if inst.conditional.is_some() || inst.name == "bfunc" {
+            return Err(format!(
+                "{}: conditional instructions are not supported",
+                path
+            ));
+        }
+        for op in instruction(
+            &circuit,
+            &path,
+            &inst.name,
+            &inst.params,
+            &inst.qubits,
+            &inst.memory,
+        )? {
+            circuit.instructions.push(Instruction {
+                op,
+                condition: None,
+            });
+        }
+    }
+    Ok(circuit)
+}
+
+/// Parse the JSON circuit IR, a list of gates on flattened qubit indices
+pub fn parse_json_ir(source: &str) -> Result<Circuit, String> {
+    let ir: JsonIr = serde_json::from_str(source).map_err(|err| format!("json_ir: {}", err))?;
+    let regs = |regs: Option<Vec<IrRegister>>, size: Option<usize>, name: &str| {
+        registers(match regs {
+            Some(regs) => regs.into_iter().map(|reg| (reg.name, reg.size)).collect(),
+            None => vec![(name.to_string(), size.unwrap_or(0))],
+        })
+    };
+    let mut circuit = Circuit {
+        qregs: regs(ir.qregs, ir.qubits, "q"),
+        cregs: regs(ir.cregs, ir.clbits, "c"),
+        instructions: Vec::new(),
+    };
+    if circuit.num_qubits() == 0 {
+        return Err("qubits: the circuit has no qubits".to_string());
+    }
+
+    for (i, inst) in ir.gates.iter().enumerate() {
+        let path = format!("gates[{}]", i);
+        let condition = match &inst.condition {
+            Some(condition) => match circuit.creg(&condition.creg) {
+                Some(_) => Some(Condition {
+                    creg: condition.creg.clone(),
+                    value: condition.value,
+                }),
+                None => {
+                    return Err(format!(
+                        "{}.condition.creg: unknown classical register {}",
+                        path, condition.creg
+                    ))
+                }
+            },
+            None => None,
+        };
+        for op in instruction(
+            &circuit,
+            &path,
+            &inst.gate,
+            &inst.params,
+            &inst.qubits,
+            &inst.clbits,
+        )? {
+            circuit.instructions.push(Instruction {
+                op,
+                condition: condition.clone(),
+            });
+        }
+    }
+    Ok(circuit)
+}
+
+/// Emit the circuit as the JSON circuit IR, the parameters without variables
+/// are numbers
+pub fn to_json_ir(circuit: &Circuit) -> String {
+    let regs = |regs: &[Register]| -> Vec<Value> {
+        regs.iter()
+            .map(|reg| json!({"name": reg.name, "size": reg.size}))
+            .collect()
+    };
+    let gates: Vec<Value> = circuit
+        .instructions
+        .iter()
+        .map(|inst| {
+            let mut gate = match &inst.op {
+                Op::Gate {
+                    name,
+                    params,
+                    qubits,
+                } => {
+                    let params: Vec<Value> = params
+                        .iter()
+                        .map(|p| match p.eval(&HashMap::new()) {
+                            Ok(x) => json!(x),
+                            Err(_) => json!(p.to_string()),
+                        })
+                        .collect();
+                    json!({"gate": name, "params": params, "qubits": qubits})
+                }
+                Op::Measure { qubit, clbit } => {
+                    json!({"gate": "measure", "qubits": [qubit], "clbits": [clbit]})
+                }
+                Op::Reset { qubit } => json!({"gate": "reset", "qubits": [qubit]}),
+                Op::Barrier { qubits } => json!({"gate": "barrier", "qubits": qubits}),
+            };
+            if let Some(condition) = &inst.condition {
+                gate["condition"] = json!({"creg": condition.creg, "value": condition.value});
+            }
+            gate
+        })
+        .collect();
+    let ir = json!({
+        "qregs": regs(&circuit.qregs),
+        "cregs": regs(&circuit.cregs),
+        "gates": gates,
+    });
+    serde_json::to_string_pretty(&ir).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `assemble` of a Bell circuit by Qiskit, trimmed of the run config
+    const QISKIT_QOBJ: &str = r#"{
+        "qobj_id": "5b2b2c1e-3f9c-4a5e-9d56-0c6a7e6d2f3a",
+        "schema_version": "1.3.0",
+        "type": "QASM",
+        "header": {},
+        "config": {"shots": 1024, "memory": false, "parameter_binds": [],
+                   "init_qubits": true, "n_qubits": 2, "memory_slots": 2},
+        "experiments": [{
+            "config": {"n_qubits": 2, "memory_slots": 2},
+            "header": {
+                "qubit_labels": [["q", 0], ["q", 1]], "n_qubits": 2,
+                "qreg_sizes": [["q", 2]],
+                "clbit_labels": [["meas", 0], ["meas", 1]], "memory_slots": 2,
+                "creg_sizes": [["meas", 2]],
+                "name": "bell", "global_phase": 0.0, "metadata": {}
+            },
+            "instructions": [
+                {"name": "u2", "params": [0.0, 3.141592653589793], "qubits": [0]},
+                {"name": "cx", "qubits": [0, 1]},
+                {"name": "barrier", "qubits": [0, 1]},
+                {"name": "measure", "qubits": [0], "memory": [0]},
+                {"name": "measure", "qubits": [1], "memory": [1]}
+            ]
+        }]
+    }"#;
+
+    fn qobj(instructions: Value) -> String {
+        json!({"experiments": [{
+            "header": {"n_qubits": 2, "memory_slots": 2},
+            "instructions": instructions,
+        }]})
+        .to_string()
+    }
+
+    fn ir(gates: Value) -> String {
+        json!({"qubits": 2, "cregs": [{"name": "c", "size": 2}], "gates": gates}).to_string()
+    }
+
+    #[test]
+    fn qiskit_qobj_is_parsed() {
+        let circuit = parse_qobj(QISKIT_QOBJ).unwrap();
+        assert_eq!(
+            circuit.qregs,
+            [Register {
+                name: "q".to_string(),
+                size: 2,
+                offset: 0
+            }]
+        );
+        assert_eq!(
+            circuit.cregs,
+            [Register {
+                name: "meas".to_string(),
+                size: 2,
+                offset: 0
+            }]
+        );
+        let ops: Vec<&Op> = circuit.instructions.iter().map(|inst| &inst.op).collect();
+        assert_eq!(
+            ops[1..],
+            [
+                &Op::Gate {
+                    name: "cx".to_string(),
+                    params: vec![],
+                    qubits: vec![0, 1]
+                },
+                &Op::Barrier { qubits: vec![0, 1] },
+                &Op::Measure { qubit: 0, clbit: 0 },
+                &Op::Measure { qubit: 1, clbit: 1 },
+            ]
+        );
+        assert!(circuit.measurements_are_terminal());
+    }
+
+    #[test]
+    fn qobj_errors_point_at_the_instruction() {
+        let err = |instructions: Value| parse_qobj(&qobj(instructions)).unwrap_err();
+        assert_eq!(
+            err(json!([{"name": "h", "qubits": [0]}, {"name": "foo", "qubits": [0]}])),
+            "experiments[0].instructions[1].name: unknown gate foo"
+        );
+        assert_eq!(
+            err(json!([{"name": "cx", "qubits": [0, 2]}])),
+            "experiments[0].instructions[0].qubits[1]: qubit 2 is out of range, the circuit has 2"
+        );
+        assert_eq!(
+            err(json!([{"name": "cx", "qubits": [1, 1]}])),
+            "experiments[0].instructions[0].qubits[1]: qubit 1 is used twice"
+        );
+        assert_eq!(
+            err(json!([{"name": "measure", "qubits": [0, 1], "memory": [0]}])),
+            "experiments[0].instructions[0]: measure has 2 qubits and 1 clbits"
+        );
+        assert_eq!(
+            err(json!([{"name": "measure", "qubits": [0], "memory": [2]}])),
+            "experiments[0].instructions[0].clbits[0]: clbit 2 is out of range, the circuit has 2"
+        );
+        assert_eq!(
+            err(json!([{"name": "rx", "qubits": [0]}])),
+            "experiments[0].instructions[0]: gate rx takes 1 parameters and 1 qubits, found 0 and 1"
+        );
+        assert_eq!(
+            err(json!([{"name": "x", "qubits": [0], "conditional": 0}])),
+            "experiments[0].instructions[0]: conditional instructions are not supported"
+        );
+        assert_eq!(
+            err(
+                json!([{"name": "bfunc", "mask": "0x1", "relation": "==", "val": "0x1",
+                        "register": 0}])
+            ),
+            "experiments[0].instructions[0]: conditional instructions are not supported"
+        );
+    }
+
+    #[test]
+    fn qobj_has_one_experiment() {
+        let qobj: Value = serde_json::from_str(QISKIT_QOBJ).unwrap();
+        let mut two = qobj.clone();
+        two["experiments"] = json!([qobj["experiments"][0], qobj["experiments"][0]]);
+        assert_eq!(
+            parse_qobj(&two.to_string()).unwrap_err(),
+            "experiments: expected one experiment, found 2, submit them with /submit_batch"
+        );
+    }
+
+    #[test]
+    fn json_ir_errors_point_at_the_gate() {
+        let err = |gates: Value| parse_json_ir(&ir(gates)).unwrap_err();
+        assert_eq!(
+            err(json!([{"gate": "x", "qubits": [0], "condition": {"creg": "d", "value": 1}}])),
+            "gates[0].condition.creg: unknown classical register d"
+        );
+        assert_eq!(
+            err(json!([{"gate": "measure", "qubits": [0, 1], "clbits": [1, 1]}])),
+            "gates[0].clbits[1]: clbit 1 is used twice"
+        );
+        assert_eq!(
+            err(json!([{"gate": "rx", "params": ["theta+"], "qubits": [0]}]))
+                .split(':')
+                .next(),
+            Some("gates[0].params[0]")
+        );
+        assert_eq!(
+            parse_json_ir(&json!({"gates": []}).to_string()).unwrap_err(),
+            "qubits: the circuit has no qubits"
+        );
+    }
+
+    #[test]
+    fn json_ir_round_trip() {
+        let circuit = parse_json_ir(&ir(json!([
+            {"gate": "rx", "params": [0.5], "qubits": [0]},
+            {"gate": "measure", "qubits": [0], "clbits": [1]},
+            {"gate": "x", "qubits": [1], "condition": {"creg": "c", "value": 2}},
+        ])))
+        .unwrap();
+        assert_eq!(parse_json_ir(&to_json_ir(&circuit)).unwrap(), circuit);
+    }
+}
@@ -0,0 +1,428 @@
+pub mod cache;
+pub mod cirq;
+pub mod gates;
+pub mod json;
+pub mod optimize;
+pub mod qasm2;
+pub mod qasm3;
+pub mod quil;
+pub mod routing;
+pub mod transpile;
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+};
+
+use serde::Deserialize;
+
+/// Parse an OpenQASM 3 or 2.0 program, by the version in its header
+pub fn parse(source: &str) -> Result<Circuit, String> {
+    if qasm3::is_qasm3(source) {
+        qasm3::parse(source)
+    } else {
+        qasm2::parse(source)
+    }
+}
+
+/// The formats of the submitted programs. OpenQASM is run as it is, the other
+/// formats are converted to OpenQASM 2.0 first.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputFormat {
+    /// OpenQASM 2.0, or OpenQASM 3 by the version in the header
+    #[serde(rename = "qasm2")]
+    Qasm2,
+    #[serde(rename = "qasm3")]
+    Qasm3,
+    /// a Qiskit `QasmQobj` with one experiment
+    #[serde(rename = "qobj")]
+    Qobj,
+    /// the gate list of `json::JsonIr`
+    #[serde(rename = "json_ir")]
+    JsonIr,
+    /// a Quil program with `DECLARE`d memory
+    #[serde(rename = "quil")]
+    Quil,
+    /// a circuit serialized by `cirq.to_json`
+    #[serde(rename = "cirq_json")]
+    CirqJson,
+}
+
+impl fmt::Display for InputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InputFormat::Qasm2 => write!(f, "qasm2"),
+            InputFormat::Qasm3 => write!(f, "qasm3"),
+            InputFormat::Qobj => write!(f, "qobj"),
+            InputFormat::JsonIr => write!(f, "json_ir"),
+            InputFormat::Quil => write!(f, "quil"),
+            InputFormat::CirqJson => write!(f, "cirq_json"),
+        }
+    }
+}
+
+/// Convert a program of `format` to OpenQASM that the agent runs
+pub fn convert_input(source: &str, format: InputFormat) -> Result<String, String> {
+    match format {
+        InputFormat::Qasm2 => Ok(source.to_string()),
+        InputFormat::Qasm3 if qasm3::is_qasm3(source) => Ok(source.to_string()),
+        InputFormat::Qasm3 => Err("expected an OPENQASM 3 program".to_string()),
+        InputFormat::Qobj => Ok(qasm2::to_qasm(&json::parse_qobj(source)?)),
+        InputFormat::JsonIr => Ok(qasm2::to_qasm(&json::parse_json_ir(source)?)),
+        InputFormat::Quil => Ok(qasm2::to_qasm(&quil::parse(source)?)),
+        InputFormat::CirqJson => Ok(qasm2::to_qasm(&cirq::parse(source)?)),
+    }
+}
+
+/// The formats a circuit can be converted to
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    #[serde(rename = "qasm2")]
+    Qasm2,
+    #[serde(rename = "json_ir")]
+    JsonIr,
+    #[serde(rename = "quil")]
+    Quil,
+    #[serde(rename = "cirq_json")]
+    CirqJson,
+}
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OutputFormat::Qasm2 => write!(f, "qasm2"),
+            OutputFormat::JsonIr => write!(f, "json_ir"),
+            OutputFormat::Quil => write!(f, "quil"),
+            OutputFormat::CirqJson => write!(f, "cirq_json"),
+        }
+    }
+}
+
+/// Convert a program between formats, through the circuit IR
+pub fn convert(source: &str, from: InputFormat, to: OutputFormat) -> Result<String, String> {
+    let circuit = match from {
+        InputFormat::Qasm2 | InputFormat::Qasm3 => parse(&convert_input(source, from)?)?,
+        InputFormat::Qobj => json::parse_qobj(source)?,
+        InputFormat::JsonIr => json::parse_json_ir(source)?,
+        InputFormat::Quil => quil::parse(source)?,
+        InputFormat::CirqJson => cirq::parse(source)?,
+    };
+    match to {
+        OutputFormat::Qasm2 => Ok(qasm2::to_qasm(&circuit)),
+        OutputFormat::JsonIr => Ok(json::to_json_ir(&circuit)),
+        OutputFormat::Quil => quil::to_quil(&circuit),
+        OutputFormat::CirqJson => cirq::to_cirq_json(&circuit),
+    }
+}
+
+/// A gate parameter, free variables are bound when the circuit is run
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Num(f64),
+    Pi,
+    Var(String),
+    Neg(Box<Expr>),
+    Add(Box<Expr>, Box<Expr>),
+    Sub(Box<Expr>, Box<Expr>),
+    Mul(Box<Expr>, Box<Expr>),
+    Div(Box<Expr>, Box<Expr>),
+    Pow(Box<Expr>, Box<Expr>),
+    Func(String, Box<Expr>),
+}
+
+impl Expr {
+    pub fn eval(&self, vars: &HashMap<String, f64>) -> Result<f64, String> {
+        Ok(match self {
+            Expr::Num(x) => *x,
+            Expr::Pi => std::f64::consts::PI,
+            Expr::Var(name) => *vars
+                .get(name)
+                .ok_or(format!("Variable {} is not bound", name))?,
+            Expr::Neg(x) => -x.eval(vars)?,
+            Expr::Add(a, b) => a.eval(vars)? + b.eval(vars)?,
+            Expr::Sub(a, b) => a.eval(vars)? - b.eval(vars)?,
+            Expr::Mul(a, b) => a.eval(vars)? * b.eval(vars)?,
+            Expr::Div(a, b) => a.eval(vars)? / b.eval(vars)?,
+            Expr::Pow(a, b) => a.eval(vars)?.powf(b.eval(vars)?),
+            Expr::Func(func, x) => {
+                let x = x.eval(vars)?;
+                match func.as_str() {
+                    "sin" => x.sin(),
+                    "cos" => x.cos(),
+                    "tan" => x.tan(),
+                    "exp" => x.exp(),
+                    "ln" => x.ln(),
+                    "sqrt" => x.sqrt(),
+                    _ => return Err(format!("Unknown function {}", func)),
+                }
+            }
+        })
+    }
+
+    /// replace the variables, used to expand the gate definitions
+    pub fn substitute(&self, vars: &HashMap<String, Expr>) -> Expr {
+        let sub = |x: &Expr| Box::new(x.substitute(vars));
+        match self {
+            Expr::Var(name) => vars.get(name).cloned().unwrap_or_else(|| self.clone()),
+            Expr::Num(_) | Expr::Pi => self.clone(),
+            Expr::Neg(x) => Expr::Neg(sub(x)),
+            Expr::Add(a, b) => Expr::Add(sub(a), sub(b)),
+            Expr::Sub(a, b) => Expr::Sub(sub(a), sub(b)),
+            Expr::Mul(a, b) => Expr::Mul(sub(a), sub(b)),
+            Expr::Div(a, b) => Expr::Div(sub(a), sub(b)),
+            Expr::Pow(a, b) => Expr::Pow(sub(a), sub(b)),
+            Expr::Func(func, x) => Expr::Func(func.clone(), sub(x)),
+        }
+    }
+
+    pub fn free_vars(&self, vars: &mut Vec<String>) {
+        match self {
+            Expr::Var(name) => {
+                if !vars.contains(name) {
+                    vars.push(name.clone())
+                }
+            }
+            Expr::Num(_) | Expr::Pi => {}
+            Expr::Neg(x) | Expr::Func(_, x) => x.free_vars(vars),
+            Expr::Add(a, b)
+            | Expr::Sub(a, b)
+            | Expr::Mul(a, b)
+            | Expr::Div(a, b)
+            | Expr::Pow(a, b) => {
+                a.free_vars(vars);
+                b.free_vars(vars);
+            }
+        }
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Expr::Num(x) if *x < 0.0 => write!(f, "({})", x),
+            Expr::Num(x) => write!(f, "{}", x),
+            Expr::Pi => write!(f, "pi"),
+            Expr::Var(name) => write!(f, "{}", name),
+            Expr::Neg(x) => write!(f, "(-{})", x),
+            Expr::Add(a, b) => write!(f, "({}+{})", a, b),
+            Expr::Sub(a, b) => write!(f, "({}-{})", a, b),
+            Expr::Mul(a, b) => write!(f, "({}*{})", a, b),
+            Expr::Div(a, b) => write!(f, "({}/{})", a, b),
+            Expr::Pow(a, b) => write!(f, "({}^{})", a, b),
+            Expr::Func(func, x) => write!(f, "{}({})", func, x),
+        }
+    }
+}
+
+/// A quantum or classical register, `offset` is the index of its first bit
+/// in the whole circuit
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Register {
+    pub name: String,
+    pub size: usize,
+    pub offset: usize,
+}
+
+/// `if (creg == value)`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Condition {
+    pub creg: String,
+    pub value: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Op {
+    Gate {
+        name: String,
+        params: Vec<Expr>,
+        qubits: Vec<usize>,
+    },
+    Measure {
+        qubit: usize,
+        clbit: usize,
+    },
+    Reset {
+        qubit: usize,
+    },
+    Barrier {
+        qubits: Vec<usize>,
+    },
+}
+
+impl Op {
+    pub fn qubits(&self) -> Vec<usize> {
+        match self {
+            Op::Gate { qubits, .. } | Op::Barrier { qubits } => qubits.clone(),
+            Op::Measure { qubit, .. } | Op::Reset { qubit } => vec![*qubit],
+        }
+    }
+
+    /// the gate name, or `measure`, `reset` and `barrier`
+    pub fn name(&self) -> &str {
+        match self {
+            Op::Gate { name, .. } => name,
+            Op::Measure { .. } => "measure",
+            Op::Reset { .. } => "reset",
+            Op::Barrier { .. } => "barrier",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Instruction {
+    pub op: Op,
+    pub condition: Option<Condition>,
+}
+
+/// The circuit representation used by the agent. Custom gates are expanded
+/// into the gates of `gates::GATES`, and qubits and clbits are flattened in
+/// the declaration order of their registers.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Circuit {
+    pub qregs: Vec<Register>,
+    pub cregs: Vec<Register>,
+    pub instructions: Vec<Instruction>,
+}
+
+impl Circuit {
+    pub fn num_qubits(&self) -> usize {
+        self.qregs.iter().map(|reg| reg.size).sum()
+    }
+
+    pub fn num_clbits(&self) -> usize {
+        self.cregs.iter().map(|reg| reg.size).sum()
+    }
+
+    pub fn creg(&self, name: &str) -> Option<&Register> {
+        self.cregs.iter().find(|reg| reg.name == name)
+    }
+
+    /// `q[1]` of the flattened qubit index
+    pub fn qubit_name(&self, qubit: usize) -> String {
+        bit_name(&self.qregs, qubit)
+    }
+
+    /// `c[1]` of the flattened clbit index
+    pub fn clbit_name(&self, clbit: usize) -> String {
+        bit_name(&self.cregs, clbit)
+    }
+
+    /// the free variables of the gate parameters
+    pub fn free_vars(&self) -> Vec<String> {
+        let mut vars = Vec::new();
+        for inst in self.instructions.iter() {
+            if let Op::Gate { params, .. } = &inst.op {
+                params.iter().for_each(|param| param.free_vars(&mut vars));
+            }
+        }
+        vars
+    }
+
+    /// bind the variables, the parameters without free variables become
+    /// numbers
+    pub fn bind(&self, vars: &HashMap<String, f64>) -> Circuit {
+        let bound: HashMap<String, Expr> = vars
+            .iter()
+            .map(|(name, value)| (name.clone(), Expr::Num(*value)))
+            .collect();
+        let mut circuit = self.clone();
+        for inst in circuit.instructions.iter_mut() {
+            if let Op::Gate { params, .. } = &mut inst.op {
+                for param in params.iter_mut() {
+                    let expr = param.substitute(&bound);
+                    *param = match expr.eval(vars) {
+                        Ok(value) => Expr::Num(value),
+                        Err(_) => expr,
+                    };
+                }
+            }
+        }
+        circuit
+    }
+
+    /// the number of instructions of each gate, measurements, resets and
+    /// barriers included
+    pub fn gate_counts(&self) -> BTreeMap<String, usize> {
+        let mut counts = BTreeMap::new();
+        for inst in self.instructions.iter() {
+            *counts.entry(inst.op.name().to_string()).or_insert(0) += 1;
+        }
+        counts
+    }
+
+    /// The number of layers of instructions. An instruction follows the last
+    /// one on its qubits, on the clbit it measures and on the register of its
+    /// condition. Barriers only align their qubits.
+    pub fn depth(&self) -> usize {
+        let mut qubits = vec![0; self.num_qubits()];
+        let mut clbits = vec![0; self.num_clbits()];
+        for inst in self.instructions.iter() {
+            let mut wires: Vec<usize> = match &inst.condition {
+                Some(condition) => self
+                    .creg(&condition.creg)
+                    .map(|reg| (reg.offset..reg.offset + reg.size).collect())
+                    .unwrap_or_default(),
+                None => Vec::new(),
+            };
+            if let Op::Measure { clbit, .. } = &inst.op {
+                wires.push(*clbit);
+            }
+            let op_qubits = inst.op.qubits();
+            let layer = op_qubits
+                .iter()
+                .map(|&qubit| qubits[qubit])
+                .chain(wires.iter().map(|&clbit| clbits[clbit]))
+                .max()
+                .unwrap_or(0);
+            let layer = match inst.op {
+                Op::Barrier { .. } => layer,
+                _ => layer + 1,
+            };
+            op_qubits.iter().for_each(|&qubit| qubits[qubit] = layer);
+            wires.iter().for_each(|&clbit| clbits[clbit] = layer);
+        }
+        qubits.into_iter().chain(clbits).max().unwrap_or(0)
+    }
+
+    /// Whether no qubit is used after it is measured, and there are no
+    /// resets or conditions. Such a circuit can be simulated once and sampled
+    /// for every shot.
+    pub fn measurements_are_terminal(&self) -> bool {
+        let mut measured = vec![false; self.num_qubits()];
+        for inst in self.instructions.iter() {
+            if inst.condition.is_some() {
+                return false;
+            }
+            match &inst.op {
+                Op::Measure { qubit, .. } => measured[*qubit] = true,
+                Op::Reset { .. } => return false,
+                Op::Gate { qubits, .. } => {
+                    if qubits.iter().any(|&qubit| measured[qubit]) {
+                        return false;
+                    }
+                }
+                Op::Barrier { .. } => {}
+            }
+        }
+        true
+    }
+
+    /// The bitstring of the clbits, registers in declaration order and the
+    /// last bit of a register is `reg[0]`, the same as `qasmsim`
+    pub fn format_clbits(&self, clbits: &[u8]) -> String {
+        let mut bitstring = String::with_capacity(clbits.len());
+        for reg in self.cregs.iter() {
+            for bit in clbits[reg.offset..reg.offset + reg.size].iter().rev() {
+                bitstring.push(if *bit == 1 { '1' } else { '0' });
+            }
+        }
+        bitstring
+    }
+}
+
+fn bit_name(regs: &[Register], index: usize) -> String {
+    regs.iter()
+        .find(|reg| reg.offset <= index && index < reg.offset + reg.size)
+        .map(|reg| format!("{}[{}]", reg.name, index - reg.offset))
+        .unwrap_or_else(|| format!("?[{}]", index))
+}
@@ -0,0 +1,410 @@
+use std::{collections::HashMap, f64::consts::PI};
+
+use super::{Circuit, Expr, Instruction, Op};
+
+/// A peephole pass, it returns the number of removed instructions
+pub type Pass = fn(&mut Circuit) -> usize;
+
+/// The passes of each `optimization_level`, level 3 repeats the passes of
+/// level 2 until they remove nothing
+pub const PASSES: &[(&str, Pass, usize)] = &[
+    ("cancel_inverses", cancel_inverses, 1),
+    ("merge_rotations", merge_rotations, 1),
+    ("remove_before_reset", remove_before_reset, 2),
+    ("drop_unmeasured", drop_unmeasured, 2),
+];
+
+pub const MAX_LEVEL: usize = 3;
+
+/// how many times level 3 repeats the passes at most
+const MAX_ROUNDS: usize = 10;
+
+/// Run the passes of the optimization level on the circuit, except the ones
+/// named in `skip`, the number of instructions removed by each pass is
+/// returned
+pub fn optimize(
+    circuit: &mut Circuit,
+    level: usize,
+    skip: &[&str],
+) -> Result<Vec<(&'static str, usize)>, String> {
+    if level > MAX_LEVEL {
+        return Err(format!(
+            "optimization_level {} is not supported, the levels are 0 to {}",
+            level, MAX_LEVEL
+        ));
+    }
+    let passes: Vec<(&'static str, Pass)> = PASSES
+        .iter()
+        .filter(|(name, _, min_level)| *min_level <= level && !skip.contains(name))
+        .map(|&(name, pass, _)| (name, pass))
+        .collect();
+    let mut removed: Vec<(&'static str, usize)> =
+        passes.iter().map(|&(name, _)| (name, 0)).collect();
+
+    let rounds = if level == MAX_LEVEL { MAX_ROUNDS } else { 1 };
+    for _ in 0..rounds {
+        let mut changed = false;
+        for (i, (_, pass)) in passes.iter().enumerate() {
+            let count = pass(circuit);
+            removed[i].1 += count;
+            changed |= count > 0;
+        }
+        if !changed {
+            break;
+        }
+    }
+    Ok(removed)
+}
+
+/// What a pass does with an instruction and the previous instruction on the
+/// same qubits
+enum Combined {
+    /// both are removed
+    Cancel,
+    /// both are replaced by one instruction
+    Merge(Instruction),
+}
+
+/// Scan the circuit and combine each instruction with the previous one on
+/// exactly the same qubits. After a cancellation the instruction before is
+/// the previous one again, so `x h h x` is removed completely.
+fn peephole(
+    circuit: &mut Circuit,
+    combine: fn(&Instruction, &Instruction) -> Option<Combined>,
+) -> usize {
+    let mut kept: Vec<Option<Instruction>> = Vec::new();
+    // the kept instructions on each qubit
+    let mut stacks: Vec<Vec<usize>> = vec![Vec::new(); circuit.num_qubits()];
+    let mut removed = 0;
+
+    for inst in std::mem::take(&mut circuit.instructions) {
+        let qubits = inst.op.qubits();
+        let previous = qubits
+            .first()
+            .and_then(|&qubit| stacks[qubit].last().copied())
+            .filter(|&j| {
+                qubits.iter().all(|&qubit| stacks[qubit].last() == Some(&j))
+                    && kept[j].as_ref().unwrap().op.qubits().len() == qubits.len()
+            });
+        if let Some(j) = previous {
+            match combine(kept[j].as_ref().unwrap(), &inst) {
+                Some(Combined::Cancel) => {
+                    kept[j] = None;
+                    qubits.iter().for_each(|&qubit| {
+                        stacks[qubit].pop();
+                    });
+                    removed += 2;
+                    continue;
+                }
+                Some(Combined::Merge(merged)) => {
+                    kept[j] = Some(merged);
+                    removed += 1;
+                    continue;
+                }
+                None => {}
+            }
+        }
+        qubits
+            .iter()
+            .for_each(|&qubit| stacks[qubit].push(kept.len()));
+        kept.push(Some(inst));
+    }
+    circuit.instructions = kept.into_iter().flatten().collect();
+    removed
+}
+
+/// the gates whose qubits can be given in any order
+fn is_symmetric(name: &str) -> bool {
+    matches!(name, "cz" | "swap" | "cp" | "cu1" | "rzz" | "rxx")
+}
+
+/// the name and qubits of two unconditional gates on the same qubits
+fn same_qubits<'a>(
+    a: &'a Instruction,
+    b: &'a Instruction,
+) -> Option<(&'a str, &'a [Expr], &'a str, &'a [Expr])> {
+    if a.condition.is_some() || b.condition.is_some() {
+        return None;
+    }
+    match (&a.op, &b.op) {
+        (
+            Op::Gate {
+                name: name_a,
+                params: params_a,
+                qubits: qubits_a,
+            },
+            Op::Gate {
+                name: name_b,
+                params: params_b,
+                qubits: qubits_b,
+            },
+        ) => {
+            let same = qubits_a == qubits_b
+                || (name_a == name_b && is_symmetric(name_a) && {
+                    let (mut a, mut b) = (qubits_a.clone(), qubits_b.clone());
+                    a.sort();
+                    b.sort();
+                    a == b
+                });
+            same.then_some((
+                name_a.as_str(),
+                &params_a[..],
+                name_b.as_str(),
+                &params_b[..],
+            ))
+        }
+        _ => None,
+    }
+}
+
+/// Remove the adjacent pairs of a gate and its inverse, e.g. `h h`,
+/// `cx cx` or `s sdg`
+pub fn cancel_inverses(circuit: &mut Circuit) -> usize {
+    peephole(circuit, |previous, inst| {
+        let (a, _, b, _) = same_qubits(previous, inst)?;
+        let self_inverse = matches!(
+            a,
+            "x" | "y" | "z" | "h" | "cx" | "cy" | "cz" | "ch" | "swap" | "ccx" | "cswap"
+        );
+        let inverse = matches!(
+            (a, b),
+            ("s", "sdg")
+                | ("sdg", "s")
+                | ("t", "tdg")
+                | ("tdg", "t")
+                | ("sx", "sxdg")
+                | ("sxdg", "sx")
+        );
+        ((self_inverse && a == b) || inverse).then_some(Combined::Cancel)
+    })
+}
+
+/// Merge the adjacent rotations about the same axis, e.g. `rz(a) rz(b)` is
+/// `rz(a+b)`. A rotation by a full turn is removed.
+pub fn merge_rotations(circuit: &mut Circuit) -> usize {
+    peephole(circuit, |previous, inst| {
+        let (a, params_a, b, params_b) = same_qubits(previous, inst)?;
+        let rotation = matches!(
+            a,
+            "rx" | "ry" | "rz" | "p" | "u1" | "crx" | "cry" | "crz" | "cp" | "cu1" | "rxx" | "rzz"
+        );
+        if !rotation || a != b {
+            return None;
+        }
+        let angle = Expr::Add(Box::new(params_a[0].clone()), Box::new(params_b[0].clone()));
+        let angle = match angle.eval(&HashMap::new()) {
+            Ok(value) => {
+                // a controlled rotation by 2 pi is a phase on the control
+                let period = match a {
+                    "crx" | "cry" | "crz" => 4.0 * PI,
+                    _ => 2.0 * PI,
+                };
+                let turns = value / period;
+                if (turns - turns.round()).abs() < 1e-12 {
+                    return Some(Combined::Cancel);
+                }
+                Expr::Num(value)
+            }
+            Err(_) => angle,
+        };
+        Some(Combined::Merge(Instruction {
+            op: Op::Gate {
+                name: a.to_string(),
+                params: vec![angle],
+                qubits: previous.op.qubits(),
+            },
+            condition: None,
+        }))
+    })
+}
+
+/// Remove the single qubit gates right before a reset of their qubit, and
+/// the resets of qubits which are still in |0>
+pub fn remove_before_reset(circuit: &mut Circuit) -> usize {
+    let mut kept: Vec<Option<Instruction>> = Vec::new();
+    let mut stacks: Vec<Vec<usize>> = vec![Vec::new(); circuit.num_qubits()];
+    let mut removed = 0;
+
+    for inst in std::mem::take(&mut circuit.instructions) {
+        if let (Op::Reset { qubit }, None) = (&inst.op, &inst.condition) {
+            while let Some(&j) = stacks[*qubit].last() {
+                let previous = kept[j].as_ref().unwrap();
+                let single = matches!(&previous.op, Op::Gate { qubits, .. } if qubits.len() == 1);
+                if !single || previous.condition.is_some() {
+                    break;
+                }
+                kept[j] = None;
+                stacks[*qubit].pop();
+                removed += 1;
+            }
+            let in_zero = match stacks[*qubit].last() {
+                None => true,
+                Some(&j) => {
+                    let previous = kept[j].as_ref().unwrap();
+                    matches!(previous.op, Op::Reset { .. }) && previous.condition.is_none()
+                }
+            };
+            if in_zero {
+                removed += 1;
+                continue;
+            }
+        }
+        inst.op
+            .qubits()
+            .iter()
+            .for_each(|&qubit| stacks[qubit].push(kept.len()));
+        kept.push(Some(inst));
+    }
+    circuit.instructions = kept.into_iter().flatten().collect();
+    removed
+}
+
+/// Remove the gates which cannot change any measurement: going backward, a
+/// qubit matters once it is measured, and a gate is kept only if one of its
+/// qubits matters. A reset makes the earlier state of its qubit irrelevant.
+pub fn drop_unmeasured(circuit: &mut Circuit) -> usize {
+    let mut live = vec![false; circuit.num_qubits()];
+    let mut kept = Vec::new();
+    let mut removed = 0;
+
+    for inst in std::mem::take(&mut circuit.instructions).into_iter().rev() {
+        let keep = match &inst.op {
+            Op::Measure { qubit, .. } => {
+                live[*qubit] = true;
+                true
+            }
+            Op::Barrier { .. } => true,
+            Op::Reset { qubit } => {
+                let keep = live[*qubit];
+                if inst.condition.is_none() {
+                    live[*qubit] = false;
+                }
+                keep
+            }
+            Op::Gate { qubits, .. } => {
+                let keep = qubits.iter().any(|&qubit| live[qubit]);
+                if keep {
+                    qubits.iter().for_each(|&qubit| live[qubit] = true);
+                }
+                keep
+            }
+        };
+        if keep {
+            kept.push(inst);
+        } else {
+            removed += 1;
+        }
+    }
+    kept.reverse();
+    circuit.instructions = kept;
+    removed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::circuit::qasm2;
+
+    fn circuit(body: &str) -> Circuit {
+        qasm2::parse(&format!("OPENQASM 2.0;\ninclude \"qelib1.inc\";\n{}", body)).unwrap()
+    }
+
+    fn names(circuit: &Circuit) -> Vec<&str> {
+        circuit
+            .instructions
+            .iter()
+            .map(|inst| inst.op.name())
+            .collect()
+    }
+
+    #[test]
+    fn inverses_cancel_from_the_inside_out() {
+        let mut c = circuit("qreg q[2];\nx q[0];\nh q[0];\nh q[0];\nx q[0];\ns q[1];\nsdg q[1];");
+        assert_eq!(cancel_inverses(&mut c), 6);
+        assert!(c.instructions.is_empty());
+
+        // cz is symmetric, cx is not
+        let mut c =
+            circuit("qreg q[2];\ncz q[0],q[1];\ncz q[1],q[0];\ncx q[0],q[1];\ncx q[1],q[0];");
+        assert_eq!(cancel_inverses(&mut c), 2);
+        assert_eq!(names(&c), ["cx", "cx"]);
+    }
+
+    #[test]
+    fn gates_in_between_or_conditions_block_the_cancellation() {
+        let mut c = circuit(
+            "qreg q[2];\ncreg c[1];\nh q[0];\ncx q[0],q[1];\nh q[0];\nx q[1];\nif(c==1) x q[1];",
+        );
+        assert_eq!(cancel_inverses(&mut c), 0);
+        assert_eq!(c.instructions.len(), 5);
+    }
+
+    #[test]
+    fn rotations_merge() {
+        let mut c = circuit("qreg q[1];\nrz(0.25) q[0];\nrz(0.5) q[0];");
+        assert_eq!(merge_rotations(&mut c), 1);
+        let Op::Gate { params, .. } = &c.instructions[0].op else {
+            panic!()
+        };
+        assert_eq!(params, &[Expr::Num(0.75)]);
+
+        // a full turn is removed, but not a controlled rotation by 2 pi
+        let mut c = circuit(
+            "qreg q[2];\nrx(pi) q[0];\nrx(pi) q[0];\ncrz(pi) q[0],q[1];\ncrz(pi) q[0],q[1];",
+        );
+        assert_eq!(merge_rotations(&mut c), 3);
+        assert_eq!(names(&c), ["crz"]);
+
+        // free variables are kept as an expression
+        let mut c = circuit("qreg q[1];\nry(theta) q[0];\nry(0.5) q[0];");
+        assert_eq!(merge_rotations(&mut c), 1);
+        assert_eq!(c.free_vars(), ["theta"]);
+    }
+
+    #[test]
+    fn gates_before_a_reset_are_removed() {
+        let mut c = circuit("qreg q[2];\nreset q[0];\nh q[0];\nx q[0];\nreset q[0];\ncx q[0],q[1];\nh q[1];\nreset q[1];");
+        assert_eq!(remove_before_reset(&mut c), 5);
+        assert_eq!(names(&c), ["cx", "reset"]);
+    }
+
+    #[test]
+    fn unmeasured_gates_are_dropped() {
+        let mut c = circuit("qreg q[3];\ncreg c[1];\nh q[0];\ncx q[0],q[1];\nh q[2];\nmeasure q[1] -> c[0];\nx q[1];");
+        assert_eq!(drop_unmeasured(&mut c), 2);
+        assert_eq!(names(&c), ["h", "cx", "measure"]);
+
+        // the state before a reset does not matter
+        let mut c =
+            circuit("qreg q[1];\ncreg c[1];\nh q[0];\nreset q[0];\nx q[0];\nmeasure q[0] -> c[0];");
+        assert_eq!(drop_unmeasured(&mut c), 1);
+        assert_eq!(names(&c), ["reset", "x", "measure"]);
+    }
+
+    #[test]
+    fn levels() {
+        let source = "qreg q[2];\ncreg c[1];\nh q[1];\nrz(0.5) q[0];\nrz(-0.5) q[0];\nh q[0];\nh q[0];\nmeasure q[0] -> c[0];";
+        let mut c = circuit(source);
+        assert_eq!(optimize(&mut c, 0, &[]).unwrap(), []);
+        assert_eq!(c.instructions.len(), 6);
+
+        let removed = optimize(&mut c, 1, &[]).unwrap();
+        assert_eq!(removed, [("cancel_inverses", 2), ("merge_rotations", 2)]);
+        assert_eq!(names(&c), ["h", "measure"]);
+
+        let removed = optimize(&mut c, 2, &[]).unwrap();
+        assert_eq!(removed.last(), Some(&("drop_unmeasured", 1)));
+        assert_eq!(names(&c), ["measure"]);
+
+        // level 3 repeats until the merged rotations uncover the inverses
+        let mut c = circuit("qreg q[1];\nh q[0];\nrz(0.5) q[0];\nrz(-0.5) q[0];\nh q[0];");
+        optimize(&mut c, 1, &[]).unwrap();
+        assert_eq!(names(&c), ["h", "h"]);
+        optimize(&mut c, 3, &[]).unwrap();
+        assert!(c.instructions.is_empty());
+
+        assert!(optimize(&mut c, 4, &[])
+            .unwrap_err()
+            .contains("levels are 0 to 3"));
+    }
+}
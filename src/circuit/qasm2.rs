@@ -0,0 +1,840 @@
+use std::collections::HashMap;
+
+use super::{
+    gates::{canonical_name, gate_info, qelib1_extension},
+    Circuit, Condition, Expr, Instruction, Op, Register,
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub(super) enum Token {
+    Ident(String),
+    Number(String),
+    Str(String),
+    Symbol(&'static str),
+}
+
+/// the symbols of OpenQASM 2.0, the longer ones first
+pub(super) const SYMBOLS: &[&str] = &[
+    "->", "==", ";", ",", "(", ")", "[", "]", "{", "}", "+", "-", "*", "/", "^",
+];
+
+/// the tokens of the source and their line numbers
+pub(super) fn tokenize(
+    source: &str,
+    symbols: &[&'static str],
+) -> Result<Vec<(Token, usize)>, String> {
+    let mut tokens = Vec::new();
+    for (line_no, line) in source.lines().enumerate() {
+        let line_no = line_no + 1;
+        let line = line.split("//").next().unwrap_or("");
+        let chars: Vec<char> = line.chars().collect();
+        let mut i = 0;
+        while i < chars.len() {
+            let c = chars[i];
+            if c.is_whitespace() {
+                i += 1;
+            } else if c.is_ascii_alphabetic() || c == '_' {
+                let start = i;
+                while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                    i += 1;
+                }
+                tokens.push((Token::Ident(chars[start..i].iter().collect()), line_no));
+            } else if c.is_ascii_digit() || c == '.' {
+                let start = i;
+                while i < chars.len()
+                    && (chars[i].is_ascii_digit()
+                        || chars[i] == '.'
+                        || chars[i] == 'e'
+                        || chars[i] == 'E'
+                        || ((chars[i] == '-' || chars[i] == '+')
+                            && (chars[i - 1] == 'e' || chars[i - 1] == 'E')))
+                {
+                    i += 1;
+                }
+                tokens.push((Token::Number(chars[start..i].iter().collect()), line_no));
+            } else if c == '"' {
+                let start = i + 1;
+                i += 1;
+                while i < chars.len() && chars[i] != '"' {
+                    i += 1;
+                }
+                if i == chars.len() {
+                    return Err(format!("line {}: unterminated string", line_no));
+                }
+                tokens.push((Token::Str(chars[start..i].iter().collect()), line_no));
+                i += 1;
+            } else {
+                let rest: String = chars[i..].iter().take(2).collect();
+                match symbols.iter().find(|symbol| rest.starts_with(**symbol)) {
+                    Some(symbol) => {
+                        tokens.push((Token::Symbol(symbol), line_no));
+                        i += symbol.len();
+                    }
+                    None => return Err(format!("line {}: unexpected character {}", line_no, c)),
+                }
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+/// a gate call inside a gate definition, the arguments are the names of the
+/// definition's qubit arguments
+#[derive(Debug, Clone)]
+struct GateCall {
+    name: String,
+    params: Vec<Expr>,
+    args: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+struct GateDef {
+    params: Vec<String>,
+    args: Vec<String>,
+    body: Vec<GateCall>,
+}
+
+/// a gate argument, a single bit or the whole register
+pub(super) enum Arg {
+    Bit(usize),
+    Reg(usize, usize),
+}
+
+/// The parser of OpenQASM 2.0, the OpenQASM 3 front end extends it with its
+/// own statements
+pub(super) struct Parser {
+    pub(super) tokens: Vec<(Token, usize)>,
+    pub(super) pos: usize,
+    pub(super) circuit: Circuit,
+    gates: HashMap<String, GateDef>,
+}
+
+/// Parse an OpenQASM 2.0 program. Identifiers in the gate parameters which are
+/// not defined are kept as free variables. qasmsim only runs a program as a
+/// whole, with its own unseeded random stream, so the agent parses the
+/// programs itself to split their shots across the pool.
+pub fn parse(source: &str) -> Result<Circuit, String> {
+    let mut parser = Parser::new(tokenize(source, SYMBOLS)?);
+    parser.program()?;
+    Ok(parser.circuit)
+}
+
+/// Parse a parameter expression, e.g. `theta/2+pi`
+pub fn parse_expr(source: &str) -> Result<Expr, String> {
+    let mut parser = Parser::new(tokenize(source, SYMBOLS)?);
+    let expr = parser.expr()?;
+    match parser.peek() {
+        Some(token) => Err(format!("unexpected {:?} in expression", token)),
+        None => Ok(expr),
+    }
+}
+
+impl Parser {
+    pub(super) fn new(tokens: Vec<(Token, usize)>) -> Self {
+        Parser {
+            tokens,
+            pos: 0,
+            circuit: Circuit::default(),
+            gates: HashMap::new(),
+        }
+    }
+
+    pub(super) fn line(&self) -> usize {
+        self.tokens
+            .get(self.pos)
+            .or(self.tokens.last())
+            .map_or(0, |(_, line)| *line)
+    }
+
+    pub(super) fn error<T>(&self, msg: &str) -> Result<T, String> {
+        Err(format!("line {}: {}", self.line(), msg))
+    }
+
+    pub(super) fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(token, _)| token)
+    }
+
+    pub(super) fn next(&mut self) -> Result<Token, String> {
+        match self.tokens.get(self.pos) {
+            Some((token, _)) => {
+                self.pos += 1;
+                Ok(token.clone())
+            }
+            None => self.error("unexpected end of program"),
+        }
+    }
+
+    pub(super) fn is_symbol(&self, symbol: &str) -> bool {
+        matches!(self.peek(), Some(Token::Symbol(s)) if *s == symbol)
+    }
+
+    pub(super) fn expect(&mut self, symbol: &str) -> Result<(), String> {
+        match self.next()? {
+            Token::Symbol(s) if s == symbol => Ok(()),
+            token => {
+                self.pos -= 1;
+                self.error(&format!("expected {}, found {:?}", symbol, token))
+            }
+        }
+    }
+
+    pub(super) fn ident(&mut self) -> Result<String, String> {
+        match self.next()? {
+            Token::Ident(ident) => Ok(ident),
+            token => {
+                self.pos -= 1;
+                self.error(&format!("expected identifier, found {:?}", token))
+            }
+        }
+    }
+
+    pub(super) fn integer(&mut self) -> Result<usize, String> {
+        match self.next()? {
+            Token::Number(number) => match number.parse() {
+                Ok(number) => Ok(number),
+                Err(_) => {
+                    self.pos -= 1;
+                    self.error(&format!("expected integer, found {}", number))
+                }
+            },
+            token => {
+                self.pos -= 1;
+                self.error(&format!("expected integer, found {:?}", token))
+            }
+        }
+    }
+
+    fn program(&mut self) -> Result<(), String> {
+        if matches!(self.peek(), Some(Token::Ident(ident)) if ident == "OPENQASM") {
+            self.pos += 1;
+            match self.next()? {
+                Token::Number(version) if version.starts_with('2') => {}
+                _ => {
+                    self.pos -= 1;
+                    return self.error("only OPENQASM 2.0 is supported");
+                }
+            }
+            self.expect(";")?;
+        }
+
+        while self.peek().is_some() {
+            self.statement()?;
+        }
+        Ok(())
+    }
+
+    fn statement(&mut self) -> Result<(), String> {
+        let keyword = self.ident()?;
+        match keyword.as_str() {
+            "include" => match self.next()? {
+                Token::Str(file) if file == "qelib1.inc" => self.expect(";"),
+                _ => {
+                    self.pos -= 1;
+                    self.error("only qelib1.inc can be included")
+                }
+            },
+            "qreg" | "creg" => {
+                let name = self.ident()?;
+                self.expect("[")?;
+                let size = self.integer()?;
+                self.expect("]")?;
+                self.expect(";")?;
+                let regs = if keyword == "qreg" {
+                    &mut self.circuit.qregs
+                } else {
+                    &mut self.circuit.cregs
+                };
+                let offset = regs.iter().map(|reg| reg.size).sum();
+                regs.push(Register { name, size, offset });
+                Ok(())
+            }
+            "gate" => self.gate_definition(),
+            "opaque" => self.error("opaque gates are not supported"),
+            "if" => {
+                self.expect("(")?;
+                let creg = self.ident()?;
+                if self.circuit.creg(&creg).is_none() {
+                    return self.error(&format!("unknown classical register {}", creg));
+                }
+                self.expect("==")?;
+                let value = self.integer()? as u64;
+                self.expect(")")?;
+                let keyword = self.ident()?;
+                self.operation(&keyword, Some(Condition { creg, value }))
+            }
+            _ => self.operation(&keyword, None),
+        }
+    }
+
+    pub(super) fn gate_definition(&mut self) -> Result<(), String> {
+        let name = self.ident()?;
+        let mut params = Vec::new();
+        if self.is_symbol("(") {
+            self.pos += 1;
+            while !self.is_symbol(")") {
+                params.push(self.ident()?);
+                if !self.is_symbol(")") {
+                    self.expect(",")?;
+                }
+            }
+            self.pos += 1;
+        }
+        let mut args = vec![self.ident()?];
+        while self.is_symbol(",") {
+            self.pos += 1;
+            args.push(self.ident()?);
+        }
+
+        self.expect("{")?;
+        let mut body = Vec::new();
+        while !self.is_symbol("}") {
+            let gate = self.ident()?;
+            let call_params = self.params()?;
+            let mut call_args = vec![self.ident()?];
+            while self.is_symbol(",") {
+                self.pos += 1;
+                call_args.push(self.ident()?);
+            }
+            self.expect(";")?;
+
+            if gate == "barrier" {
+                continue;
+            }
+            for arg in call_args.iter() {
+                if !args.contains(arg) {
+                    return self.error(&format!("unknown argument {} in gate {}", arg, name));
+                }
+            }
+            self.check_gate(&gate, call_params.len(), call_args.len())?;
+            body.push(GateCall {
+                name: canonical_name(&gate).to_string(),
+                params: call_params,
+                args: call_args,
+            });
+        }
+        self.pos += 1;
+
+        // the gates of qelib1.inc are builtin
+        if gate_info(canonical_name(&name)).is_none() {
+            self.gates.insert(name, GateDef { params, args, body });
+        }
+        Ok(())
+    }
+
+    pub(super) fn check_gate(
+        &self,
+        name: &str,
+        params: usize,
+        qubits: usize,
+    ) -> Result<(), String> {
+        let expected = match gate_info(canonical_name(name)) {
+            Some(info) => info,
+            None => match self.gates.get(name) {
+                Some(def) => (def.params.len(), def.args.len()),
+                None => return self.error(&format!("unknown gate {}", name)),
+            },
+        };
+        if expected != (params, qubits) {
+            return self.error(&format!(
+                "gate {} takes {} parameters and {} qubits, found {} and {}",
+                name, expected.0, expected.1, params, qubits
+            ));
+        }
+        Ok(())
+    }
+
+    pub(super) fn params(&mut self) -> Result<Vec<Expr>, String> {
+        let mut params = Vec::new();
+        if self.is_symbol("(") {
+            self.pos += 1;
+            while !self.is_symbol(")") {
+                params.push(self.expr()?);
+                if !self.is_symbol(")") {
+                    self.expect(",")?;
+                }
+            }
+            self.pos += 1;
+        }
+        Ok(params)
+    }
+
+    fn arg(&mut self, quantum: bool) -> Result<Arg, String> {
+        let name = self.ident()?;
+        let regs = if quantum {
+            &self.circuit.qregs
+        } else {
+            &self.circuit.cregs
+        };
+        let (offset, size) = match regs.iter().find(|reg| reg.name == name) {
+            Some(reg) => (reg.offset, reg.size),
+            None => return self.error(&format!("unknown register {}", name)),
+        };
+
+        if self.is_symbol("[") {
+            self.pos += 1;
+            let index = self.integer()?;
+            if index >= size {
+                return self.error(&format!(
+                    "index {} is out of register {}[{}]",
+                    index, name, size
+                ));
+            }
+            self.expect("]")?;
+            Ok(Arg::Bit(offset + index))
+        } else {
+            Ok(Arg::Reg(offset, size))
+        }
+    }
+
+    /// the bits of each application, broadcasting the whole registers
+    pub(super) fn broadcast(&self, args: &[Arg]) -> Result<Vec<Vec<usize>>, String> {
+        let mut size = None;
+        for arg in args {
+            if let Arg::Reg(_, reg_size) = arg {
+                if size.is_some_and(|size| size != *reg_size) {
+                    return self.error("registers of different sizes");
+                }
+                size = Some(*reg_size);
+            }
+        }
+
+        Ok((0..size.unwrap_or(1))
+            .map(|i| {
+                args.iter()
+                    .map(|arg| match arg {
+                        Arg::Bit(bit) => *bit,
+                        Arg::Reg(offset, _) => offset + i,
+                    })
+                    .collect()
+            })
+            .collect())
+    }
+
+    fn operation(&mut self, keyword: &str, condition: Option<Condition>) -> Result<(), String> {
+        match keyword {
+            "measure" => {
+                let qubit = self.arg(true)?;
+                self.expect("->")?;
+                let clbit = self.arg(false)?;
+                self.expect(";")?;
+                self.measure(qubit, clbit, &condition)?;
+            }
+            "reset" => {
+                let qubit = self.arg(true)?;
+                self.expect(";")?;
+                for bits in self.broadcast(&[qubit])? {
+                    self.push(Op::Reset { qubit: bits[0] }, &condition);
+                }
+            }
+            "barrier" => {
+                let mut qubits = Vec::new();
+                loop {
+                    match self.arg(true)? {
+                        Arg::Bit(bit) => qubits.push(bit),
+                        Arg::Reg(offset, size) => qubits.extend(offset..offset + size),
+                    }
+                    if !self.is_symbol(",") {
+                        break;
+                    }
+                    self.pos += 1;
+                }
+                self.expect(";")?;
+                self.push(Op::Barrier { qubits }, &condition);
+            }
+            _ => {
+                let params = self.params()?;
+                let mut args = vec![self.arg(true)?];
+                while self.is_symbol(",") {
+                    self.pos += 1;
+                    args.push(self.arg(true)?);
+                }
+                self.expect(";")?;
+                self.gate(keyword, params, &args, &condition)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// apply a gate to each of the broadcast arguments
+    pub(super) fn gate(
+        &mut self,
+        name: &str,
+        params: Vec<Expr>,
+        args: &[Arg],
+        condition: &Option<Condition>,
+    ) -> Result<(), String> {
+        self.check_gate(name, params.len(), args.len())?;
+        for qubits in self.broadcast(args)? {
+            let mut unique = qubits.clone();
+            unique.sort();
+            unique.dedup();
+            if unique.len() != qubits.len() {
+                return self.error(&format!("gate {} uses a qubit twice", name));
+            }
+            self.apply(name, params.clone(), qubits, condition, 0)?;
+        }
+        Ok(())
+    }
+
+    /// measure each of the broadcast qubits into its clbit
+    pub(super) fn measure(
+        &mut self,
+        qubit: Arg,
+        clbit: Arg,
+        condition: &Option<Condition>,
+    ) -> Result<(), String> {
+        for bits in self.broadcast(&[qubit, clbit])? {
+            self.push(
+                Op::Measure {
+                    qubit: bits[0],
+                    clbit: bits[1],
+                },
+                condition,
+            );
+        }
+        Ok(())
+    }
+
+    pub(super) fn push(&mut self, op: Op, condition: &Option<Condition>) {
+        self.circuit.instructions.push(Instruction {
+            op,
+            condition: condition.clone(),
+        });
+    }
+
+    /// apply a gate, expanding the custom gate definitions
+    pub(super) fn apply(
+        &mut self,
+        name: &str,
+        params: Vec<Expr>,
+        qubits: Vec<usize>,
+        condition: &Option<Condition>,
+        depth: usize,
+    ) -> Result<(), String> {
+        let name = canonical_name(name);
+        if gate_info(name).is_some() {
+            self.push(
+                Op::Gate {
+                    name: name.to_string(),
+                    params,
+                    qubits,
+                },
+                condition,
+            );
+            return Ok(());
+        }
+        if depth > 64 {
+            return self.error(&format!("gate {} is defined recursively", name));
+        }
+
+        let def = self.gates[name].clone();
+        let bound: HashMap<String, Expr> = def.params.iter().cloned().zip(params).collect();
+        let args: HashMap<&String, usize> = def.args.iter().zip(qubits).collect();
+        for call in def.body.iter() {
+            self.apply(
+                &call.name,
+                call.params
+                    .iter()
+                    .map(|param| param.substitute(&bound))
+                    .collect(),
+                call.args.iter().map(|arg| args[arg]).collect(),
+                condition,
+                depth + 1,
+            )?;
+        }
+        Ok(())
+    }
+
+    pub(super) fn expr(&mut self) -> Result<Expr, String> {
+        let mut lhs = self.term()?;
+        loop {
+            if self.is_symbol("+") {
+                self.pos += 1;
+                lhs = Expr::Add(Box::new(lhs), Box::new(self.term()?));
+            } else if self.is_symbol("-") {
+                self.pos += 1;
+                lhs = Expr::Sub(Box::new(lhs), Box::new(self.term()?));
+            } else {
+                return Ok(lhs);
+            }
+        }
+    }
+
+    fn term(&mut self) -> Result<Expr, String> {
+        let mut lhs = self.factor()?;
+        loop {
+            if self.is_symbol("*") {
+                self.pos += 1;
+                lhs = Expr::Mul(Box::new(lhs), Box::new(self.factor()?));
+            } else if self.is_symbol("/") {
+                self.pos += 1;
+                lhs = Expr::Div(Box::new(lhs), Box::new(self.factor()?));
+            } else {
+                return Ok(lhs);
+            }
+        }
+    }
+
+    fn factor(&mut self) -> Result<Expr, String> {
+        let base = self.unary()?;
+        if self.is_symbol("^") {
+            self.pos += 1;
+            return Ok(Expr::Pow(Box::new(base), Box::new(self.factor()?)));
+        }
+        Ok(base)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        if self.is_symbol("-") {
+            self.pos += 1;
+            return Ok(Expr::Neg(Box::new(self.unary()?)));
+        }
+        if self.is_symbol("+") {
+            self.pos += 1;
+            return self.unary();
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Expr, String> {
+        match self.next()? {
+            Token::Number(number) => match number.parse() {
+                Ok(number) => Ok(Expr::Num(number)),
+                Err(_) => {
+                    self.pos -= 1;
+                    self.error(&format!("invalid number {}", number))
+                }
+            },
+            Token::Ident(ident) if ident == "pi" => Ok(Expr::Pi),
+            Token::Ident(ident)
+                if matches!(
+                    ident.as_str(),
+                    "sin" | "cos" | "tan" | "exp" | "ln" | "sqrt"
+                ) =>
+            {
+                self.expect("(")?;
+                let arg = self.expr()?;
+                self.expect(")")?;
+                Ok(Expr::Func(ident, Box::new(arg)))
+            }
+            Token::Ident(ident) => Ok(Expr::Var(ident)),
+            Token::Symbol("(") => {
+                let expr = self.expr()?;
+                self.expect(")")?;
+                Ok(expr)
+            }
+            token => {
+                self.pos -= 1;
+                self.error(&format!("unexpected {:?} in expression", token))
+            }
+        }
+    }
+}
+
+/// Emit the circuit as OpenQASM 2.0. The gates which are not in the original
+/// `qelib1.inc` are defined in the program so that `qasmsim` can run it.
+pub fn to_qasm(circuit: &Circuit) -> String {
+    let mut qasm = "OPENQASM 2.0;\ninclude \"qelib1.inc\";\n".to_string();
+
+    let mut defined = Vec::new();
+    for inst in circuit.instructions.iter() {
+        if let Op::Gate { name, .. } = &inst.op {
+            if let Some(definition) = qelib1_extension(name) {
+                // cswap uses ccx, cu uses u1 and u3, they are all builtin
+                if !defined.contains(name) {
+                    defined.push(name.clone());
+                    qasm.push_str(definition);
+                    qasm.push('\n');
+                }
+            }
+        }
+    }
+
+    for reg in circuit.qregs.iter() {
+        qasm.push_str(&format!("qreg {}[{}];\n", reg.name, reg.size));
+    }
+    for reg in circuit.cregs.iter() {
+        qasm.push_str(&format!("creg {}[{}];\n", reg.name, reg.size));
+    }
+
+    for inst in circuit.instructions.iter() {
+        if let Some(condition) = &inst.condition {
+            qasm.push_str(&format!("if({}=={}) ", condition.creg, condition.value));
+        }
+        let qubits = |qubits: &[usize]| {
+            qubits
+                .iter()
+                .map(|&qubit| circuit.qubit_name(qubit))
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        match &inst.op {
+            Op::Gate {
+                name,
+                params,
+                qubits: args,
+            } => {
+                qasm.push_str(name);
+                if !params.is_empty() {
+                    let params: Vec<String> = params.iter().map(|p| p.to_string()).collect();
+                    qasm.push_str(&format!("({})", params.join(",")));
+                }
+                qasm.push_str(&format!(" {};\n", qubits(args)));
+            }
+            Op::Measure { qubit, clbit } => qasm.push_str(&format!(
+                "measure {} -> {};\n",
+                circuit.qubit_name(*qubit),
+                circuit.clbit_name(*clbit)
+            )),
+            Op::Reset { qubit } => {
+                qasm.push_str(&format!("reset {};\n", circuit.qubit_name(*qubit)))
+            }
+            Op::Barrier { qubits: args } => qasm.push_str(&format!("barrier {};\n", qubits(args))),
+        }
+    }
+    qasm
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEADER: &str = "OPENQASM 2.0;\ninclude \"qelib1.inc\";\n";
+
+    fn parse_body(body: &str) -> Result<Circuit, String> {
+        parse(&format!("{}{}", HEADER, body))
+    }
+
+    fn gate_names(circuit: &Circuit) -> Vec<&str> {
+        circuit
+            .instructions
+            .iter()
+            .map(|inst| inst.op.name())
+            .collect()
+    }
+
+    #[test]
+    fn registers_are_flattened_in_declaration_order() {
+        let circuit =
+            parse_body("qreg a[2];\nqreg b[1];\ncreg c[3];\nh a[0];\ncx a[1],b[0];\n").unwrap();
+        assert_eq!(circuit.num_qubits(), 3);
+        assert_eq!(circuit.qregs[1].offset, 2);
+        assert_eq!(
+            circuit.instructions[1].op,
+            Op::Gate {
+                name: "cx".to_string(),
+                params: vec![],
+                qubits: vec![1, 2],
+            }
+        );
+    }
+
+    #[test]
+    fn whole_registers_are_broadcast() {
+        let circuit = parse_body("qreg q[3];\ncreg c[3];\nh q;\nmeasure q -> c;\n").unwrap();
+        assert_eq!(
+            gate_names(&circuit),
+            vec!["h", "h", "h", "measure", "measure", "measure"]
+        );
+        assert_eq!(
+            circuit.instructions[5].op,
+            Op::Measure { qubit: 2, clbit: 2 }
+        );
+    }
+
+    #[test]
+    fn custom_gates_are_expanded() {
+        let circuit = parse_body(
+            "gate bell(t) a,b { h a; cx a,b; rz(t/2) b; }\nqreg q[2];\nbell(pi) q[1],q[0];\n",
+        )
+        .unwrap();
+        assert_eq!(gate_names(&circuit), vec!["h", "cx", "rz"]);
+        assert_eq!(circuit.instructions[1].op.qubits(), vec![1, 0]);
+        match &circuit.instructions[2].op {
+            Op::Gate { params, .. } => {
+                let angle = params[0].eval(&HashMap::new()).unwrap();
+                assert!((angle - std::f64::consts::FRAC_PI_2).abs() < 1e-12);
+            }
+            op => panic!("expected rz, found {:?}", op),
+        }
+    }
+
+    #[test]
+    fn undefined_parameters_are_free_variables() {
+        let circuit = parse_body("qreg q[1];\nrx(theta*2) q[0];\nry(phi) q[0];\n").unwrap();
+        assert_eq!(circuit.free_vars(), vec!["theta", "phi"]);
+    }
+
+    #[test]
+    fn conditions_are_kept() {
+        let circuit = parse_body("qreg q[1];\ncreg c[2];\nif(c==3) x q[0];\n").unwrap();
+        assert_eq!(
+            circuit.instructions[0].condition,
+            Some(Condition {
+                creg: "c".to_string(),
+                value: 3
+            })
+        );
+    }
+
+    #[test]
+    fn errors_name_the_line() {
+        let cases = [
+            ("qreg q[1];\nfoo q[0];\n", "line 4: unknown gate foo"),
+            (
+                "qreg q[1];\nx q[1];\n",
+                "line 4: index 1 is out of register q[1]",
+            ),
+            ("qreg q[1];\nx r[0];\n", "line 4: unknown register r"),
+            (
+                "qreg q[1];\nrx q[0];\n",
+                "line 4: gate rx takes 1 parameters and 1 qubits, found 0 and 1",
+            ),
+            (
+                "qreg q[2];\nqreg r[3];\ncx q,r;\n",
+                "line 5: registers of different sizes",
+            ),
+            (
+                "qreg q[2];\ncx q[0],q[0];\n",
+                "line 4: gate cx uses a qubit twice",
+            ),
+            ("qreg q[1];\nmeasure q[0] -> c[0];\n", "unknown register c"),
+            ("qreg q[1];\nh q[0]\n", "line 4: unexpected end of program"),
+            ("opaque g q;\n", "opaque gates are not supported"),
+        ];
+        for (body, expected) in cases {
+            let err = parse_body(body).unwrap_err();
+            assert!(err.contains(expected), "{:?}: {}", body, err);
+        }
+    }
+
+    #[test]
+    fn only_version_two_is_parsed() {
+        let err = parse("OPENQASM 3.0;\nqubit q;\n").unwrap_err();
+        assert!(err.contains("only OPENQASM 2.0 is supported"), "{}", err);
+        let err = parse("OPENQASM 2.0;\ninclude \"other.inc\";\n").unwrap_err();
+        assert!(err.contains("only qelib1.inc can be included"), "{}", err);
+    }
+
+    #[test]
+    fn expressions_follow_precedence() {
+        let vars = HashMap::from([("x".to_string(), 3.0)]);
+        let eval = |source: &str| parse_expr(source).unwrap().eval(&vars).unwrap();
+        assert_eq!(eval("1+2*x"), 7.0);
+        assert_eq!(eval("2*x^2"), 18.0);
+        assert_eq!(eval("(1+2)*x/9"), 1.0);
+        assert!((eval("cos(pi)") + 1.0).abs() < 1e-12);
+        assert!(parse_expr("1+").is_err());
+        assert!(parse_expr("1 2").is_err());
+    }
+
+    #[test]
+    fn emitted_program_parses_to_the_same_circuit() {
+        let circuit = parse_body(
+            "qreg q[3];\ncreg c[3];\nh q[0];\ncswap q[0],q[1],q[2];\nrx(theta) q[1];\n\
+             barrier q;\nreset q[2];\nmeasure q -> c;\nif(c==1) x q[0];\n",
+        )
+        .unwrap();
+        assert_eq!(parse(&to_qasm(&circuit)).unwrap(), circuit);
+    }
+}
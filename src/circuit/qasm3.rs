@@ -0,0 +1,633 @@
+use std::collections::HashMap;
+
+use super::{
+    qasm2::{tokenize, Arg, Parser, Token},
+    Circuit, Condition, Expr, Op, Register,
+};
+
+/// the symbols of the OpenQASM 3 subset, the longer ones first
+const SYMBOLS: &[&str] = &[
+    "->", "==", "!=", "<=", ">=", "=", ":", "<", ">", "@", ";", ",", "(", ")", "[", "]", "{", "}",
+    "+", "-", "*", "/", "^",
+];
+
+/// the keywords of OpenQASM 3 which are outside the subset
+const UNSUPPORTED: &[&str] = &[
+    "while",
+    "def",
+    "extern",
+    "box",
+    "delay",
+    "switch",
+    "return",
+    "break",
+    "continue",
+    "let",
+    "output",
+    "defcal",
+    "cal",
+    "defcalgrammar",
+    "opaque",
+    "pragma",
+    "durationof",
+    "stretch",
+];
+
+/// the classical types, only the parameters declared by `input` are supported
+const TYPES: &[&str] = &[
+    "float", "angle", "int", "uint", "bool", "complex", "duration",
+];
+
+/// Whether the program declares `OPENQASM 3`
+pub fn is_qasm3(source: &str) -> bool {
+    let source = strip_block_comments(source);
+    let mut words = source
+        .lines()
+        .map(|line| line.split("//").next().unwrap_or(""))
+        .flat_map(|line| line.split_whitespace());
+    words.next() == Some("OPENQASM") && words.next().is_some_and(|word| word.starts_with('3'))
+}
+
+/// replace the `/* */` comments by spaces, keeping the line numbers
+fn strip_block_comments(source: &str) -> String {
+    let mut stripped = String::with_capacity(source.len());
+    let mut rest = source;
+    while let Some(start) = rest.find("/*") {
+        stripped.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find("*/")
+            .map_or(rest.len(), |end| start + end + 2);
+        stripped.extend(rest[start..end].chars().filter(|c| *c == '\n'));
+        rest = &rest[end..];
+    }
+    stripped.push_str(rest);
+    stripped
+}
+
+/// the most loop iterations unrolled in a program, nested loops included
+pub const MAX_UNROLLED_ITERATIONS: usize = 100_000;
+/// the most instructions of an unrolled program
+pub const MAX_INSTRUCTIONS: usize = 1_000_000;
+
+struct Qasm3Parser {
+    parser: Parser,
+    /// loop iterations unrolled so far
+    iterations: usize,
+    /// constants and loop variables, substituted where they are used
+    scope: HashMap<String, Expr>,
+    /// the parameters declared by `input`, bound by `vars` when it is run
+    inputs: Vec<String>,
+}
+
+/// Parse a program of the OpenQASM 3 subset: `qubit` and `bit` registers,
+/// `input` parameters, `const` values, gate definitions, gate calls,
+/// `c = measure q`, `reset`, `barrier`, `if (c == n)` and `for` loops over
+/// ranges or sets, which are unrolled up to `MAX_UNROLLED_ITERATIONS` and
+/// `MAX_INSTRUCTIONS`. The `input` parameters are kept as free variables. Other constructs are reported with their line.
+pub fn parse(source: &str) -> Result<Circuit, String> {
+    let mut parser = Qasm3Parser {
+        parser: Parser::new(tokenize(&strip_block_comments(source), SYMBOLS)?),
+        iterations: 0,
+        scope: HashMap::new(),
+        inputs: Vec::new(),
+    };
+    parser.program()?;
+    Ok(parser.parser.circuit)
+}
+
+impl Qasm3Parser {
+    fn error<T>(&self, msg: &str) -> Result<T, String> {
+        self.parser.error(msg)
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(self.parser.peek(), Some(Token::Ident(ident)) if ident == keyword)
+    }
+
+    fn program(&mut self) -> Result<(), String> {
+        if self.is_keyword("OPENQASM") {
+            self.parser.pos += 1;
+            match self.parser.next()? {
+                Token::Number(version) if version.starts_with('3') => {}
+                _ => {
+                    self.parser.pos -= 1;
+                    return self.error("expected OPENQASM 3");
+                }
+            }
+            self.parser.expect(";")?;
+        }
+
+        while self.parser.peek().is_some() {
+            self.statement(&None)?;
+        }
+        Ok(())
+    }
+
+    /// a statement, or a block of statements in braces
+    fn block(&mut self, condition: &Option<Condition>) -> Result<(), String> {
+        if self.parser.is_symbol("{") {
+            self.parser.pos += 1;
+            while !self.parser.is_symbol("}") {
+                self.statement(condition)?;
+            }
+            self.parser.pos += 1;
+            Ok(())
+        } else {
+            self.statement(condition)
+        }
+    }
+
+    fn statement(&mut self, condition: &Option<Condition>) -> Result<(), String> {
+        let keyword = self.parser.ident()?;
+        match keyword.as_str() {
+            "include" => match self.parser.next()? {
+                Token::Str(file) if file == "stdgates.inc" || file == "qelib1.inc" => {
+                    self.parser.expect(";")
+                }
+                _ => {
+                    self.parser.pos -= 1;
+                    self.error("only stdgates.inc can be included")
+                }
+            },
+            "qubit" | "bit" => {
+                let size = if self.parser.is_symbol("[") {
+                    self.parser.pos += 1;
+                    let size = self.index()?;
+                    self.parser.expect("]")?;
+                    size
+                } else {
+                    1
+                };
+                let name = self.parser.ident()?;
+                if self.parser.is_symbol("=") {
+                    return self.error("initialized bits are not supported");
+                }
+                self.parser.expect(";")?;
+                self.declare(keyword == "qubit", name, size)
+            }
+            "qreg" | "creg" => {
+                let name = self.parser.ident()?;
+                self.parser.expect("[")?;
+                let size = self.index()?;
+                self.parser.expect("]")?;
+                self.parser.expect(";")?;
+                self.declare(keyword == "qreg", name, size)
+            }
+            "input" => {
+                self.classical_type()?;
+                let name = self.parser.ident()?;
+                self.parser.expect(";")?;
+                self.inputs.push(name);
+                Ok(())
+            }
+            "const" => {
+                self.classical_type()?;
+                let name = self.parser.ident()?;
+                self.parser.expect("=")?;
+                let value = self.expr()?;
+                self.parser.expect(";")?;
+                self.scope.insert(name, value);
+                Ok(())
+            }
+            "gate" => self.parser.gate_definition(),
+            "if" => self.if_statement(condition),
+            "for" => self.for_statement(condition),
+            "measure" => {
+                // the OpenQASM 2.0 form `measure q -> c;`
+                let qubit = self.arg(true, None)?;
+                self.parser.expect("->")?;
+                let clbit = self.arg(false, None)?;
+                self.parser.expect(";")?;
+                self.parser.measure(qubit, clbit, condition)
+            }
+            "reset" => {
+                let qubit = self.arg(true, None)?;
+                self.parser.expect(";")?;
+                for bits in self.parser.broadcast(&[qubit])? {
+                    self.parser.push(Op::Reset { qubit: bits[0] }, condition);
+                }
+                Ok(())
+            }
+            "barrier" => {
+                let mut qubits = Vec::new();
+                while !self.parser.is_symbol(";") {
+                    match self.arg(true, None)? {
+                        Arg::Bit(bit) => qubits.push(bit),
+                        Arg::Reg(offset, size) => qubits.extend(offset..offset + size),
+                    }
+                    if !self.parser.is_symbol(";") {
+                        self.parser.expect(",")?;
+                    }
+                }
+                self.parser.pos += 1;
+                // `barrier;` is on all the qubits
+                if qubits.is_empty() {
+                    qubits = (0..self.parser.circuit.num_qubits()).collect();
+                }
+                self.parser.push(Op::Barrier { qubits }, condition);
+                Ok(())
+            }
+            "gphase" => {
+                // a global phase does not change the measurements
+                self.parser.params()?;
+                self.parser.expect(";")
+            }
+            "ctrl" | "negctrl" | "inv" | "pow" => {
+                self.parser.pos -= 1;
+                self.error("gate modifiers are not supported")
+            }
+            _ if UNSUPPORTED.contains(&keyword.as_str()) => {
+                self.parser.pos -= 1;
+                self.error(&format!("{} is not supported", keyword))
+            }
+            _ if TYPES.contains(&keyword.as_str()) => {
+                self.parser.pos -= 1;
+                self.error(
+                    "classical variables are not supported, declare the parameters with input",
+                )
+            }
+            _ if self.parser.circuit.creg(&keyword).is_some() => {
+                // `c = measure q;`
+                let clbit = self.arg(false, Some(keyword))?;
+                self.parser.expect("=")?;
+                if !self.is_keyword("measure") {
+                    return self.error("only measurements can be assigned to bits");
+                }
+                self.parser.pos += 1;
+                let qubit = self.arg(true, None)?;
+                self.parser.expect(";")?;
+                self.parser.measure(qubit, clbit, condition)
+            }
+            _ => {
+                let params = self
+                    .parser
+                    .params()?
+                    .into_iter()
+                    .map(|param| self.bind(param))
+                    .collect::<Result<Vec<_>, _>>()?;
+                let mut args = vec![self.arg(true, None)?];
+                while self.parser.is_symbol(",") {
+                    self.parser.pos += 1;
+                    args.push(self.arg(true, None)?);
+                }
+                self.parser.expect(";")?;
+                self.parser.gate(&keyword, params, &args, condition)
+            }
+        }
+    }
+
+    fn declare(&mut self, quantum: bool, name: String, size: usize) -> Result<(), String> {
+        let circuit = &self.parser.circuit;
+        if circuit
+            .qregs
+            .iter()
+            .chain(circuit.cregs.iter())
+            .any(|reg| reg.name == name)
+        {
+            return self.error(&format!("{} is already declared", name));
+        }
+        let regs = if quantum {
+            &mut self.parser.circuit.qregs
+        } else {
+            &mut self.parser.circuit.cregs
+        };
+        let offset = regs.iter().map(|reg| reg.size).sum();
+        regs.push(Register { name, size, offset });
+        Ok(())
+    }
+
+    /// `float`, `float[64]`, `angle`, `int[32]`, ...
+    fn classical_type(&mut self) -> Result<(), String> {
+        let ty = self.parser.ident()?;
+        if !TYPES.contains(&ty.as_str()) || ty == "complex" || ty == "duration" {
+            self.parser.pos -= 1;
+            return self.error(&format!("type {} is not supported", ty));
+        }
+        if self.parser.is_symbol("[") {
+            self.parser.pos += 1;
+            self.parser.integer()?;
+            self.parser.expect("]")?;
+        }
+        Ok(())
+    }
+
+    /// `if (c == n)` with a statement or a block, the condition is on a
+    /// whole register as in OpenQASM 2.0
+    fn if_statement(&mut self, condition: &Option<Condition>) -> Result<(), String> {
+        if condition.is_some() {
+            return self.error("nested conditions are not supported");
+        }
+        self.parser.expect("(")?;
+        let creg = self.parser.ident()?;
+        if self.parser.circuit.creg(&creg).is_none() {
+            return self.error(&format!("unknown classical register {}", creg));
+        }
+        if self.parser.is_symbol("[") {
+            return self.error("conditions on a single bit are not supported");
+        }
+        self.parser.expect("==")?;
+        let value = self.index()? as u64;
+        self.parser.expect(")")?;
+        self.block(&Some(Condition { creg, value }))?;
+
+        if self.is_keyword("else") {
+            return self.error("else is not supported");
+        }
+        Ok(())
+    }
+
+    /// `for uint i in [start:end]`, `[start:step:end]` or `{a, b, c}`, the
+    /// ranges include the end. The body is parsed again for each value.
+    fn for_statement(&mut self, condition: &Option<Condition>) -> Result<(), String> {
+        let mut name = self.parser.ident()?;
+        if self.parser.is_symbol("[") {
+            self.parser.pos += 1;
+            self.parser.integer()?;
+            self.parser.expect("]")?;
+        }
+        if !self.is_keyword("in") {
+            // the first identifier is the type
+            name = self.parser.ident()?;
+        }
+        if !self.is_keyword("in") {
+            return self.error("expected in");
+        }
+        self.parser.pos += 1;
+
+        let mut values = Vec::new();
+        if self.parser.is_symbol("{") {
+            self.parser.pos += 1;
+            while !self.parser.is_symbol("}") {
+                values.push(self.index()? as i64);
+                if !self.parser.is_symbol("}") {
+                    self.parser.expect(",")?;
+                }
+            }
+            self.parser.pos += 1;
+        } else {
+            self.parser.expect("[")?;
+            let mut bounds = vec![self.integer_expr()?];
+            while self.parser.is_symbol(":") {
+                self.parser.pos += 1;
+                bounds.push(self.integer_expr()?);
+            }
+            self.parser.expect("]")?;
+            let (start, step, end) = match bounds[..] {
+                [start, end] => (start, 1, end),
+                [start, step, end] => (start, step, end),
+                _ => return self.error("expected a range [start:end] or [start:step:end]"),
+            };
+            if step == 0 {
+                return self.error("the step of a range cannot be 0");
+            }
+            let mut value = Some(start);
+            while let Some(current) =
+                value.filter(|&value| (step > 0 && value <= end) || (step < 0 && value >= end))
+            {
+                if values.len() >= MAX_UNROLLED_ITERATIONS {
+                    return self.too_many_iterations();
+                }
+                values.push(current);
+                value = current.checked_add(step);
+            }
+        }
+
+        let shadowed = self.scope.remove(&name);
+        let body = self.parser.pos;
+        for value in values.iter() {
+            self.iterations += 1;
+            if self.iterations > MAX_UNROLLED_ITERATIONS {
+                return self.too_many_iterations();
+            }
+            self.parser.pos = body;
+            self.scope.insert(name.clone(), Expr::Num(*value as f64));
+            self.block(condition)?;
+            if self.parser.circuit.instructions.len() > MAX_INSTRUCTIONS {
+                return self.error(&format!(
+                    "the unrolled loops have more than {} instructions",
+                    MAX_INSTRUCTIONS
+                ));
+            }
+        }
+        if values.is_empty() {
+            self.skip_block()?;
+        }
+        self.scope.remove(&name);
+        if let Some(shadowed) = shadowed {
+            self.scope.insert(name, shadowed);
+        }
+        Ok(())
+    }
+
+    fn too_many_iterations<T>(&self) -> Result<T, String> {
+        self.error(&format!(
+            "the loops unroll to more than {} iterations",
+            MAX_UNROLLED_ITERATIONS
+        ))
+    }
+
+    /// skip a statement or a block without parsing it, for an empty loop
+    fn skip_block(&mut self) -> Result<(), String> {
+        let mut depth = 0;
+        loop {
+            match self.parser.next()? {
+                Token::Symbol("{") => depth += 1,
+                Token::Symbol("}") => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Ok(());
+                    }
+                }
+                Token::Symbol(";") if depth == 0 => return Ok(()),
+                _ => {}
+            }
+        }
+    }
+
+    /// an expression with the constants and loop variables substituted, the
+    /// other identifiers must be `input` parameters
+    fn expr(&mut self) -> Result<Expr, String> {
+        let expr = self.parser.expr()?;
+        self.bind(expr)
+    }
+
+    fn bind(&self, expr: Expr) -> Result<Expr, String> {
+        let expr = expr.substitute(&self.scope);
+        let mut vars = Vec::new();
+        expr.free_vars(&mut vars);
+        match vars.iter().find(|var| !self.inputs.contains(var)) {
+            Some(var) => self.error(&format!(
+                "unknown identifier {}, declare the parameters with input",
+                var
+            )),
+            None => Ok(expr),
+        }
+    }
+
+    /// an integer known when parsing, e.g. a register index with loop variables
+    fn integer_expr(&mut self) -> Result<i64, String> {
+        let value = self
+            .expr()?
+            .eval(&HashMap::new())
+            .or_else(|_| self.error("expected a constant integer"))?;
+        if value.fract() != 0.0 {
+            return self.error(&format!("expected an integer, found {}", value));
+        }
+        Ok(value as i64)
+    }
+
+    fn index(&mut self) -> Result<usize, String> {
+        match self.integer_expr()? {
+            index if index >= 0 => Ok(index as usize),
+            index => self.error(&format!("expected a non-negative integer, found {}", index)),
+        }
+    }
+
+    /// a register or a bit of it, `name` if its identifier is already read
+    fn arg(&mut self, quantum: bool, name: Option<String>) -> Result<Arg, String> {
+        let name = match name {
+            Some(name) => name,
+            None => self.parser.ident()?,
+        };
+        let regs = if quantum {
+            &self.parser.circuit.qregs
+        } else {
+            &self.parser.circuit.cregs
+        };
+        let (offset, size) = match regs.iter().find(|reg| reg.name == name) {
+            Some(reg) => (reg.offset, reg.size),
+            None => return self.error(&format!("unknown register {}", name)),
+        };
+
+        if self.parser.is_symbol("[") {
+            self.parser.pos += 1;
+            let index = self.index()?;
+            if self.parser.is_symbol(":") || self.parser.is_symbol(",") {
+                return self.error("register slices are not supported");
+            }
+            if index >= size {
+                return self.error(&format!(
+                    "index {} is out of register {}[{}]",
+                    index, name, size
+                ));
+            }
+            self.parser.expect("]")?;
+            Ok(Arg::Bit(offset + index))
+        } else {
+            Ok(Arg::Reg(offset, size))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_body(body: &str) -> Result<Circuit, String> {
+        parse(&format!(
+            "OPENQASM 3.0;\ninclude \"stdgates.inc\";\n{}",
+            body
+        ))
+    }
+
+    fn gate_qubits(circuit: &Circuit) -> Vec<Vec<usize>> {
+        circuit
+            .instructions
+            .iter()
+            .map(|inst| inst.op.qubits())
+            .collect()
+    }
+
+    #[test]
+    fn version_is_detected() {
+        assert!(is_qasm3("// header\nOPENQASM 3.0;\nqubit q;"));
+        assert!(is_qasm3("/* a\nb */ OPENQASM 3;"));
+        assert!(!is_qasm3("OPENQASM 2.0;\nqreg q[1];"));
+    }
+
+    #[test]
+    fn declarations_measurements_and_inputs() {
+        let circuit = parse_body(
+            "input float theta;\nqubit[2] q;\nbit[2] c;\nrx(theta) q[0];\ncx q[0], q[1];\n\
+             c = measure q;\nc[0] = measure q[1];\n",
+        )
+        .unwrap();
+        assert_eq!(circuit.num_qubits(), 2);
+        assert_eq!(circuit.free_vars(), vec!["theta"]);
+        let names: Vec<&str> = circuit.instructions.iter().map(|i| i.op.name()).collect();
+        assert_eq!(names, vec!["rx", "cx", "measure", "measure", "measure"]);
+        assert_eq!(
+            circuit.instructions[4].op,
+            Op::Measure { qubit: 1, clbit: 0 }
+        );
+    }
+
+    #[test]
+    fn loops_are_unrolled() {
+        let circuit =
+            parse_body("qubit[4] q;\nfor uint i in [0:2] { h q[i]; }\nfor i in {3, 1} x q[i];\n")
+                .unwrap();
+        assert_eq!(
+            gate_qubits(&circuit),
+            vec![vec![0], vec![1], vec![2], vec![3], vec![1]]
+        );
+        let circuit = parse_body(
+            "qubit[4] q;\nconst int n = 3;\nfor int i in [n:-2:0] { cx q[i], q[i-1]; }\n",
+        )
+        .unwrap();
+        assert_eq!(gate_qubits(&circuit), vec![vec![3, 2], vec![1, 0]]);
+        // an empty range skips the body
+        let circuit = parse_body("qubit q;\nfor i in [1:0] { h q; }\nx q;\n").unwrap();
+        assert_eq!(circuit.instructions.len(), 1);
+    }
+
+    #[test]
+    fn conditions_apply_to_the_block() {
+        let circuit =
+            parse_body("qubit[2] q;\nbit[2] c;\nc = measure q;\nif (c == 2) { x q[0]; h q[1]; }\n")
+                .unwrap();
+        assert!(circuit.instructions[2..]
+            .iter()
+            .all(|inst| inst.condition.as_ref().is_some_and(|c| c.value == 2)));
+        let err = parse_body("qubit q;\nbit c;\nif (c == 1) x q; else h q;\n").unwrap_err();
+        assert!(err.contains("else is not supported"), "{}", err);
+    }
+
+    #[test]
+    fn unsupported_constructs_name_the_line() {
+        let cases = [
+            (
+                "qubit q;\nwhile (true) { }\n",
+                "line 4: while is not supported",
+            ),
+            (
+                "qubit q;\nctrl @ x q, q;\n",
+                "line 4: gate modifiers are not supported",
+            ),
+            ("int n;\n", "line 3: classical variables are not supported"),
+            ("qubit q;\nqubit q;\n", "line 4: q is already declared"),
+        ];
+        for (body, expected) in cases {
+            let err = parse_body(body).unwrap_err();
+            assert!(err.contains(expected), "{:?}: {}", body, err);
+        }
+    }
+
+    #[test]
+    fn unrolling_is_limited() {
+        let err = parse_body("qubit q;\nfor i in [0:1000000000000] { x q; }\n").unwrap_err();
+        assert!(err.contains("more than 100000 iterations"), "{}", err);
+        // nested loops count all their iterations
+        let err =
+            parse_body("qubit q;\nfor i in [1:1000] { for j in [1:1000] { } }\n").unwrap_err();
+        assert!(err.contains("more than 100000 iterations"), "{}", err);
+        let err =
+            parse_body("qubit[1000] q;\nfor i in [1:1000] { for j in [1:99] { barrier; x q; } }\n")
+                .unwrap_err();
+        assert!(err.contains("more than 1000000 instructions"), "{}", err);
+        // a range at the end of the integers does not overflow
+        let circuit =
+            parse_body("qubit q;\nfor i in [9223372036854775807:9223372036854775807] x q;\n");
+        assert!(circuit.is_ok());
+    }
+}
@@ -0,0 +1,572 @@
+use std::collections::HashMap;
+
+use super::{gates::gate_info, qasm2, Circuit, Expr, Instruction, Op, Register};
+
+/// Quil gates and their names in `gates::GATES`
+const QUIL_GATES: &[(&str, &str)] = &[
+    ("I", "id"),
+    ("X", "x"),
+    ("Y", "y"),
+    ("Z", "z"),
+    ("H", "h"),
+    ("S", "s"),
+    ("T", "t"),
+    ("RX", "rx"),
+    ("RY", "ry"),
+    ("RZ", "rz"),
+    ("PHASE", "p"),
+    ("CNOT", "cx"),
+    ("CZ", "cz"),
+    ("CPHASE", "cp"),
+    ("SWAP", "swap"),
+    ("CCNOT", "ccx"),
+    ("CSWAP", "cswap"),
+];
+
+/// Quil instructions which do not change the measurements
+const IGNORED: &[&str] = &["PRAGMA", "NOP", "WAIT", "FENCE"];
+
+/// Quil instructions outside the supported subset
+const UNSUPPORTED: &[&str] = &[
+    "DEFGATE",
+    "DEFCIRCUIT",
+    "LABEL",
+    "JUMP",
+    "JUMP-WHEN",
+    "JUMP-UNLESS",
+    "MOVE",
+    "EXCHANGE",
+    "CONVERT",
+    "LOAD",
+    "STORE",
+    "NEG",
+    "NOT",
+    "AND",
+    "IOR",
+    "XOR",
+    "ADD",
+    "SUB",
+    "MUL",
+    "DIV",
+    "EQ",
+    "GT",
+    "GE",
+    "LT",
+    "LE",
+    "DEFFRAME",
+    "DEFWAVEFORM",
+    "DEFCAL",
+    "PULSE",
+    "CAPTURE",
+    "RAW-CAPTURE",
+    "DELAY",
+    "SET-FREQUENCY",
+    "SET-PHASE",
+    "SHIFT-PHASE",
+    "INCLUDE",
+];
+
+/// `theta[0]` is the variable `theta`, `theta[1]` is `theta_1`
+fn var_name(name: &str, index: usize) -> String {
+    if index == 0 {
+        name.to_string()
+    } else {
+        format!("{}_{}", name, index)
+    }
+}
+
+/// replace the memory references `name[i]` of an expression by variables
+fn replace_refs(expr: &str) -> String {
+    let mut replaced = String::with_capacity(expr.len());
+    let mut rest = expr;
+    while let Some(start) = rest.find('[') {
+        let Some(end) = rest[start..].find(']') else {
+            break;
+        };
+        let name_start = rest[..start]
+            .rfind(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .map_or(0, |i| i + 1);
+        let index = rest[start + 1..start + end].trim().parse().unwrap_or(0);
+        replaced.push_str(&rest[..name_start]);
+        replaced.push_str(&var_name(&rest[name_start..start], index));
+        rest = &rest[start + end + 1..];
+    }
+    replaced.push_str(rest);
+    replaced
+}
+
+/// split at the commas which are not in parentheses
+fn split_params(params: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let (mut depth, mut start) = (0, 0);
+    for (i, c) in params.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                parts.push(&params[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(&params[start..]);
+    parts
+}
+
+/// a classical bit `ro[1]`, or `ro` for `ro[0]`
+fn clbit(circuit: &Circuit, arg: &str) -> Result<usize, String> {
+    let (name, index) = match arg.split_once('[') {
+        Some((name, index)) => (
+            name,
+            index
+                .trim_end_matches(']')
+                .parse::<usize>()
+                .map_err(|_| format!("invalid memory reference {}", arg))?,
+        ),
+        None => (arg, 0),
+    };
+    match circuit.creg(name) {
+        Some(reg) if index < reg.size => Ok(reg.offset + index),
+        Some(reg) => Err(format!(
+            "index {} is out of memory {}[{}]",
+            index, name, reg.size
+        )),
+        None => Err(format!("unknown memory {}, declare it as BIT", name)),
+    }
+}
+
+fn qubit(arg: &str) -> Result<usize, String> {
+    arg.parse()
+        .map_err(|_| format!("expected a qubit index, found {}", arg))
+}
+
+/// Parse a Quil program: `DECLARE` of `BIT` memory (the classical registers)
+/// and `REAL` memory (the variables), the standard gates with `DAGGER`,
+/// `MEASURE` and `RESET`. The qubits are one register `q` up to the largest
+/// index. `theta[0]` is the variable `theta` and `theta[1]` is `theta_1`.
+pub fn parse(source: &str) -> Result<Circuit, String> {
+    let mut circuit = Circuit::default();
+    let mut instructions = Vec::new();
+
+    for (line_no, line) in source.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let error = |msg: String| format!("line {}: {}", line_no + 1, msg);
+        let words: Vec<&str> = line.split_whitespace().collect();
+
+        match words[0] {
+            "DECLARE" => {
+                let (name, ty) = match words[..] {
+                    [_, name, ty] => (name, ty),
+                    _ => return Err(error(format!("invalid declaration {}", line))),
+                };
+                let (ty, size) = match ty.split_once('[') {
+                    Some((ty, size)) => (
+                        ty,
+                        size.trim_end_matches(']')
+                            .parse()
+                            .map_err(|_| error(format!("invalid size {}", size)))?,
+                    ),
+                    None => (ty, 1),
+                };
+                match ty {
+                    "BIT" => {
+                        let offset = circuit.num_clbits();
+                        circuit.cregs.push(Register {
+                            name: name.to_string(),
+                            size,
+                            offset,
+                        });
+                    }
+                    "REAL" => {}
+                    _ => return Err(error(format!("memory of type {} is not supported", ty))),
+                }
+            }
+            "MEASURE" => {
+                let (qubit, target) = match words[..] {
+                    [_, qubit, target] => (qubit, target),
+                    _ => return Err(error("MEASURE needs a qubit and a memory".to_string())),
+                };
+                instructions.push(Op::Measure {
+                    qubit: self::qubit(qubit).map_err(error)?,
+                    clbit: self::clbit(&circuit, target).map_err(error)?,
+                });
+            }
+            "RESET" => match words[..] {
+                [_] => instructions.push(Op::Reset { qubit: usize::MAX }),
+                [_, qubit] => instructions.push(Op::Reset {
+                    qubit: self::qubit(qubit).map_err(error)?,
+                }),
+                _ => return Err(error("RESET takes at most one qubit".to_string())),
+            },
+            "HALT" => break,
+            keyword if IGNORED.contains(&keyword) => {}
+            keyword if UNSUPPORTED.contains(&keyword) => {
+                return Err(error(format!("{} is not supported", keyword)))
+            }
+            _ => instructions.push(gate(line).map_err(error)?),
+        }
+    }
+
+    let num_qubits = instructions
+        .iter()
+        .flat_map(|op| op.qubits())
+        .filter(|&qubit| qubit != usize::MAX)
+        .max()
+        .map_or(0, |qubit| qubit + 1);
+    circuit.qregs.push(Register {
+        name: "q".to_string(),
+        size: num_qubits,
+        offset: 0,
+    });
+    for op in instructions {
+        // `RESET` alone resets all the qubits
+        let ops = match op {
+            Op::Reset { qubit: usize::MAX } => {
+                (0..num_qubits).map(|qubit| Op::Reset { qubit }).collect()
+            }
+            op => vec![op],
+        };
+        circuit
+            .instructions
+            .extend(ops.into_iter().map(|op| Instruction {
+                op,
+                condition: None,
+            }));
+    }
+    Ok(circuit)
+}
+
+/// a gate application, `DAGGER` is supported for the gates whose inverse
+/// is a gate of the agent, and `CONTROLLED` for the gates with a controlled
+/// version, e.g. `CONTROLLED RX` is `crx`
+fn gate(line: &str) -> Result<Op, String> {
+    let mut rest = line;
+    let mut dagger = false;
+    let mut controls = 0;
+    loop {
+        let word = rest.split_whitespace().next().unwrap_or("");
+        match word {
+            "DAGGER" => dagger = !dagger,
+            "CONTROLLED" => controls += 1,
+            "FORKED" => return Err(format!("{} is not supported", word)),
+            _ => break,
+        }
+        rest = rest.trim_start()[word.len()..].trim_start();
+    }
+
+    let name_end = rest
+        .find(|c: char| c == '(' || c.is_whitespace())
+        .unwrap_or(rest.len());
+    let name = &rest[..name_end];
+    rest = &rest[name_end..];
+    let mut params = Vec::new();
+    if rest.starts_with('(') {
+        let end = rest
+            .rfind(')')
+            .ok_or_else(|| format!("unclosed parameters of {}", name))?;
+        for param in split_params(&rest[1..end]) {
+            params.push(qasm2::parse_expr(&replace_refs(param))?);
+        }
+        rest = &rest[end + 1..];
+    }
+    let qubits = rest
+        .split_whitespace()
+        .map(qubit)
+        .collect::<Result<Vec<usize>, String>>()?;
+
+    let gate = match QUIL_GATES.iter().find(|(quil, _)| *quil == name) {
+        Some((_, gate)) => *gate,
+        None => return Err(format!("unknown gate {}", name)),
+    };
+    let (gate, params) = match (dagger, gate) {
+        (false, _)
+        | (true, "id" | "x" | "y" | "z" | "h" | "cx" | "cz" | "swap" | "ccx" | "cswap") => {
+            (gate.to_string(), params)
+        }
+        (true, "s" | "t") => (format!("{}dg", gate), params),
+        (true, "rx" | "ry" | "rz" | "p" | "cp") => (
+            gate.to_string(),
+            params.into_iter().map(|p| Expr::Neg(Box::new(p))).collect(),
+        ),
+        (true, _) => return Err(format!("DAGGER {} is not supported", name)),
+    };
+    let gate = match (controls, gate.as_str()) {
+        (0, _) => gate,
+        (1, "x") => "cx".to_string(),
+        (1, "cx") => "ccx".to_string(),
+        (1, "swap") => "cswap".to_string(),
+        (1, _) if gate_info(&format!("c{}", gate)).is_some_and(|(_, qubits)| qubits == 2) => {
+            format!("c{}", gate)
+        }
+        _ => return Err(format!("CONTROLLED {} is not supported", name)),
+    };
+
+    let expected = gate_info(&gate).unwrap();
+    if expected != (params.len(), qubits.len()) {
+        return Err(format!(
+            "gate {} takes {} parameters and {} qubits, found {} and {}",
+            name,
+            expected.0,
+            expected.1,
+            params.len(),
+            qubits.len()
+        ));
+    }
+    let mut unique = qubits.clone();
+    unique.sort();
+    unique.dedup();
+    if unique.len() != qubits.len() {
+        return Err(format!("gate {} uses a qubit twice", name));
+    }
+    Ok(Op::Gate {
+        name: gate,
+        params,
+        qubits,
+    })
+}
+
+/// a parameter in Quil, the variables are the first `REAL` of their name
+fn quil_expr(expr: &Expr) -> String {
+    let mut vars = Vec::new();
+    expr.free_vars(&mut vars);
+    let refs: HashMap<String, Expr> = vars
+        .into_iter()
+        .map(|var| (var.clone(), Expr::Var(format!("{}[0]", var))))
+        .collect();
+    expr.substitute(&refs).to_string()
+}
+
+/// the Quil instructions of a gate, the gates without a Quil equivalent are
+/// decomposed up to a global phase
+fn quil_gate(name: &str, params: &[Expr], qubits: &[usize]) -> Result<Vec<String>, String> {
+    let q = |i: usize| qubits[i].to_string();
+    let p = |i: usize| quil_expr(&params[i]);
+    let half_pi = Expr::Div(Box::new(Expr::Pi), Box::new(Expr::Num(2.0)));
+    Ok(match name {
+        "sdg" => vec![format!("DAGGER S {}", q(0))],
+        "tdg" => vec![format!("DAGGER T {}", q(0))],
+        "u1" => vec![format!("PHASE({}) {}", p(0), q(0))],
+        "cu1" => vec![format!("CPHASE({}) {} {}", p(0), q(0), q(1))],
+        "sx" => vec![format!("RX(pi/2) {}", q(0))],
+        "sxdg" => vec![format!("RX(-pi/2) {}", q(0))],
+        // u3(theta, phi, lambda) = rz(phi) ry(theta) rz(lambda)
+        "u3" => vec![
+            format!("RZ({}) {}", p(2), q(0)),
+            format!("RY({}) {}", p(0), q(0)),
+            format!("RZ({}) {}", p(1), q(0)),
+        ],
+        "u2" => vec![
+            format!("RZ({}) {}", p(1), q(0)),
+            format!("RY({}) {}", quil_expr(&half_pi), q(0)),
+            format!("RZ({}) {}", p(0), q(0)),
+        ],
+        "cy" | "ch" | "crx" | "cry" | "crz" => {
+            let target = name[1..].to_uppercase();
+            match params.first() {
+                Some(_) => vec![format!("CONTROLLED {}({}) {} {}", target, p(0), q(0), q(1))],
+                None => vec![format!("CONTROLLED {} {} {}", target, q(0), q(1))],
+            }
+        }
+        "rzz" => vec![
+            format!("CNOT {} {}", q(0), q(1)),
+            format!("RZ({}) {}", p(0), q(1)),
+            format!("CNOT {} {}", q(0), q(1)),
+        ],
+        _ => match QUIL_GATES.iter().find(|(_, gate)| *gate == name) {
+            Some((quil, _)) => {
+                let mut instruction = quil.to_string();
+                if !params.is_empty() {
+                    let params: Vec<String> = params.iter().map(quil_expr).collect();
+                    instruction.push_str(&format!("({})", params.join(", ")));
+                }
+                for qubit in qubits {
+                    instruction.push_str(&format!(" {}", qubit));
+                }
+                vec![instruction]
+            }
+            None => return Err(format!("gate {} cannot be written in Quil", name)),
+        },
+    })
+}
+
+/// Write the circuit as Quil, the classical registers are `BIT` memory and the
+/// variables are `REAL` memory. Conditions are not supported.
+pub fn to_quil(circuit: &Circuit) -> Result<String, String> {
+    let mut quil = String::new();
+    for reg in circuit.cregs.iter() {
+        quil.push_str(&format!("DECLARE {} BIT[{}]\n", reg.name, reg.size));
+    }
+    for var in circuit.free_vars() {
+        quil.push_str(&format!("DECLARE {} REAL[1]\n", var));
+    }
+
+    for inst in circuit.instructions.iter() {
+        if inst.condition.is_some() {
+            return Err("conditions cannot be written in Quil".to_string());
+        }
+        match &inst.op {
+            Op::Gate {
+                name,
+                params,
+                qubits,
+            } => {
+                for line in quil_gate(name, params, qubits)? {
+                    quil.push_str(&line);
+                    quil.push('\n');
+                }
+            }
+            Op::Measure { qubit, clbit } => {
+                let name = circuit.clbit_name(*clbit);
+                quil.push_str(&format!("MEASURE {} {}\n", qubit, name));
+            }
+            Op::Reset { qubit } => quil.push_str(&format!("RESET {}\n", qubit)),
+            Op::Barrier { .. } => {}
+        }
+    }
+    Ok(quil)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulator::statevector::StateVector;
+    use num::complex::Complex64;
+
+    /// a state where no amplitude is 0, so that the gates are told apart
+    const PREPARE: &str = "qreg q[3];\ncreg c[3];\nh q[0];\nry(0.3) q[1];\nrx(0.7) q[2];\n";
+
+    fn circuit(body: &str) -> Circuit {
+        qasm2::parse(&format!(
+            "OPENQASM 2.0;\ninclude \"qelib1.inc\";\n{}{}",
+            PREPARE, body
+        ))
+        .unwrap()
+    }
+
+    fn amplitudes(circuit: &Circuit) -> Vec<Complex64> {
+        let mut state = StateVector::new(circuit.num_qubits()).unwrap();
+        for inst in circuit.instructions.iter() {
+            if let Op::Gate {
+                name,
+                params,
+                qubits,
+            } = &inst.op
+            {
+                let params: Vec<f64> = params
+                    .iter()
+                    .map(|param| param.eval(&Default::default()).unwrap())
+                    .collect();
+                state.apply_gate(name, &params, qubits).unwrap();
+            }
+        }
+        state.amplitudes
+    }
+
+    /// the states are equal up to a global phase
+    fn assert_same_state(a: &Circuit, b: &Circuit) {
+        let (a, b) = (amplitudes(a), amplitudes(b));
+        let phase = a[0] / b[0];
+        for (x, y) in a.iter().zip(b.iter()) {
+            assert!((x - y * phase).norm() < 1e-9, "{:?} != {:?}", a, b);
+        }
+    }
+
+    #[test]
+    fn gates_are_kept_by_a_round_trip() {
+        for gate in [
+            "x q[0];",
+            "y q[0];",
+            "z q[0];",
+            "h q[0];",
+            "s q[0];",
+            "sdg q[0];",
+            "t q[0];",
+            "tdg q[0];",
+            "sx q[0];",
+            "sxdg q[0];",
+            "rx(0.4) q[0];",
+            "ry(0.4) q[0];",
+            "rz(0.4) q[0];",
+            "p(0.4) q[0];",
+            "u1(0.4) q[0];",
+            "u2(0.4,1.1) q[0];",
+            "u3(0.4,1.1,-0.6) q[0];",
+            "cx q[0],q[1];",
+            "cy q[0],q[1];",
+            "cz q[0],q[1];",
+            "ch q[0],q[1];",
+            "crx(0.4) q[0],q[1];",
+            "cry(0.4) q[0],q[1];",
+            "crz(0.4) q[0],q[1];",
+            "cp(0.4) q[0],q[1];",
+            "cu1(0.4) q[0],q[1];",
+            "rzz(0.4) q[0],q[1];",
+            "swap q[0],q[1];",
+            "ccx q[0],q[1],q[2];",
+            "cswap q[0],q[1],q[2];",
+        ] {
+            let original = circuit(gate);
+            let quil = to_quil(&original).unwrap();
+            let parsed = parse(&quil).unwrap_or_else(|err| panic!("{}: {}\n{}", gate, err, quil));
+            assert_same_state(&original, &parsed);
+        }
+    }
+
+    #[test]
+    fn measurements_and_variables_are_kept_by_a_round_trip() {
+        let original = circuit("rx(theta) q[0];\nmeasure q[0] -> c[2];\nreset q[1];\n");
+        let quil = to_quil(&original).unwrap();
+        assert!(
+            quil.starts_with("DECLARE c BIT[3]\nDECLARE theta REAL[1]\n"),
+            "{}",
+            quil
+        );
+        assert!(
+            quil.contains("RX(theta[0]) 0\nMEASURE 0 c[2]\nRESET 1\n"),
+            "{}",
+            quil
+        );
+        let parsed = parse(&quil).unwrap();
+        assert_eq!(parsed.cregs, original.cregs);
+        assert_eq!(parsed.free_vars(), ["theta"]);
+    }
+
+    #[test]
+    fn gates_without_quil_are_errors() {
+        for (gate, name) in [
+            ("csx q[0],q[1];", "csx"),
+            ("cu3(0.1,0.2,0.3) q[0],q[1];", "cu3"),
+            ("cu(0.1,0.2,0.3,0.4) q[0],q[1];", "cu"),
+            ("rxx(0.4) q[0],q[1];", "rxx"),
+        ] {
+            assert_eq!(
+                to_quil(&circuit(gate)).unwrap_err(),
+                format!("gate {} cannot be written in Quil", name)
+            );
+        }
+        assert_eq!(
+            to_quil(&circuit("measure q[0] -> c[0];\nif(c==1) x q[1];\n")).unwrap_err(),
+            "conditions cannot be written in Quil"
+        );
+    }
+
+    #[test]
+    fn unsupported_quil_is_an_error() {
+        assert_eq!(
+            parse("DECLARE ro BIT\nH 0\nJUMP @end\n").unwrap_err(),
+            "line 3: JUMP is not supported"
+        );
+        assert_eq!(
+            parse("DAGGER RX(0.1) 0\nDAGGER U 0\n").unwrap_err(),
+            "line 2: unknown gate U"
+        );
+        assert_eq!(
+            parse("MEASURE 0 ro[0]\n").unwrap_err(),
+            "line 1: unknown memory ro, declare it as BIT"
+        );
+    }
+}
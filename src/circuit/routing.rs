@@ -0,0 +1,724 @@
+use std::collections::VecDeque;
+
+use serde::{Deserialize, Serialize};
+
+use super::{Circuit, Instruction, Op, Register};
+
+/// how many two-qubit gates after the front layer are looked at
+const EXTENDED_SET_SIZE: usize = 20;
+/// weight of the extended set in the swap score
+const EXTENDED_SET_WEIGHT: f64 = 0.5;
+/// the decay of the qubits of a swap, so that the swaps spread over qubits
+const DECAY_RATE: f64 = 0.001;
+const DECAY_RESET: usize = 5;
+
+/// The physical qubits which can run a two-qubit gate together. The edges
+/// are undirected, a gate can be run in both directions.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct CouplingMap {
+    /// `linear`, `ring`, `grid`, `heavy_hex` or the edges as JSON
+    pub spec: String,
+    pub num_qubits: usize,
+    pub edges: Vec<(usize, usize)>,
+}
+
+impl CouplingMap {
+    /// Build the map of `num_qubits` physical qubits from a preset or a
+    /// JSON list of edges such as `[[0, 1], [1, 2]]`
+    pub fn new(spec: &str, num_qubits: usize) -> Result<Self, String> {
+        let spec = spec.trim();
+        let edges = match spec {
+            "linear" => (1..num_qubits).map(|i| (i - 1, i)).collect(),
+            "ring" => {
+                let mut edges: Vec<(usize, usize)> = (1..num_qubits).map(|i| (i - 1, i)).collect();
+                if num_qubits > 2 {
+                    edges.push((num_qubits - 1, 0));
+                }
+                edges
+            }
+            "grid" => grid(num_qubits),
+            "heavy_hex" => heavy_hex(num_qubits),
+            _ => {
+                let edges: Vec<(usize, usize)> = serde_json::from_str(spec).map_err(|_| {
+                    format!(
+                        "expected linear, ring, grid, heavy_hex or a list of edges, found {}",
+                        spec
+                    )
+                })?;
+                let mut unique: Vec<(usize, usize)> = Vec::new();
+                for (i, &(a, b)) in edges.iter().enumerate() {
+                    if a >= num_qubits || b >= num_qubits {
+                        return Err(format!(
+                            "edges[{}]: qubit {} is out of range, the device has {}",
+                            i,
+                            a.max(b),
+                            num_qubits
+                        ));
+                    }
+                    if a == b {
+                        return Err(format!("edges[{}]: qubit {} is coupled to itself", i, a));
+                    }
+                    if !unique.contains(&(a, b)) && !unique.contains(&(b, a)) {
+                        unique.push((a, b));
+                    }
+                }
+                unique
+            }
+        };
+        Ok(CouplingMap {
+            spec: spec.to_string(),
+            num_qubits,
+            edges,
+        })
+    }
+
+    pub fn neighbors(&self) -> Vec<Vec<usize>> {
+        let mut neighbors = vec![Vec::new(); self.num_qubits];
+        for &(a, b) in self.edges.iter() {
+            neighbors[a].push(b);
+            neighbors[b].push(a);
+        }
+        neighbors
+    }
+
+    /// the shortest distances between the qubits, `usize::MAX` if they are
+    /// not connected
+    pub fn distances(&self) -> Vec<Vec<usize>> {
+        let neighbors = self.neighbors();
+        (0..self.num_qubits)
+            .map(|source| {
+                let mut distance = vec![usize::MAX; self.num_qubits];
+                distance[source] = 0;
+                let mut queue = VecDeque::from([source]);
+                while let Some(qubit) = queue.pop_front() {
+                    for &next in neighbors[qubit].iter() {
+                        if distance[next] == usize::MAX {
+                            distance[next] = distance[qubit] + 1;
+                            queue.push_back(next);
+                        }
+                    }
+                }
+                distance
+            })
+            .collect()
+    }
+}
+
+/// a near square grid, row by row
+fn grid(num_qubits: usize) -> Vec<(usize, usize)> {
+    let cols = (num_qubits as f64).sqrt().ceil().max(1.0) as usize;
+    let mut edges = Vec::new();
+    for qubit in 0..num_qubits {
+        if (qubit + 1) % cols != 0 && qubit + 1 < num_qubits {
+            edges.push((qubit, qubit + 1));
+        }
+        if qubit + cols < num_qubits {
+            edges.push((qubit, qubit + cols));
+        }
+    }
+    edges
+}
+
+/// The heavy-hex lattice of the IBM devices: rows of qubits joined by a
+/// bridge qubit every 4 columns, on alternating columns. The lattice is
+/// numbered in breadth-first order, so any number of qubits is connected.
+fn heavy_hex(num_qubits: usize) -> Vec<(usize, usize)> {
+    // rows of 4k+3 qubits, about as many rows as columns
+    let mut width = 3;
+    while width * width < num_qubits {
+        width += 4;
+    }
+    let mut edges = Vec::new();
+    let mut row: Vec<usize> = (0..width).collect();
+    edges.extend((1..width).map(|col| (row[col - 1], row[col])));
+    let mut size = width;
+    let mut r = 0;
+    while size < num_qubits {
+        let bridges: Vec<usize> = (if r % 2 == 0 { 0 } else { 2 }..width).step_by(4).collect();
+        let bridge_ids: Vec<usize> = (size..size + bridges.len()).collect();
+        size += bridges.len();
+        let next: Vec<usize> = (size..size + width).collect();
+        size += width;
+        edges.extend((1..width).map(|col| (next[col - 1], next[col])));
+        for (&col, &bridge) in bridges.iter().zip(bridge_ids.iter()) {
+            edges.push((row[col], bridge));
+            edges.push((bridge, next[col]));
+        }
+        row = next;
+        r += 1;
+    }
+
+    // renumber breadth-first from qubit 0 and keep the first qubits
+    let mut neighbors = vec![Vec::new(); size];
+    for &(a, b) in edges.iter() {
+        neighbors[a].push(b);
+        neighbors[b].push(a);
+    }
+    let mut order = vec![usize::MAX; neighbors.len()];
+    let mut queue = VecDeque::from([0]);
+    order[0] = 0;
+    let mut next_id = 1;
+    while let Some(qubit) = queue.pop_front() {
+        for &next in neighbors[qubit].iter() {
+            if order[next] == usize::MAX {
+                order[next] = next_id;
+                next_id += 1;
+                queue.push_back(next);
+            }
+        }
+    }
+    edges
+        .into_iter()
+        .map(|(a, b)| (order[a].min(order[b]), order[a].max(order[b])))
+        .filter(|&(_, b)| b < num_qubits)
+        .collect()
+}
+
+/// The placement of the circuit qubits on the physical qubits, the qubits
+/// beyond the circuit are ancillas
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Layout {
+    /// physical qubit of each circuit qubit
+    physical: Vec<usize>,
+    /// circuit qubit of each physical qubit
+    virtual_: Vec<usize>,
+}
+
+impl Layout {
+    fn trivial(num_qubits: usize) -> Self {
+        Layout {
+            physical: (0..num_qubits).collect(),
+            virtual_: (0..num_qubits).collect(),
+        }
+    }
+
+    /// exchange the circuit qubits of two physical qubits
+    fn swap(&mut self, a: usize, b: usize) {
+        self.virtual_.swap(a, b);
+        self.physical[self.virtual_[a]] = a;
+        self.physical[self.virtual_[b]] = b;
+    }
+}
+
+/// A circuit routed on a coupling map, its qubits are the physical qubits
+#[derive(Debug, Clone)]
+pub struct Routed {
+    pub circuit: Circuit,
+    /// physical qubit of each circuit qubit at the start and the end
+    pub initial_layout: Vec<usize>,
+    pub final_layout: Vec<usize>,
+    pub swaps: usize,
+}
+
+/// the instructions which must run before each instruction, through the
+/// qubits, the measured clbits and the registers of the conditions
+fn dependencies(circuit: &Circuit, instructions: &[Instruction]) -> (Vec<Vec<usize>>, Vec<usize>) {
+    let num_qubits = circuit.num_qubits();
+    let mut last: Vec<Option<usize>> = vec![None; num_qubits + circuit.num_clbits()];
+    let mut successors: Vec<Vec<usize>> = vec![Vec::new(); instructions.len()];
+    let mut predecessors = vec![0; instructions.len()];
+    for (i, inst) in instructions.iter().enumerate() {
+        let mut wires = inst.op.qubits();
+        if let Op::Measure { clbit, .. } = inst.op {
+            wires.push(num_qubits + clbit);
+        }
+        if let Some(reg) = inst
+            .condition
+            .as_ref()
+            .and_then(|condition| circuit.creg(&condition.creg))
+        {
+            wires.extend((reg.offset..reg.offset + reg.size).map(|clbit| num_qubits + clbit));
+        }
+        for wire in wires {
+            if let Some(previous) = last[wire] {
+                if !successors[previous].contains(&i) {
+                    successors[previous].push(i);
+                    predecessors[i] += 1;
+                }
+            }
+            last[wire] = Some(i);
+        }
+    }
+    (successors, predecessors)
+}
+
+fn two_qubit(inst: &Instruction) -> Option<(usize, usize)> {
+    match &inst.op {
+        Op::Gate { qubits, .. } if qubits.len() == 2 => Some((qubits[0], qubits[1])),
+        _ => None,
+    }
+}
+
+/// One pass of the SABRE heuristic. The instructions of the front layer run
+/// as soon as their qubits are adjacent, otherwise the swap which brings the
+/// front layer and the next gates closest is inserted.
+fn sabre(
+    circuit: &Circuit,
+    instructions: &[Instruction],
+    map: &CouplingMap,
+    distances: &[Vec<usize>],
+    layout: &mut Layout,
+) -> Result<(Vec<Instruction>, usize), String> {
+    let neighbors = map.neighbors();
+    let (successors, mut predecessors) = dependencies(circuit, instructions);
+    let mut front: Vec<usize> = (0..instructions.len())
+        .filter(|&i| predecessors[i] == 0)
+        .collect();
+    let mut routed = Vec::new();
+    let mut swaps = 0;
+    let mut decay = vec![1.0_f64; map.num_qubits];
+    let mut stalled = 0;
+
+    let distance =
+        |layout: &Layout, (a, b): (usize, usize)| distances[layout.physical[a]][layout.physical[b]];
+
+    while !front.is_empty() {
+        // run every instruction of the front layer whose qubits are adjacent
+        let mut executed = false;
+        let mut i = 0;
+        while i < front.len() {
+            let node = front[i];
+            let inst = &instructions[node];
+            if let Some(pair) = two_qubit(inst) {
+                match distance(layout, pair) {
+                    usize::MAX => {
+                        return Err(format!(
+                            "physical qubits {} and {} are not connected in the coupling map",
+                            layout.physical[pair.0], layout.physical[pair.1]
+                        ))
+                    }
+                    1 => {}
+                    _ => {
+                        i += 1;
+                        continue;
+                    }
+                }
+            }
+            routed.push(Instruction {
+                op: map_op(&inst.op, &layout.physical),
+                condition: inst.condition.clone(),
+            });
+            front.swap_remove(i);
+            for &next in successors[node].iter() {
+                predecessors[next] -= 1;
+                if predecessors[next] == 0 {
+                    front.push(next);
+                }
+            }
+            executed = true;
+        }
+        if front.is_empty() {
+            break;
+        }
+        if executed {
+            decay.iter_mut().for_each(|d| *d = 1.0);
+            stalled = 0;
+            continue;
+        }
+
+        let blocked: Vec<(usize, usize)> = front
+            .iter()
+            .filter_map(|&node| two_qubit(&instructions[node]))
+            .collect();
+        let mut swap_pairs = Vec::new();
+        if stalled > 10 * map.num_qubits {
+            // the heuristic is stuck, move the first gate along a shortest
+            // path until its qubits are adjacent
+            let (a, b) = blocked[0];
+            let target = layout.physical[b];
+            let mut qubit = layout.physical[a];
+            while distances[qubit][target] > 1 {
+                let next = *neighbors[qubit]
+                    .iter()
+                    .find(|&&next| distances[next][target] + 1 == distances[qubit][target])
+                    .unwrap();
+                swap_pairs.push((qubit, next));
+                qubit = next;
+            }
+        } else {
+            let extended = extended_set(instructions, &successors, &front);
+            let mut best: Option<((usize, usize), f64)> = None;
+            for &(a, b) in blocked.iter() {
+                for physical in [layout.physical[a], layout.physical[b]] {
+                    for &next in neighbors[physical].iter() {
+                        let pair = (physical.min(next), physical.max(next));
+                        let mut trial = layout.clone();
+                        trial.swap(pair.0, pair.1);
+                        let cost = |gates: &[(usize, usize)]| {
+                            if gates.is_empty() {
+                                return 0.0;
+                            }
+                            // the unconnected gates of the extended set cost usize::MAX
+                            let total: f64 = gates
+                                .iter()
+                                .map(|&gate| distance(&trial, gate) as f64)
+                                .sum();
+                            total / gates.len() as f64
+                        };
+                        let score = decay[pair.0].max(decay[pair.1])
+                            * (cost(&blocked) + EXTENDED_SET_WEIGHT * cost(&extended));
+                        if best.is_none_or(|(_, best)| score < best) {
+                            best = Some((pair, score));
+                        }
+                    }
+                }
+            }
+            swap_pairs.push(best.unwrap().0);
+        }
+
+        for (a, b) in swap_pairs {
+            routed.push(Instruction {
+                op: Op::Gate {
+                    name: "swap".to_string(),
+                    params: Vec::new(),
+                    qubits: vec![a, b],
+                },
+                condition: None,
+            });
+            layout.swap(a, b);
+            swaps += 1;
+            stalled += 1;
+            decay[a] += DECAY_RATE;
+            decay[b] += DECAY_RATE;
+            if swaps % DECAY_RESET == 0 {
+                decay.iter_mut().for_each(|d| *d = 1.0);
+            }
+        }
+    }
+    Ok((routed, swaps))
+}
+
+/// the two-qubit gates which follow the front layer
+fn extended_set(
+    instructions: &[Instruction],
+    successors: &[Vec<usize>],
+    front: &[usize],
+) -> Vec<(usize, usize)> {
+    let mut extended = Vec::new();
+    let mut visited = front.to_vec();
+    let mut queue: VecDeque<usize> = front.iter().copied().collect();
+    while let Some(node) = queue.pop_front() {
+        for &next in successors[node].iter() {
+            if visited.contains(&next) {
+                continue;
+            }
+            visited.push(next);
+            if let Some(pair) = two_qubit(&instructions[next]) {
+                extended.push(pair);
+                if extended.len() >= EXTENDED_SET_SIZE {
+                    return extended;
+                }
+            }
+            queue.push_back(next);
+        }
+    }
+    extended
+}
+
+fn map_op(op: &Op, physical: &[usize]) -> Op {
+    match op {
+        Op::Gate {
+            name,
+            params,
+            qubits,
+        } => Op::Gate {
+            name: name.clone(),
+            params: params.clone(),
+            qubits: qubits.iter().map(|&qubit| physical[qubit]).collect(),
+        },
+        Op::Measure { qubit, clbit } => Op::Measure {
+            qubit: physical[*qubit],
+            clbit: *clbit,
+        },
+        Op::Reset { qubit } => Op::Reset {
+            qubit: physical[*qubit],
+        },
+        Op::Barrier { qubits } => Op::Barrier {
+            qubits: qubits.iter().map(|&qubit| physical[qubit]).collect(),
+        },
+    }
+}
+
+/// Route a circuit of one and two-qubit gates on the coupling map with
+/// SABRE. The initial layout is found by routing the two-qubit gates
+/// forward and backward from the trivial layout, then the circuit is routed
+/// from it. The routed circuit has one register `q` of the physical qubits.
+pub fn route(circuit: &Circuit, map: &CouplingMap) -> Result<Routed, String> {
+    let num_qubits = circuit.num_qubits();
+    if num_qubits > map.num_qubits {
+        return Err(format!(
+            "the circuit has {} qubits, the coupling map has {}",
+            num_qubits, map.num_qubits
+        ));
+    }
+    if let Some(inst) = circuit
+        .instructions
+        .iter()
+        .find(|inst| matches!(&inst.op, Op::Gate { qubits, .. } if qubits.len() > 2))
+    {
+        return Err(format!(
+            "gate {} acts on more than two qubits and cannot be routed",
+            inst.op.name()
+        ));
+    }
+
+    // the routing works on the physical qubits, the ancillas are extra
+    // circuit qubits which are never used
+    let mut padded = circuit.clone();
+    padded.qregs = vec![Register {
+        name: "q".to_string(),
+        size: map.num_qubits,
+        offset: 0,
+    }];
+    let distances = map.distances();
+
+    let gates: Vec<Instruction> = circuit
+        .instructions
+        .iter()
+        .filter(|inst| two_qubit(inst).is_some())
+        .map(|inst| Instruction {
+            op: inst.op.clone(),
+            condition: None,
+        })
+        .collect();
+    let mut layout = Layout::trivial(map.num_qubits);
+    sabre(&padded, &gates, map, &distances, &mut layout)?;
+    let reversed: Vec<Instruction> = gates.into_iter().rev().collect();
+    sabre(&padded, &reversed, map, &distances, &mut layout)?;
+
+    // terminal measurements are kept at the end, on the final layout, so
+    // that the routed circuit can still be sampled
+    let terminal = circuit.measurements_are_terminal();
+    let (measurements, instructions): (Vec<Instruction>, Vec<Instruction>) = circuit
+        .instructions
+        .iter()
+        .cloned()
+        .partition(|inst| terminal && matches!(inst.op, Op::Measure { .. }));
+
+    let initial_layout = layout.physical[..num_qubits].to_vec();
+    let (mut instructions, swaps) = sabre(&padded, &instructions, map, &distances, &mut layout)?;
+    instructions.extend(measurements.iter().map(|inst| Instruction {
+        op: map_op(&inst.op, &layout.physical),
+        condition: None,
+    }));
+    padded.instructions = instructions;
+    Ok(Routed {
+        circuit: padded,
+        initial_layout,
+        final_layout: layout.physical[..num_qubits].to_vec(),
+        swaps,
+    })
+}
+
+/// Keep only the qubits which are used or in `keep`, in order, so that a
+/// routed circuit is simulated without the idle physical qubits. The index
+/// of each kept qubit in the compacted circuit is returned with it.
+pub fn compact(circuit: &Circuit, keep: &[usize]) -> (Circuit, Vec<usize>) {
+    let mut used = vec![false; circuit.num_qubits()];
+    keep.iter().for_each(|&qubit| used[qubit] = true);
+    for inst in circuit.instructions.iter() {
+        inst.op
+            .qubits()
+            .into_iter()
+            .for_each(|qubit| used[qubit] = true);
+    }
+    let mut index = vec![0; used.len()];
+    let mut size = 0;
+    for (qubit, used) in used.iter().enumerate() {
+        if *used {
+            index[qubit] = size;
+            size += 1;
+        }
+    }
+    let compacted = Circuit {
+        qregs: vec![Register {
+            name: "q".to_string(),
+            size: size.max(1),
+            offset: 0,
+        }],
+        cregs: circuit.cregs.clone(),
+        instructions: circuit
+            .instructions
+            .iter()
+            .map(|inst| Instruction {
+                op: map_op(&inst.op, &index),
+                condition: inst.condition.clone(),
+            })
+            .collect(),
+    };
+    (compacted, index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{circuit::qasm2, simulator::statevector::StateVector};
+    use num::complex::Complex64;
+
+    fn circuit(body: &str) -> Circuit {
+        qasm2::parse(&format!("OPENQASM 2.0;\ninclude \"qelib1.inc\";\n{}", body)).unwrap()
+    }
+
+    fn amplitudes(circuit: &Circuit) -> Vec<Complex64> {
+        let mut state = StateVector::new(circuit.num_qubits()).unwrap();
+        for inst in circuit.instructions.iter() {
+            if let Op::Gate {
+                name,
+                params,
+                qubits,
+            } = &inst.op
+            {
+                let params: Vec<f64> = params
+                    .iter()
+                    .map(|param| param.eval(&Default::default()).unwrap())
+                    .collect();
+                state.apply_gate(name, &params, qubits).unwrap();
+            }
+        }
+        state.amplitudes
+    }
+
+    /// the routed gates are on the edges, and the routed state is the state
+    /// of the circuit with its qubits on the final layout
+    fn assert_routed(circuit: &Circuit, map: &CouplingMap) -> Routed {
+        let routed = route(circuit, map).unwrap();
+        for inst in routed.circuit.instructions.iter() {
+            if let Some((a, b)) = two_qubit(inst) {
+                assert!(map.edges.contains(&(a, b)) || map.edges.contains(&(b, a)));
+            }
+        }
+        let expected = amplitudes(circuit);
+        let actual = amplitudes(&routed.circuit);
+        for (index, amplitude) in expected.iter().enumerate() {
+            let physical: usize = routed
+                .final_layout
+                .iter()
+                .enumerate()
+                .filter(|&(qubit, _)| index >> qubit & 1 == 1)
+                .map(|(_, &physical)| 1 << physical)
+                .sum();
+            assert!((actual[physical] - amplitude).norm() < 1e-9);
+        }
+        routed
+    }
+
+    #[test]
+    fn presets_and_edges() {
+        assert_eq!(
+            CouplingMap::new("linear", 3).unwrap().edges,
+            [(0, 1), (1, 2)]
+        );
+        assert_eq!(
+            CouplingMap::new("ring", 3).unwrap().edges,
+            [(0, 1), (1, 2), (2, 0)]
+        );
+        assert_eq!(
+            CouplingMap::new("grid", 4).unwrap().edges,
+            [(0, 1), (0, 2), (1, 3), (2, 3)]
+        );
+        assert_eq!(
+            CouplingMap::new("[[0, 1], [1, 0], [1, 2]]", 3)
+                .unwrap()
+                .edges,
+            [(0, 1), (1, 2)]
+        );
+        assert!(CouplingMap::new("[[0, 3]]", 3)
+            .unwrap_err()
+            .contains("out of range"));
+        assert!(CouplingMap::new("[[1, 1]]", 3)
+            .unwrap_err()
+            .contains("itself"));
+        assert!(CouplingMap::new("star", 3).is_err());
+    }
+
+    #[test]
+    fn heavy_hex_is_connected() {
+        for num_qubits in [1, 7, 27, 127] {
+            let map = CouplingMap::new("heavy_hex", num_qubits).unwrap();
+            let distances = map.distances();
+            assert!(distances[0].iter().all(|&distance| distance != usize::MAX));
+            let degrees = map.neighbors().iter().map(|n| n.len()).max().unwrap();
+            assert!(degrees <= 3);
+        }
+    }
+
+    #[test]
+    fn adjacent_gates_need_no_swaps() {
+        let map = CouplingMap::new("linear", 3).unwrap();
+        let routed = assert_routed(
+            &circuit("qreg q[3];\nh q[0];\ncx q[0],q[1];\ncx q[1],q[2];"),
+            &map,
+        );
+        assert_eq!(routed.swaps, 0);
+        assert_eq!(routed.initial_layout, routed.final_layout);
+    }
+
+    #[test]
+    fn distant_gates_are_routed() {
+        let map = CouplingMap::new("linear", 5).unwrap();
+        let source = "qreg q[4];\nh q[0];\ncx q[0],q[3];\nry(0.3) q[3];\ncx q[3],q[1];\n\
+                      cx q[2],q[0];\nrz(0.7) q[1];\ncx q[1],q[3];\ncx q[0],q[2];";
+        let routed = assert_routed(&circuit(source), &map);
+        assert_eq!(routed.circuit.num_qubits(), 5);
+
+        let map = CouplingMap::new("linear", 3).unwrap();
+        let routed = assert_routed(
+            &circuit("qreg q[3];\nh q[0];\ncx q[0],q[1];\ncx q[1],q[2];\ncx q[2],q[0];"),
+            &map,
+        );
+        assert!(routed.swaps > 0);
+    }
+
+    #[test]
+    fn terminal_measurements_follow_the_final_layout() {
+        let map = CouplingMap::new("linear", 3).unwrap();
+        let source = "qreg q[3];\ncreg c[3];\ncx q[0],q[1];\ncx q[1],q[2];\ncx q[2],q[0];\n\
+                      measure q -> c;";
+        let routed = route(&circuit(source), &map).unwrap();
+        let measurements: Vec<&Op> = routed
+            .circuit
+            .instructions
+            .iter()
+            .map(|inst| &inst.op)
+            .skip_while(|op| !matches!(op, Op::Measure { .. }))
+            .collect();
+        assert_eq!(measurements.len(), 3);
+        for op in measurements {
+            let Op::Measure { qubit, clbit } = op else {
+                panic!("{:?}", op)
+            };
+            assert_eq!(*qubit, routed.final_layout[*clbit]);
+        }
+    }
+
+    #[test]
+    fn unroutable_circuits() {
+        let source = "qreg q[3];\nccx q[0],q[1],q[2];";
+        let err = route(&circuit(source), &CouplingMap::new("linear", 3).unwrap()).unwrap_err();
+        assert!(err.contains("more than two qubits"), "{}", err);
+        let err = route(
+            &circuit("qreg q[4];"),
+            &CouplingMap::new("linear", 3).unwrap(),
+        )
+        .unwrap_err();
+        assert!(err.contains("the coupling map has 3"), "{}", err);
+        let map = CouplingMap::new("[[0, 1], [2, 3]]", 4).unwrap();
+        let err = route(&circuit("qreg q[4];\ncx q[0],q[1];\ncx q[0],q[2];"), &map);
+        assert!(err.unwrap_err().contains("not connected"));
+    }
+
+    #[test]
+    fn compact_drops_the_idle_qubits() {
+        let source = circuit("qreg q[5];\nh q[1];\ncx q[1],q[4];");
+        let (compacted, index) = compact(&source, &[]);
+        assert_eq!(compacted.num_qubits(), 2);
+        assert_eq!(compacted.instructions[1].op.qubits(), [0, 1]);
+        assert_eq!((index[1], index[4]), (0, 1));
+
+        // the qubits to keep stay even when they are idle
+        let (compacted, index) = compact(&source, &[3]);
+        assert_eq!(compacted.num_qubits(), 3);
+        assert_eq!(compacted.instructions[1].op.qubits(), [0, 2]);
+        assert_eq!(index[3], 1);
+    }
+}
@@ -0,0 +1,505 @@
+use std::{
+    collections::HashMap,
+    f64::consts::{FRAC_PI_2, FRAC_PI_4, PI},
+};
+
+use super::{
+    gates::{canonical_name, gate_info, GATES},
+    qasm2, Circuit, Expr, Instruction, Op,
+};
+
+/// Definitions of the multi-qubit gates with `cx` and single qubit gates,
+/// following `qelib1.inc`. The qubits are `a`, `b` and `c`, the parameters
+/// `p0`, `p1`, ...
+const DEFINITIONS: &[(&str, &str)] = &[
+    ("cy", "sdg b; cx a,b; s b;"),
+    ("cz", "h b; cx a,b; h b;"),
+    (
+        "ch",
+        "h b; sdg b; cx a,b; h b; t b; cx a,b; t b; h b; s b; x b; s a;",
+    ),
+    ("csx", "h b; cu1(pi/2) a,b; h b;"),
+    (
+        "crx",
+        "u1(pi/2) b; cx a,b; u3(-p0/2,0,0) b; cx a,b; u3(p0/2,-pi/2,0) b;",
+    ),
+    ("cry", "ry(p0/2) b; cx a,b; ry(-p0/2) b; cx a,b;"),
+    ("crz", "u1(p0/2) b; cx a,b; u1(-p0/2) b; cx a,b;"),
+    (
+        "cp",
+        "u1(p0/2) a; cx a,b; u1(-p0/2) b; cx a,b; u1(p0/2) b;",
+    ),
+    (
+        "cu1",
+        "u1(p0/2) a; cx a,b; u1(-p0/2) b; cx a,b; u1(p0/2) b;",
+    ),
+    (
+        "cu3",
+        "u1((p2+p1)/2) a; u1((p2-p1)/2) b; cx a,b; u3(-p0/2,0,-(p1+p2)/2) b; cx a,b; u3(p0/2,p1,0) b;",
+    ),
+    (
+        "cu",
+        "u1(p3) a; u1((p2+p1)/2) a; u1((p2-p1)/2) b; cx a,b; u3(-p0/2,0,-(p1+p2)/2) b; cx a,b; u3(p0/2,p1,0) b;",
+    ),
+    ("swap", "cx a,b; cx b,a; cx a,b;"),
+    (
+        "rxx",
+        "u3(pi/2,p0,0) a; h b; cx a,b; u1(-p0) b; cx a,b; h b; u2(-pi,pi-p0) a;",
+    ),
+    ("rzz", "cx a,b; u1(p0) b; cx a,b;"),
+    (
+        "ccx",
+        "h c; cx b,c; tdg c; cx a,c; t c; cx b,c; tdg c; cx a,c; t b; t c; h c; cx a,b; t a; tdg b; cx a,b;",
+    ),
+    ("cswap", "cx c,b; ccx a,b,c; cx c,b;"),
+];
+
+/// How the single qubit gates are written in the basis, `rz` is the name of
+/// the native phase gate (`rz`, `p` or `u1`)
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum OneQubit {
+    /// `u3(theta, phi, lambda)`
+    U3,
+    /// `rz(lambda) ry(theta) rz(phi)`
+    ZY { rz: String },
+    /// `rz(lambda - pi/2) rx(theta) rz(phi + pi/2)`
+    ZX { rz: String },
+    /// `rz(lambda) sx rz(theta + pi) sx rz(phi + pi)`
+    ZSx { rz: String },
+}
+
+/// A native gate set. The circuits are rewritten into these gates up to a
+/// global phase, the way a device compiles them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Basis {
+    pub gates: Vec<String>,
+    one_qubit: OneQubit,
+    /// the native gate `cx` is written with
+    entangler: String,
+}
+
+impl Basis {
+    /// Check that the gates can express any circuit: a single qubit gate set
+    /// (`u3`, or a phase gate with `ry`, `rx` or `sx`) and one of `cx`,
+    /// `cz`, `cp` or `cu1`
+    pub fn new(gates: &[String]) -> Result<Self, String> {
+        let mut names: Vec<String> = Vec::new();
+        for gate in gates {
+            let name = canonical_name(gate.trim());
+            if gate_info(name).is_none() {
+                return Err(format!("unknown basis gate {}", gate));
+            }
+            if !names.iter().any(|known| known == name) {
+                names.push(name.to_string());
+            }
+        }
+        let has = |name: &str| names.iter().any(|known| known == name);
+
+        let rz = ["rz", "p", "u1"].into_iter().find(|&name| has(name));
+        let one_qubit = match rz {
+            _ if has("u3") => OneQubit::U3,
+            Some(rz) if has("ry") => OneQubit::ZY { rz: rz.to_string() },
+            Some(rz) if has("rx") => OneQubit::ZX { rz: rz.to_string() },
+            Some(rz) if has("sx") => OneQubit::ZSx { rz: rz.to_string() },
+            _ => {
+                return Err(format!(
+                    "basis gates {} cannot express all single qubit gates, add u3, or rz with ry, rx or sx",
+                    names.join(",")
+                ))
+            }
+        };
+        let entangler = ["cx", "cz", "cp", "cu1"]
+            .into_iter()
+            .find(|&name| has(name))
+            .ok_or(format!(
+                "basis gates {} have no two qubit gate, add cx, cz or cp",
+                names.join(",")
+            ))?;
+
+        Ok(Basis {
+            entangler: entangler.to_string(),
+            gates: names,
+            one_qubit,
+        })
+    }
+
+    /// all the gates on one or two qubits, only the gates on three qubits
+    /// are expanded, e.g. before the routing
+    pub fn two_qubit_gates() -> Self {
+        let gates: Vec<String> = GATES
+            .iter()
+            .filter(|(_, _, qubits)| *qubits <= 2)
+            .map(|(name, _, _)| name.to_string())
+            .collect();
+        Basis::new(&gates).unwrap()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.gates.iter().any(|gate| gate == name)
+    }
+
+    /// write a single qubit gate `u3(theta, phi, lambda)` in the basis
+    fn one_qubit(&self, [theta, phi, lambda]: [Expr; 3], qubit: usize, ops: &mut Vec<Op>) {
+        let gate = |name: &str, params: Vec<Expr>| Op::Gate {
+            name: name.to_string(),
+            params,
+            qubits: vec![qubit],
+        };
+        let push_rz = |rz: &str, angle: Expr, ops: &mut Vec<Op>| {
+            let angle = normalize(angle);
+            if !is_angle(&angle, 0.0) {
+                ops.push(gate(rz, vec![angle]));
+            }
+        };
+
+        match &self.one_qubit {
+            OneQubit::U3 => ops.push(gate(
+                "u3",
+                vec![normalize(theta), normalize(phi), normalize(lambda)],
+            )),
+            OneQubit::ZY { rz } | OneQubit::ZX { rz } | OneQubit::ZSx { rz }
+                if is_angle(&normalize(theta.clone()), 0.0) =>
+            {
+                push_rz(rz, add(phi, lambda), ops)
+            }
+            OneQubit::ZY { rz } => {
+                push_rz(rz, lambda, ops);
+                ops.push(gate("ry", vec![normalize(theta)]));
+                push_rz(rz, phi, ops);
+            }
+            OneQubit::ZX { rz } => {
+                push_rz(rz, add(lambda, Expr::Num(-FRAC_PI_2)), ops);
+                ops.push(gate("rx", vec![normalize(theta)]));
+                push_rz(rz, add(phi, Expr::Num(FRAC_PI_2)), ops);
+            }
+            OneQubit::ZSx { rz } => {
+                let sx = || gate("sx", Vec::new());
+                if is_angle(&normalize(theta.clone()), FRAC_PI_2) {
+                    // u2(phi, lambda)
+                    push_rz(rz, add(lambda, Expr::Num(-FRAC_PI_2)), ops);
+                    ops.push(sx());
+                    push_rz(rz, add(phi, Expr::Num(FRAC_PI_2)), ops);
+                } else {
+                    push_rz(rz, lambda, ops);
+                    ops.push(sx());
+                    push_rz(rz, add(theta, Expr::Num(PI)), ops);
+                    ops.push(sx());
+                    push_rz(rz, add(phi, Expr::Num(PI)), ops);
+                }
+            }
+        }
+    }
+
+    /// rewrite a gate into the basis, the multi-qubit gates are expanded with
+    /// their definitions until only basis gates are left
+    fn decompose(
+        &self,
+        name: &str,
+        params: Vec<Expr>,
+        qubits: Vec<usize>,
+        definitions: &mut HashMap<String, Circuit>,
+        ops: &mut Vec<Op>,
+    ) -> Result<(), String> {
+        if self.contains(name) {
+            ops.push(Op::Gate {
+                name: name.to_string(),
+                params,
+                qubits,
+            });
+            return Ok(());
+        }
+        if name == "id" {
+            return Ok(());
+        }
+        if qubits.len() == 1 {
+            let u3 = u3_params(name, &params)
+                .ok_or(format!("gate {} cannot be written in the basis", name))?;
+            self.one_qubit(u3, qubits[0], ops);
+            return Ok(());
+        }
+
+        let body = match name {
+            "cx" if self.entangler == "cz" => "h b; cz a,b; h b;".to_string(),
+            "cx" => format!("h b; {}(pi) a,b; h b;", self.entangler),
+            _ => DEFINITIONS
+                .iter()
+                .find(|(gate, _)| *gate == name)
+                .map(|(_, body)| body.to_string())
+                .ok_or(format!("gate {} cannot be written in the basis", name))?,
+        };
+        if !definitions.contains_key(name) {
+            definitions.insert(name.to_string(), definition(&body)?);
+        }
+        let bound: HashMap<String, Expr> = params
+            .into_iter()
+            .enumerate()
+            .map(|(i, param)| (format!("p{}", i), param))
+            .collect();
+        for inst in definitions[name].instructions.clone() {
+            if let Op::Gate {
+                name,
+                params,
+                qubits: args,
+            } = inst.op
+            {
+                self.decompose(
+                    &name,
+                    params
+                        .iter()
+                        .map(|param| param.substitute(&bound))
+                        .collect(),
+                    args.iter().map(|&arg| qubits[arg]).collect(),
+                    definitions,
+                    ops,
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// parse the body of a definition, the qubits `a`, `b` and `c` are 0, 1
+/// and 2
+fn definition(body: &str) -> Result<Circuit, String> {
+    qasm2::parse(&format!(
+        "OPENQASM 2.0;\ninclude \"qelib1.inc\";\nqreg a[1];\nqreg b[1];\nqreg c[1];\n{}",
+        body
+    ))
+}
+
+/// a single qubit gate as `u3(theta, phi, lambda)`, up to a global phase
+fn u3_params(name: &str, params: &[Expr]) -> Option<[Expr; 3]> {
+    let num = Expr::Num;
+    let param = |i: usize| params.get(i).cloned();
+    Some(match name {
+        "x" => [num(PI), num(0.0), num(PI)],
+        "y" => [num(PI), num(FRAC_PI_2), num(FRAC_PI_2)],
+        "z" => [num(0.0), num(0.0), num(PI)],
+        "h" => [num(FRAC_PI_2), num(0.0), num(PI)],
+        "s" => [num(0.0), num(0.0), num(FRAC_PI_2)],
+        "sdg" => [num(0.0), num(0.0), num(-FRAC_PI_2)],
+        "t" => [num(0.0), num(0.0), num(FRAC_PI_4)],
+        "tdg" => [num(0.0), num(0.0), num(-FRAC_PI_4)],
+        "sx" => [num(FRAC_PI_2), num(-FRAC_PI_2), num(FRAC_PI_2)],
+        "sxdg" => [num(FRAC_PI_2), num(FRAC_PI_2), num(-FRAC_PI_2)],
+        "rx" => [param(0)?, num(-FRAC_PI_2), num(FRAC_PI_2)],
+        "ry" => [param(0)?, num(0.0), num(0.0)],
+        "rz" | "p" | "u1" => [num(0.0), num(0.0), param(0)?],
+        "u2" => [num(FRAC_PI_2), param(0)?, param(1)?],
+        "u3" => [param(0)?, param(1)?, param(2)?],
+        _ => return None,
+    })
+}
+
+fn add(a: Expr, b: Expr) -> Expr {
+    Expr::Add(Box::new(a), Box::new(b))
+}
+
+/// fold a constant angle into (-pi, pi], a turn is only a global phase
+fn normalize(angle: Expr) -> Expr {
+    match angle.eval(&HashMap::new()) {
+        Ok(value) => {
+            let value = value.rem_euclid(2.0 * PI);
+            let value = if value > PI { value - 2.0 * PI } else { value };
+            // avoid -0 and rounding noise around the multiples of pi
+            Expr::Num(if value.abs() < 1e-12 { 0.0 } else { value })
+        }
+        Err(_) => angle,
+    }
+}
+
+fn is_angle(angle: &Expr, value: f64) -> bool {
+    matches!(angle, Expr::Num(x) if (x - value).abs() < 1e-12)
+}
+
+/// Rewrite the circuit into the basis gates. Measurements, resets and
+/// barriers are kept, the gates made from a conditional gate keep its
+/// condition.
+pub fn transpile(circuit: &Circuit, basis: &Basis) -> Result<Circuit, String> {
+    let mut definitions = HashMap::new();
+    let mut transpiled = Circuit {
+        qregs: circuit.qregs.clone(),
+        cregs: circuit.cregs.clone(),
+        instructions: Vec::new(),
+    };
+    for inst in circuit.instructions.iter() {
+        let mut ops = Vec::new();
+        match &inst.op {
+            Op::Gate {
+                name,
+                params,
+                qubits,
+            } => basis.decompose(
+                name,
+                params.clone(),
+                qubits.clone(),
+                &mut definitions,
+                &mut ops,
+            )?,
+            op => ops.push(op.clone()),
+        }
+        transpiled
+            .instructions
+            .extend(ops.into_iter().map(|op| Instruction {
+                op,
+                condition: inst.condition.clone(),
+            }));
+    }
+    Ok(transpiled)
+}
+
+/// Parse a comma separated list of basis gates such as `rz,sx,x,cx`. An
+/// empty list is no basis, the circuits are run as they are.
+pub fn parse_basis_gates(list: &str) -> Result<Option<Vec<String>>, String> {
+    let gates: Vec<String> = list
+        .split(',')
+        .map(|gate| gate.trim().to_string())
+        .filter(|gate| !gate.is_empty())
+        .collect();
+    if gates.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(Basis::new(&gates)?.gates))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulator::statevector::StateVector;
+    use num::complex::Complex64;
+
+    const BASES: &[&str] = &["u3,cx", "rz,ry,cz", "p,rx,cp", "rz,sx,x,cx", "u1,sx,cu1"];
+
+    fn basis(list: &str) -> Basis {
+        Basis::new(&parse_basis_gates(list).unwrap().unwrap()).unwrap()
+    }
+
+    /// the columns of the unitary of the gates of the circuit
+    fn unitary(circuit: &Circuit) -> Vec<Vec<Complex64>> {
+        let num_qubits = circuit.num_qubits();
+        (0..1 << num_qubits)
+            .map(|column| {
+                let mut state = StateVector::new(num_qubits).unwrap();
+                state.amplitudes[0] = Complex64::new(0.0, 0.0);
+                state.amplitudes[column] = Complex64::new(1.0, 0.0);
+                for inst in circuit.instructions.iter() {
+                    if let Op::Gate {
+                        name,
+                        params,
+                        qubits,
+                    } = &inst.op
+                    {
+                        let params: Vec<f64> = params
+                            .iter()
+                            .map(|param| param.eval(&HashMap::new()).unwrap())
+                            .collect();
+                        state.apply_gate(name, &params, qubits).unwrap();
+                    }
+                }
+                state.amplitudes
+            })
+            .collect()
+    }
+
+    /// the unitaries are equal up to a global phase
+    fn assert_equivalent(a: &Circuit, b: &Circuit, what: &str) {
+        let (a, b) = (unitary(a), unitary(b));
+        let (a, b) = (a.concat(), b.concat());
+        let pivot = (0..a.len())
+            .max_by(|&i, &j| a[i].norm().total_cmp(&a[j].norm()))
+            .unwrap();
+        let phase = a[pivot] / b[pivot];
+        assert!((phase.norm() - 1.0).abs() < 1e-9, "{}", what);
+        for (x, y) in a.iter().zip(b.iter()) {
+            assert!((x - y * phase).norm() < 1e-9, "{}", what);
+        }
+    }
+
+    #[test]
+    fn every_gate_is_equivalent_in_every_basis() {
+        let params = [0.7, -1.3, 2.1, 0.4];
+        for list in BASES {
+            let basis = basis(list);
+            for (name, num_params, num_qubits) in GATES {
+                let params: Vec<String> = params[..*num_params]
+                    .iter()
+                    .map(|p| p.to_string())
+                    .collect();
+                let qubits: Vec<String> = (0..*num_qubits).map(|q| format!("q[{}]", q)).collect();
+                let params = if params.is_empty() {
+                    String::new()
+                } else {
+                    format!("({})", params.join(","))
+                };
+                let circuit = qasm2::parse(&format!(
+                    "OPENQASM 2.0;\ninclude \"qelib1.inc\";\nqreg q[{}];\n{}{} {};\n",
+                    num_qubits,
+                    name,
+                    params,
+                    qubits.join(",")
+                ))
+                .unwrap();
+                let what = format!("{} in {}", name, list);
+                let transpiled = transpile(&circuit, &basis).expect(&what);
+                for inst in transpiled.instructions.iter() {
+                    if let Op::Gate { name, .. } = &inst.op {
+                        assert!(basis.contains(name), "{}: {}", what, name);
+                    }
+                }
+                assert_equivalent(&circuit, &transpiled, &what);
+            }
+        }
+    }
+
+    #[test]
+    fn conditions_and_measurements_are_kept() {
+        let circuit = qasm2::parse(
+            "OPENQASM 2.0;\ninclude \"qelib1.inc\";\nqreg q[2];\ncreg c[1];\n\
+             measure q[0] -> c[0];\nif(c==1) h q[1];\n",
+        )
+        .unwrap();
+        let transpiled = transpile(&circuit, &basis("rz,sx,x,cx")).unwrap();
+        assert_eq!(transpiled.instructions[0], circuit.instructions[0]);
+        assert!(transpiled.instructions[1..]
+            .iter()
+            .all(|inst| inst.condition == circuit.instructions[1].condition));
+        // variables are kept in the angles
+        let circuit =
+            qasm2::parse("OPENQASM 2.0;\ninclude \"qelib1.inc\";\nqreg q[1];\nrx(theta) q[0];\n")
+                .unwrap();
+        let transpiled = transpile(&circuit, &basis("rz,ry,cx")).unwrap();
+        assert_eq!(transpiled.free_vars(), ["theta"]);
+    }
+
+    #[test]
+    fn basis_is_validated() {
+        let gates = |list: &str| list.split(',').map(String::from).collect::<Vec<_>>();
+        let basis = Basis::new(&gates("U,CX,u3, cx")).unwrap();
+        assert_eq!(basis.gates, ["u3", "cx"]);
+        assert_eq!(
+            Basis::new(&gates("rz,sx,foo,cx")).unwrap_err(),
+            "unknown basis gate foo"
+        );
+        assert_eq!(
+            Basis::new(&gates("rz,h,cx")).unwrap_err(),
+            "basis gates rz,h,cx cannot express all single qubit gates, add u3, or rz with ry, rx or sx"
+        );
+        assert_eq!(
+            Basis::new(&gates("rz,sx,swap")).unwrap_err(),
+            "basis gates rz,sx,swap have no two qubit gate, add cx, cz or cp"
+        );
+        assert!(Basis::two_qubit_gates().contains("rxx"));
+        assert!(!Basis::two_qubit_gates().contains("ccx"));
+    }
+
+    #[test]
+    fn basis_gates_are_parsed() {
+        assert_eq!(parse_basis_gates("").unwrap(), None);
+        assert_eq!(parse_basis_gates(" , ").unwrap(), None);
+        assert_eq!(
+            parse_basis_gates(" rz, sx ,x,cx,").unwrap(),
+            Some(["rz", "sx", "x", "cx"].map(String::from).to_vec())
+        );
+        assert!(parse_basis_gates("rz,sx").is_err());
+    }
+}
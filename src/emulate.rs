@@ -3,7 +3,12 @@ use serde::Deserialize;
 use serde_json::{json, Value};
 use std::{collections::HashMap, fmt};
 
-use crate::SharedState;
+use crate::{
+    circuit::{self, routing::CouplingMap, InputFormat},
+    qubits::CReg,
+    simulator::noise::NoiseModel,
+    SharedState,
+};
 
 #[derive(Deserialize, Debug, Clone)]
 pub enum EmulateMode {
@@ -19,6 +24,8 @@ pub enum EmulateMode {
     Expectation,
     #[serde(rename = "vqe")]
     Vqe,
+    #[serde(rename = "sweep")]
+    Sweep,
 }
 
 /// For the `EmulateMode` enum, we need to implement the `FromStr` trait to
@@ -42,6 +49,7 @@ impl fmt::Display for EmulateMode {
             EmulateMode::Min => write!(f, "min"),
             EmulateMode::Expectation => write!(f, "expectation"),
             EmulateMode::Vqe => write!(f, "vqe"),
+            EmulateMode::Sweep => write!(f, "sweep"),
         }
     }
 }
@@ -57,14 +65,75 @@ pub struct EmulateMessage {
     pub iterations: Option<usize>,
     pub vars: Option<String>,
     pub vars_range: Option<String>,
+    // only when the mode is sweep, the json of `sweep::SweepSpec`
+    pub sweep: Option<String>,
+    /// seed of the sampling, a random one is used and reported if not given
+    pub seed: Option<u64>,
+    /// the seed was drawn by the agent, the client did not give one
+    #[serde(skip)]
+    pub random_seed: bool,
+    /// id of the job, so that its progress can be streamed while it runs, a
+    /// new one is used if not given
+    pub job_id: Option<String>,
+    /// shots between two partial results in the progress stream
+    pub progress_every: Option<usize>,
+    /// format of the program in `qasm`, OpenQASM by default
+    pub input_format: Option<InputFormat>,
+    /// the peephole passes run before the simulation, 0 (none) to 3
+    pub optimization_level: Option<usize>,
+    /// the name of a backend of `/backends`, by default the stabilizer for
+    /// Clifford circuits and the statevector otherwise
+    pub backend: Option<String>,
+    /// the largest bond of the mps backend
+    pub bond_dimension: Option<usize>,
+    /// the share of the norm below which the mps backend drops a singular
+    /// value
+    pub truncation_threshold: Option<f64>,
+    /// Pauli strings, e.g. `XZI`, whose expectation values the
+    /// density_matrix backend computes exactly
+    pub observables: Option<Vec<String>>,
 }
 
 /// For simulator use
 #[derive(Deserialize, Debug)]
 pub struct EmulateInfo {
+    // the program with the variables replaced, for qasmsim
     pub qasm: String,
+    // the program as submitted and its variables, bound when it is run
+    pub template: String,
+    pub vars: HashMap<String, f64>,
     pub shots: Option<usize>,
     pub mode: Option<EmulateMode>,
+    // seed of the per-shot simulation, random if not given
+    pub seed: Option<u64>,
+    // the seed was drawn by the agent, only a seed the client gave is an
+    // error for the programs qasmsim runs
+    #[serde(default)]
+    pub random_seed: bool,
+    // the native gates of the device, the circuit is transpiled into them
+    pub basis_gates: Option<Vec<String>>,
+    // the coupling map of the device, the circuit is routed on it
+    pub coupling_map: Option<CouplingMap>,
+    // the passes of `circuit::optimize` to run, none if not given
+    pub optimization_level: Option<usize>,
+    // the backend the circuit is routed to and the settings of the mps
+    // backend
+    pub backend: Option<String>,
+    pub bond_dimension: Option<usize>,
+    pub truncation_threshold: Option<f64>,
+    // the noise of the device and the observables of the density matrix
+    // backend
+    pub noise: Option<NoiseModel>,
+    pub observables: Option<Vec<String>>,
+}
+
+/// Result of the simulator, `metadata` is reported with the job result, and
+/// the exact results of the backend, e.g. `probabilities`, next to `Result`
+#[derive(Debug, Clone, Default)]
+pub struct EmulateResult {
+    pub sequences: Vec<String>,
+    pub metadata: serde_json::Map<String, Value>,
+    pub exact: serde_json::Map<String, Value>,
 }
 
 pub fn post_process_msg_agg(seq: Vec<String>, init_pos: usize) -> Json<Value> {
@@ -140,77 +209,152 @@ pub async fn post_process_msg(
     state: SharedState,
     seq: Vec<String>,
     mode: String,
+    job_id: &str,
+    cregs: &[CReg],
+    metadata: serde_json::Map<String, Value>,
+    exact: serde_json::Map<String, Value>,
 ) -> Result<Json<Value>, String> {
     let mut state_w = state.write().await;
     let init_pos = state_w.qmem.current_pos;
+    let generation = state_w.qmem.next_generation();
     for s in seq.iter() {
-        state_w.qmem.update_results(s);
+        state_w.qmem.update_results(s, cregs);
     }
     state_w.qmem.dump_file(&state_w.measure_path);
 
-    match mode.as_str() {
-        "sequence" => Ok(Json(json!({
+    let mut json = match mode.as_str() {
+        "sequence" => Json(json!({
             "init_position": init_pos,
             "Result": [seq],
-        }))),
-        "aggregation" => Ok(post_process_msg_agg(seq, init_pos)),
-        "max" => Ok(post_process_msg_minmax(seq, true, init_pos)),
-        "min" => Ok(post_process_msg_minmax(seq, false, init_pos)),
-        "expectation" => Ok(post_process_msg_expe(seq, init_pos)),
-        _ => Err("Invalid mode".to_string()),
+        })),
+        "aggregation" => post_process_msg_agg(seq.clone(), init_pos),
+        "max" => post_process_msg_minmax(seq.clone(), true, init_pos),
+        "min" => post_process_msg_minmax(seq.clone(), false, init_pos),
+        "expectation" => post_process_msg_expe(seq.clone(), init_pos),
+        _ => return Err("Invalid mode".to_string()),
+    };
+    json["job_id"] = json!(job_id);
+    json["generation"] = json!(generation);
+    json["seed"] = metadata.get("seed").cloned().unwrap_or(Value::Null);
+    json["metadata"] = json!(metadata);
+    for (key, value) in exact.iter() {
+        json[key] = value.clone();
+    }
+
+    // keep the full result of the job, the rows in qmem may be overwritten
+    let mut counts = HashMap::new();
+    for s in seq.iter() {
+        *counts.entry(s.clone()).or_insert(0) += 1;
+    }
+    state_w.jobs.insert(
+        job_id,
+        json!({
+            "job_id": job_id,
+            "mode": mode,
+            "shots": seq.len(),
+            "init_position": init_pos,
+            "generation": generation,
+            "cregs": cregs,
+            "counts": counts,
+            "sequences": seq,
+            "metadata": metadata,
+            "exact": exact,
+        }),
+    );
+
+    Ok(json)
+}
+
+impl EmulateMessage {
+    /// fix the seed of the task so that it can be reported and replayed, a
+    /// random one is drawn if the client did not give one
+    pub fn fix_seed(&mut self) -> u64 {
+        if self.seed.is_none() {
+            self.seed = Some(rand::random());
+            self.random_seed = true;
+        }
+        self.seed.unwrap()
     }
 }
 
+/// convert the program of the message to OpenQASM, the errors point at the
+/// invalid part of the input
+pub fn convert_input(msg: &mut EmulateMessage) -> Result<(), String> {
+    if let Some(format) = msg.input_format.take() {
+        msg.qasm = circuit::convert_input(&msg.qasm, format)
+            .map_err(|err| format!("Invalid {} input: {}", format, err))?;
+    }
+    Ok(())
+}
+
 pub fn pre_process_msg(msg: EmulateMessage) -> EmulateInfo {
-    let vars = serde_json::from_str::<HashMap<String, f32>>(
+    let vars = serde_json::from_str::<HashMap<String, f64>>(
         msg.vars.clone().unwrap_or("{}".to_string()).as_str(),
     )
     .unwrap();
 
-    let mut qasm_ = msg.qasm.clone();
-
-    if msg.vars.is_some() {
-        for (key, value) in vars.iter() {
-            qasm_ = qasm_.replace(key, &value.to_string());
-        }
-    }
-
     EmulateInfo {
-        qasm: qasm_,
+        qasm: bind_vars(&msg.qasm, &vars),
+        template: msg.qasm,
+        vars,
         shots: if msg.shots == 0 {
             Some(1)
         } else {
             Some(msg.shots)
         },
         mode: msg.mode,
+        seed: msg.seed,
+        random_seed: msg.random_seed,
+        basis_gates: None,
+        coupling_map: None,
+        optimization_level: msg.optimization_level,
+        backend: msg.backend,
+        bond_dimension: msg.bond_dimension,
+        truncation_threshold: msg.truncation_threshold,
+        noise: None,
+        observables: msg.observables,
     }
 }
 
+/// replace the variables in the program text
+pub fn bind_vars(qasm: &str, vars: &HashMap<String, f64>) -> String {
+    let mut qasm_ = qasm.to_string();
+    for (key, value) in vars.iter() {
+        qasm_ = qasm_.replace(key, &value.to_string());
+    }
+    qasm_
+}
+
 pub fn pre_process_msg_vqe(
     msg: EmulateMessage,
     vars_range: HashMap<String, (f32, f32)>,
     iteration: usize,
     iterations: usize,
 ) -> EmulateInfo {
-    let mut vars: HashMap<String, f32> = HashMap::new();
+    let mut vars: HashMap<String, f64> = HashMap::new();
 
     for (key, value) in vars_range {
         vars.insert(
             key,
-            value.0 + (value.1 - value.0) * iteration as f32 / (iterations - 1) as f32,
+            (value.0 + (value.1 - value.0) * iteration as f32 / (iterations - 1) as f32) as f64,
         );
     }
 
-    let mut qasm_ = msg.qasm.clone();
-    if !vars.is_empty() {
-        for (key, value) in vars.iter() {
-            qasm_ = qasm_.replace(key, &value.to_string());
-        }
-    }
-
     EmulateInfo {
-        qasm: qasm_,
+        qasm: bind_vars(&msg.qasm, &vars),
+        template: msg.qasm,
+        vars,
         shots: None,
         mode: msg.mode,
+        seed: msg.seed,
+        random_seed: msg.random_seed,
+        basis_gates: None,
+        coupling_map: None,
+        optimization_level: None,
+        backend: msg.backend,
+        bond_dimension: None,
+        truncation_threshold: None,
+        noise: None,
+        observables: None,
     }
 }
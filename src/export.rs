@@ -0,0 +1,407 @@
+use std::{collections::BTreeMap, fmt, str::FromStr, sync::Arc};
+
+use arrow_array::{ArrayRef, RecordBatch, StringArray, UInt64Array, UInt8Array};
+use arrow_ipc::writer::StreamWriter;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::qubits::QMemory;
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub enum ExportFormat {
+    #[serde(rename = "csv")]
+    Csv,
+    #[serde(rename = "npy")]
+    Npy,
+    #[serde(rename = "arrow")]
+    Arrow,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct ParseExportFormatError;
+
+impl fmt::Display for ParseExportFormatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Invalid export format, should be csv, npy or arrow")
+    }
+}
+
+impl FromStr for ExportFormat {
+    type Err = ParseExportFormatError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(ExportFormat::Csv),
+            "npy" => Ok(ExportFormat::Npy),
+            "arrow" => Ok(ExportFormat::Arrow),
+            _ => Err(ParseExportFormatError),
+        }
+    }
+}
+
+impl ExportFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "text/csv",
+            ExportFormat::Npy => "application/octet-stream",
+            ExportFormat::Arrow => "application/vnd.apache.arrow.stream",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Npy => "npy",
+            ExportFormat::Arrow => "arrow",
+        }
+    }
+}
+
+/// A table of measured bits, one row per shot
+pub struct BitTable {
+    /// names of the bit columns, `c3, c2, c1, c0` for a 4-bit register
+    pub columns: Vec<String>,
+    pub generations: Vec<u64>,
+    pub rows: Vec<Vec<u8>>,
+}
+
+/// The written rows of the classical storage from the oldest to the most
+/// recent, or only one of its registers
+pub fn memory_table(qmem: &QMemory, creg: Option<&str>) -> Result<BitTable, String> {
+    let mut positions: Vec<usize> = (0..qmem.capacity)
+        .filter(|&pos| qmem.generations[pos] != 0)
+        .collect();
+    positions.sort_by_key(|&pos| qmem.generations[pos]);
+
+    let (name, width) = match creg {
+        Some(creg) => {
+            positions.retain(|&pos| qmem.registers[pos].contains_key(creg));
+            let width = positions
+                .first()
+                .map(|&pos| qmem.registers[pos][creg].len())
+                .ok_or(format!("No register {} in the classical storage", creg))?;
+            // the same name may be declared with another size by other jobs
+            positions.retain(|&pos| qmem.registers[pos][creg].len() == width);
+            (creg, width)
+        }
+        None => ("q", qmem.qubits),
+    };
+
+    Ok(BitTable {
+        columns: (0..width)
+            .rev()
+            .map(|bit| format!("{}{}", name, bit))
+            .collect(),
+        generations: positions.iter().map(|&pos| qmem.generations[pos]).collect(),
+        rows: positions
+            .iter()
+            .map(|&pos| match creg {
+                Some(creg) => qmem.registers[pos][creg].clone(),
+                None => qmem.mem[pos].clone(),
+            })
+            .collect(),
+    })
+}
+
+/// The shots of a stored job as a table, `result` is the record kept by the
+/// `JobStore`. The spaces between the registers of a sequence are dropped, as
+/// in `split_registers`.
+pub fn job_table(result: &Value) -> Result<BitTable, String> {
+    let sequences: Vec<String> = serde_json::from_value(result["sequences"].clone())
+        .map_err(|_| "Job has no sequences".to_string())?;
+    let sequences: Vec<Vec<u8>> = sequences
+        .iter()
+        .map(|s| {
+            s.chars()
+                .filter(|c| !c.is_whitespace())
+                .map(|c| u8::from(c == '1'))
+                .collect()
+        })
+        .collect();
+    let width = sequences.iter().map(|bits| bits.len()).max().unwrap_or(0);
+
+    Ok(BitTable {
+        columns: (0..width).rev().map(|bit| format!("c{}", bit)).collect(),
+        generations: (0..sequences.len() as u64).collect(),
+        rows: sequences
+            .iter()
+            .map(|bits| {
+                let mut row = vec![0; width - bits.len()];
+                row.extend(bits);
+                row
+            })
+            .collect(),
+    })
+}
+
+/// The counts of a stored job, sorted by bitstring
+pub fn job_counts(result: &Value) -> Result<BTreeMap<String, u64>, String> {
+    serde_json::from_value(result["counts"].clone()).map_err(|_| "Job has no counts".to_string())
+}
+
+pub fn table_to_csv(table: &BitTable, index: &str) -> Vec<u8> {
+    let mut csv = format!("{},{}\n", index, table.columns.join(","));
+    for (generation, row) in table.generations.iter().zip(table.rows.iter()) {
+        csv.push_str(&generation.to_string());
+        for bit in row {
+            csv.push(',');
+            csv.push(if *bit == 1 { '1' } else { '0' });
+        }
+        csv.push('\n');
+    }
+    csv.into_bytes()
+}
+
+pub fn counts_to_csv(counts: &BTreeMap<String, u64>) -> Vec<u8> {
+    let mut csv = "bitstring,count\n".to_string();
+    for (bitstring, count) in counts {
+        csv.push_str(&format!("{},{}\n", bitstring, count));
+    }
+    csv.into_bytes()
+}
+
+/// NumPy `.npy` version 1.0 of a `rows x columns` uint8 matrix
+pub fn table_to_npy(table: &BitTable) -> Vec<u8> {
+    let mut header = format!(
+        "{{'descr': '|u1', 'fortran_order': False, 'shape': ({}, {}), }}",
+        table.rows.len(),
+        table.columns.len()
+    );
+    // magic (6) + version (2) + header length (2) + header is aligned to 64
+    // bytes and ends with a newline
+    let padding = (64 - (10 + header.len() + 1) % 64) % 64;
+    header.push_str(&" ".repeat(padding));
+    header.push('\n');
+
+    let mut npy = b"\x93NUMPY\x01\x00".to_vec();
+    npy.extend_from_slice(&(header.len() as u16).to_le_bytes());
+    npy.extend_from_slice(header.as_bytes());
+    for row in table.rows.iter() {
+        npy.extend_from_slice(row);
+    }
+    npy
+}
+
+fn batch_to_arrow(batch: RecordBatch) -> Result<Vec<u8>, String> {
+    let mut buffer = Vec::new();
+    let mut writer =
+        StreamWriter::try_new(&mut buffer, &batch.schema()).map_err(|err| err.to_string())?;
+    writer.write(&batch).map_err(|err| err.to_string())?;
+    writer.finish().map_err(|err| err.to_string())?;
+    drop(writer);
+    Ok(buffer)
+}
+
+/// Arrow IPC stream with a uint64 index column and one uint8 column per bit
+pub fn table_to_arrow(table: &BitTable, index: &str) -> Result<Vec<u8>, String> {
+    let mut columns: Vec<(String, ArrayRef)> = vec![(
+        index.to_string(),
+        Arc::new(UInt64Array::from(table.generations.clone())),
+    )];
+    for (i, name) in table.columns.iter().enumerate() {
+        columns.push((
+            name.clone(),
+            Arc::new(UInt8Array::from(
+                table.rows.iter().map(|row| row[i]).collect::<Vec<u8>>(),
+            )),
+        ));
+    }
+
+    batch_to_arrow(RecordBatch::try_from_iter(columns).map_err(|err| err.to_string())?)
+}
+
+pub fn counts_to_arrow(counts: &BTreeMap<String, u64>) -> Result<Vec<u8>, String> {
+    let columns: Vec<(&str, ArrayRef)> = vec![
+        (
+            "bitstring",
+            Arc::new(StringArray::from_iter_values(counts.keys())),
+        ),
+        (
+            "count",
+            Arc::new(UInt64Array::from_iter_values(counts.values().copied())),
+        ),
+    ];
+
+    batch_to_arrow(RecordBatch::try_from_iter(columns).map_err(|err| err.to_string())?)
+}
+
+/// export the classical storage, indexed by the generation of each row
+pub fn export_memory(
+    qmem: &QMemory,
+    creg: Option<&str>,
+    format: ExportFormat,
+) -> Result<Vec<u8>, String> {
+    let table = memory_table(qmem, creg)?;
+    match format {
+        ExportFormat::Csv => Ok(table_to_csv(&table, "generation")),
+        ExportFormat::Npy => Ok(table_to_npy(&table)),
+        ExportFormat::Arrow => table_to_arrow(&table, "generation"),
+    }
+}
+
+/// export a stored job, csv and arrow have the counts, npy has the shots
+/// matrix since it can only hold numbers
+pub fn export_job(result: &Value, format: ExportFormat) -> Result<Vec<u8>, String> {
+    match format {
+        ExportFormat::Csv => Ok(counts_to_csv(&job_counts(result)?)),
+        ExportFormat::Npy => Ok(table_to_npy(&job_table(result)?)),
+        ExportFormat::Arrow => counts_to_arrow(&job_counts(result)?),
+    }
+}
+
+/// `qasmsim-agent export [--format csv|npy|arrow] [--input MEASURE_PATH]
+/// [--creg NAME] [--output FILE]`, writes to stdout without `--output`
+pub fn export_cli(args: &[String]) -> Result<(), String> {
+    let mut format = ExportFormat::Csv;
+    let mut input = std::env::var("MEASURE_PATH").unwrap_or_else(|_| "./measure.pkl".to_string());
+    let mut creg = None;
+    let mut output = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let value = args
+            .next()
+            .ok_or(format!("Missing value of argument {}", arg))?;
+        match arg.as_str() {
+            "-f" | "--format" => format = value.parse().map_err(|err| format!("{}", err))?,
+            "-i" | "--input" => input = value.clone(),
+            "-c" | "--creg" => creg = Some(value.clone()),
+            "-o" | "--output" => output = Some(value.clone()),
+            _ => return Err(format!("Unknown argument {}", arg)),
+        }
+    }
+
+    if !std::path::Path::new(&input).exists() {
+        return Err(format!("Classical storage {} not found", input));
+    }
+    let data = export_memory(&QMemory::read_file(&input), creg.as_deref(), format)?;
+
+    match output {
+        Some(output) => std::fs::write(output, data).map_err(|err| err.to_string()),
+        None => {
+            use std::io::Write;
+            std::io::stdout()
+                .write_all(&data)
+                .map_err(|err| err.to_string())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use arrow_array::cast::AsArray;
+    use arrow_array::types::{UInt64Type, UInt8Type};
+    use arrow_ipc::reader::StreamReader;
+    use serde_json::json;
+
+    use super::*;
+    use crate::qubits::parse_cregs;
+
+    /// three shots of `creg a[2]; creg b[1];`, the first row is overwritten
+    fn memory() -> QMemory {
+        let cregs = parse_cregs("creg a[2];\ncreg b[1];\n");
+        let mut qmem = QMemory::new(3, 2);
+        for string in ["01 1", "10 0", "11 1"] {
+            qmem.update_results(string, &cregs);
+        }
+        qmem
+    }
+
+    #[test]
+    fn csv_has_a_row_per_shot() {
+        let csv = export_memory(&memory(), None, ExportFormat::Csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "generation,q2,q1,q0\n2,1,0,0\n3,1,1,1\n"
+        );
+        let csv = export_memory(&memory(), Some("a"), ExportFormat::Csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "generation,a1,a0\n2,1,0\n3,1,1\n"
+        );
+        let csv = export_memory(&memory(), Some("b"), ExportFormat::Csv).unwrap();
+        assert_eq!(String::from_utf8(csv).unwrap(), "generation,b0\n2,0\n3,1\n");
+        assert_eq!(
+            export_memory(&memory(), Some("c"), ExportFormat::Csv).unwrap_err(),
+            "No register c in the classical storage"
+        );
+
+        let counts = json!({"counts": {"01": 3, "11": 1}});
+        let csv = export_job(&counts, ExportFormat::Csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "bitstring,count\n01,3\n11,1\n"
+        );
+    }
+
+    #[test]
+    fn npy_header_is_aligned() {
+        let npy = export_memory(&memory(), None, ExportFormat::Npy).unwrap();
+        assert_eq!(&npy[..8], b"\x93NUMPY\x01\x00");
+        let header_len = u16::from_le_bytes([npy[8], npy[9]]) as usize;
+        assert_eq!((10 + header_len) % 64, 0);
+        let header = std::str::from_utf8(&npy[10..10 + header_len]).unwrap();
+        assert!(header.starts_with("{'descr': '|u1', 'fortran_order': False, 'shape': (2, 3), }"));
+        assert!(header.ends_with('\n'));
+        assert_eq!(&npy[10 + header_len..], [1, 0, 0, 1, 1, 1]);
+    }
+
+    #[test]
+    fn arrow_reads_back() {
+        let arrow = export_memory(&memory(), Some("a"), ExportFormat::Arrow).unwrap();
+        let batches: Vec<RecordBatch> = StreamReader::try_new(arrow.as_slice(), None)
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(batches.len(), 1);
+        let batch = &batches[0];
+        let names: Vec<String> = batch
+            .schema()
+            .fields()
+            .iter()
+            .map(|field| field.name().clone())
+            .collect();
+        assert_eq!(names, ["generation", "a1", "a0"]);
+        assert_eq!(
+            batch.column(0).as_primitive::<UInt64Type>().values(),
+            &[2, 3]
+        );
+        assert_eq!(
+            batch.column(1).as_primitive::<UInt8Type>().values(),
+            &[1, 1]
+        );
+        assert_eq!(
+            batch.column(2).as_primitive::<UInt8Type>().values(),
+            &[0, 1]
+        );
+
+        let counts = json!({"counts": {"01": 3, "11": 1}});
+        let arrow = export_job(&counts, ExportFormat::Arrow).unwrap();
+        let batch = StreamReader::try_new(arrow.as_slice(), None)
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap();
+        let bitstrings: Vec<&str> = batch
+            .column(0)
+            .as_string::<i32>()
+            .iter()
+            .flatten()
+            .collect();
+        assert_eq!(bitstrings, ["01", "11"]);
+        assert_eq!(
+            batch.column(1).as_primitive::<UInt64Type>().values(),
+            &[3, 1]
+        );
+    }
+
+    #[test]
+    fn job_table_drops_the_register_separators() {
+        let table = job_table(&json!({"sequences": ["1 01", "0 11", "10"]})).unwrap();
+        assert_eq!(table.columns, vec!["c2", "c1", "c0"]);
+        assert_eq!(
+            table.rows,
+            vec![vec![1, 0, 1], vec![0, 1, 1], vec![0, 1, 0]]
+        );
+    }
+}
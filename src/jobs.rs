@@ -0,0 +1,165 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Retention policy of the per-job result storage. A job result is dropped
+/// once it is older than `ttl` seconds, or when the total size of the stored
+/// results exceeds `max_bytes` (oldest jobs are dropped first). The newest
+/// result is kept even when it alone exceeds `max_bytes`, so that it can be
+/// queried once.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct RetentionPolicy {
+    pub ttl: Option<u64>,
+    pub max_bytes: Option<usize>,
+}
+
+impl RetentionPolicy {
+    /// read the policy from `JOB_TTL` and `JOB_MAX_BYTES`, unset means
+    /// unlimited
+    pub fn from_env() -> Self {
+        RetentionPolicy {
+            ttl: std::env::var("JOB_TTL")
+                .ok()
+                .and_then(|ttl| ttl.parse().ok()),
+            max_bytes: std::env::var("JOB_MAX_BYTES")
+                .ok()
+                .and_then(|max_bytes| max_bytes.parse().ok()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct JobRecord {
+    pub id: String,
+    /// unix timestamp in seconds
+    pub created: u64,
+    /// size of the serialized result, used by the retention policy
+    pub bytes: usize,
+    pub result: Value,
+}
+
+/// Results of finished jobs keyed by job id. Unlike `QMemory`, a stored result
+/// is never overwritten by other jobs, it only disappears when the retention
+/// policy evicts it.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct JobStore {
+    pub policy: RetentionPolicy,
+    pub jobs: HashMap<String, JobRecord>,
+    // job ids in insertion order, the front is the oldest one
+    order: VecDeque<String>,
+    total_bytes: usize,
+}
+
+pub fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+impl JobStore {
+    pub fn new(policy: RetentionPolicy) -> Self {
+        JobStore {
+            policy,
+            ..Default::default()
+        }
+    }
+
+    pub fn new_job_id() -> String {
+        uuid::Uuid::new_v4().to_string()
+    }
+
+    pub fn total_bytes(&self) -> usize {
+        self.total_bytes
+    }
+
+    pub fn insert(&mut self, id: &str, result: Value) {
+        let bytes = result.to_string().len();
+        if let Some(old) = self.jobs.remove(id) {
+            self.total_bytes -= old.bytes;
+            self.order.retain(|job_id| job_id != id);
+        }
+
+        self.jobs.insert(
+            id.to_string(),
+            JobRecord {
+                id: id.to_string(),
+                created: now_secs(),
+                bytes,
+                result,
+            },
+        );
+        self.order.push_back(id.to_string());
+        self.total_bytes += bytes;
+        self.evict();
+    }
+
+    pub fn get(&mut self, id: &str) -> Option<&JobRecord> {
+        self.evict();
+        self.jobs.get(id)
+    }
+
+    /// drop the jobs which are expired or exceed the size budget, but not the
+    /// newest one for its size
+    pub fn evict(&mut self) {
+        let now = now_secs();
+        while let Some(id) = self.order.front() {
+            let record = &self.jobs[id];
+            let expired = self
+                .policy
+                .ttl
+                .is_some_and(|ttl| now.saturating_sub(record.created) > ttl);
+            let oversized = self.order.len() > 1
+                && self
+                    .policy
+                    .max_bytes
+                    .is_some_and(|max_bytes| self.total_bytes > max_bytes);
+
+            if !expired && !oversized {
+                break;
+            }
+
+            let id = self.order.pop_front().unwrap();
+            let record = self.jobs.remove(&id).unwrap();
+            self.total_bytes -= record.bytes;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn store(max_bytes: usize) -> JobStore {
+        JobStore::new(RetentionPolicy {
+            ttl: None,
+            max_bytes: Some(max_bytes),
+        })
+    }
+
+    #[test]
+    fn oldest_results_are_evicted_first() {
+        let mut jobs = store(40);
+        jobs.insert("a", json!({"Result": "0123456789"}));
+        jobs.insert("b", json!({"Result": "0123456789"}));
+        assert!(jobs.get("a").is_none());
+        assert!(jobs.get("b").is_some());
+        assert!(jobs.total_bytes() <= 40);
+    }
+
+    #[test]
+    fn oversized_result_is_kept_until_the_next_one() {
+        let mut jobs = store(10);
+        jobs.insert("a", json!({"Result": "0123456789"}));
+        assert_eq!(jobs.get("a").unwrap().result["Result"], "0123456789");
+        jobs.insert("b", json!({"Result": "0123456789"}));
+        assert!(jobs.get("a").is_none());
+        assert!(jobs.get("b").is_some());
+    }
+}
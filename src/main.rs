@@ -1,17 +1,38 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
 
 use axum::{
+    body::Bytes,
     extract::{Query, Request, State},
     http::{header, StatusCode},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
     routing, Form, Json, RequestExt, Router,
 };
 use emulate::{EmulateMessage, EmulateMode};
 use serde::Deserialize;
 use serde_json::{json, Value};
-use tokio::sync::{oneshot, RwLock};
+use tokio::sync::{broadcast, mpsc, oneshot, RwLock};
+use tokio_stream::wrappers::ReceiverStream;
+pub mod analyze;
+pub mod batch;
+pub mod circuit;
 pub mod emulate;
+pub mod export;
+pub mod jobs;
 pub mod optimizer;
+pub mod pool;
+pub mod progress;
 pub mod qubits;
+pub mod simulator;
+pub mod snapshot;
+pub mod sweep;
 pub mod thread;
 
 #[derive(Debug, Clone)]
@@ -19,6 +40,11 @@ pub struct ServerState {
     pub measure_path: String,
     pub qmem: qubits::QMemory,
     pub qreg: qubits::QResgister,
+    pub jobs: jobs::JobStore,
+    pub pool: Arc<pool::ComputePool>,
+    pub cache: Arc<circuit::cache::CircuitCache>,
+    pub backends: Arc<simulator::backend::BackendRegistry>,
+    pub progress: Arc<progress::ProgressHub>,
 }
 
 type SharedState = Arc<RwLock<ServerState>>;
@@ -28,22 +54,140 @@ type SharedState = Arc<RwLock<ServerState>>;
 pub struct ClassicalInfo {
     pub qubits: Option<usize>,
     pub capacity: Option<usize>,
+    /// drop all the stored results before resizing
+    pub clear: Option<bool>,
+    /// only report what would be lost, nothing is changed
+    pub dry_run: Option<bool>,
+    /// the native gates of the device, e.g. `rz,sx,x,cx`, empty to run the
+    /// circuits as they are
+    pub basis_gates: Option<String>,
+    /// `linear`, `ring`, `grid`, `heavy_hex` or a JSON list of edges, empty
+    /// for all-to-all qubits
+    pub coupling_map: Option<String>,
+    /// the JSON of `simulator::noise::NoiseModel`, empty for no noise
+    pub noise: Option<String>,
 }
 
 /// For classical storage query
 #[derive(Deserialize, Debug, Clone)]
 pub struct MeasurePos {
     pub pos: usize,
+    /// only return this classical register
+    pub creg: Option<String>,
+}
+
+/// For job result query
+#[derive(Deserialize, Debug, Clone)]
+pub struct JobQuery {
+    pub id: String,
+}
+
+/// For classical storage and job result export
+#[derive(Deserialize, Debug, Clone)]
+pub struct ExportQuery {
+    pub format: Option<export::ExportFormat>,
+    /// export the counts of this job instead of the classical storage
+    pub job_id: Option<String>,
+    /// only export this classical register of the classical storage
+    pub creg: Option<String>,
+}
+
+/// A program to convert between the circuit formats
+#[derive(Deserialize, Debug, Clone)]
+pub struct ConvertMessage {
+    pub from: circuit::InputFormat,
+    pub to: circuit::OutputFormat,
+    pub source: String,
+}
+
+/// For snapshot and restore, the default path is `SNAPSHOT_PATH`
+#[derive(Deserialize, Debug, Clone)]
+pub struct SnapshotQuery {
+    pub path: Option<String>,
+}
+
+impl SnapshotQuery {
+    /// The snapshot file, `SNAPSHOT_PATH` or the file name `path` in its
+    /// directory, other files cannot be read or written
+    pub fn path(&self) -> Result<PathBuf, String> {
+        let default = PathBuf::from(
+            std::env::var("SNAPSHOT_PATH").unwrap_or_else(|_| "./snapshot.json".to_string()),
+        );
+        let Some(name) = &self.path else {
+            return Ok(default);
+        };
+        let invalid = || {
+            format!(
+                "Invalid snapshot path {}, it should be a file name in the directory of SNAPSHOT_PATH",
+                name
+            )
+        };
+        let mut components = Path::new(name).components();
+        if !matches!(
+            (components.next(), components.next()),
+            (Some(Component::Normal(_)), None)
+        ) {
+            return Err(invalid());
+        }
+
+        let dir = match default.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let dir = dir
+            .canonicalize()
+            .map_err(|err| format!("Snapshot directory {}: {}", dir.display(), err))?;
+        let path = dir.join(name);
+        // a link in the directory may lead out of it
+        match path.canonicalize() {
+            Ok(target) if !target.starts_with(&dir) => Err(invalid()),
+            _ => Ok(path),
+        }
+    }
 }
 
 /// consume_task is the main function to consume the task
 /// it will spawn the quantum_thread and classical_thread execept for VQE
 /// for VQE, it will spawn multiple classical_thread_vqe amd quantum_thread_vqe
+/// for sweep, it will run the task for every point of the sweep as a batch
 pub async fn consume_task(
     state: SharedState,
     Form(mut message): Form<emulate::EmulateMessage>,
 ) -> (StatusCode, Json<Value>) {
     message.mode = Some(message.mode.unwrap_or(EmulateMode::Aggregation));
+    if let Err(err) = emulate::convert_input(&mut message) {
+        return (StatusCode::BAD_REQUEST, Json(json!({ "Error": err })));
+    }
+    // the seed is fixed here so that it can be reported and replayed
+    let seed = message.fix_seed();
+    let job_id = message
+        .job_id
+        .clone()
+        .unwrap_or_else(jobs::JobStore::new_job_id);
+    let (pool, cache, backends, progress) = {
+        let state_r = state.read().await;
+        if state_r.jobs.jobs.contains_key(&job_id) {
+            return (
+                StatusCode::CONFLICT,
+                Json(json!({"Error": format!("Job {} already exists", job_id)})),
+            );
+        }
+        if let Err(err) = state_r.backends.check(&message) {
+            return (StatusCode::BAD_REQUEST, Json(json!({ "Error": err })));
+        }
+        // the points of a sweep are jobs of their own
+        if !matches!(message.mode, Some(EmulateMode::Sweep)) {
+            if let Err(err) = state_r.progress.start(&job_id) {
+                return (StatusCode::CONFLICT, Json(json!({ "Error": err })));
+            }
+        }
+        (
+            state_r.pool.clone(),
+            state_r.cache.clone(),
+            state_r.backends.clone(),
+            progress::Progress::new(state_r.progress.clone(), job_id, message.progress_every),
+        )
+    };
 
     match message.mode {
         Some(EmulateMode::Aggregation)
@@ -54,23 +198,40 @@ pub async fn consume_task(
             let (msg_tx, msg_rx) = oneshot::channel();
             let (res_tx, res_rx) = oneshot::channel();
 
-            tokio::spawn(thread::quantum_thread(msg_rx, res_tx));
-            tokio::spawn(thread::classical_thread(state, message, msg_tx, res_rx))
-                .await
-                .unwrap()
+            tokio::spawn(thread::quantum_thread(
+                pool,
+                cache,
+                backends,
+                progress.clone(),
+                msg_rx,
+                res_tx,
+            ));
+            tokio::spawn(thread::classical_thread(
+                state, message, progress, msg_tx, res_rx,
+            ))
+            .await
+            .unwrap()
         }
+        Some(EmulateMode::Sweep) => sweep::consume_sweep(state, message).await,
         Some(EmulateMode::Vqe) => {
             let vars_range = match serde_json::from_str::<HashMap<String, (f32, f32)>>(
                 message.vars.clone().unwrap_or("{}".to_string()).as_str(),
             ) {
                 Ok(vars_range) => vars_range,
                 Err(_) => {
+                    progress.failed(&json!("Invalid vars range"));
                     return (
                         StatusCode::BAD_REQUEST,
                         Json(json!({"Error": "Invalid vars range"})),
-                    )
+                    );
                 }
             };
+            // the optimization passes are not run on VQE programs
+            if message.optimization_level.is_some_and(|level| level > 0) {
+                let err = "optimization_level is not supported in vqe mode";
+                progress.failed(&json!(err));
+                return (StatusCode::BAD_REQUEST, Json(json!({ "Error": err })));
+            }
 
             // if default value is 1, then the variable will NAN
             let iterations = message.iterations.unwrap_or(2);
@@ -79,12 +240,20 @@ pub async fn consume_task(
             for index in 0..iterations {
                 let (msg_tx, msg_rx) = oneshot::channel();
                 let (res_tx, res_rx) = oneshot::channel();
-                tokio::spawn(thread::quantum_thread_vqe(msg_rx, res_tx));
+                tokio::spawn(thread::quantum_thread_vqe(
+                    pool.clone(),
+                    backends.clone(),
+                    progress.clone(),
+                    msg_rx,
+                    res_tx,
+                ));
                 match tokio::spawn(thread::classical_thread_vqe(
+                    state.clone(),
                     message.clone(),
                     vars_range.clone(),
                     index,
                     iterations,
+                    progress.clone(),
                     msg_tx,
                     res_rx,
                 ))
@@ -92,96 +261,218 @@ pub async fn consume_task(
                 {
                     Ok((status, json)) => {
                         if status != StatusCode::OK {
+                            progress.failed(&json["Error"]);
                             return (status, json);
                         }
                         results.push(json);
                     }
                     Err(err) => {
+                        progress.failed(&json!(format!("{}", err)));
                         return (
                             StatusCode::BAD_REQUEST,
                             Json(json!({"Error": format!("{}", err)})),
-                        )
+                        );
                     }
                 };
             }
-            (StatusCode::OK, Json(json!({"Result": "Success"})))
+            let result = json!({"Result": "Success", "seed": seed, "job_id": progress.job_id});
+            progress.done(&result);
+            (StatusCode::OK, Json(result))
         }
         _ => unreachable!(),
     }
 }
 
-/// endpoint to submit the task
-pub async fn submit(
-    State(state): State<SharedState>,
-    request: Request,
-) -> (StatusCode, Json<Value>) {
+/// read the task of a form or JSON body
+async fn extract_message(request: Request) -> Result<EmulateMessage, (StatusCode, Json<Value>)> {
     match request.headers().get(header::CONTENT_TYPE) {
         Some(content_type) => match content_type.to_str().unwrap() {
             "application/x-www-form-urlencoded" => {
                 let Form(message) = request.extract().await.unwrap();
-                consume_task(state, Form(message)).await
+                Ok(message)
             }
             "application/json" => {
                 let Json::<EmulateMessage>(message) = request.extract().await.unwrap();
-                consume_task(state, Form(message)).await
+                Ok(message)
             }
-            _ => (
+            _ => Err((
                 StatusCode::BAD_REQUEST,
                 Json(json!({"Error": format!("content type {:?} not support", content_type)})),
-            ),
+            )),
         },
-        _ => (
+        _ => Err((
             StatusCode::BAD_REQUEST,
             Json(json!({"Error": format!("content type not specified")})),
-        ),
+        )),
     }
 }
 
-pub async fn update_classical(
+/// endpoint to submit the task
+pub async fn submit(
     State(state): State<SharedState>,
     request: Request,
 ) -> (StatusCode, Json<Value>) {
-    match request.headers().get(header::CONTENT_TYPE) {
-        Some(content_type) => match content_type.to_str().unwrap() {
-            "application/x-www-form-urlencoded" => {
-                let Form(message): Form<ClassicalInfo> = request.extract().await.unwrap();
-                let mut state_w = state.write().await;
+    match extract_message(request).await {
+        Ok(message) => consume_task(state, Form(message)).await,
+        Err(err) => err,
+    }
+}
 
-                if message.qubits.is_some() {
-                    state_w.qmem.update_qubits(message.qubits.unwrap());
-                    state_w.qreg.update_qubits(message.qubits.unwrap());
-                }
+/// endpoint to report the properties of the circuit of a task without
+/// running it
+pub async fn analyze(
+    State(state): State<SharedState>,
+    request: Request,
+) -> (StatusCode, Json<Value>) {
+    match extract_message(request).await {
+        Ok(message) => analyze::analyze_task(state, message).await,
+        Err(err) => err,
+    }
+}
 
-                if message.capacity.is_some() {
-                    state_w.qmem.update_capacity(message.capacity.unwrap());
-                }
+/// consume_classical applies the classical storage update, resizing keeps the
+/// stored results which still fit
+pub async fn consume_classical(
+    state: SharedState,
+    message: ClassicalInfo,
+) -> (StatusCode, Json<Value>) {
+    if message.qubits == Some(0) || message.capacity == Some(0) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"Error": "qubits and capacity should be larger than 0"})),
+        );
+    }
 
-                state_w.qmem.dump_file(&state_w.measure_path);
+    let basis_gates = match message
+        .basis_gates
+        .as_deref()
+        .map(circuit::transpile::parse_basis_gates)
+        .transpose()
+    {
+        Ok(basis_gates) => basis_gates,
+        Err(err) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({"Error": format!("Invalid basis_gates: {}", err)})),
+            )
+        }
+    };
 
-                (
-                    StatusCode::OK,
-                    Json(json!({"Result": format!("Update classical info with {:?}", message)})),
-                )
-            }
-            "application/json" => {
-                let Json::<ClassicalInfo>(message) = request.extract().await.unwrap();
-                let mut state_w = state.write().await;
+    let noise = match message
+        .noise
+        .as_deref()
+        .map(simulator::noise::parse_noise)
+        .transpose()
+    {
+        Ok(noise) => noise,
+        Err(err) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({"Error": format!("Invalid noise: {}", err)})),
+            )
+        }
+    };
 
-                if message.qubits.is_some() {
-                    state_w.qmem.update_qubits(message.qubits.unwrap());
-                    state_w.qreg.update_qubits(message.qubits.unwrap());
-                }
+    let mut state_w = state.write().await;
+    // the jobs are checked against the widest circuit of their backend when
+    // they run, the device is as wide as the widest backend
+    let (max_qubits, widest) = state_w.backends.max_qubits(&state_w.pool.memory);
+    if message.qubits.is_some_and(|qubits| qubits > max_qubits) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"Error": format!(
+                "qubits should be at most {}, the widest circuit of the {} backend in the memory budget of {}, see /backends",
+                max_qubits,
+                widest,
+                pool::format_bytes(state_w.pool.memory.budget),
+            )})),
+        );
+    }
+    let qubits = message.qubits.unwrap_or(state_w.qmem.qubits);
+    let capacity = message.capacity.unwrap_or(state_w.qmem.capacity);
 
-                if message.capacity.is_some() {
-                    state_w.qmem.update_capacity(message.capacity.unwrap());
+    // the presets follow the number of qubits
+    let coupling_map = match message.coupling_map.clone().or_else(|| {
+        state_w
+            .qreg
+            .coupling_map
+            .as_ref()
+            .map(|map| map.spec.clone())
+    }) {
+        Some(spec) if !spec.trim().is_empty() => {
+            match circuit::routing::CouplingMap::new(&spec, qubits) {
+                Ok(map) => Some(map),
+                Err(err) => {
+                    return (
+                        StatusCode::BAD_REQUEST,
+                        Json(json!({"Error": format!("Invalid coupling_map: {}", err)})),
+                    )
                 }
+            }
+        }
+        _ => None,
+    };
 
-                state_w.qmem.dump_file(&state_w.measure_path);
+    if message.dry_run.unwrap_or(false) {
+        let report = if message.clear.unwrap_or(false) {
+            let mut cleared = state_w.qmem.clone();
+            cleared.clear();
+            cleared.plan_resize(qubits, capacity)
+        } else {
+            state_w.qmem.plan_resize(qubits, capacity)
+        };
+        return (StatusCode::OK, Json(json!({"DryRun": report})));
+    }
 
-                (
-                    StatusCode::OK,
-                    Json(json!({"Result": format!("Update classical info with {:?}", message)})),
-                )
+    if message.clear.unwrap_or(false) {
+        state_w.qmem.clear();
+    }
+
+    let report = state_w.qmem.resize(qubits, capacity);
+    if let Some(qubits) = message.qubits {
+        state_w.qreg.update_qubits(qubits);
+    }
+    if let Some(basis_gates) = basis_gates {
+        state_w.qreg.basis_gates = basis_gates;
+    }
+    state_w.qreg.coupling_map = coupling_map;
+    if let Some(noise) = noise {
+        state_w.qreg.noise = noise;
+    }
+
+    state_w.qmem.dump_file(&state_w.measure_path);
+
+    (
+        StatusCode::OK,
+        Json(json!({
+            "Result": format!("Update classical info with {:?}", message),
+            "Report": report,
+        })),
+    )
+}
+
+/// endpoint to submit a batch of tasks, the body is a list of tasks, or a
+/// task with a list of variable `bindings`
+pub async fn submit_batch(
+    State(state): State<SharedState>,
+    Json(batch): Json<batch::BatchMessage>,
+) -> (StatusCode, Json<Value>) {
+    batch::consume_batch(state, batch).await
+}
+
+pub async fn update_classical(
+    State(state): State<SharedState>,
+    request: Request,
+) -> (StatusCode, Json<Value>) {
+    match request.headers().get(header::CONTENT_TYPE) {
+        Some(content_type) => match content_type.to_str().unwrap() {
+            "application/x-www-form-urlencoded" => {
+                let Form(message): Form<ClassicalInfo> = request.extract().await.unwrap();
+                consume_classical(state, message).await
+            }
+            "application/json" => {
+                let Json::<ClassicalInfo>(message) = request.extract().await.unwrap();
+                consume_classical(state, message).await
             }
             _ => (
                 StatusCode::BAD_REQUEST,
@@ -200,18 +491,278 @@ pub async fn get_measure(
     Query(pos): Query<MeasurePos>,
 ) -> (StatusCode, Json<Value>) {
     let state_r = state.read().await;
-    if pos.pos > state_r.qmem.capacity {
+    if pos.pos >= state_r.qmem.capacity {
         (
             StatusCode::BAD_REQUEST,
             Json(
-                json!({"Error": format!("Quert position {} is larger than capacity {}", pos.pos, state_r.qmem.capacity)}),
+                json!({"Error": format!("Quert position {} is out of capacity {}", pos.pos, state_r.qmem.capacity)}),
             ),
         )
+    } else if let Some(creg) = &pos.creg {
+        match state_r.qmem.registers[pos.pos].get(creg) {
+            Some(bits) => (
+                StatusCode::OK,
+                Json(json!({
+                    "Results": bits,
+                    "creg": creg,
+                    "generation": state_r.qmem.generations[pos.pos],
+                })),
+            ),
+            None => (
+                StatusCode::NOT_FOUND,
+                Json(json!({"Error": format!("No register {} at position {}", creg, pos.pos)})),
+            ),
+        }
     } else {
         (
             StatusCode::OK,
-            Json(json!({"Results": state_r.qmem.mem[pos.pos]})),
+            Json(json!({
+                "Results": state_r.qmem.mem[pos.pos],
+                "Registers": state_r.qmem.registers[pos.pos],
+                "generation": state_r.qmem.generations[pos.pos],
+            })),
+        )
+    }
+}
+
+/// endpoint to query the full result of a job by its id
+pub async fn get_job(
+    State(state): State<SharedState>,
+    Query(query): Query<JobQuery>,
+) -> (StatusCode, Json<Value>) {
+    let mut state_w = state.write().await;
+    match state_w.jobs.get(&query.id) {
+        Some(record) => (StatusCode::OK, Json(json!(record))),
+        None => (
+            StatusCode::NOT_FOUND,
+            Json(json!({"Error": format!("Job {} not found or expired", query.id)})),
+        ),
+    }
+}
+
+/// endpoint to export the classical storage or a job result as csv, npy or
+/// arrow
+pub async fn export_results(
+    State(state): State<SharedState>,
+    Query(query): Query<ExportQuery>,
+) -> Response {
+    let format = query.format.unwrap_or(export::ExportFormat::Csv);
+    let (name, data) = match &query.job_id {
+        Some(job_id) => {
+            let mut state_w = state.write().await;
+            match state_w.jobs.get(job_id) {
+                Some(record) => (job_id.clone(), export::export_job(&record.result, format)),
+                None => {
+                    return (
+                        StatusCode::NOT_FOUND,
+                        Json(json!({"Error": format!("Job {} not found or expired", job_id)})),
+                    )
+                        .into_response()
+                }
+            }
+        }
+        None => (
+            "measure".to_string(),
+            export::export_memory(&state.read().await.qmem, query.creg.as_deref(), format),
+        ),
+    };
+
+    match data {
+        Ok(data) => (
+            [
+                (header::CONTENT_TYPE, format.content_type().to_string()),
+                (
+                    header::CONTENT_DISPOSITION,
+                    format!("attachment; filename=\"{}.{}\"", name, format.extension()),
+                ),
+            ],
+            data,
         )
+            .into_response(),
+        Err(err) => (StatusCode::BAD_REQUEST, Json(json!({ "Error": err }))).into_response(),
+    }
+}
+
+/// endpoint to stream the progress of a job as server-sent events: `state`
+/// (queued, running), `partial` counts, VQE `iteration`s, and at last `done`
+/// with the result or `failed` with the error. The earlier events are sent
+/// first, so it can be opened before or while the job runs. Too many streams
+/// waiting for jobs which did not start are rejected.
+pub async fn stream_job(
+    State(state): State<SharedState>,
+    Query(query): Query<JobQuery>,
+) -> Response {
+    let (history, mut rx, finished) = match state.read().await.progress.subscribe(&query.id) {
+        Ok(subscription) => subscription,
+        Err(err) => {
+            return (StatusCode::TOO_MANY_REQUESTS, Json(json!({ "Error": err }))).into_response()
+        }
+    };
+    let (tx, stream) = mpsc::channel::<Result<Event, Infallible>>(16);
+
+    tokio::spawn(async move {
+        let event = |value: Value| {
+            Ok(Event::default()
+                .event(value["event"].as_str().unwrap_or("message"))
+                .data(value.to_string()))
+        };
+        for value in history {
+            if tx.send(event(value)).await.is_err() {
+                return;
+            }
+        }
+        if finished {
+            return;
+        }
+        loop {
+            match rx.recv().await {
+                Ok(value) => {
+                    let is_final = progress::is_final(&value);
+                    if tx.send(event(value)).await.is_err() || is_final {
+                        return;
+                    }
+                }
+                // a slow client misses some partial results
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return,
+            }
+        }
+    });
+
+    Sse::new(ReceiverStream::new(stream))
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
+
+/// endpoint to convert a program to another format without running it
+pub async fn convert(Json(message): Json<ConvertMessage>) -> (StatusCode, Json<Value>) {
+    match circuit::convert(&message.source, message.from, message.to) {
+        Ok(converted) => (
+            StatusCode::OK,
+            Json(json!({
+                "Result": converted,
+                "from": message.from.to_string(),
+                "to": message.to.to_string(),
+            })),
+        ),
+        Err(err) => (
+            StatusCode::BAD_REQUEST,
+            Json(
+                json!({"Error": format!("Cannot convert {} to {}: {}", message.from, message.to, err)}),
+            ),
+        ),
+    }
+}
+
+/// endpoint to list the backends, with what they simulate and the widest
+/// circuit each runs in the memory budget
+pub async fn backends(State(state): State<SharedState>) -> (StatusCode, Json<Value>) {
+    let state_r = state.read().await;
+    (
+        StatusCode::OK,
+        Json(json!({
+            "Result": state_r.backends.info(&state_r.pool.memory),
+        })),
+    )
+}
+
+/// endpoint to report the agent configuration and load
+pub async fn info(State(state): State<SharedState>) -> (StatusCode, Json<Value>) {
+    let state_r = state.read().await;
+    (
+        StatusCode::OK,
+        Json(json!({
+            "pool": state_r.pool.info(),
+            "circuit_cache": state_r.cache.info(),
+            "qubits": state_r.qreg.qubits.len(),
+            "idle_qubits": state_r.qreg.idle,
+            "basis_gates": state_r.qreg.basis_gates,
+            "coupling_map": state_r.qreg.coupling_map.as_ref().map(|map| &map.spec),
+            "noise": state_r.qreg.noise,
+            "classical": {
+                "qubits": state_r.qmem.qubits,
+                "capacity": state_r.qmem.capacity,
+            },
+            "jobs": state_r.jobs.jobs.len(),
+        })),
+    )
+}
+
+/// endpoint to download a snapshot of the agent state
+pub async fn get_snapshot(State(state): State<SharedState>) -> (StatusCode, Json<Value>) {
+    let snapshot = snapshot::Snapshot::take(&*state.read().await);
+    (StatusCode::OK, Json(json!(snapshot)))
+}
+
+/// endpoint to write a snapshot of the agent state to a file
+pub async fn write_snapshot(
+    State(state): State<SharedState>,
+    Query(query): Query<SnapshotQuery>,
+) -> (StatusCode, Json<Value>) {
+    let path = match query.path() {
+        Ok(path) => path,
+        Err(err) => return (StatusCode::BAD_REQUEST, Json(json!({ "Error": err }))),
+    };
+    let snapshot = snapshot::Snapshot::take(&*state.read().await);
+    match std::fs::write(&path, snapshot.to_bytes()) {
+        Ok(_) => (
+            StatusCode::OK,
+            Json(json!({
+                "Result": format!("Write snapshot to {}", path.display()),
+                "version": snapshot.version,
+                "created": snapshot.created,
+            })),
+        ),
+        Err(err) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({"Error": format!("Write snapshot to {} failed: {}", path.display(), err)})),
+        ),
+    }
+}
+
+/// endpoint to restore the agent state from the snapshot in the request body,
+/// or from a snapshot file if the body is empty
+pub async fn restore_snapshot(
+    State(state): State<SharedState>,
+    Query(query): Query<SnapshotQuery>,
+    body: Bytes,
+) -> (StatusCode, Json<Value>) {
+    let data = if body.is_empty() {
+        let path = match query.path() {
+            Ok(path) => path,
+            Err(err) => return (StatusCode::BAD_REQUEST, Json(json!({ "Error": err }))),
+        };
+        match std::fs::read(&path) {
+            Ok(data) => data,
+            Err(err) => {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    Json(
+                        json!({"Error": format!("Read snapshot {} failed: {}", path.display(), err)}),
+                    ),
+                )
+            }
+        }
+    } else {
+        body.to_vec()
+    };
+
+    match snapshot::Snapshot::from_bytes(&data) {
+        Ok(snapshot) => {
+            let (version, created) = (snapshot.version, snapshot.created);
+            let mut state_w = state.write().await;
+            snapshot.restore(&mut state_w);
+            state_w.qmem.dump_file(&state_w.measure_path);
+            (
+                StatusCode::OK,
+                Json(json!({
+                    "Result": "Restore snapshot",
+                    "version": version,
+                    "created": created,
+                })),
+            )
+        }
+        Err(err) => (StatusCode::BAD_REQUEST, Json(json!({ "Error": err }))),
     }
 }
 
@@ -221,6 +772,15 @@ async fn main() {
         dotenv::dotenv().ok();
     }
 
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(|arg| arg.as_str()) == Some("export") {
+        if let Err(err) = export::export_cli(&args[2..]) {
+            eprintln!("Error: {}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     let measure_path =
         std::env::var("MEASURE_PATH").unwrap_or_else(|_| "./measure.pkl".to_string());
 
@@ -230,18 +790,208 @@ async fn main() {
         qubits::QMemory::default()
     };
 
+    let mut qreg = qubits::QResgister::new(qmem.qubits);
+    let basis_gates = std::env::var("BASIS_GATES").unwrap_or_default();
+    qreg.basis_gates = match circuit::transpile::parse_basis_gates(&basis_gates) {
+        Ok(basis_gates) => basis_gates,
+        Err(err) => {
+            eprintln!("Error: BASIS_GATES: {}", err);
+            std::process::exit(1);
+        }
+    };
+    if let Ok(spec) = std::env::var("COUPLING_MAP") {
+        match circuit::routing::CouplingMap::new(&spec, qmem.qubits) {
+            Ok(map) => qreg.coupling_map = Some(map),
+            Err(err) => {
+                eprintln!("Error: COUPLING_MAP: {}", err);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let noise = std::env::var("NOISE_MODEL").unwrap_or_default();
+    qreg.noise = match simulator::noise::parse_noise(&noise) {
+        Ok(noise) => noise,
+        Err(err) => {
+            eprintln!("Error: NOISE_MODEL: {}", err);
+            std::process::exit(1);
+        }
+    };
+
+    let pool = match pool::ComputePool::from_env() {
+        Ok(pool) => pool,
+        Err(err) => {
+            eprintln!("Error: MEMORY_BUDGET: {}", err);
+            std::process::exit(1);
+        }
+    };
+
     let state = Arc::new(RwLock::new(ServerState {
         measure_path: measure_path.clone(),
-        qreg: qubits::QResgister::new(qmem.qubits),
+        qreg,
         qmem,
+        jobs: jobs::JobStore::new(jobs::RetentionPolicy::from_env()),
+        pool: Arc::new(pool),
+        cache: Arc::new(circuit::cache::CircuitCache::from_env()),
+        backends: Arc::new(simulator::backend::BackendRegistry::default()),
+        progress: Arc::new(progress::ProgressHub::new()),
     }));
 
     let listener_addr = std::env::var("LISTENER_ADDR").unwrap_or("0.0.0.0:3003".to_string());
     let qpp_router = Router::new()
         .route("/submit", routing::post(submit))
+        .route("/submit_batch", routing::post(submit_batch))
         .route("/update", routing::post(update_classical))
         .route("/get_measure", routing::get(get_measure))
+        .route("/get_job", routing::get(get_job))
+        .route("/stream", routing::get(stream_job))
+        .route("/export", routing::get(export_results))
+        .route("/analyze", routing::post(analyze))
+        .route("/convert", routing::post(convert))
+        .route("/info", routing::get(info))
+        .route("/backends", routing::get(backends))
+        .route(
+            "/admin/snapshot",
+            routing::get(get_snapshot).post(write_snapshot),
+        )
+        .route("/admin/restore", routing::post(restore_snapshot))
         .with_state(state);
     let listener = tokio::net::TcpListener::bind(listener_addr).await.unwrap();
     axum::serve(listener, qpp_router).await.unwrap();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a program only qasmsim runs
+    pub const OPAQUE: &str = "OPENQASM 2.0;\ninclude \"qelib1.inc\";\nqreg q[1];\nopaque g q;\n";
+
+    /// the state of an agent with `qubits` qubits, its measurements are kept
+    /// in a temporary file
+    pub fn state(qubits: usize) -> SharedState {
+        let measure_path = std::env::temp_dir()
+            .join(format!("measure-{}.json", jobs::JobStore::new_job_id()))
+            .to_string_lossy()
+            .to_string();
+        Arc::new(RwLock::new(ServerState {
+            measure_path,
+            qmem: qubits::QMemory::new(qubits, 4),
+            qreg: qubits::QResgister::new(qubits),
+            jobs: jobs::JobStore::new(jobs::RetentionPolicy {
+                ttl: None,
+                max_bytes: None,
+            }),
+            pool: Arc::new(pool::ComputePool::new(
+                1,
+                1,
+                pool::MemoryBudget::new(1 << 30),
+            )),
+            cache: Arc::new(circuit::cache::CircuitCache::new(8)),
+            backends: Arc::new(simulator::backend::BackendRegistry::default()),
+            progress: Arc::new(progress::ProgressHub::new()),
+        }))
+    }
+
+    fn message(task: Value) -> EmulateMessage {
+        serde_json::from_value(task).unwrap()
+    }
+
+    #[tokio::test]
+    async fn only_a_given_seed_is_rejected_for_qasmsim() {
+        let state = state(2);
+        let (_, json) = consume_task(
+            state.clone(),
+            Form(message(json!({"qasm": OPAQUE, "qubits": 1, "shots": 1}))),
+        )
+        .await;
+        assert!(!json["Error"].to_string().contains("seed"), "{}", json.0);
+
+        let (status, json) = consume_task(
+            state.clone(),
+            Form(message(
+                json!({"qasm": OPAQUE, "qubits": 1, "shots": 1, "seed": 7}),
+            )),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(json["Error"]
+            .as_str()
+            .unwrap()
+            .starts_with("seed is not supported"));
+        assert_eq!(state.read().await.qreg.idle, 2);
+    }
+
+    #[test]
+    fn snapshot_files_are_in_the_directory_of_snapshot_path() {
+        let dir = std::env::temp_dir().join(format!("snapshots-{}", jobs::JobStore::new_job_id()));
+        std::fs::create_dir(&dir).unwrap();
+        let dir = dir.canonicalize().unwrap();
+        std::os::unix::fs::symlink("/etc/passwd", dir.join("link.json")).unwrap();
+        std::env::set_var("SNAPSHOT_PATH", dir.join("snapshot.json"));
+        let path = |path: Option<&str>| {
+            SnapshotQuery {
+                path: path.map(String::from),
+            }
+            .path()
+        };
+        assert_eq!(path(None).unwrap(), dir.join("snapshot.json"));
+        assert_eq!(path(Some("before.json")).unwrap(), dir.join("before.json"));
+        for name in [
+            "../etc/passwd",
+            "/etc/passwd",
+            "a/b.json",
+            "..",
+            "link.json",
+        ] {
+            assert_eq!(
+                path(Some(name)).unwrap_err(),
+                format!(
+                    "Invalid snapshot path {}, it should be a file name in the directory of SNAPSHOT_PATH",
+                    name
+                )
+            );
+        }
+        std::env::remove_var("SNAPSHOT_PATH");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn convert_message(from: &str, to: &str, source: &str) -> Json<ConvertMessage> {
+        Json(serde_json::from_value(json!({"from": from, "to": to, "source": source})).unwrap())
+    }
+
+    #[tokio::test]
+    async fn programs_are_converted() {
+        let bell = "OPENQASM 2.0;\ninclude \"qelib1.inc\";\nqreg q[2];\ncreg c[2];\n\
+                    h q[0];\ncx q[0],q[1];\nmeasure q[1] -> c[1];\n";
+        let (status, json) = convert(convert_message("qasm2", "quil", bell)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            json["Result"],
+            "DECLARE c BIT[2]\nH 0\nCNOT 0 1\nMEASURE 1 c[1]\n"
+        );
+        assert_eq!(
+            (&json["from"], &json["to"]),
+            (&json!("qasm2"), &json!("quil"))
+        );
+
+        let (status, json) = convert(convert_message("quil", "qasm2", "H 0\nJUMP @end\n")).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            json["Error"],
+            "Cannot convert quil to qasm2: line 2: JUMP is not supported"
+        );
+
+        let (status, json) = convert(convert_message(
+            "qasm2",
+            "cirq_json",
+            &bell.replace("cx", "cy"),
+        ))
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            json["Error"],
+            "Cannot convert qasm2 to cirq_json: gate cy cannot be written in Cirq JSON"
+        );
+    }
+}
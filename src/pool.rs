@@ -0,0 +1,370 @@
+use std::sync::{
+    atomic::{AtomicU64, AtomicUsize, Ordering},
+    Arc,
+};
+
+use axum::http::StatusCode;
+use serde_json::{json, Value};
+use tokio::sync::{Semaphore, TryAcquireError};
+
+use crate::progress::Progress;
+
+/// Pool of blocking threads running the simulations, so that a heavy circuit
+/// does not stall the async runtime. At most `size` simulations run at once,
+/// and at most `max_queue` wait for a thread, later ones are rejected. The
+/// jobs reserve their memory from `memory` before they are simulated.
+#[derive(Debug)]
+pub struct ComputePool {
+    pub size: usize,
+    pub max_queue: usize,
+    pub memory: Arc<MemoryBudget>,
+    permits: Arc<Semaphore>,
+    queued: AtomicUsize,
+    running: AtomicUsize,
+}
+
+/// the error of a job rejected by a full pool
+pub const POOL_BUSY: &str = "Compute pool is busy";
+
+/// the status of a failed job, a full pool asks the client to retry later
+pub fn error_status(err: &str) -> StatusCode {
+    if err.starts_with(POOL_BUSY) {
+        StatusCode::SERVICE_UNAVAILABLE
+    } else {
+        StatusCode::BAD_REQUEST
+    }
+}
+
+/// bytes of the statevector of `num_qubits` qubits, 16 for each amplitude
+pub fn statevector_bytes(num_qubits: usize) -> u64 {
+    2u64.saturating_pow(num_qubits.min(u32::MAX as usize) as u32)
+        .saturating_mul(16)
+}
+
+/// e.g. `512 B`, `1.5 GiB`
+pub fn format_bytes(bytes: u64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < units.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, units[unit])
+    }
+}
+
+/// Parse a number of bytes, with an optional `K`, `M`, `G` or `T` suffix of
+/// powers of 1024, e.g. `8G`
+pub fn parse_bytes(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let digits = value.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let shift = match value[digits.len()..].to_ascii_uppercase().as_str() {
+        "" | "B" => 0,
+        "K" | "KB" | "KIB" => 10,
+        "M" | "MB" | "MIB" => 20,
+        "G" | "GB" | "GIB" => 30,
+        "T" | "TB" | "TIB" => 40,
+        suffix => return Err(format!("unknown unit {} in {}", suffix, value)),
+    };
+    digits
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .and_then(|bytes| bytes.checked_mul(1 << shift))
+        .ok_or_else(|| format!("{} is not a number of bytes", value))
+}
+
+/// The memory the simulations may use at once. A job reserves the memory it
+/// needs before it runs and is rejected when the reservation would exceed the
+/// budget, so the jobs running at once never add up past it.
+#[derive(Debug)]
+pub struct MemoryBudget {
+    pub budget: u64,
+    used: AtomicU64,
+}
+
+/// memory reserved by a job, released when the job is done or dropped
+#[derive(Debug)]
+pub struct MemoryReservation {
+    memory: Arc<MemoryBudget>,
+    pub bytes: u64,
+}
+
+impl Drop for MemoryReservation {
+    fn drop(&mut self) {
+        self.memory.used.fetch_sub(self.bytes, Ordering::SeqCst);
+    }
+}
+
+/// the memory limit of the cgroup of the agent, v2 or v1, none if it is
+/// unlimited
+fn cgroup_memory_limit() -> Option<u64> {
+    [
+        "/sys/fs/cgroup/memory.max",
+        "/sys/fs/cgroup/memory/memory.limit_in_bytes",
+    ]
+    .iter()
+    .find_map(|path| std::fs::read_to_string(path).ok()?.trim().parse().ok())
+}
+
+/// the memory of the machine
+fn total_memory() -> Option<u64> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    let line = meminfo.lines().find(|line| line.starts_with("MemTotal:"))?;
+    let kib: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kib * 1024)
+}
+
+impl Default for MemoryBudget {
+    /// half of the memory limit of the cgroup, e.g. of a container, or of
+    /// the machine if it has less, or 4 GiB if both are unknown
+    fn default() -> Self {
+        // an unlimited cgroup v1 reports a huge limit
+        let memory = match (cgroup_memory_limit(), total_memory()) {
+            (Some(limit), Some(total)) => Some(limit.min(total)),
+            (limit, total) => limit.or(total),
+        };
+        MemoryBudget::new(memory.map_or(4 << 30, |memory| memory / 2))
+    }
+}
+
+impl MemoryBudget {
+    pub fn new(budget: u64) -> Self {
+        MemoryBudget {
+            budget,
+            used: AtomicU64::new(0),
+        }
+    }
+
+    /// read the budget from `MEMORY_BUDGET`, e.g. `8G`
+    pub fn from_env() -> Result<Self, String> {
+        match std::env::var("MEMORY_BUDGET") {
+            Ok(budget) => Ok(MemoryBudget::new(parse_bytes(&budget)?)),
+            Err(_) => Ok(MemoryBudget::default()),
+        }
+    }
+
+    pub fn used(&self) -> u64 {
+        self.used.load(Ordering::SeqCst)
+    }
+
+    /// the most qubits a job can be simulated with, it needs up to two
+    /// statevectors
+    pub fn max_qubits(&self) -> usize {
+        (0..64)
+            .take_while(|&n| statevector_bytes(n).saturating_mul(2) <= self.budget)
+            .last()
+            .unwrap_or(0)
+    }
+
+    /// reserve `bytes` for a job, `what` describes the job in the errors
+    pub fn reserve(self: &Arc<Self>, bytes: u64, what: &str) -> Result<MemoryReservation, String> {
+        if bytes > self.budget {
+            return Err(format!(
+                "{} needs {} of memory, more than the memory budget of {}",
+                what,
+                format_bytes(bytes),
+                format_bytes(self.budget)
+            ));
+        }
+        self.used
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                (used + bytes <= self.budget).then_some(used + bytes)
+            })
+            .map_err(|used| {
+                format!(
+                    "{} needs {} of memory, only {} of the memory budget of {} is free while other jobs run",
+                    what,
+                    format_bytes(bytes),
+                    format_bytes(self.budget - used),
+                    format_bytes(self.budget)
+                )
+            })?;
+        Ok(MemoryReservation {
+            memory: self.clone(),
+            bytes,
+        })
+    }
+
+    pub fn info(&self) -> Value {
+        json!({
+            "budget": self.budget,
+            "used": self.used(),
+            "max_qubits": self.max_qubits(),
+        })
+    }
+}
+
+/// decrease the counter when the task is done or dropped
+struct CountGuard<'a>(&'a AtomicUsize);
+
+impl<'a> CountGuard<'a> {
+    fn new(counter: &'a AtomicUsize) -> Self {
+        counter.fetch_add(1, Ordering::SeqCst);
+        CountGuard(counter)
+    }
+}
+
+impl Drop for CountGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Default for ComputePool {
+    fn default() -> Self {
+        let size = std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1);
+        ComputePool::new(size, size * 16, MemoryBudget::default())
+    }
+}
+
+impl ComputePool {
+    pub fn new(size: usize, max_queue: usize, memory: MemoryBudget) -> Self {
+        let size = size.max(1);
+        ComputePool {
+            size,
+            max_queue,
+            memory: Arc::new(memory),
+            permits: Arc::new(Semaphore::new(size)),
+            queued: AtomicUsize::new(0),
+            running: AtomicUsize::new(0),
+        }
+    }
+
+    /// read the pool size from `WORKER_THREADS` and the queue length from
+    /// `WORKER_QUEUE`, defaults to the number of cores and 16 times of it,
+    /// and the memory budget from `MEMORY_BUDGET`
+    pub fn from_env() -> Result<Self, String> {
+        let default = ComputePool::default();
+        Ok(ComputePool::new(
+            std::env::var("WORKER_THREADS")
+                .ok()
+                .and_then(|size| size.parse().ok())
+                .unwrap_or(default.size),
+            std::env::var("WORKER_QUEUE")
+                .ok()
+                .and_then(|max_queue| max_queue.parse().ok())
+                .unwrap_or(default.max_queue),
+            MemoryBudget::from_env()?,
+        ))
+    }
+
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::SeqCst)
+    }
+
+    pub fn running(&self) -> usize {
+        self.running.load(Ordering::SeqCst)
+    }
+
+    /// run `f` on a blocking thread once the pool has a free one
+    pub async fn run<F, T>(&self, f: F) -> Result<T, String>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        self.run_job(None, f).await
+    }
+
+    /// `run` the work of a job, the job is published as running once it gets
+    /// a thread
+    pub async fn run_for<F, T>(&self, progress: &Progress, f: F) -> Result<T, String>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        self.run_job(Some(progress), f).await
+    }
+
+    async fn run_job<F, T>(&self, progress: Option<&Progress>, f: F) -> Result<T, String>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let permit = match self.permits.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(TryAcquireError::Closed) => return Err("Compute pool is closed".to_string()),
+            Err(TryAcquireError::NoPermits) => {
+                // the job waits only if the queue has room for it
+                self.queued
+                    .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |queued| {
+                        (queued < self.max_queue).then_some(queued + 1)
+                    })
+                    .map_err(|_| format!("{}, {} tasks are waiting", POOL_BUSY, self.max_queue))?;
+                let _queued = CountGuard(&self.queued);
+                self.permits
+                    .clone()
+                    .acquire_owned()
+                    .await
+                    .map_err(|err| err.to_string())?
+            }
+        };
+
+        if let Some(progress) = progress {
+            progress.running();
+        }
+        let _running = CountGuard::new(&self.running);
+        tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            f()
+        })
+        .await
+        .map_err(|err| format!("Simulation failed: {}", err))
+    }
+
+    pub fn info(&self) -> Value {
+        json!({
+            "size": self.size,
+            "max_queue": self.max_queue,
+            "running": self.running(),
+            "queued": self.queued(),
+            "memory": self.memory.info(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn empty_queue_runs_on_a_free_thread() {
+        let pool = ComputePool::new(1, 0, MemoryBudget::new(0));
+        assert_eq!(pool.run(|| 1).await, Ok(1));
+        assert_eq!(pool.queued(), 0);
+    }
+
+    #[tokio::test]
+    async fn full_queue_rejects_the_job() {
+        let pool = Arc::new(ComputePool::new(1, 0, MemoryBudget::new(0)));
+        let (started_tx, started_rx) = std::sync::mpsc::channel();
+        let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+        let running = tokio::spawn({
+            let pool = pool.clone();
+            async move {
+                pool.run(move || {
+                    started_tx.send(()).unwrap();
+                    release_rx.recv().unwrap();
+                })
+                .await
+            }
+        });
+        tokio::task::spawn_blocking(move || started_rx.recv().unwrap())
+            .await
+            .unwrap();
+
+        let err = pool.run(|| ()).await.unwrap_err();
+        assert!(err.starts_with(POOL_BUSY));
+        assert_eq!(error_status(&err), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(pool.queued(), 0);
+
+        release_tx.send(()).unwrap();
+        assert_eq!(running.await.unwrap(), Ok(()));
+    }
+}
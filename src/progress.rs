@@ -0,0 +1,256 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use serde_json::{json, Value};
+use tokio::sync::broadcast;
+
+use crate::jobs::now_secs;
+
+/// seconds a finished job stays streamable
+const FINISHED_TTL: u64 = 60;
+/// seconds a stream subscribed before its job started waits for the job
+const PENDING_TTL: u64 = 600;
+/// the most streams waiting for their job to start
+const MAX_PENDING: usize = 1024;
+
+#[derive(Debug)]
+struct JobChannel {
+    created: u64,
+    /// whether a job of the id was submitted, otherwise only streams wait
+    /// for it
+    started: bool,
+    finished: Option<u64>,
+    history: Vec<Value>,
+    tx: broadcast::Sender<Value>,
+}
+
+impl JobChannel {
+    fn new() -> Self {
+        JobChannel {
+            created: now_secs(),
+            started: false,
+            finished: None,
+            history: Vec::new(),
+            tx: broadcast::channel(64).0,
+        }
+    }
+}
+
+/// Progress events of the jobs, for `/stream`. Each job has a broadcast
+/// channel and the history of its events, so a client which subscribes late,
+/// or before the job is submitted, still receives all the events. Only the
+/// latest partial result is kept in the history. A job id is claimed by
+/// `start` while its job is pending or running.
+#[derive(Debug, Default)]
+pub struct ProgressHub {
+    channels: Mutex<HashMap<String, JobChannel>>,
+}
+
+impl ProgressHub {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Claim the id of a new job, it is an error while another job of the id
+    /// is pending or running. The events of a finished job of the id are
+    /// dropped, the streams opened before the job keep waiting for it.
+    pub fn start(&self, job_id: &str) -> Result<(), String> {
+        let mut channels = self.channels.lock().unwrap();
+        let channel = channels
+            .entry(job_id.to_string())
+            .or_insert_with(JobChannel::new);
+        if channel.finished.is_some() {
+            *channel = JobChannel::new();
+        } else if channel.started {
+            return Err(format!("Job {} is already pending or running", job_id));
+        }
+        channel.started = true;
+        Ok(())
+    }
+
+    pub fn publish(&self, job_id: &str, event: Value) {
+        let mut channels = self.channels.lock().unwrap();
+        let channel = channels
+            .entry(job_id.to_string())
+            .or_insert_with(JobChannel::new);
+        channel.started = true;
+
+        let is_final = is_final(&event);
+        if event["event"] == "partial" {
+            channel.history.retain(|event| event["event"] != "partial");
+        }
+        channel.history.push(event.clone());
+        if is_final {
+            channel.finished = Some(now_secs());
+        }
+        // no receiver is not an error
+        let _ = channel.tx.send(event);
+    }
+
+    /// The past events, the receiver of the later ones, and whether the job
+    /// is already finished. At most `MAX_PENDING` streams wait for jobs which
+    /// did not start.
+    pub fn subscribe(
+        &self,
+        job_id: &str,
+    ) -> Result<(Vec<Value>, broadcast::Receiver<Value>, bool), String> {
+        self.prune();
+        let mut channels = self.channels.lock().unwrap();
+        if !channels.contains_key(job_id)
+            && channels.values().filter(|channel| !channel.started).count() >= MAX_PENDING
+        {
+            return Err(format!(
+                "{} streams are waiting for their jobs, submit the job first",
+                MAX_PENDING
+            ));
+        }
+        let channel = channels
+            .entry(job_id.to_string())
+            .or_insert_with(JobChannel::new);
+        Ok((
+            channel.history.clone(),
+            channel.tx.subscribe(),
+            channel.finished.is_some(),
+        ))
+    }
+
+    /// drop the finished jobs, and the streams whose job never started
+    pub fn prune(&self) {
+        let now = now_secs();
+        self.channels
+            .lock()
+            .unwrap()
+            .retain(|_, channel| match channel.finished {
+                Some(finished) => now.saturating_sub(finished) < FINISHED_TTL,
+                None => channel.started || now.saturating_sub(channel.created) < PENDING_TTL,
+            });
+    }
+}
+
+/// whether it is the last event of a job
+pub fn is_final(event: &Value) -> bool {
+    event["event"] == "done" || event["event"] == "failed"
+}
+
+/// The events of one job. `every` is the number of shots between two partial
+/// results, the shots are split into chunks of at most this size.
+#[derive(Debug, Clone)]
+pub struct Progress {
+    pub hub: Arc<ProgressHub>,
+    pub job_id: String,
+    pub every: Option<usize>,
+    running: Arc<AtomicBool>,
+}
+
+impl Progress {
+    pub fn new(hub: Arc<ProgressHub>, job_id: String, every: Option<usize>) -> Self {
+        Progress {
+            hub,
+            job_id,
+            every,
+            running: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn state(&self, state: &str) {
+        self.hub
+            .publish(&self.job_id, json!({"event": "state", "state": state}));
+    }
+
+    /// the job got a thread of the compute pool, only its first thread is
+    /// published
+    pub fn running(&self) {
+        if !self.running.swap(true, Ordering::SeqCst) {
+            self.state("running");
+        }
+    }
+
+    /// the counts of the first `shots` shots
+    pub fn partial(&self, shots: usize, counts: &HashMap<String, usize>) {
+        self.hub.publish(
+            &self.job_id,
+            json!({"event": "partial", "shots": shots, "counts": counts}),
+        );
+    }
+
+    /// an iteration of VQE, its variables and the expectations
+    pub fn iteration(&self, iteration: usize, vars: &HashMap<String, f64>, expectation: &[f64]) {
+        self.hub.publish(
+            &self.job_id,
+            json!({
+                "event": "iteration",
+                "iteration": iteration,
+                "vars": vars,
+                "expectation": expectation,
+            }),
+        );
+    }
+
+    pub fn done(&self, result: &Value) {
+        self.hub
+            .publish(&self.job_id, json!({"event": "done", "result": result}));
+    }
+
+    pub fn failed(&self, error: &Value) {
+        self.hub
+            .publish(&self.job_id, json!({"event": "failed", "error": error}));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn job_id_is_claimed_until_the_job_finishes() {
+        let hub = ProgressHub::new();
+        hub.start("a").unwrap();
+        assert!(hub.start("a").is_err());
+        hub.publish("a", json!({"event": "state", "state": "queued"}));
+        assert!(hub.start("a").is_err());
+        hub.publish("a", json!({"event": "failed", "error": "no"}));
+
+        // a new job of the id does not replay the events of the old one
+        hub.start("a").unwrap();
+        let (history, _, finished) = hub.subscribe("a").unwrap();
+        assert!(history.is_empty());
+        assert!(!finished);
+    }
+
+    #[test]
+    fn stream_opened_before_the_job_receives_it() {
+        let hub = ProgressHub::new();
+        let (_, mut rx, _) = hub.subscribe("b").unwrap();
+        hub.start("b").unwrap();
+        hub.publish("b", json!({"event": "state", "state": "queued"}));
+        assert_eq!(rx.try_recv().unwrap()["state"], "queued");
+    }
+
+    #[test]
+    fn pending_streams_are_capped() {
+        let hub = ProgressHub::new();
+        for i in 0..MAX_PENDING {
+            hub.subscribe(&i.to_string()).unwrap();
+        }
+        assert!(hub.subscribe("late").is_err());
+        // an existing stream, or one of a started job, is not pending
+        hub.subscribe("0").unwrap();
+        hub.start("1").unwrap();
+        hub.subscribe("late").unwrap();
+    }
+
+    #[test]
+    fn running_is_published_once() {
+        let hub = Arc::new(ProgressHub::new());
+        let progress = Progress::new(hub.clone(), "c".to_string(), None);
+        progress.running();
+        progress.clone().running();
+        let (history, _, _) = hub.subscribe("c").unwrap();
+        assert_eq!(history.len(), 1);
+    }
+}
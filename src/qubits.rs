@@ -1,7 +1,110 @@
-use std::io::{Read, Write};
+use std::{
+    collections::BTreeMap,
+    io::{Read, Write},
+};
 
 use serde::{Deserialize, Serialize};
 
+use crate::{circuit::routing::CouplingMap, simulator::noise::NoiseModel};
+
+/// A classical register declared in the QASM, e.g. `creg c[8];`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct CReg {
+    pub name: String,
+    pub size: usize,
+}
+
+/// Parse the `creg` (or OpenQASM 3 `bit`) declarations of a QASM program in
+/// declaration order
+pub fn parse_cregs(qasm: &str) -> Vec<CReg> {
+    parse_registers(qasm, "creg", "bit")
+}
+
+/// Parse the `qreg` (or OpenQASM 3 `qubit`) declarations, for the programs
+/// which are not parsed into a circuit
+pub fn parse_qregs(qasm: &str) -> Vec<CReg> {
+    parse_registers(qasm, "qreg", "qubit")
+}
+
+fn parse_registers(qasm: &str, keyword: &str, keyword3: &str) -> Vec<CReg> {
+    qasm.split(';')
+        .filter_map(|stmt| {
+            // skip the comments before the statement
+            let stmt = stmt
+                .lines()
+                .map(|line| line.split("//").next().unwrap_or(""))
+                .collect::<Vec<_>>()
+                .join(" ");
+            let stmt = stmt.trim();
+            if let Some(decl) = stmt.strip_prefix(keyword) {
+                let (name, size) = decl.trim().split_once('[')?;
+                Some(CReg {
+                    name: name.trim().to_string(),
+                    size: size.trim().strip_suffix(']')?.trim().parse().ok()?,
+                })
+            } else {
+                // OpenQASM 3 `bit[2] c` or `bit c`
+                let decl = stmt.strip_prefix(keyword3)?;
+                match decl.trim().strip_prefix('[') {
+                    Some(decl) => {
+                        let (size, name) = decl.split_once(']')?;
+                        Some(CReg {
+                            name: name.trim().to_string(),
+                            size: size.trim().parse().ok()?,
+                        })
+                    }
+                    None if decl.starts_with(char::is_whitespace) => Some(CReg {
+                        name: decl.trim().to_string(),
+                        size: 1,
+                    }),
+                    None => None,
+                }
+            }
+        })
+        .collect()
+}
+
+/// Split a measured bitstring into its registers. The bitstring is the
+/// registers in declaration order, either concatenated or separated by
+/// spaces. Inside a register the last bit is `reg[0]`.
+pub fn split_registers(string: &str, cregs: &[CReg]) -> BTreeMap<String, Vec<u8>> {
+    let total: usize = cregs.iter().map(|creg| creg.size).sum();
+    let mut bits: Vec<u8> = string
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .map(|c| u8::from(c == '1'))
+        .collect();
+    // pad or cut the high bits so that the bitstring matches the registers
+    if bits.len() < total {
+        bits.splice(0..0, vec![0; total - bits.len()]);
+    } else {
+        bits.drain(..bits.len() - total);
+    }
+
+    let mut registers = BTreeMap::new();
+    let mut offset = 0;
+    for creg in cregs {
+        registers.insert(creg.name.clone(), bits[offset..offset + creg.size].to_vec());
+        offset += creg.size;
+    }
+    registers
+}
+
+/// What a resize of `QMemory` keeps and loses
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ResizeReport {
+    pub qubits: usize,
+    pub capacity: usize,
+    /// rows which are kept after the resize
+    pub kept_rows: usize,
+    /// the oldest rows which do not fit in the new capacity
+    pub dropped_rows: usize,
+    /// generations of the dropped rows
+    pub dropped_generations: Vec<u64>,
+    /// rows which lose at least one measured `1` because of the new width
+    pub truncated_rows: usize,
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct QMemory {
     pub mem: Vec<Vec<u8>>,
@@ -9,6 +112,15 @@ pub struct QMemory {
     pub qubits: usize,
     pub capacity: usize,
     pub current_pos: usize,
+    // generation of each row, a row gets the next generation every time it
+    // is written, 0 means the row is never written
+    #[serde(default)]
+    pub generations: Vec<u64>,
+    #[serde(default)]
+    pub last_generation: u64,
+    // the same rows split by the classical registers of the job
+    #[serde(default)]
+    pub registers: Vec<BTreeMap<String, Vec<u8>>>,
 }
 
 impl Default for QMemory {
@@ -18,6 +130,9 @@ impl Default for QMemory {
             qubits: 20,
             capacity: 20,
             current_pos: 0,
+            generations: vec![0; 20],
+            last_generation: 0,
+            registers: vec![BTreeMap::new(); 20],
         }
     }
 }
@@ -29,12 +144,38 @@ impl QMemory {
             qubits,
             capacity,
             current_pos: 0,
+            generations: vec![0; capacity],
+            last_generation: 0,
+            registers: vec![BTreeMap::new(); capacity],
         }
     }
 
     pub fn read_file(path: &str) -> Self {
         let reader: Box<dyn Read> = Box::new(std::fs::File::open(path).unwrap());
-        serde_pickle::from_reader(reader, Default::default()).unwrap()
+        let qmem: QMemory = serde_pickle::from_reader(reader, Default::default()).unwrap();
+        qmem.upgrade()
+    }
+
+    /// read a pickle dumped by `dump_file`
+    pub fn from_pickle(data: &[u8]) -> Result<Self, String> {
+        serde_pickle::from_slice::<QMemory>(data, Default::default())
+            .map(QMemory::upgrade)
+            .map_err(|err| err.to_string())
+    }
+
+    /// fill the fields missing in the files dumped by older versions
+    fn upgrade(mut self) -> Self {
+        // generations were not tracked, treat all rows as written, the oldest
+        // one is at current_pos
+        if self.generations.len() != self.capacity {
+            self.generations = vec![0; self.capacity];
+            for i in 0..self.capacity {
+                self.last_generation += 1;
+                self.generations[(self.current_pos + i) % self.capacity] = self.last_generation;
+            }
+        }
+        self.registers.resize(self.capacity, BTreeMap::new());
+        self
     }
 
     pub fn dump_file(&self, path: &str) {
@@ -48,39 +189,136 @@ impl QMemory {
         .unwrap();
     }
 
-    /// update the capacity of the result, will make the result to be empty
-    pub fn update_capacity(&mut self, capacity: usize) {
-        self.capacity = capacity;
-        self.current_pos = 0;
-        self.mem = vec![vec![0; self.qubits]; capacity];
+    /// update the capacity of the result, the most recent rows are kept
+    pub fn update_capacity(&mut self, capacity: usize) -> ResizeReport {
+        self.resize(self.qubits, capacity)
+    }
+
+    /// update the qubits of the result, each row is padded or truncated on
+    /// the high bits
+    pub fn update_qubits(&mut self, qubits: usize) -> ResizeReport {
+        self.resize(qubits, self.capacity)
+    }
+
+    /// positions of the written rows, from the oldest to the most recent
+    fn written_positions(&self) -> Vec<usize> {
+        let mut positions: Vec<usize> = (0..self.capacity)
+            .filter(|&pos| self.generations[pos] != 0)
+            .collect();
+        positions.sort_by_key(|&pos| self.generations[pos]);
+        positions
     }
 
-    /// update the qubits of the result, will make the result to be empty
-    pub fn update_qubits(&mut self, qubits: usize) {
+    /// report what `resize` would keep and lose without changing anything
+    pub fn plan_resize(&self, qubits: usize, capacity: usize) -> ResizeReport {
+        let positions = self.written_positions();
+        let dropped = positions.len().saturating_sub(capacity);
+
+        ResizeReport {
+            qubits,
+            capacity,
+            kept_rows: positions.len() - dropped,
+            dropped_rows: dropped,
+            dropped_generations: positions[..dropped]
+                .iter()
+                .map(|&pos| self.generations[pos])
+                .collect(),
+            truncated_rows: positions[dropped..]
+                .iter()
+                .filter(|&&pos| self.mem[pos][..self.qubits.saturating_sub(qubits)].contains(&1))
+                .count(),
+        }
+    }
+
+    /// Resize the storage without losing the data which still fits. When the
+    /// capacity stays, or grows while the ring has free rows, the rows keep
+    /// their positions, so the `init_position`s handed out before stay valid.
+    /// When a full ring grows, or the capacity shrinks, the kept rows are
+    /// moved to the front in the order they were written, so the next rows
+    /// are written after the newest one. The rows keep their generations.
+    pub fn resize(&mut self, qubits: usize, capacity: usize) -> ResizeReport {
+        let report = self.plan_resize(qubits, capacity);
+        let full = self.generations[self.current_pos] != 0;
+        let positions = if capacity == self.capacity || (capacity > self.capacity && !full) {
+            (0..self.capacity).collect()
+        } else {
+            self.written_positions()[report.dropped_rows..].to_vec()
+        };
+
+        let mut mem = vec![vec![0; qubits]; capacity];
+        let mut generations = vec![0; capacity];
+        let mut registers = vec![BTreeMap::new(); capacity];
+        for (new_pos, &pos) in positions.iter().enumerate() {
+            // rows are right aligned, the last bit is c[0]
+            let row = &self.mem[pos];
+            let width = row.len().min(qubits);
+            mem[new_pos][qubits - width..].copy_from_slice(&row[row.len() - width..]);
+            generations[new_pos] = self.generations[pos];
+            registers[new_pos] = std::mem::take(&mut self.registers[pos]);
+        }
+
+        // a ring with free rows keeps writing where it was
+        if capacity < self.capacity || (capacity > self.capacity && full) {
+            self.current_pos = report.kept_rows % capacity;
+        }
+        self.mem = mem;
+        self.generations = generations;
+        self.registers = registers;
         self.qubits = qubits;
+        self.capacity = capacity;
+        report
+    }
+
+    /// drop all the results, generations keep increasing so the cleared rows
+    /// can still be detected by the clients
+    pub fn clear(&mut self) {
+        self.mem = vec![vec![0; self.qubits]; self.capacity];
+        self.generations = vec![0; self.capacity];
+        self.registers = vec![BTreeMap::new(); self.capacity];
         self.current_pos = 0;
-        self.mem = vec![vec![0; qubits]; self.capacity];
     }
 
-    pub fn update_results(&mut self, string: &String) {
+    /// the generation the next written row will get
+    pub fn next_generation(&self) -> u64 {
+        self.last_generation + 1
+    }
+
+    /// Write one measured bitstring. The flat row keeps the low bits which fit
+    /// in `qubits`, the registers keep all of them.
+    pub fn update_results(&mut self, string: &str, cregs: &[CReg]) {
         let mut mz_res: Vec<u8> = vec![0; self.qubits];
-        for (i, c) in string.chars().rev().enumerate() {
-            if c == '1' {
-                mz_res[self.qubits - i - 1] = 1;
-            } else {
-                mz_res[self.qubits - i - 1] = 0;
-            }
+        for (i, c) in string
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .rev()
+            .take(self.qubits)
+            .enumerate()
+        {
+            mz_res[self.qubits - i - 1] = u8::from(c == '1');
         }
         self.mem[self.current_pos] = mz_res;
+        self.registers[self.current_pos] = split_registers(string, cregs);
+        self.last_generation += 1;
+        self.generations[self.current_pos] = self.last_generation;
         self.current_pos += 1;
         self.current_pos %= self.capacity;
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct QResgister {
     pub qubits: Vec<bool>,
     pub idle: usize,
+    /// the native gates of the device, the circuits are transpiled into them
+    #[serde(default)]
+    pub basis_gates: Option<Vec<String>>,
+    /// the pairs of physical qubits which can run two-qubit gates, the
+    /// circuits are routed on them
+    #[serde(default)]
+    pub coupling_map: Option<CouplingMap>,
+    /// the noise of the device, simulated by the density matrix backend
+    #[serde(default)]
+    pub noise: Option<NoiseModel>,
 }
 
 impl Default for QResgister {
@@ -88,6 +326,9 @@ impl Default for QResgister {
         QResgister {
             qubits: vec![false; 20],
             idle: 20,
+            basis_gates: None,
+            coupling_map: None,
+            noise: None,
         }
     }
 }
@@ -97,6 +338,9 @@ impl QResgister {
         QResgister {
             qubits: vec![false; num_qubits],
             idle: num_qubits,
+            basis_gates: None,
+            coupling_map: None,
+            noise: None,
         }
     }
 
@@ -108,4 +352,88 @@ impl QResgister {
     pub fn update_idle(&mut self, idle: usize) {
         self.idle = idle;
     }
+
+    /// the qubits allocated by the running jobs
+    pub fn busy(&self) -> usize {
+        self.qubits.len().saturating_sub(self.idle)
+    }
+
+    /// give back the qubits of a finished job, the register may have been
+    /// resized or restored while it ran
+    pub fn release(&mut self, qubits: usize) {
+        self.idle = (self.idle + qubits).min(self.qubits.len());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn written(qmem: &QMemory) -> Vec<(usize, u64)> {
+        (0..qmem.capacity)
+            .filter(|&pos| qmem.generations[pos] != 0)
+            .map(|pos| (pos, qmem.generations[pos]))
+            .collect()
+    }
+
+    #[test]
+    fn growing_keeps_the_positions() {
+        let mut qmem = QMemory::new(2, 4);
+        for string in ["01", "10"] {
+            qmem.update_results(string, &[]);
+        }
+        let before = written(&qmem);
+        let report = qmem.resize(3, 5);
+        assert_eq!(report.dropped_rows, 0);
+        assert_eq!(written(&qmem), before);
+        assert_eq!(qmem.mem[0], vec![0, 0, 1]);
+        assert_eq!(qmem.current_pos, 2);
+    }
+
+    #[test]
+    fn growing_a_full_ring_writes_the_new_rows_first() {
+        let mut qmem = QMemory::new(2, 2);
+        for string in ["01", "10", "11"] {
+            qmem.update_results(string, &[]);
+        }
+        // the ring wrapped, the oldest row is at position 1
+        assert_eq!(written(&qmem), vec![(0, 3), (1, 2)]);
+        let report = qmem.resize(2, 4);
+        assert_eq!(report.dropped_rows, 0);
+        assert_eq!(written(&qmem), vec![(0, 2), (1, 3)]);
+        assert_eq!(qmem.mem[..2], [vec![1, 0], vec![1, 1]]);
+        assert_eq!(qmem.current_pos, 2);
+
+        for string in ["01", "10", "11"] {
+            qmem.update_results(string, &[]);
+        }
+        // the new rows are filled before the oldest row is overwritten
+        assert_eq!(qmem.generations, vec![6, 3, 4, 5]);
+    }
+
+    #[test]
+    fn shrinking_keeps_the_newest_rows() {
+        let mut qmem = QMemory::new(2, 4);
+        for string in ["01", "10", "11"] {
+            qmem.update_results(string, &[]);
+        }
+        let report = qmem.resize(2, 2);
+        assert_eq!(report.dropped_generations, vec![1]);
+        assert_eq!(written(&qmem), vec![(0, 2), (1, 3)]);
+        assert_eq!(qmem.mem, vec![vec![1, 0], vec![1, 1]]);
+        assert_eq!(qmem.current_pos, 0);
+    }
+
+    #[test]
+    fn release_stays_within_the_register() {
+        let mut qreg = QResgister::new(4);
+        qreg.update_idle(1);
+        assert_eq!(qreg.busy(), 3);
+        // the register was restored with fewer qubits while the job ran
+        qreg.update_qubits(2);
+        qreg.update_idle(0);
+        qreg.release(3);
+        assert_eq!(qreg.idle, 2);
+        assert_eq!(qreg.busy(), 0);
+    }
 }
@@ -0,0 +1,449 @@
+use std::{fmt, future::Future, pin::Pin, sync::Arc};
+
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::{
+    circuit::{gates::GATES, Circuit, Instruction, Op},
+    emulate::{EmulateInfo, EmulateMessage, EmulateMode},
+    pool::{ComputePool, MemoryBudget},
+    progress::Progress,
+};
+
+use super::{density, external, mps, stabilizer, statevector};
+
+/// the backend of the tasks which do not name one and whose circuit no other
+/// backend prefers
+pub const DEFAULT_BACKEND: &str = "statevector";
+
+/// What a backend simulates, reported by `/backends`
+#[derive(Debug, Clone, Serialize)]
+pub struct Capabilities {
+    pub name: &'static str,
+    pub description: &'static str,
+    /// the gates it runs
+    pub gates: Vec<&'static str>,
+    /// whether it simulates the noise of the device
+    pub noise: bool,
+    /// whether gates, resets and conditions may follow a measurement
+    pub mid_circuit_measurements: bool,
+    /// whether it reports the exact probabilities and the expectation values
+    /// of `observables`
+    pub exact: bool,
+    /// whether the shots follow the seed of the task
+    pub seeded: bool,
+    /// whether it computes the expectation of the `vqe` mode
+    pub vqe: bool,
+    /// the fields of the task which configure it
+    pub settings: Vec<&'static str>,
+}
+
+/// A compiled circuit to simulate, with the task it comes from
+pub struct Job {
+    pub pool: Arc<ComputePool>,
+    pub circuit: Arc<Circuit>,
+    /// the task, with the variables of the circuit and the settings of the
+    /// backend
+    pub info: Arc<EmulateInfo>,
+    pub shots: usize,
+    pub seed: u64,
+    /// chunks of shots simulated at once by the per shot simulation
+    pub parallel: usize,
+    pub progress: Progress,
+}
+
+/// The shots of a job, what the backend reports in the metadata, and its
+/// exact results reported next to `Result`
+#[derive(Debug, Default)]
+pub struct Output {
+    pub sequences: Vec<String>,
+    pub metadata: serde_json::Map<String, Value>,
+    pub exact: serde_json::Map<String, Value>,
+}
+
+impl Output {
+    pub fn new(sequences: Vec<String>) -> Self {
+        Output {
+            sequences,
+            ..Default::default()
+        }
+    }
+}
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// A simulator engine. The circuit of a task is compiled for the device,
+/// then `prepare`d for the backend, its memory is reserved and it is `run`
+/// on the compute pool.
+pub trait Backend: fmt::Debug + Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn capabilities(&self) -> Capabilities;
+
+    /// the widest circuit the backend runs in the memory budget
+    fn max_qubits(&self, memory: &MemoryBudget) -> usize;
+
+    /// whether the backend runs the circuit when the task does not name a
+    /// backend
+    fn preferred(&self, _circuit: &Circuit, _info: &EmulateInfo) -> bool {
+        false
+    }
+
+    /// Check that the backend runs the circuit with the settings of the
+    /// task, and return the circuit it simulates
+    fn prepare(&self, circuit: Arc<Circuit>, info: &EmulateInfo) -> Result<Arc<Circuit>, String>;
+
+    /// bytes of one simulated state of the circuit
+    fn state_bytes(&self, circuit: &Circuit, info: &EmulateInfo) -> u64;
+
+    /// Bytes of memory the simulation of the circuit needs. Sampling keeps
+    /// the state and its probabilities, the per shot simulation keeps the
+    /// initial state and a copy of it for each of the `parallel` chunks.
+    fn memory_bytes(&self, circuit: &Circuit, info: &EmulateInfo, parallel: usize) -> u64 {
+        let state_bytes = self.state_bytes(circuit, info);
+        if circuit.measurements_are_terminal() {
+            state_bytes.saturating_add(state_bytes / 2)
+        } else {
+            state_bytes.saturating_mul(2 * parallel as u64)
+        }
+    }
+
+    /// `sampling` when the shots are sampled from the final state, otherwise
+    /// `per_shot`
+    fn simulation_path(&self, circuit: &Circuit) -> &'static str {
+        if circuit.measurements_are_terminal() {
+            "sampling"
+        } else {
+            "per_shot"
+        }
+    }
+
+    /// the work of updating the whole state and of sampling a shot from
+    /// it, in amplitudes, for the runtime estimate of `/analyze`
+    fn state_cost(&self, circuit: &Circuit, info: &EmulateInfo) -> (f64, f64);
+
+    /// the work of an instruction, every gate, measurement and reset updates
+    /// the whole state of work `size`
+    fn instruction_cost(
+        &self,
+        inst: &Instruction,
+        size: f64,
+        _circuit: &Circuit,
+        _info: &EmulateInfo,
+    ) -> f64 {
+        match inst.op {
+            Op::Barrier { .. } => 0.0,
+            _ => size,
+        }
+    }
+
+    fn run(&self, job: Job) -> BoxFuture<'_, Result<Output, String>>;
+
+    /// Run an OpenQASM 2.0 program as it is, e.g. one the agent cannot
+    /// parse, and return the measured bits of each shot. Only the backends
+    /// with their own parser run it.
+    fn run_program(
+        &self,
+        _pool: Arc<ComputePool>,
+        _qasm: String,
+        _shots: Option<usize>,
+        _progress: Progress,
+    ) -> BoxFuture<'_, Result<Vec<String>, String>> {
+        Box::pin(async move {
+            Err(format!(
+                "The {} backend only runs the programs the agent parses",
+                self.name()
+            ))
+        })
+    }
+
+    /// Compute the expectation of an OpenQASM 2.0 program exactly, for an
+    /// iteration of the `vqe` mode
+    fn run_vqe(
+        &self,
+        _pool: Arc<ComputePool>,
+        _qasm: String,
+        _shots: Option<usize>,
+        _progress: Progress,
+    ) -> BoxFuture<'_, Result<qasmsim::Execution, String>> {
+        Box::pin(async move { Err(format!("The {} backend does not run vqe", self.name())) })
+    }
+}
+
+/// the largest number of qubits up to `limit` whose simulation needs at most
+/// `budget` bytes, the memory grows with the qubits
+pub fn widest(limit: usize, budget: u64, bytes: impl Fn(usize) -> u64) -> usize {
+    (1..=limit)
+        .take_while(|&n| bytes(n) <= budget)
+        .last()
+        .unwrap_or(0)
+}
+
+/// the names of all the gates of `qelib1.inc`
+pub fn all_gates() -> Vec<&'static str> {
+    GATES.iter().map(|&(name, _, _)| name).collect()
+}
+
+/// The backends the tasks are routed to by name
+#[derive(Debug)]
+pub struct BackendRegistry {
+    backends: Vec<Arc<dyn Backend>>,
+}
+
+impl Default for BackendRegistry {
+    /// the backends of the agent
+    fn default() -> Self {
+        let mut registry = BackendRegistry::new();
+        registry.register(Arc::new(statevector::StatevectorBackend));
+        registry.register(Arc::new(stabilizer::StabilizerBackend));
+        registry.register(Arc::new(mps::MpsBackend));
+        registry.register(Arc::new(density::DensityMatrixBackend));
+        registry.register(Arc::new(external::QasmsimBackend));
+        registry
+    }
+}
+
+impl BackendRegistry {
+    pub fn new() -> Self {
+        BackendRegistry {
+            backends: Vec::new(),
+        }
+    }
+
+    /// add a backend, it replaces the backend of the same name
+    pub fn register(&mut self, backend: Arc<dyn Backend>) {
+        match self
+            .backends
+            .iter()
+            .position(|other| other.name() == backend.name())
+        {
+            Some(i) => self.backends[i] = backend,
+            None => self.backends.push(backend),
+        }
+    }
+
+    pub fn names(&self) -> Vec<&'static str> {
+        self.backends.iter().map(|backend| backend.name()).collect()
+    }
+
+    pub fn get(&self, name: &str) -> Result<Arc<dyn Backend>, String> {
+        self.backends
+            .iter()
+            .find(|backend| backend.name() == name)
+            .cloned()
+            .ok_or_else(|| {
+                format!(
+                    "Unknown backend {}, the backends are {}",
+                    name,
+                    self.names().join(", ")
+                )
+            })
+    }
+
+    /// Check that the backend named by a task exists and runs its mode, so
+    /// that the task fails before it is started
+    pub fn check(&self, message: &EmulateMessage) -> Result<(), String> {
+        let Some(name) = &message.backend else {
+            return Ok(());
+        };
+        let backend = self.get(name)?;
+        if matches!(message.mode, Some(EmulateMode::Vqe)) && !backend.capabilities().vqe {
+            let vqe: Vec<&str> = self
+                .backends
+                .iter()
+                .filter(|backend| backend.capabilities().vqe)
+                .map(|backend| backend.name())
+                .collect();
+            return Err(format!(
+                "vqe is not run by the {} backend, only by {}",
+                name,
+                vqe.join(", ")
+            ));
+        }
+        Ok(())
+    }
+
+    /// The backend named by the task, or the first one which prefers the
+    /// circuit, e.g. the stabilizer backend for Clifford circuits, or the
+    /// default backend. `observables` need a backend with exact results.
+    pub fn select(
+        &self,
+        info: &EmulateInfo,
+        circuit: &Circuit,
+    ) -> Result<Arc<dyn Backend>, String> {
+        let backend = match &info.backend {
+            Some(name) => self.get(name)?,
+            None => match self
+                .backends
+                .iter()
+                .find(|backend| backend.preferred(circuit, info))
+            {
+                Some(backend) => backend.clone(),
+                None => self.get(DEFAULT_BACKEND)?,
+            },
+        };
+        if info.observables.is_some() && !backend.capabilities().exact {
+            let exact: Vec<&str> = self
+                .backends
+                .iter()
+                .filter(|backend| backend.capabilities().exact)
+                .map(|backend| backend.name())
+                .collect();
+            return Err(format!(
+                "observables are not computed by the {} backend, only by {}",
+                backend.name(),
+                exact.join(", ")
+            ));
+        }
+        Ok(backend)
+    }
+
+    /// the widest circuit of any backend, and its backend
+    pub fn max_qubits(&self, memory: &MemoryBudget) -> (usize, &'static str) {
+        self.backends
+            .iter()
+            .map(|backend| (backend.max_qubits(memory), backend.name()))
+            .max_by_key(|&(qubits, _)| qubits)
+            .unwrap_or((0, DEFAULT_BACKEND))
+    }
+
+    /// the capabilities of each backend, and the widest circuit it runs in
+    /// the memory budget
+    pub fn info(&self, memory: &MemoryBudget) -> Value {
+        json!(self
+            .backends
+            .iter()
+            .map(|backend| {
+                let mut capabilities = json!(backend.capabilities());
+                capabilities["max_qubits"] = json!(backend.max_qubits(memory));
+                capabilities["default"] = json!(backend.name() == DEFAULT_BACKEND);
+                capabilities
+            })
+            .collect::<Vec<Value>>())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{circuit::qasm2, emulate::pre_process_msg};
+
+    fn message(task: Value) -> EmulateMessage {
+        let mut task = task;
+        task["qasm"] = json!("");
+        task["qubits"] = json!(1);
+        task["shots"] = json!(1);
+        serde_json::from_value(task).unwrap()
+    }
+
+    fn info(task: Value) -> EmulateInfo {
+        pre_process_msg(message(task))
+    }
+
+    fn circuit(body: &str) -> Circuit {
+        qasm2::parse(&format!("OPENQASM 2.0;\ninclude \"qelib1.inc\";\n{}", body)).unwrap()
+    }
+
+    #[test]
+    fn backends_are_found_by_name() {
+        let registry = BackendRegistry::default();
+        assert_eq!(
+            registry.names(),
+            [
+                "statevector",
+                "stabilizer",
+                "mps",
+                "density_matrix",
+                "qasmsim"
+            ]
+        );
+        assert_eq!(registry.get("mps").unwrap().name(), "mps");
+        let err = registry.get("gpu").unwrap_err();
+        assert!(err.starts_with("Unknown backend gpu, the backends are statevector"));
+
+        // a backend of the same name is replaced
+        let mut registry = BackendRegistry::new();
+        registry.register(Arc::new(statevector::StatevectorBackend));
+        registry.register(Arc::new(statevector::StatevectorBackend));
+        assert_eq!(registry.names(), [DEFAULT_BACKEND]);
+    }
+
+    #[test]
+    fn backend_is_selected_for_the_circuit() {
+        let registry = BackendRegistry::default();
+        let clifford = circuit("qreg q[2];\nh q[0];\ncx q[0],q[1];");
+        let rotation = circuit("qreg q[1];\nrx(0.3) q[0];");
+        let select = |task: Value, circuit: &Circuit| {
+            registry
+                .select(&info(task), circuit)
+                .map(|backend| backend.name())
+        };
+        assert_eq!(select(json!({}), &clifford).unwrap(), "stabilizer");
+        assert_eq!(select(json!({}), &rotation).unwrap(), DEFAULT_BACKEND);
+        assert_eq!(select(json!({"backend": "mps"}), &clifford).unwrap(), "mps");
+        assert!(select(json!({"backend": "gpu"}), &clifford).is_err());
+
+        let observables = json!({"observables": ["ZZ"]});
+        let err = select(observables.clone(), &clifford).unwrap_err();
+        assert_eq!(
+            err,
+            "observables are not computed by the stabilizer backend, only by density_matrix"
+        );
+        let mut task = observables;
+        task["backend"] = json!("density_matrix");
+        assert_eq!(select(task, &clifford).unwrap(), "density_matrix");
+    }
+
+    #[test]
+    fn tasks_are_checked_before_they_start() {
+        let registry = BackendRegistry::default();
+        assert!(registry.check(&message(json!({}))).is_ok());
+        assert!(registry.check(&message(json!({"backend": "mps"}))).is_ok());
+        let err = registry
+            .check(&message(json!({"backend": "gpu"})))
+            .unwrap_err();
+        assert!(err.starts_with("Unknown backend gpu"), "{}", err);
+
+        let vqe =
+            |backend: &str| registry.check(&message(json!({"mode": "vqe", "backend": backend})));
+        assert!(vqe("qasmsim").is_ok());
+        assert_eq!(
+            vqe("statevector").unwrap_err(),
+            "vqe is not run by the statevector backend, only by qasmsim"
+        );
+    }
+
+    #[test]
+    fn qasmsim_rejects_a_given_seed() {
+        let registry = BackendRegistry::default();
+        let qasmsim = registry.get(external::QASMSIM).unwrap();
+        let circuit = Arc::new(circuit("qreg q[1];\nh q[0];\n"));
+        let err = qasmsim
+            .prepare(circuit.clone(), &info(json!({"seed": 3})))
+            .unwrap_err();
+        assert!(err.starts_with("seed is not supported"), "{}", err);
+
+        // the seed the agent draws for the task is not an error
+        let mut drawn = message(json!({}));
+        drawn.fix_seed();
+        assert!(qasmsim.prepare(circuit, &pre_process_msg(drawn)).is_ok());
+    }
+
+    #[test]
+    fn info_reports_every_backend() {
+        let registry = BackendRegistry::default();
+        let memory = MemoryBudget::new(1 << 30);
+        let info = registry.info(&memory);
+        let backends = info.as_array().unwrap();
+        assert_eq!(backends.len(), 5);
+        assert!(backends
+            .iter()
+            .all(|backend| backend["default"] == json!(backend["name"] == DEFAULT_BACKEND)));
+        assert_eq!(backends[0]["max_qubits"], json!(memory.max_qubits()));
+        // the stabilizer backend is the widest
+        assert_eq!(
+            registry.max_qubits(&memory),
+            (stabilizer::MAX_QUBITS, "stabilizer")
+        );
+    }
+}
@@ -0,0 +1,574 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+};
+
+use num::complex::Complex64;
+use rand::Rng;
+use serde_json::json;
+
+use crate::{
+    circuit::{
+        transpile::{transpile, Basis},
+        Circuit, Instruction, Op,
+    },
+    emulate::EmulateInfo,
+    pool::{statevector_bytes, MemoryBudget},
+};
+
+use super::{
+    backend::{all_gates, widest, Backend, BoxFuture, Capabilities, Job, Output},
+    mps::gate_matrix,
+    noise::{self, Channel, NoiseModel},
+    statevector::shot_rng,
+};
+
+/// the probabilities below which an outcome is not reported, they are zero
+/// up to rounding
+const ZERO_PROBABILITY: f64 = 1e-14;
+
+/// Density matrix of `num_qubits` qubits, the entry of row `r` and column `c`
+/// is at `r << num_qubits | c`, so qubit `k` is the bit `k + num_qubits` of
+/// the row and the bit `k` of the column
+#[derive(Debug, Clone)]
+pub struct DensityMatrix {
+    pub num_qubits: usize,
+    pub data: Vec<Complex64>,
+}
+
+impl DensityMatrix {
+    /// the state |0...0><0...0|
+    pub fn new(num_qubits: usize) -> Self {
+        let mut data = vec![Complex64::new(0.0, 0.0); 1 << (2 * num_qubits)];
+        data[0] = Complex64::new(1.0, 0.0);
+        DensityMatrix { num_qubits, data }
+    }
+
+    /// multiply by `matrix` on the index bits `bits`, bit `j` of the index of
+    /// the matrix is `bits[j]`
+    fn apply_matrix(&mut self, bits: &[usize], matrix: &[Complex64]) {
+        let dim = 1 << bits.len();
+        let offsets: Vec<usize> = (0..dim)
+            .map(|j| {
+                bits.iter()
+                    .enumerate()
+                    .fold(0, |offset, (t, &bit)| offset | (j >> t & 1) << bit)
+            })
+            .collect();
+        let mask = offsets[dim - 1];
+        let mut values = vec![Complex64::new(0.0, 0.0); dim];
+        for i in 0..self.data.len() {
+            if i & mask != 0 {
+                continue;
+            }
+            for (value, offset) in values.iter_mut().zip(offsets.iter()) {
+                *value = self.data[i + offset];
+            }
+            for (row, offset) in offsets.iter().enumerate() {
+                self.data[i + offset] = values
+                    .iter()
+                    .enumerate()
+                    .map(|(col, value)| matrix[row * dim + col] * value)
+                    .sum();
+            }
+        }
+    }
+
+    /// `rho -> K rho K†` for a matrix `K` on `qubits`
+    pub fn apply_operator(&mut self, qubits: &[usize], matrix: &[Complex64]) {
+        let rows: Vec<usize> = qubits.iter().map(|q| q + self.num_qubits).collect();
+        let conjugate: Vec<Complex64> = matrix.iter().map(|x| x.conj()).collect();
+        self.apply_matrix(&rows, matrix);
+        self.apply_matrix(qubits, &conjugate);
+    }
+
+    pub fn apply_gate(
+        &mut self,
+        name: &str,
+        params: &[f64],
+        qubits: &[usize],
+    ) -> Result<(), String> {
+        let matrix = gate_matrix(name, params, qubits.len())?;
+        self.apply_operator(qubits, &matrix);
+        Ok(())
+    }
+
+    /// `rho -> sum K rho K†`
+    pub fn apply_channel(&mut self, channel: &Channel) {
+        let mut sum = vec![Complex64::new(0.0, 0.0); self.data.len()];
+        for kraus in channel.kraus.iter() {
+            let mut term = self.clone();
+            term.apply_operator(&channel.qubits, kraus);
+            sum.iter_mut()
+                .zip(term.data.iter())
+                .for_each(|(total, x)| *total += x);
+        }
+        self.data = sum;
+    }
+
+    /// the diagonal, the probabilities of the basis states
+    pub fn probabilities(&self) -> Vec<f64> {
+        let dim = 1 << self.num_qubits;
+        (0..dim)
+            .map(|i| self.data[i << self.num_qubits | i].re)
+            .collect()
+    }
+
+    /// `Tr(rho P)`, `P|c> = i^ny (-1)^|c & z| |c ^ x>`
+    pub fn expectation(&self, pauli: &Pauli) -> f64 {
+        let dim = 1 << self.num_qubits;
+        let sum: Complex64 = (0..dim)
+            .map(|c| {
+                let sign = if (c & pauli.z).count_ones().is_multiple_of(2) {
+                    1.0
+                } else {
+                    -1.0
+                };
+                self.data[c << self.num_qubits | (c ^ pauli.x)] * sign
+            })
+            .sum();
+        (sum * Complex64::i().powu(pauli.y as u32)).re
+    }
+}
+
+/// A Pauli operator, the qubits with an X or a Y in `x`, with a Z or a Y in
+/// `z`, and the number of Y
+#[derive(Debug, Clone, Copy)]
+pub struct Pauli {
+    pub x: usize,
+    pub z: usize,
+    pub y: usize,
+}
+
+/// The letter of each qubit of a Pauli string with one letter for each
+/// qubit, in the order of the bitstrings: registers in declaration order and
+/// the last letter of a register is `reg[0]`
+fn pauli_letters(circuit: &Circuit, spec: &str) -> Result<Vec<char>, String> {
+    let letters: Vec<char> = spec.trim().chars().collect();
+    if letters.len() != circuit.num_qubits() {
+        return Err(format!(
+            "Invalid observable {}: it should have one letter for each of the {} qubits",
+            spec,
+            circuit.num_qubits()
+        ));
+    }
+    let mut qubits = vec!['I'; letters.len()];
+    let mut letters = letters.into_iter();
+    for reg in circuit.qregs.iter() {
+        for qubit in (reg.offset..reg.offset + reg.size).rev() {
+            qubits[qubit] = match letters.next().unwrap_or('I') {
+                letter @ ('I' | 'X' | 'Y' | 'Z') => letter,
+                letter => {
+                    return Err(format!(
+                        "Invalid observable {}: {} is not one of I, X, Y, Z",
+                        spec, letter
+                    ))
+                }
+            };
+        }
+    }
+    Ok(qubits)
+}
+
+/// Parse a Pauli string of the qubits of the circuit, see `pauli_letters`
+pub fn parse_pauli(circuit: &Circuit, spec: &str) -> Result<Pauli, String> {
+    let mut pauli = Pauli { x: 0, z: 0, y: 0 };
+    for (qubit, letter) in pauli_letters(circuit, spec)?.into_iter().enumerate() {
+        match letter {
+            'X' => pauli.x |= 1 << qubit,
+            'Z' => pauli.z |= 1 << qubit,
+            'Y' => {
+                pauli.x |= 1 << qubit;
+                pauli.z |= 1 << qubit;
+                pauli.y += 1;
+            }
+            _ => {}
+        }
+    }
+    Ok(pauli)
+}
+
+/// Rewrite a Pauli string of the qubits of `circuit` on the qubits of the
+/// circuit it is compiled to, which has one register of `num_qubits` qubits
+/// and where qubit `k` of `circuit` is `qubits[k]`
+pub fn relabel_pauli(
+    circuit: &Circuit,
+    spec: &str,
+    qubits: &[usize],
+    num_qubits: usize,
+) -> Result<String, String> {
+    let mut relabeled = vec!['I'; num_qubits];
+    for (qubit, letter) in pauli_letters(circuit, spec)?.into_iter().enumerate() {
+        relabeled[num_qubits - 1 - qubits[qubit]] = letter;
+    }
+    Ok(relabeled.into_iter().collect())
+}
+
+/// Expand the gates to one and two qubits, so that the noise is the one of
+/// the native gates, and check that the measurements are at the end: after a
+/// qubit is measured no gate or reset acts on it, and nothing is
+/// conditioned, so the probabilities are read from the final state
+pub fn expand(circuit: &Circuit) -> Result<Circuit, String> {
+    let mut measured = vec![false; circuit.num_qubits()];
+    for inst in circuit.instructions.iter() {
+        if inst.condition.is_some() {
+            return Err("The density_matrix backend does not run conditions".to_string());
+        }
+        match &inst.op {
+            Op::Measure { qubit, .. } => measured[*qubit] = true,
+            Op::Gate { .. } | Op::Reset { .. } => {
+                if let Some(&qubit) = inst.op.qubits().iter().find(|&&qubit| measured[qubit]) {
+                    return Err(format!(
+                        "The density_matrix backend needs the measurements at the end, {} is used after it is measured",
+                        circuit.qubit_name(qubit)
+                    ));
+                }
+            }
+            Op::Barrier { .. } => {}
+        }
+    }
+    transpile(circuit, &Basis::two_qubit_gates())
+}
+
+/// The exact results of the density matrix backend
+#[derive(Debug, Clone, Default)]
+pub struct Exact {
+    /// the probability of each bitstring of the clbits
+    pub probabilities: BTreeMap<String, f64>,
+    /// the expectation value of each observable
+    pub expectation_values: Vec<f64>,
+}
+
+/// Simulate the circuit with the noise of the device, then compute the exact
+/// probabilities of the measured bitstrings, with the readout errors, and
+/// the expectation values of the observables in the state before the
+/// measurements. The shots are sampled from the probabilities.
+pub fn sample_shots(
+    circuit: &Circuit,
+    vars: &HashMap<String, f64>,
+    shots: usize,
+    seed: u64,
+    noise: &NoiseModel,
+    observables: &[Pauli],
+) -> Result<(Vec<String>, Exact), String> {
+    let mut state = DensityMatrix::new(circuit.num_qubits());
+    let mut measures = Vec::new();
+    for inst in circuit.instructions.iter() {
+        match &inst.op {
+            Op::Gate {
+                name,
+                params,
+                qubits,
+            } => {
+                let params = params
+                    .iter()
+                    .map(|param| param.eval(vars))
+                    .collect::<Result<Vec<f64>, String>>()?;
+                state.apply_gate(name, &params, qubits)?;
+                for channel in noise.after_gate(qubits) {
+                    state.apply_channel(&channel);
+                }
+            }
+            Op::Measure { qubit, clbit } => measures.push((*qubit, *clbit)),
+            Op::Reset { qubit } => state.apply_channel(&noise::reset(*qubit)),
+            Op::Barrier { .. } => {}
+        }
+    }
+
+    let mut outcomes: BTreeMap<Vec<u8>, f64> = BTreeMap::new();
+    for (index, p) in state.probabilities().into_iter().enumerate() {
+        let mut clbits = vec![0; circuit.num_clbits()];
+        for &(qubit, clbit) in measures.iter() {
+            clbits[clbit] = (index >> qubit & 1) as u8;
+        }
+        *outcomes.entry(clbits).or_insert(0.0) += p;
+    }
+    if noise.readout_error > 0.0 {
+        let mut clbits: Vec<usize> = measures.iter().map(|&(_, clbit)| clbit).collect();
+        clbits.sort();
+        clbits.dedup();
+        for clbit in clbits {
+            let mut flipped = BTreeMap::new();
+            for (bits, p) in outcomes {
+                let mut other = bits.clone();
+                other[clbit] ^= 1;
+                *flipped.entry(bits).or_insert(0.0) += (1.0 - noise.readout_error) * p;
+                *flipped.entry(other).or_insert(0.0) += noise.readout_error * p;
+            }
+            outcomes = flipped;
+        }
+    }
+    let probabilities: BTreeMap<String, f64> = outcomes
+        .into_iter()
+        .filter(|&(_, p)| p > ZERO_PROBABILITY)
+        .map(|(bits, p)| (circuit.format_clbits(&bits), p))
+        .collect();
+
+    let bitstrings: Vec<&String> = probabilities.keys().collect();
+    let mut cumulative: Vec<f64> = probabilities.values().copied().collect();
+    for i in 1..cumulative.len() {
+        cumulative[i] += cumulative[i - 1];
+    }
+    let total = cumulative.last().copied().unwrap_or(0.0);
+    let sequences = (0..shots)
+        .map(|shot| {
+            let r = shot_rng(seed, shot).gen::<f64>() * total;
+            let index = cumulative
+                .partition_point(|&p| p <= r)
+                .min(cumulative.len().saturating_sub(1));
+            bitstrings
+                .get(index)
+                .map(|s| s.to_string())
+                .unwrap_or_default()
+        })
+        .collect();
+
+    let expectation_values = observables
+        .iter()
+        .map(|pauli| state.expectation(pauli))
+        .collect();
+    Ok((
+        sequences,
+        Exact {
+            probabilities,
+            expectation_values,
+        },
+    ))
+}
+
+/// The density_matrix backend, the only one with the noise of the device and
+/// the exact results
+#[derive(Debug)]
+pub struct DensityMatrixBackend;
+
+impl DensityMatrixBackend {
+    fn observables(circuit: &Circuit, info: &EmulateInfo) -> Result<Vec<Pauli>, String> {
+        info.observables
+            .iter()
+            .flatten()
+            .map(|spec| parse_pauli(circuit, spec))
+            .collect()
+    }
+}
+
+impl Backend for DensityMatrixBackend {
+    fn name(&self) -> &'static str {
+        "density_matrix"
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            name: self.name(),
+            description:
+                "a density matrix with the noise of the device, exact results for small circuits",
+            gates: all_gates(),
+            noise: true,
+            mid_circuit_measurements: false,
+            exact: true,
+            seeded: true,
+            vqe: false,
+            settings: vec!["observables"],
+        }
+    }
+
+    fn max_qubits(&self, memory: &MemoryBudget) -> usize {
+        widest(31, memory.budget, |n| {
+            statevector_bytes(2 * n).saturating_mul(3)
+        })
+    }
+
+    fn prepare(&self, circuit: Arc<Circuit>, info: &EmulateInfo) -> Result<Arc<Circuit>, String> {
+        let circuit = expand(&circuit)?;
+        DensityMatrixBackend::observables(&circuit, info)?;
+        Ok(Arc::new(circuit))
+    }
+
+    /// as big as the statevector of twice the qubits
+    fn state_bytes(&self, circuit: &Circuit, _info: &EmulateInfo) -> u64 {
+        statevector_bytes(2 * circuit.num_qubits())
+    }
+
+    /// a channel sums its terms into a second matrix
+    fn memory_bytes(&self, circuit: &Circuit, info: &EmulateInfo, _parallel: usize) -> u64 {
+        self.state_bytes(circuit, info).saturating_mul(3)
+    }
+
+    fn simulation_path(&self, _circuit: &Circuit) -> &'static str {
+        "exact"
+    }
+
+    fn state_cost(&self, circuit: &Circuit, _info: &EmulateInfo) -> (f64, f64) {
+        (2.0 * 4f64.powf(circuit.num_qubits() as f64), 1.0)
+    }
+
+    /// a gate updates all the entries twice, and again for each Kraus matrix
+    /// of the noise, the measurements are read from the diagonal
+    fn instruction_cost(
+        &self,
+        inst: &Instruction,
+        size: f64,
+        _circuit: &Circuit,
+        info: &EmulateInfo,
+    ) -> f64 {
+        match &inst.op {
+            Op::Gate { qubits, .. } => {
+                let kraus: usize = info
+                    .noise
+                    .unwrap_or_default()
+                    .after_gate(qubits)
+                    .iter()
+                    .map(|channel| channel.kraus.len() + 1)
+                    .sum();
+                size * (1 + kraus) as f64
+            }
+            Op::Reset { .. } => size * 3.0,
+            Op::Measure { .. } | Op::Barrier { .. } => 0.0,
+        }
+    }
+
+    fn run(&self, job: Job) -> BoxFuture<'_, Result<Output, String>> {
+        Box::pin(async move {
+            let Job {
+                pool,
+                circuit,
+                info,
+                shots,
+                seed,
+                progress,
+                ..
+            } = job;
+            let noise = info.noise.unwrap_or_default();
+            let observables = DensityMatrixBackend::observables(&circuit, &info)?;
+            let (sequences, exact) = pool
+                .run_for(&progress, move || {
+                    sample_shots(&circuit, &info.vars, shots, seed, &noise, &observables)
+                })
+                .await
+                .and_then(|result| result)?;
+            let mut output = Output::new(sequences);
+            output.metadata.insert("noise".to_string(), json!(noise));
+            output
+                .exact
+                .insert("probabilities".to_string(), json!(exact.probabilities));
+            output.exact.insert(
+                "expectation_values".to_string(),
+                json!(exact.expectation_values),
+            );
+            Ok(output)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::circuit::qasm2;
+
+    fn circuit(body: &str) -> Circuit {
+        qasm2::parse(&format!("OPENQASM 2.0;\ninclude \"qelib1.inc\";\n{}", body)).unwrap()
+    }
+
+    fn exact(body: &str, noise: NoiseModel, observables: &[&str]) -> Exact {
+        let circuit = expand(&circuit(body)).unwrap();
+        let observables: Vec<Pauli> = observables
+            .iter()
+            .map(|spec| parse_pauli(&circuit, spec).unwrap())
+            .collect();
+        sample_shots(&circuit, &HashMap::new(), 10, 1, &noise, &observables)
+            .unwrap()
+            .1
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-9,
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn bell_state() {
+        let bell = "qreg q[2];\ncreg c[2];\nh q[0];\ncx q[0],q[1];\nmeasure q -> c;";
+        let exact = exact(bell, NoiseModel::default(), &["XX", "YY", "ZZ", "ZI"]);
+        assert_eq!(exact.probabilities.len(), 2);
+        assert_close(exact.probabilities["00"], 0.5);
+        assert_close(exact.probabilities["11"], 0.5);
+        for (value, expected) in exact.expectation_values.iter().zip([1.0, -1.0, 1.0, 0.0]) {
+            assert_close(*value, expected);
+        }
+    }
+
+    #[test]
+    fn noise_channels() {
+        let flip = "qreg q[1];\ncreg c[1];\nx q[0];\nmeasure q[0] -> c[0];";
+        let noise = NoiseModel {
+            depolarizing: 0.3,
+            ..Default::default()
+        };
+        assert_close(exact(flip, noise, &[]).probabilities["0"], 0.2);
+        let noise = NoiseModel {
+            amplitude_damping: 0.25,
+            ..Default::default()
+        };
+        assert_close(exact(flip, noise, &[]).probabilities["0"], 0.25);
+        let noise = NoiseModel {
+            readout_error: 0.1,
+            ..Default::default()
+        };
+        assert_close(exact(flip, noise, &[]).probabilities["0"], 0.1);
+
+        // the phase damping shrinks the coherence, not the populations
+        let plus = "qreg q[1];\nh q[0];";
+        let noise = NoiseModel {
+            phase_damping: 0.36,
+            ..Default::default()
+        };
+        let values = exact(plus, noise, &["X", "Z"]).expectation_values;
+        assert_close(values[0], 0.8);
+        assert_close(values[1], 0.0);
+    }
+
+    #[test]
+    fn reset_is_a_channel() {
+        let exact = exact(
+            "qreg q[2];\ncreg c[2];\nh q[0];\ncx q[0],q[1];\nreset q[0];\nmeasure q -> c;",
+            NoiseModel::default(),
+            &["ZI"],
+        );
+        assert_close(exact.probabilities["00"], 0.5);
+        assert_close(exact.probabilities["10"], 0.5);
+        assert_close(exact.expectation_values[0], 0.0);
+    }
+
+    #[test]
+    fn observables_are_read_in_bitstring_order() {
+        let c = circuit("qreg a[2];\nqreg b[1];");
+        let pauli = parse_pauli(&c, "XZY").unwrap();
+        // a[1] is X, a[0] is Z and b[0] is Y
+        assert_eq!((pauli.x, pauli.z, pauli.y), (0b110, 0b101, 1));
+        assert!(parse_pauli(&c, "XZ")
+            .unwrap_err()
+            .contains("each of the 3 qubits"));
+        assert!(parse_pauli(&c, "XZA")
+            .unwrap_err()
+            .contains("A is not one of"));
+
+        // a[0] on qubit 3, a[1] on 0 and b[0] on 1 of 4
+        assert_eq!(relabel_pauli(&c, "XZY", &[3, 0, 1], 4).unwrap(), "ZIYX");
+        assert!(relabel_pauli(&c, "XZ", &[3, 0, 1], 4).is_err());
+    }
+
+    #[test]
+    fn measurements_must_be_at_the_end() {
+        let err = expand(&circuit(
+            "qreg q[1];\ncreg c[1];\nmeasure q[0] -> c[0];\nx q[0];",
+        ));
+        assert!(err
+            .unwrap_err()
+            .contains("q[0] is used after it is measured"));
+        let err = expand(&circuit("qreg q[1];\ncreg c[1];\nif(c==1) x q[0];"));
+        assert!(err.unwrap_err().contains("does not run conditions"));
+    }
+}
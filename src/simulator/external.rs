@@ -0,0 +1,135 @@
+use std::sync::Arc;
+
+use serde_json::Value;
+
+use crate::{
+    circuit::{qasm2, Circuit},
+    emulate::EmulateInfo,
+    pool::{statevector_bytes, ComputePool, MemoryBudget},
+    progress::Progress,
+};
+
+use super::backend::{all_gates, Backend, BoxFuture, Capabilities, Job, Output};
+
+/// the name of the backend of qasmsim
+pub const QASMSIM: &str = "qasmsim";
+
+/// Run an OpenQASM 2.0 program on qasmsim and return the measured bits of
+/// each shot
+pub fn run_sequences(qasm: &str, shots: Option<usize>) -> Result<Vec<String>, String> {
+    qasmsim::run_mode(qasm, shots, "sequence".to_string())
+        .map(|result| result.sequences().clone().unwrap_or_default())
+        .map_err(|err| err.to_string())
+}
+
+/// Run an OpenQASM 2.0 program on qasmsim, its expectation is computed
+/// exactly
+pub fn run_exact(qasm: &str, shots: Option<usize>) -> Result<qasmsim::Execution, String> {
+    qasmsim::run(qasm, shots).map_err(|err| err.to_string())
+}
+
+/// The qasmsim crate, it also runs the programs the agent cannot parse and
+/// the expectation of the vqe mode. It cannot be seeded, the seed is
+/// reported as null.
+#[derive(Debug)]
+pub struct QasmsimBackend;
+
+impl Backend for QasmsimBackend {
+    fn name(&self) -> &'static str {
+        QASMSIM
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            name: self.name(),
+            description: "the statevector of the qasmsim crate, it cannot be seeded",
+            gates: all_gates(),
+            noise: false,
+            mid_circuit_measurements: true,
+            exact: false,
+            seeded: false,
+            vqe: true,
+            settings: vec![],
+        }
+    }
+
+    fn max_qubits(&self, memory: &MemoryBudget) -> usize {
+        memory.max_qubits()
+    }
+
+    /// qasmsim cannot be seeded, a seed the client gave is an error
+    fn prepare(&self, circuit: Arc<Circuit>, info: &EmulateInfo) -> Result<Arc<Circuit>, String> {
+        if info.seed.is_some() && !info.random_seed {
+            return Err(
+                "seed is not supported by the qasmsim backend, it cannot be seeded".to_string(),
+            );
+        }
+        Ok(circuit)
+    }
+
+    fn state_bytes(&self, circuit: &Circuit, _info: &EmulateInfo) -> u64 {
+        statevector_bytes(circuit.num_qubits())
+    }
+
+    /// qasmsim simulates one shot at a time
+    fn memory_bytes(&self, circuit: &Circuit, info: &EmulateInfo, _parallel: usize) -> u64 {
+        self.state_bytes(circuit, info)
+    }
+
+    fn simulation_path(&self, _circuit: &Circuit) -> &'static str {
+        "qasmsim"
+    }
+
+    fn state_cost(&self, circuit: &Circuit, _info: &EmulateInfo) -> (f64, f64) {
+        (2f64.powf(circuit.num_qubits() as f64), 1.0)
+    }
+
+    fn run(&self, job: Job) -> BoxFuture<'_, Result<Output, String>> {
+        Box::pin(async move {
+            let Job {
+                pool,
+                circuit,
+                info,
+                shots,
+                progress,
+                ..
+            } = job;
+            let qasm = qasm2::to_qasm(&circuit.bind(&info.vars));
+            let sequences = pool
+                .run_for(&progress, move || run_sequences(&qasm, Some(shots)))
+                .await
+                .and_then(|result| result)?;
+            let mut output = Output::new(sequences);
+            output.metadata.insert("seed".to_string(), Value::Null);
+            Ok(output)
+        })
+    }
+
+    fn run_program(
+        &self,
+        pool: Arc<ComputePool>,
+        qasm: String,
+        shots: Option<usize>,
+        progress: Progress,
+    ) -> BoxFuture<'_, Result<Vec<String>, String>> {
+        Box::pin(async move {
+            pool.run_for(&progress, move || run_sequences(&qasm, shots))
+                .await
+                .and_then(|result| result)
+        })
+    }
+
+    fn run_vqe(
+        &self,
+        pool: Arc<ComputePool>,
+        qasm: String,
+        shots: Option<usize>,
+        progress: Progress,
+    ) -> BoxFuture<'_, Result<qasmsim::Execution, String>> {
+        Box::pin(async move {
+            pool.run_for(&progress, move || run_exact(&qasm, shots))
+                .await
+                .and_then(|result| result)
+        })
+    }
+}
@@ -0,0 +1,220 @@
+pub mod backend;
+pub mod density;
+pub mod external;
+pub mod mps;
+pub mod noise;
+pub mod stabilizer;
+pub mod statevector;
+
+use std::{
+    collections::HashMap,
+    ops::Range,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use tokio::{sync::Semaphore, task::JoinHandle};
+
+use crate::{pool::ComputePool, progress::Progress};
+
+/// shots of a chunk, one chunk for each thread of the pool, and at most
+/// `every` shots so that the partial results are published that often
+pub fn chunk_size(threads: usize, shots: usize, every: Option<usize>) -> usize {
+    shots
+        .div_ceil(threads.min(shots).max(1))
+        .min(every.unwrap_or(usize::MAX))
+        .max(1)
+}
+
+/// chunks of shots simulated at once by the per shot simulation, one for
+/// each thread of the pool, but no more than `budget` bytes of memory hold
+pub fn parallel_chunks(
+    state_bytes: u64,
+    threads: usize,
+    shots: usize,
+    every: Option<usize>,
+    budget: u64,
+) -> usize {
+    let chunks = shots.div_ceil(chunk_size(threads, shots, every));
+    let per_chunk = state_bytes.saturating_mul(2).max(1);
+    chunks
+        .min(threads)
+        .min((budget / per_chunk) as usize)
+        .max(1)
+}
+
+/// the chunks of a job, aborted if the job is dropped before they finish
+struct Chunks<T>(Vec<JoinHandle<T>>);
+
+impl<T> Drop for Chunks<T> {
+    fn drop(&mut self) {
+        self.0.iter().for_each(|handle| handle.abort());
+    }
+}
+
+/// Split the shots into chunks run in parallel on the compute pool, at most
+/// `parallel` of them at once. `run` simulates the shots of a chunk, it also
+/// returns what the backend reports of them. Each shot has its own random
+/// stream derived from the job seed, so the merged result is the same as
+/// running all shots on one thread. The counts so far are published as each
+/// chunk is merged. When a chunk fails the chunks which did not start are
+/// skipped, and the running ones are waited for, so that no chunk outlives
+/// the memory reserved for the job.
+pub async fn run_shots_parallel<F, T>(
+    pool: Arc<ComputePool>,
+    shots: usize,
+    progress: &Progress,
+    parallel: usize,
+    run: F,
+) -> Result<(Vec<String>, Vec<T>), String>
+where
+    F: Fn(Range<usize>) -> Result<(Vec<String>, T), String> + Clone + Send + 'static,
+    T: Send + 'static,
+{
+    let chunk_size = chunk_size(pool.size, shots, progress.every);
+    let permits = Arc::new(Semaphore::new(parallel.max(1)));
+    let failed = Arc::new(AtomicBool::new(false));
+
+    let mut chunks = Chunks(Vec::new());
+    for start in (0..shots).step_by(chunk_size) {
+        let end = (start + chunk_size).min(shots);
+        let (pool, run, progress) = (pool.clone(), run.clone(), progress.clone());
+        let (permits, failed) = (permits.clone(), failed.clone());
+        chunks.0.push(tokio::spawn(async move {
+            let _permit = permits
+                .acquire_owned()
+                .await
+                .map_err(|err| err.to_string())?;
+            if failed.load(Ordering::SeqCst) {
+                return Err("Another chunk of the job failed".to_string());
+            }
+            pool.run_for(&progress, move || run(start..end))
+                .await
+                .and_then(|chunk| chunk)
+                .inspect_err(|_| failed.store(true, Ordering::SeqCst))
+        }));
+    }
+
+    let mut sequences = Vec::with_capacity(shots);
+    let mut reports = Vec::new();
+    let mut counts = HashMap::new();
+    let mut error = None;
+    for handle in chunks.0.iter_mut() {
+        let chunk = match handle
+            .await
+            .map_err(|err| err.to_string())
+            .and_then(|chunk| chunk)
+        {
+            Ok(chunk) => chunk,
+            Err(err) => {
+                failed.store(true, Ordering::SeqCst);
+                error.get_or_insert(err);
+                continue;
+            }
+        };
+        if error.is_some() {
+            continue;
+        }
+        let (chunk, report) = chunk;
+        for s in chunk.into_iter() {
+            *counts.entry(s.clone()).or_insert(0) += 1;
+            sequences.push(s);
+        }
+        reports.push(report);
+        progress.partial(sequences.len(), &counts);
+    }
+    match error {
+        Some(err) => Err(err),
+        None => Ok((sequences, reports)),
+    }
+}
+
+/// Publish the counts of shots which were all sampled at once, every
+/// `progress.every` shots like the chunks of `run_shots_parallel`. Nothing is
+/// published without `every`, the result has all the shots.
+pub fn publish_sampled(progress: &Progress, sequences: &[String]) {
+    let Some(every) = progress.every.filter(|&every| every > 0) else {
+        return;
+    };
+    let mut counts = HashMap::new();
+    for (shot, s) in sequences.iter().enumerate() {
+        *counts.entry(s.clone()).or_insert(0) += 1;
+        if (shot + 1) % every == 0 || shot + 1 == sequences.len() {
+            progress.partial(shot + 1, &counts);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{pool::MemoryBudget, progress::ProgressHub};
+    use std::{sync::atomic::AtomicUsize, thread, time::Duration};
+
+    #[tokio::test]
+    async fn failed_chunk_stops_the_other_chunks() {
+        let pool = Arc::new(ComputePool::new(2, 8, MemoryBudget::new(1 << 30)));
+        let progress = Progress::new(Arc::new(ProgressHub::new()), "job".to_string(), Some(1));
+        let (running, started) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+        let (r, s) = (running.clone(), started.clone());
+        let result = run_shots_parallel(pool, 8, &progress, 2, move |shots| {
+            s.fetch_add(1, Ordering::SeqCst);
+            r.fetch_add(1, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(if shots.start == 0 {
+                10
+            } else {
+                50
+            }));
+            r.fetch_sub(1, Ordering::SeqCst);
+            match shots.start {
+                0 => Err("chunk failed".to_string()),
+                _ => Ok((vec!["0".to_string(); shots.len()], ())),
+            }
+        })
+        .await;
+        assert_eq!(result.unwrap_err(), "chunk failed");
+        assert_eq!(running.load(Ordering::SeqCst), 0);
+        assert!(started.load(Ordering::SeqCst) < 8);
+    }
+
+    #[tokio::test]
+    async fn chunks_are_merged_in_order() {
+        let pool = Arc::new(ComputePool::new(2, 8, MemoryBudget::new(1 << 30)));
+        let progress = Progress::new(Arc::new(ProgressHub::new()), "job".to_string(), Some(2));
+        let (sequences, reports) = run_shots_parallel(pool, 6, &progress, 3, |shots| {
+            Ok((shots.clone().map(|s| s.to_string()).collect(), shots.start))
+        })
+        .await
+        .unwrap();
+        assert_eq!(sequences, ["0", "1", "2", "3", "4", "5"]);
+        assert_eq!(reports, [0, 2, 4]);
+    }
+
+    #[test]
+    fn sampled_shots_are_published_every_few_shots() {
+        let hub = Arc::new(ProgressHub::new());
+        let (_, mut rx, _) = hub.subscribe("job").unwrap();
+        let progress = Progress::new(hub, "job".to_string(), Some(2));
+        publish_sampled(&progress, &["0", "1", "1", "0", "1"].map(String::from));
+        let events: Vec<_> = std::iter::from_fn(|| rx.try_recv().ok()).collect();
+        assert_eq!(
+            events
+                .iter()
+                .map(|event| &event["shots"])
+                .collect::<Vec<_>>(),
+            [2, 4, 5]
+        );
+        assert_eq!(events[1]["counts"], serde_json::json!({"0": 2, "1": 2}));
+
+        // without `every` the result has all the shots
+        let hub = Arc::new(ProgressHub::new());
+        let (_, mut rx, _) = hub.subscribe("job").unwrap();
+        publish_sampled(
+            &Progress::new(hub, "job".to_string(), None),
+            &["0".to_string()],
+        );
+        assert!(rx.try_recv().is_err());
+    }
+}
@@ -0,0 +1,925 @@
+use std::{collections::HashMap, sync::Arc};
+
+use num::complex::Complex64;
+use rand::Rng;
+use serde::Serialize;
+use serde_json::json;
+
+use crate::{
+    circuit::{
+        transpile::{transpile, Basis},
+        Circuit, Instruction, Op,
+    },
+    emulate::EmulateInfo,
+    pool::MemoryBudget,
+};
+
+use super::{
+    backend::{all_gates, widest, Backend, BoxFuture, Capabilities, Job, Output},
+    run_shots_parallel,
+    statevector::{register_value, shot_rng, StateVector},
+};
+
+pub const DEFAULT_BOND_DIMENSION: usize = 64;
+pub const DEFAULT_TRUNCATION_THRESHOLD: f64 = 1e-12;
+/// the widest circuit the backend simulates
+pub const MAX_QUBITS: usize = 1024;
+
+/// the singular values dropped without counting them as truncation, they
+/// are zero up to rounding
+const ZERO_WEIGHT: f64 = 1e-28;
+const MAX_SWEEPS: usize = 100;
+
+fn zero() -> Complex64 {
+    Complex64::new(0.0, 0.0)
+}
+
+/// Settings of the MPS simulation. After each two-qubit gate at most
+/// `bond_dimension` singular values are kept, and the ones whose share of
+/// the norm is below `truncation_threshold` are dropped.
+#[derive(Debug, Clone, Copy)]
+pub struct MpsConfig {
+    pub bond_dimension: usize,
+    pub truncation_threshold: f64,
+}
+
+impl MpsConfig {
+    pub fn new(
+        bond_dimension: Option<usize>,
+        truncation_threshold: Option<f64>,
+    ) -> Result<Self, String> {
+        let config = MpsConfig {
+            bond_dimension: bond_dimension.unwrap_or(DEFAULT_BOND_DIMENSION),
+            truncation_threshold: truncation_threshold.unwrap_or(DEFAULT_TRUNCATION_THRESHOLD),
+        };
+        if config.bond_dimension == 0 {
+            return Err("bond_dimension should be larger than 0".to_string());
+        }
+        if !(0.0..1.0).contains(&config.truncation_threshold) {
+            return Err("truncation_threshold should be at least 0 and less than 1".to_string());
+        }
+        Ok(config)
+    }
+}
+
+/// What the truncation did to a simulation, reported with the result, the
+/// worst of all shots
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct MpsStats {
+    /// the share of the norm dropped by the truncations, summed over the
+    /// gates
+    pub truncation_error: f64,
+    /// the product of the shares kept by each truncation, an estimate of the
+    /// fidelity to the exact state
+    pub fidelity: f64,
+    /// the largest bond dimension reached
+    pub max_bond: usize,
+}
+
+impl Default for MpsStats {
+    fn default() -> Self {
+        MpsStats {
+            truncation_error: 0.0,
+            fidelity: 1.0,
+            max_bond: 1,
+        }
+    }
+}
+
+impl MpsStats {
+    fn merge(&mut self, other: &MpsStats) {
+        self.truncation_error = self.truncation_error.max(other.truncation_error);
+        self.fidelity = self.fidelity.min(other.fidelity);
+        self.max_bond = self.max_bond.max(other.max_bond);
+    }
+
+    /// the stats of all the chunks of shots
+    pub fn merged(stats: &[MpsStats]) -> MpsStats {
+        let mut merged = MpsStats::default();
+        stats.iter().for_each(|stats| merged.merge(stats));
+        merged
+    }
+}
+
+/// bytes of an MPS of `num_qubits` qubits whose bonds are at most
+/// `bond_dimension`
+pub fn memory_bytes(num_qubits: usize, bond_dimension: usize) -> u64 {
+    let bond = |cut: usize| -> u64 {
+        let exact = 2u64.saturating_pow(cut.min(num_qubits - cut).min(63) as u32);
+        exact.min(bond_dimension as u64)
+    };
+    (0..num_qubits)
+        .map(|site| {
+            (2 * 16u64)
+                .saturating_mul(bond(site))
+                .saturating_mul(bond(site + 1))
+        })
+        .fold(0, u64::saturating_add)
+}
+
+/// The tensor of a site, `data[(left * 2 + bit) * right + r]`
+#[derive(Debug, Clone)]
+struct Site {
+    left: usize,
+    right: usize,
+    data: Vec<Complex64>,
+}
+
+impl Site {
+    fn index(&self, l: usize, bit: usize, r: usize) -> usize {
+        (l * 2 + bit) * self.right + r
+    }
+}
+
+/// Singular value decomposition `m = u * diag(s) * vh` of a `rows` x `cols`
+/// matrix by one-sided Jacobi rotations, the singular values are sorted in
+/// decreasing order
+fn svd(m: &[Complex64], rows: usize, cols: usize) -> (Vec<Complex64>, Vec<f64>, Vec<Complex64>) {
+    if rows < cols {
+        // m^† = u' s vh', so m = vh'^† s u'^†
+        let mut mh = vec![zero(); rows * cols];
+        for i in 0..rows {
+            for j in 0..cols {
+                mh[j * rows + i] = m[i * cols + j].conj();
+            }
+        }
+        let (u, s, vh) = svd(&mh, cols, rows);
+        let k = s.len();
+        let mut u2 = vec![zero(); rows * k];
+        let mut vh2 = vec![zero(); k * cols];
+        for i in 0..rows {
+            for j in 0..k {
+                u2[i * k + j] = vh[j * rows + i].conj();
+            }
+        }
+        for j in 0..k {
+            for c in 0..cols {
+                vh2[j * cols + c] = u[c * k + j].conj();
+            }
+        }
+        return (u2, s, vh2);
+    }
+
+    let n = cols;
+    let mut w = m.to_vec();
+    let mut v = vec![zero(); n * n];
+    (0..n).for_each(|i| v[i * n + i] = Complex64::new(1.0, 0.0));
+
+    for _ in 0..MAX_SWEEPS {
+        let mut rotated = false;
+        for p in 0..n {
+            for q in p + 1..n {
+                let (mut alpha, mut beta, mut gamma) = (0.0, 0.0, zero());
+                for i in 0..rows {
+                    let (a, b) = (w[i * n + p], w[i * n + q]);
+                    alpha += a.norm_sqr();
+                    beta += b.norm_sqr();
+                    gamma += a.conj() * b;
+                }
+                if gamma.norm() <= 1e-15 * (alpha * beta).sqrt() || gamma.norm() == 0.0 {
+                    continue;
+                }
+                rotated = true;
+                // rotate the phase of q away, then a real Jacobi rotation
+                let phase = (gamma / gamma.norm()).conj();
+                let zeta = (beta - alpha) / (2.0 * gamma.norm());
+                let t = zeta.signum() / (zeta.abs() + (1.0 + zeta * zeta).sqrt());
+                let c = 1.0 / (1.0 + t * t).sqrt();
+                let s = c * t;
+                for (mat, len) in [(&mut w, rows), (&mut v, n)] {
+                    for i in 0..len {
+                        let a = mat[i * n + p];
+                        let b = mat[i * n + q] * phase;
+                        mat[i * n + p] = a * c - b * s;
+                        mat[i * n + q] = a * s + b * c;
+                    }
+                }
+            }
+        }
+        if !rotated {
+            break;
+        }
+    }
+
+    let norms: Vec<f64> = (0..n)
+        .map(|j| {
+            (0..rows)
+                .map(|i| w[i * n + j].norm_sqr())
+                .sum::<f64>()
+                .sqrt()
+        })
+        .collect();
+    let mut order: Vec<usize> = (0..n).collect();
+    order.sort_by(|&a, &b| norms[b].total_cmp(&norms[a]));
+
+    let mut u = vec![zero(); rows * n];
+    let mut vh = vec![zero(); n * n];
+    for (k, &j) in order.iter().enumerate() {
+        if norms[j] > 0.0 {
+            for i in 0..rows {
+                u[i * n + k] = w[i * n + j] / norms[j];
+            }
+        }
+        for c in 0..n {
+            vh[k * n + c] = v[c * n + j].conj();
+        }
+    }
+    (u, order.iter().map(|&j| norms[j]).collect(), vh)
+}
+
+/// The matrix of a gate on `k` qubits, bit `j` of the basis index is
+/// `qubits[j]`, built by applying the gate of the statevector simulator to
+/// each basis state
+pub fn gate_matrix(name: &str, params: &[f64], k: usize) -> Result<Vec<Complex64>, String> {
+    let dim = 1 << k;
+    let qubits: Vec<usize> = (0..k).collect();
+    let mut matrix = vec![zero(); dim * dim];
+    for col in 0..dim {
+        let mut state = StateVector::new(k)?;
+        state.amplitudes[0] = zero();
+        state.amplitudes[col] = Complex64::new(1.0, 0.0);
+        state.apply_gate(name, params, &qubits)?;
+        for row in 0..dim {
+            matrix[row * dim + col] = state.amplitudes[row];
+        }
+    }
+    Ok(matrix)
+}
+
+/// A matrix product state, the qubits are moved along the chain so that the
+/// two-qubit gates act on neighbouring sites. The state is kept in mixed
+/// canonical form around `center`, so the truncations are optimal and the
+/// probabilities are read from one site.
+#[derive(Debug, Clone)]
+pub struct Mps {
+    sites: Vec<Site>,
+    /// the site of each qubit
+    position: Vec<usize>,
+    /// the qubit on each site
+    qubit_at: Vec<usize>,
+    center: usize,
+    config: MpsConfig,
+    pub stats: MpsStats,
+}
+
+impl Mps {
+    pub fn new(num_qubits: usize, config: MpsConfig) -> Self {
+        let mut data = vec![zero(); 2];
+        data[0] = Complex64::new(1.0, 0.0);
+        Mps {
+            sites: vec![
+                Site {
+                    left: 1,
+                    right: 1,
+                    data
+                };
+                num_qubits
+            ],
+            position: (0..num_qubits).collect(),
+            qubit_at: (0..num_qubits).collect(),
+            center: 0,
+            config,
+            stats: MpsStats::default(),
+        }
+    }
+
+    /// the number of singular values to keep and the share of the norm they
+    /// drop, at most `max_bond` of them when truncating
+    fn keep(&self, s: &[f64], truncate: bool) -> (usize, f64) {
+        let total: f64 = s.iter().map(|x| x * x).sum();
+        let cut = if truncate {
+            self.config.truncation_threshold.max(ZERO_WEIGHT)
+        } else {
+            ZERO_WEIGHT
+        };
+        let mut keep = s
+            .iter()
+            .take_while(|&&x| total > 0.0 && x * x / total > cut)
+            .count()
+            .max(1);
+        if truncate {
+            keep = keep.min(self.config.bond_dimension);
+        }
+        let dropped = s[keep..]
+            .iter()
+            .map(|x| x * x / total)
+            .filter(|&weight| weight > ZERO_WEIGHT)
+            .sum();
+        (keep, dropped)
+    }
+
+    /// move the orthogonality center to `target`
+    fn move_center(&mut self, target: usize) {
+        while self.center < target {
+            let c = self.center;
+            let site = &self.sites[c];
+            let (site_left, rows, cols) = (site.left, site.left * 2, site.right);
+            let (u, s, vh) = svd(&site.data, rows, cols);
+            let n = s.len();
+            let (k, _) = self.keep(&s, false);
+            let mut left = vec![zero(); rows * k];
+            for i in 0..rows {
+                left[i * k..(i + 1) * k].copy_from_slice(&u[i * n..i * n + k]);
+            }
+            let next = &self.sites[c + 1];
+            let mut right = vec![zero(); k * 2 * next.right];
+            for a in 0..k {
+                for m in 0..cols {
+                    let x = vh[a * cols + m] * s[a];
+                    if x == zero() {
+                        continue;
+                    }
+                    for j in 0..2 * next.right {
+                        right[a * 2 * next.right + j] += x * next.data[m * 2 * next.right + j];
+                    }
+                }
+            }
+            let next_right = next.right;
+            self.sites[c] = Site {
+                left: site_left,
+                right: k,
+                data: left,
+            };
+            self.sites[c + 1] = Site {
+                left: k,
+                right: next_right,
+                data: right,
+            };
+            self.center += 1;
+        }
+        while self.center > target {
+            let c = self.center;
+            let site = &self.sites[c];
+            let (rows, cols) = (site.left, 2 * site.right);
+            let (u, s, vh) = svd(&site.data, rows, cols);
+            let n = s.len();
+            let (k, _) = self.keep(&s, false);
+            let right = vh[..k * cols].to_vec();
+            let prev = &self.sites[c - 1];
+            let mut left = vec![zero(); prev.left * 2 * k];
+            for i in 0..prev.left * 2 {
+                for m in 0..rows {
+                    let x = prev.data[i * rows + m];
+                    if x == zero() {
+                        continue;
+                    }
+                    for a in 0..k {
+                        left[i * k + a] += x * u[m * n + a] * s[a];
+                    }
+                }
+            }
+            let (prev_left, site_right) = (prev.left, site.right);
+            self.sites[c - 1] = Site {
+                left: prev_left,
+                right: k,
+                data: left,
+            };
+            self.sites[c] = Site {
+                left: k,
+                right: site_right,
+                data: right,
+            };
+            self.center -= 1;
+        }
+    }
+
+    fn apply_1q(&mut self, matrix: &[Complex64], qubit: usize) {
+        let site = &mut self.sites[self.position[qubit]];
+        for l in 0..site.left {
+            for r in 0..site.right {
+                let (i0, i1) = (site.index(l, 0, r), site.index(l, 1, r));
+                let (a, b) = (site.data[i0], site.data[i1]);
+                site.data[i0] = matrix[0] * a + matrix[1] * b;
+                site.data[i1] = matrix[2] * a + matrix[3] * b;
+            }
+        }
+    }
+
+    /// Apply a two-qubit gate to the sites `i` and `i + 1`, `flipped` when
+    /// the first qubit of the gate is on `i + 1`. The bond between them is
+    /// truncated.
+    fn apply_adjacent(&mut self, matrix: &[Complex64], i: usize, flipped: bool) {
+        self.move_center(i);
+        let (a, b) = (&self.sites[i], &self.sites[i + 1]);
+        let (left, mid, right) = (a.left, a.right, b.right);
+
+        // theta[l][s1][s2][r]
+        let mut theta = vec![zero(); left * 4 * right];
+        for l in 0..left {
+            for s1 in 0..2 {
+                for m in 0..mid {
+                    let x = a.data[a.index(l, s1, m)];
+                    if x == zero() {
+                        continue;
+                    }
+                    for s2 in 0..2 {
+                        for r in 0..right {
+                            theta[((l * 2 + s1) * 2 + s2) * right + r] +=
+                                x * b.data[b.index(m, s2, r)];
+                        }
+                    }
+                }
+            }
+        }
+        let gate_index = |s1: usize, s2: usize| if flipped { s2 | s1 << 1 } else { s1 | s2 << 1 };
+        let mut updated = vec![zero(); theta.len()];
+        for l in 0..left {
+            for t1 in 0..2 {
+                for t2 in 0..2 {
+                    for s1 in 0..2 {
+                        for s2 in 0..2 {
+                            let g = matrix[gate_index(t1, t2) * 4 + gate_index(s1, s2)];
+                            if g == zero() {
+                                continue;
+                            }
+                            for r in 0..right {
+                                updated[((l * 2 + t1) * 2 + t2) * right + r] +=
+                                    g * theta[((l * 2 + s1) * 2 + s2) * right + r];
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        let (rows, cols) = (left * 2, 2 * right);
+        let (u, s, vh) = svd(&updated, rows, cols);
+        let n = s.len();
+        let (k, dropped) = self.keep(&s, true);
+        self.stats.truncation_error += dropped;
+        self.stats.fidelity *= 1.0 - dropped;
+        self.stats.max_bond = self.stats.max_bond.max(k);
+        // the kept singular values keep the norm of the state
+        let kept: f64 = s[..k].iter().map(|x| x * x).sum();
+        let total: f64 = s.iter().map(|x| x * x).sum();
+        let scale = if kept > 0.0 {
+            (total / kept).sqrt()
+        } else {
+            1.0
+        };
+
+        let mut first = vec![zero(); rows * k];
+        for r in 0..rows {
+            first[r * k..(r + 1) * k].copy_from_slice(&u[r * n..r * n + k]);
+        }
+        let mut second = vec![zero(); k * cols];
+        for j in 0..k {
+            for c in 0..cols {
+                second[j * cols + c] = vh[j * cols + c] * s[j] * scale;
+            }
+        }
+        self.sites[i] = Site {
+            left,
+            right: k,
+            data: first,
+        };
+        self.sites[i + 1] = Site {
+            left: k,
+            right,
+            data: second,
+        };
+        self.center = i + 1;
+    }
+
+    /// swap the qubits on the sites `i` and `i + 1`
+    fn swap_sites(&mut self, i: usize, swap: &[Complex64]) {
+        self.apply_adjacent(swap, i, false);
+        let (a, b) = (self.qubit_at[i], self.qubit_at[i + 1]);
+        self.qubit_at.swap(i, i + 1);
+        self.position[a] = i + 1;
+        self.position[b] = i;
+    }
+
+    pub fn apply_gate(
+        &mut self,
+        name: &str,
+        params: &[f64],
+        qubits: &[usize],
+    ) -> Result<(), String> {
+        match qubits {
+            [qubit] => self.apply_1q(&gate_matrix(name, params, 1)?, *qubit),
+            [a, b] => {
+                let matrix = gate_matrix(name, params, 2)?;
+                // move the later qubit next to the other one
+                let swap = gate_matrix("swap", &[], 2)?;
+                let (first, second) = if self.position[*a] < self.position[*b] {
+                    (*a, *b)
+                } else {
+                    (*b, *a)
+                };
+                while self.position[second] > self.position[first] + 1 {
+                    let site = self.position[second] - 1;
+                    self.swap_sites(site, &swap);
+                }
+                self.apply_adjacent(&matrix, self.position[first], first != *a);
+            }
+            _ => {
+                return Err(format!(
+                    "Gate {} on {} qubits is not supported by the mps backend",
+                    name,
+                    qubits.len()
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    pub fn probability_one(&mut self, qubit: usize) -> f64 {
+        let position = self.position[qubit];
+        self.move_center(position);
+        let site = &self.sites[position];
+        let (mut p0, mut p1) = (0.0, 0.0);
+        for l in 0..site.left {
+            for r in 0..site.right {
+                p0 += site.data[site.index(l, 0, r)].norm_sqr();
+                p1 += site.data[site.index(l, 1, r)].norm_sqr();
+            }
+        }
+        p1 / (p0 + p1)
+    }
+
+    /// measure a qubit and collapse the state
+    pub fn measure<R: Rng>(&mut self, qubit: usize, rng: &mut R) -> u8 {
+        let p1 = self.probability_one(qubit);
+        let outcome = u8::from(rng.gen::<f64>() < p1);
+        let norm = if outcome == 1 { p1 } else { 1.0 - p1 }.sqrt();
+        let site = &mut self.sites[self.position[qubit]];
+        for l in 0..site.left {
+            for r in 0..site.right {
+                for bit in 0..2 {
+                    let i = site.index(l, bit, r);
+                    if bit as u8 == outcome {
+                        site.data[i] /= norm;
+                    } else {
+                        site.data[i] = zero();
+                    }
+                }
+            }
+        }
+        outcome
+    }
+
+    pub fn reset<R: Rng>(&mut self, qubit: usize, rng: &mut R) {
+        if self.measure(qubit, rng) == 1 {
+            self.apply_1q(&gate_matrix("x", &[], 1).unwrap(), qubit);
+        }
+    }
+
+    /// Sample all qubits at once without changing the state, site by site
+    /// from the left while the sites on the right are right-canonical
+    pub fn sample<R: Rng>(&mut self, rng: &mut R) -> Vec<u8> {
+        self.move_center(0);
+        let mut bits = vec![0; self.sites.len()];
+        let mut env = vec![Complex64::new(1.0, 0.0)];
+        for (i, site) in self.sites.iter().enumerate() {
+            let branch = |bit: usize| -> Vec<Complex64> {
+                (0..site.right)
+                    .map(|r| {
+                        env.iter()
+                            .enumerate()
+                            .map(|(l, x)| x * site.data[site.index(l, bit, r)])
+                            .sum()
+                    })
+                    .collect()
+            };
+            let (w0, w1) = (branch(0), branch(1));
+            let p0: f64 = w0.iter().map(|x| x.norm_sqr()).sum();
+            let p1: f64 = w1.iter().map(|x| x.norm_sqr()).sum();
+            let (bit, w, p) = if rng.gen::<f64>() * (p0 + p1) < p1 {
+                (1, w1, p1)
+            } else {
+                (0, w0, p0)
+            };
+            bits[self.qubit_at[i]] = bit;
+            env = w.into_iter().map(|x| x / p.sqrt()).collect();
+        }
+        bits
+    }
+}
+
+/// Apply one instruction to the state, measurements are written to `clbits`
+fn apply_instruction<R: Rng>(
+    circuit: &Circuit,
+    inst: &Instruction,
+    vars: &HashMap<String, f64>,
+    state: &mut Mps,
+    clbits: &mut [u8],
+    rng: &mut R,
+) -> Result<(), String> {
+    if let Some(condition) = &inst.condition {
+        if register_value(circuit, clbits, &condition.creg) != condition.value {
+            return Ok(());
+        }
+    }
+
+    match &inst.op {
+        Op::Gate {
+            name,
+            params,
+            qubits,
+        } => {
+            let params = params
+                .iter()
+                .map(|param| param.eval(vars))
+                .collect::<Result<Vec<f64>, String>>()?;
+            state.apply_gate(name, &params, qubits)?;
+        }
+        Op::Measure { qubit, clbit } => clbits[*clbit] = state.measure(*qubit, rng),
+        Op::Reset { qubit } => state.reset(*qubit, rng),
+        Op::Barrier { .. } => {}
+    }
+    Ok(())
+}
+
+/// the gates on three qubits are expanded, the MPS applies up to two
+pub fn expand(circuit: &Circuit) -> Result<Circuit, String> {
+    if circuit.num_qubits() > MAX_QUBITS {
+        return Err(format!(
+            "The mps backend simulates at most {} qubits, the circuit has {}",
+            MAX_QUBITS,
+            circuit.num_qubits()
+        ));
+    }
+    transpile(circuit, &Basis::two_qubit_gates())
+}
+
+/// Simulate the gates once and sample every shot from the final MPS. Only
+/// valid when `circuit.measurements_are_terminal()`.
+pub fn sample_shots(
+    circuit: &Circuit,
+    vars: &HashMap<String, f64>,
+    shots: usize,
+    seed: u64,
+    config: MpsConfig,
+) -> Result<(Vec<String>, MpsStats), String> {
+    let mut state = Mps::new(circuit.num_qubits(), config);
+    let mut clbits = vec![0; circuit.num_clbits()];
+    let mut rng = shot_rng(seed, 0);
+    let mut measures = Vec::new();
+    for inst in circuit.instructions.iter() {
+        match &inst.op {
+            Op::Measure { qubit, clbit } => measures.push((*qubit, *clbit)),
+            Op::Reset { .. } => return Err("Reset is not a terminal measurement".to_string()),
+            _ => apply_instruction(circuit, inst, vars, &mut state, &mut clbits, &mut rng)?,
+        }
+    }
+
+    let sequences = (0..shots)
+        .map(|shot| {
+            let bits = state.sample(&mut shot_rng(seed, shot));
+            let mut clbits = vec![0; circuit.num_clbits()];
+            for &(qubit, clbit) in measures.iter() {
+                clbits[clbit] = bits[qubit];
+            }
+            circuit.format_clbits(&clbits)
+        })
+        .collect();
+    Ok((sequences, state.stats))
+}
+
+/// Simulate each shot of `shots` separately, the gates before the first
+/// measurement, reset or condition are simulated once
+pub fn run_shots(
+    circuit: &Circuit,
+    vars: &HashMap<String, f64>,
+    shots: std::ops::Range<usize>,
+    seed: u64,
+    config: MpsConfig,
+) -> Result<(Vec<String>, MpsStats), String> {
+    let prefix = circuit
+        .instructions
+        .iter()
+        .position(|inst| inst.condition.is_some() || !matches!(inst.op, Op::Gate { .. }))
+        .unwrap_or(circuit.instructions.len());
+
+    let mut initial = Mps::new(circuit.num_qubits(), config);
+    let mut clbits = vec![0; circuit.num_clbits()];
+    let mut rng = shot_rng(seed, 0);
+    for inst in circuit.instructions[..prefix].iter() {
+        apply_instruction(circuit, inst, vars, &mut initial, &mut clbits, &mut rng)?;
+    }
+
+    let mut stats = initial.stats;
+    let sequences = shots
+        .map(|shot| {
+            let mut state = initial.clone();
+            let mut clbits = vec![0; circuit.num_clbits()];
+            let mut rng = shot_rng(seed, shot);
+            for inst in circuit.instructions[prefix..].iter() {
+                apply_instruction(circuit, inst, vars, &mut state, &mut clbits, &mut rng)?;
+            }
+            stats.merge(&state.stats);
+            Ok(circuit.format_clbits(&clbits))
+        })
+        .collect::<Result<Vec<String>, String>>()?;
+    Ok((sequences, stats))
+}
+
+/// The mps backend, its bonds are set by `bond_dimension` and
+/// `truncation_threshold`
+#[derive(Debug)]
+pub struct MpsBackend;
+
+impl MpsBackend {
+    fn config(info: &EmulateInfo) -> Result<MpsConfig, String> {
+        MpsConfig::new(info.bond_dimension, info.truncation_threshold)
+    }
+}
+
+impl Backend for MpsBackend {
+    fn name(&self) -> &'static str {
+        "mps"
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            name: self.name(),
+            description: "a matrix product state, for wide circuits with little entanglement",
+            gates: all_gates(),
+            noise: false,
+            mid_circuit_measurements: true,
+            exact: false,
+            seeded: true,
+            vqe: false,
+            settings: vec!["bond_dimension", "truncation_threshold"],
+        }
+    }
+
+    /// with the default bond dimension
+    fn max_qubits(&self, memory: &MemoryBudget) -> usize {
+        widest(MAX_QUBITS, memory.budget, |n| {
+            memory_bytes(n, DEFAULT_BOND_DIMENSION).saturating_mul(2)
+        })
+    }
+
+    fn prepare(&self, circuit: Arc<Circuit>, info: &EmulateInfo) -> Result<Arc<Circuit>, String> {
+        MpsBackend::config(info)?;
+        Ok(Arc::new(expand(&circuit)?))
+    }
+
+    fn state_bytes(&self, circuit: &Circuit, info: &EmulateInfo) -> u64 {
+        let bond_dimension = info.bond_dimension.unwrap_or(DEFAULT_BOND_DIMENSION);
+        memory_bytes(circuit.num_qubits(), bond_dimension)
+    }
+
+    /// a gate contracts two sites of the largest bond, a shot is sampled
+    /// site by site
+    fn state_cost(&self, circuit: &Circuit, info: &EmulateInfo) -> (f64, f64) {
+        let num_qubits = circuit.num_qubits() as f64;
+        let bond_dimension = info.bond_dimension.unwrap_or(DEFAULT_BOND_DIMENSION);
+        let bond = (bond_dimension as f64).min(2f64.powf(num_qubits / 2.0));
+        (8.0 * bond.powi(3), num_qubits * bond * bond)
+    }
+
+    fn run(&self, job: Job) -> BoxFuture<'_, Result<Output, String>> {
+        Box::pin(async move {
+            let Job {
+                pool,
+                circuit,
+                info,
+                shots,
+                seed,
+                parallel,
+                progress,
+            } = job;
+            let config = MpsBackend::config(&info)?;
+            let (sequences, stats) = if circuit.measurements_are_terminal() {
+                pool.run_for(&progress, move || {
+                    sample_shots(&circuit, &info.vars, shots, seed, config)
+                })
+                .await
+                .and_then(|result| result)?
+            } else {
+                let (sequences, reports) =
+                    run_shots_parallel(pool, shots, &progress, parallel, move |range| {
+                        run_shots(&circuit, &info.vars, range, seed, config)
+                    })
+                    .await?;
+                (sequences, MpsStats::merged(&reports))
+            };
+            let mut output = Output::new(sequences);
+            output.metadata.insert(
+                "mps".to_string(),
+                json!({
+                    "bond_dimension": config.bond_dimension,
+                    "truncation_threshold": config.truncation_threshold,
+                    "truncation_error": stats.truncation_error,
+                    "fidelity": stats.fidelity,
+                    "max_bond": stats.max_bond,
+                }),
+            );
+            Ok(output)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::circuit::qasm2;
+
+    fn circuit(body: &str) -> Circuit {
+        qasm2::parse(&format!("OPENQASM 2.0;\ninclude \"qelib1.inc\";\n{}", body)).unwrap()
+    }
+
+    fn config(bond_dimension: usize) -> MpsConfig {
+        MpsConfig::new(Some(bond_dimension), None).unwrap()
+    }
+
+    fn gates(state: &mut Mps, gates: &[(&str, &[f64], &[usize])]) {
+        for (name, params, qubits) in gates {
+            state.apply_gate(name, params, qubits).unwrap();
+        }
+    }
+
+    #[test]
+    fn svd_reconstructs_the_matrix() {
+        for (rows, cols) in [(4, 2), (2, 4), (3, 3)] {
+            let m: Vec<Complex64> = (0..rows * cols)
+                .map(|i| Complex64::new((i as f64 * 0.7).sin(), (i as f64 * 1.3).cos()))
+                .collect();
+            let (u, s, vh) = svd(&m, rows, cols);
+            let k = s.len();
+            assert!(s.windows(2).all(|pair| pair[0] >= pair[1]));
+            for i in 0..rows {
+                for c in 0..cols {
+                    let x: Complex64 = (0..k).map(|a| u[i * k + a] * s[a] * vh[a * cols + c]).sum();
+                    assert!((x - m[i * cols + c]).norm() < 1e-9);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn matches_the_statevector() {
+        let program: &[(&str, &[f64], &[usize])] = &[
+            ("h", &[], &[0]),
+            ("ry", &[0.4], &[3]),
+            ("cx", &[], &[0, 3]),
+            ("rx", &[1.1], &[1]),
+            ("cx", &[], &[3, 1]),
+            ("cu1", &[0.9], &[4, 0]),
+            ("swap", &[], &[1, 4]),
+            ("crz", &[0.3], &[2, 4]),
+        ];
+        let mut mps = Mps::new(5, config(64));
+        gates(&mut mps, program);
+        let mut state = StateVector::new(5).unwrap();
+        for (name, params, qubits) in program {
+            state.apply_gate(name, params, qubits).unwrap();
+        }
+        for qubit in 0..5 {
+            assert!((mps.probability_one(qubit) - state.probability_one(qubit)).abs() < 1e-9);
+        }
+        assert_eq!(mps.stats.truncation_error, 0.0);
+        assert!((mps.stats.fidelity - 1.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn ghz_needs_a_bond_of_two() {
+        let mut source = "qreg q[30];\ncreg c[30];\nh q[0];\n".to_string();
+        (1..30).for_each(|i| source += &format!("cx q[{}],q[{}];\n", i - 1, i));
+        source += "measure q -> c;";
+        let circuit = expand(&circuit(&source)).unwrap();
+        let (sequences, stats) =
+            sample_shots(&circuit, &HashMap::new(), 50, 7, config(64)).unwrap();
+        assert!(sequences
+            .iter()
+            .all(|s| s == &"0".repeat(30) || s == &"1".repeat(30)));
+        assert!(sequences.iter().any(|s| s.starts_with('1')));
+        assert_eq!(stats.max_bond, 2);
+    }
+
+    #[test]
+    fn truncation_is_reported() {
+        let mut mps = Mps::new(2, config(1));
+        gates(&mut mps, &[("h", &[], &[0]), ("cx", &[], &[0, 1])]);
+        assert_eq!(mps.stats.max_bond, 1);
+        assert!((mps.stats.truncation_error - 0.5).abs() < 1e-9);
+        assert!((mps.stats.fidelity - 0.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn mid_circuit_measurements_collapse_the_state() {
+        let source = "qreg q[2];\ncreg c[2];\nh q[0];\nmeasure q[0] -> c[0];\n\
+                      if(c==1) x q[1];\nmeasure q[1] -> c[1];";
+        let circuit = expand(&circuit(source)).unwrap();
+        let (sequences, _) = run_shots(&circuit, &HashMap::new(), 0..40, 3, config(4)).unwrap();
+        assert!(sequences.iter().all(|s| s == "00" || s == "11"));
+        assert!(sequences.iter().any(|s| s == "11"));
+        // the shots follow the seed, whatever the chunks
+        let (first, _) = run_shots(&circuit, &HashMap::new(), 0..20, 3, config(4)).unwrap();
+        assert_eq!(first, sequences[..20]);
+    }
+
+    #[test]
+    fn settings_are_checked() {
+        assert!(MpsConfig::new(Some(0), None).is_err());
+        assert!(MpsConfig::new(None, Some(1.0)).is_err());
+        assert_eq!(MpsConfig::new(None, None).unwrap().bond_dimension, 64);
+        assert!(expand(&circuit("qreg q[1025];")).is_err());
+        // the bonds are capped by the cut and the bond dimension
+        assert_eq!(memory_bytes(2, 64), 2 * 32 * 2);
+        assert_eq!(memory_bytes(4, 1), 4 * 32);
+    }
+}
@@ -0,0 +1,157 @@
+use num::complex::Complex64;
+use serde::{Deserialize, Serialize};
+
+/// The noise of the device, the probabilities of the errors after each gate
+/// and measurement. It is only simulated by the density matrix backend.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct NoiseModel {
+    /// depolarizing error on the qubit of a single qubit gate
+    #[serde(default)]
+    pub depolarizing: f64,
+    /// depolarizing error on the pair of qubits of a two qubit gate
+    #[serde(default)]
+    pub depolarizing_2q: f64,
+    /// decay of |1> to |0> on each qubit of a gate
+    #[serde(default)]
+    pub amplitude_damping: f64,
+    /// loss of the phase on each qubit of a gate
+    #[serde(default)]
+    pub phase_damping: f64,
+    /// flip of a measured bit
+    #[serde(default)]
+    pub readout_error: f64,
+}
+
+/// A channel `rho -> sum K rho K†` on `qubits`, bit `j` of the index of the
+/// Kraus matrices is `qubits[j]`
+#[derive(Debug, Clone)]
+pub struct Channel {
+    pub qubits: Vec<usize>,
+    pub kraus: Vec<Vec<Complex64>>,
+}
+
+fn c(re: f64) -> Complex64 {
+    Complex64::new(re, 0.0)
+}
+
+/// I, X, Y and Z
+fn paulis() -> [[Complex64; 4]; 4] {
+    let (o, l, i) = (c(0.0), c(1.0), Complex64::new(0.0, 1.0));
+    [[l, o, o, l], [o, l, l, o], [o, -i, i, o], [l, o, o, -l]]
+}
+
+/// `b ⊗ a`, `a` acts on bit 0 of the index
+fn kron(a: &[Complex64; 4], b: &[Complex64; 4]) -> Vec<Complex64> {
+    let mut matrix = vec![c(0.0); 16];
+    for row in 0..4 {
+        for col in 0..4 {
+            matrix[row * 4 + col] = a[(row & 1) * 2 + (col & 1)] * b[(row >> 1) * 2 + (col >> 1)];
+        }
+    }
+    matrix
+}
+
+/// parse the JSON of a noise model, empty for no noise
+pub fn parse_noise(spec: &str) -> Result<Option<NoiseModel>, String> {
+    if spec.trim().is_empty() {
+        return Ok(None);
+    }
+    let noise: NoiseModel = serde_json::from_str(spec).map_err(|err| err.to_string())?;
+    noise.validate()?;
+    Ok(Some(noise))
+}
+
+impl NoiseModel {
+    pub fn validate(&self) -> Result<(), String> {
+        for (name, p) in [
+            ("depolarizing", self.depolarizing),
+            ("depolarizing_2q", self.depolarizing_2q),
+            ("amplitude_damping", self.amplitude_damping),
+            ("phase_damping", self.phase_damping),
+            ("readout_error", self.readout_error),
+        ] {
+            if !(0.0..=1.0).contains(&p) {
+                return Err(format!("{} should be between 0 and 1, not {}", name, p));
+            }
+        }
+        Ok(())
+    }
+
+    /// The channels after a gate on `qubits`: the depolarizing error of the
+    /// gate, then the damping of each qubit
+    pub fn after_gate(&self, qubits: &[usize]) -> Vec<Channel> {
+        let mut channels = Vec::new();
+        let paulis = paulis();
+        match qubits {
+            [qubit] if self.depolarizing > 0.0 => {
+                let p = self.depolarizing;
+                channels.push(Channel {
+                    qubits: vec![*qubit],
+                    kraus: paulis
+                        .iter()
+                        .enumerate()
+                        .map(|(k, pauli)| {
+                            let weight = if k == 0 { 1.0 - p } else { p / 3.0 };
+                            pauli.iter().map(|x| x * weight.sqrt()).collect()
+                        })
+                        .collect(),
+                });
+            }
+            [a, b] if self.depolarizing_2q > 0.0 => {
+                let p = self.depolarizing_2q;
+                let mut kraus = Vec::new();
+                for (k, pauli_b) in paulis.iter().enumerate() {
+                    for (j, pauli_a) in paulis.iter().enumerate() {
+                        let weight = if j == 0 && k == 0 { 1.0 - p } else { p / 15.0 };
+                        kraus.push(
+                            kron(pauli_a, pauli_b)
+                                .into_iter()
+                                .map(|x| x * weight.sqrt())
+                                .collect(),
+                        );
+                    }
+                }
+                channels.push(Channel {
+                    qubits: vec![*a, *b],
+                    kraus,
+                });
+            }
+            _ => {}
+        }
+        for &qubit in qubits {
+            if self.amplitude_damping > 0.0 {
+                let gamma = self.amplitude_damping;
+                channels.push(Channel {
+                    qubits: vec![qubit],
+                    kraus: vec![
+                        vec![c(1.0), c(0.0), c(0.0), c((1.0 - gamma).sqrt())],
+                        vec![c(0.0), c(gamma.sqrt()), c(0.0), c(0.0)],
+                    ],
+                });
+            }
+            if self.phase_damping > 0.0 {
+                let lambda = self.phase_damping;
+                channels.push(Channel {
+                    qubits: vec![qubit],
+                    kraus: vec![
+                        vec![c(1.0), c(0.0), c(0.0), c((1.0 - lambda).sqrt())],
+                        vec![c(0.0), c(0.0), c(0.0), c(lambda.sqrt())],
+                    ],
+                });
+            }
+        }
+        channels
+    }
+}
+
+/// the reset of a qubit to |0>
+pub fn reset(qubit: usize) -> Channel {
+    Channel {
+        qubits: vec![qubit],
+        kraus: vec![
+            vec![c(1.0), c(0.0), c(0.0), c(0.0)],
+            vec![c(0.0), c(1.0), c(0.0), c(0.0)],
+        ],
+    }
+}
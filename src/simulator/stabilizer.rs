@@ -0,0 +1,711 @@
+use std::{collections::HashMap, f64::consts::FRAC_PI_2, sync::Arc};
+
+use rand::Rng;
+
+use crate::{
+    circuit::{Circuit, Instruction, Op},
+    emulate::EmulateInfo,
+    pool::MemoryBudget,
+};
+
+use super::{
+    backend::{widest, Backend, BoxFuture, Capabilities, Job, Output},
+    run_shots_parallel,
+    statevector::{register_value, shot_rng},
+};
+
+/// the widest circuit of the backend, its tableau takes 128 MiB
+pub const MAX_QUBITS: usize = 16384;
+
+/// the gates of the backend, the rotations only by multiples of pi/2
+pub const GATES: &[&str] = &[
+    "id", "x", "y", "z", "h", "s", "sdg", "sx", "sxdg", "rz", "p", "u1", "rx", "ry", "u2", "u3",
+    "cx", "cz", "cy", "swap", "cp", "cu1", "rzz", "rxx",
+];
+
+/// A gate of the tableau, on the qubits of the decomposed gate
+#[derive(Debug, Clone, Copy)]
+enum Clifford {
+    H(usize),
+    S(usize),
+    X(usize),
+    Y(usize),
+    Z(usize),
+    Cx(usize, usize),
+}
+
+use Clifford::*;
+
+/// `theta` in quarter turns, if it is a multiple of pi/2
+fn quarter_turns(theta: f64) -> Option<usize> {
+    let turns = theta / FRAC_PI_2;
+    ((turns - turns.round()).abs() < 1e-9).then(|| turns.round().rem_euclid(4.0) as usize)
+}
+
+/// `rz(k pi/2)` up to a global phase
+fn rz(k: usize, q: usize) -> Vec<Clifford> {
+    vec![S(q); k]
+}
+
+fn rx(k: usize, q: usize) -> Vec<Clifford> {
+    [vec![H(q)], rz(k, q), vec![H(q)]].concat()
+}
+
+/// `ry = s rx sdg`
+fn ry(k: usize, q: usize) -> Vec<Clifford> {
+    [rz(3, q), rx(k, q), rz(1, q)].concat()
+}
+
+fn rzz(k: usize) -> Vec<Clifford> {
+    [vec![Cx(0, 1)], rz(k, 1), vec![Cx(0, 1)]].concat()
+}
+
+/// The decomposition of a gate into the gates of the tableau, up to a global
+/// phase, or none if the gate is not a Clifford gate with these parameters
+fn decompose(name: &str, params: &[f64]) -> Option<Vec<Clifford>> {
+    let turns = params
+        .iter()
+        .map(|&theta| quarter_turns(theta))
+        .collect::<Option<Vec<usize>>>()?;
+    let gates = match (name, &turns[..]) {
+        ("id", []) => vec![],
+        ("x", []) => vec![X(0)],
+        ("y", []) => vec![Y(0)],
+        ("z", []) => vec![Z(0)],
+        ("h", []) => vec![H(0)],
+        ("s", []) => rz(1, 0),
+        ("sdg", []) => rz(3, 0),
+        ("sx", []) => rx(1, 0),
+        ("sxdg", []) => rx(3, 0),
+        ("rz" | "p" | "u1", [k]) => rz(*k, 0),
+        ("rx", [k]) => rx(*k, 0),
+        ("ry", [k]) => ry(*k, 0),
+        ("u2", [phi, lambda]) => [rz(*lambda, 0), ry(1, 0), rz(*phi, 0)].concat(),
+        ("u3", [theta, phi, lambda]) => [rz(*lambda, 0), ry(*theta, 0), rz(*phi, 0)].concat(),
+        ("cx", []) => vec![Cx(0, 1)],
+        ("cz", []) => vec![H(1), Cx(0, 1), H(1)],
+        ("cy", []) => [rz(3, 1), vec![Cx(0, 1)], rz(1, 1)].concat(),
+        ("swap", []) => vec![Cx(0, 1), Cx(1, 0), Cx(0, 1)],
+        ("cp" | "cu1", [0]) => vec![],
+        ("cp" | "cu1", [2]) => vec![H(1), Cx(0, 1), H(1)],
+        ("rzz", [k]) => rzz(*k),
+        ("rxx", [k]) => [vec![H(0), H(1)], rzz(*k), vec![H(0), H(1)]].concat(),
+        _ => return None,
+    };
+    Some(gates)
+}
+
+/// Whether all the gates of the circuit are Clifford gates once the
+/// variables are bound, so that it can be simulated with a tableau
+pub fn is_clifford(circuit: &Circuit, vars: &HashMap<String, f64>) -> bool {
+    first_non_clifford(circuit, vars).is_none()
+}
+
+/// the first gate which is not a Clifford gate, as in the program
+pub fn first_non_clifford(circuit: &Circuit, vars: &HashMap<String, f64>) -> Option<String> {
+    circuit.instructions.iter().find_map(|inst| match &inst.op {
+        Op::Gate {
+            name,
+            params,
+            qubits,
+        } => {
+            let clifford = params
+                .iter()
+                .map(|param| param.eval(vars))
+                .collect::<Result<Vec<f64>, String>>()
+                .ok()
+                .and_then(|params| decompose(name, &params))
+                .is_some();
+            (!clifford).then(|| {
+                let params: Vec<String> = params.iter().map(|param| param.to_string()).collect();
+                let qubits: Vec<String> = qubits
+                    .iter()
+                    .map(|&qubit| circuit.qubit_name(qubit))
+                    .collect();
+                if params.is_empty() {
+                    format!("{} {}", name, qubits.join(","))
+                } else {
+                    format!("{}({}) {}", name, params.join(","), qubits.join(","))
+                }
+            })
+        }
+        _ => None,
+    })
+}
+
+/// bytes of the tableau of `num_qubits` qubits
+pub fn memory_bytes(num_qubits: usize) -> u64 {
+    let rows = 2 * num_qubits as u64 + 1;
+    rows * (2 * num_qubits.div_ceil(64) as u64 * 8 + 1)
+}
+
+/// The stabilizer tableau of Aaronson and Gottesman, the rows `0..n` are the
+/// destabilizers, `n..2n` the stabilizers and `2n` is scratch space. The X
+/// and Z bits of a row are packed into words.
+#[derive(Debug, Clone)]
+pub struct Tableau {
+    num_qubits: usize,
+    words: usize,
+    x: Vec<u64>,
+    z: Vec<u64>,
+    r: Vec<bool>,
+}
+
+impl Tableau {
+    /// the state |0...0>
+    pub fn new(num_qubits: usize) -> Self {
+        let words = num_qubits.div_ceil(64).max(1);
+        let rows = 2 * num_qubits + 1;
+        let mut tableau = Tableau {
+            num_qubits,
+            words,
+            x: vec![0; rows * words],
+            z: vec![0; rows * words],
+            r: vec![false; rows],
+        };
+        for q in 0..num_qubits {
+            tableau.x[q * words + q / 64] |= 1 << (q % 64);
+            tableau.z[(q + num_qubits) * words + q / 64] |= 1 << (q % 64);
+        }
+        tableau
+    }
+
+    fn bit(bits: &[u64], words: usize, row: usize, q: usize) -> bool {
+        bits[row * words + q / 64] >> (q % 64) & 1 == 1
+    }
+
+    fn x(&self, row: usize, q: usize) -> bool {
+        Tableau::bit(&self.x, self.words, row, q)
+    }
+
+    fn z(&self, row: usize, q: usize) -> bool {
+        Tableau::bit(&self.z, self.words, row, q)
+    }
+
+    fn apply(&mut self, gate: Clifford) {
+        let words = self.words;
+        let mask = |q: usize| (q / 64, 1u64 << (q % 64));
+        for row in 0..2 * self.num_qubits {
+            let base = row * words;
+            match gate {
+                H(q) => {
+                    let (w, m) = mask(q);
+                    let (x, z) = (self.x[base + w] & m, self.z[base + w] & m);
+                    self.r[row] ^= x != 0 && z != 0;
+                    self.x[base + w] ^= x ^ z;
+                    self.z[base + w] ^= x ^ z;
+                }
+                S(q) => {
+                    let (w, m) = mask(q);
+                    let (x, z) = (self.x[base + w] & m, self.z[base + w] & m);
+                    self.r[row] ^= x != 0 && z != 0;
+                    self.z[base + w] ^= x;
+                }
+                X(q) => self.r[row] ^= self.z[base + q / 64] >> (q % 64) & 1 == 1,
+                Z(q) => self.r[row] ^= self.x[base + q / 64] >> (q % 64) & 1 == 1,
+                Y(q) => {
+                    let (w, m) = mask(q);
+                    self.r[row] ^= ((self.x[base + w] ^ self.z[base + w]) & m) != 0;
+                }
+                Cx(a, b) => {
+                    let (xa, za) = (self.x(row, a), self.z(row, a));
+                    let (xb, zb) = (self.x(row, b), self.z(row, b));
+                    self.r[row] ^= xa && zb && (xb == za);
+                    let (wb, mb) = mask(b);
+                    let (wa, ma) = mask(a);
+                    if xa {
+                        self.x[base + wb] ^= mb;
+                    }
+                    if zb {
+                        self.z[base + wa] ^= ma;
+                    }
+                }
+            }
+        }
+    }
+
+    /// multiply the row `h` by the row `i`, keeping track of the phase
+    fn rowsum(&mut self, h: usize, i: usize) {
+        let words = self.words;
+        let (mut plus, mut minus) = (0i64, 0i64);
+        for w in 0..words {
+            let (x1, z1) = (self.x[i * words + w], self.z[i * words + w]);
+            let (x2, z2) = (self.x[h * words + w], self.z[h * words + w]);
+            // the exponent of i of each product of single qubit Paulis
+            let p = (x1 & z1 & z2 & !x2) | (x1 & !z1 & z2 & x2) | (!x1 & z1 & x2 & !z2);
+            let m = (x1 & z1 & !z2 & x2) | (x1 & !z1 & z2 & !x2) | (!x1 & z1 & x2 & z2);
+            plus += p.count_ones() as i64;
+            minus += m.count_ones() as i64;
+            self.x[h * words + w] ^= x1;
+            self.z[h * words + w] ^= z1;
+        }
+        let phase = 2 * self.r[h] as i64 + 2 * self.r[i] as i64 + plus - minus;
+        self.r[h] = phase.rem_euclid(4) == 2;
+    }
+
+    fn copy_row(&mut self, from: usize, to: usize) {
+        let words = self.words;
+        self.x
+            .copy_within(from * words..(from + 1) * words, to * words);
+        self.z
+            .copy_within(from * words..(from + 1) * words, to * words);
+        self.r[to] = self.r[from];
+    }
+
+    pub fn apply_gate(
+        &mut self,
+        name: &str,
+        params: &[f64],
+        qubits: &[usize],
+    ) -> Result<(), String> {
+        let gates = decompose(name, params)
+            .ok_or_else(|| format!("Gate {} is not a Clifford gate", name))?;
+        for gate in gates {
+            self.apply(match gate {
+                H(q) => H(qubits[q]),
+                S(q) => S(qubits[q]),
+                X(q) => X(qubits[q]),
+                Y(q) => Y(qubits[q]),
+                Z(q) => Z(qubits[q]),
+                Cx(a, b) => Cx(qubits[a], qubits[b]),
+            });
+        }
+        Ok(())
+    }
+
+    /// measure a qubit in the Z basis and collapse the state
+    pub fn measure<R: Rng>(&mut self, qubit: usize, rng: &mut R) -> u8 {
+        self.measure_with(qubit, || rng.gen())
+    }
+
+    /// Measure a qubit, a random outcome is the bit returned by `random`.
+    /// Whether an outcome is random only depends on the gates and earlier
+    /// measurements, not on their outcomes.
+    pub fn measure_with<F: FnOnce() -> bool>(&mut self, qubit: usize, random: F) -> u8 {
+        let n = self.num_qubits;
+        match (n..2 * n).find(|&p| self.x(p, qubit)) {
+            // the outcome is random
+            Some(p) => {
+                for i in 0..2 * n {
+                    if i != p && self.x(i, qubit) {
+                        self.rowsum(i, p);
+                    }
+                }
+                self.copy_row(p, p - n);
+                let words = self.words;
+                self.x[p * words..(p + 1) * words].fill(0);
+                self.z[p * words..(p + 1) * words].fill(0);
+                self.z[p * words + qubit / 64] |= 1 << (qubit % 64);
+                let outcome = random();
+                self.r[p] = outcome;
+                outcome as u8
+            }
+            // the outcome is determined by the stabilizers
+            None => {
+                let (scratch, words) = (2 * n, self.words);
+                self.x[scratch * words..].fill(0);
+                self.z[scratch * words..].fill(0);
+                self.r[scratch] = false;
+                for i in 0..n {
+                    if self.x(i, qubit) {
+                        self.rowsum(scratch, i + n);
+                    }
+                }
+                self.r[scratch] as u8
+            }
+        }
+    }
+
+    pub fn reset<R: Rng>(&mut self, qubit: usize, rng: &mut R) {
+        if self.measure(qubit, rng) == 1 {
+            self.apply(X(qubit));
+        }
+    }
+}
+
+/// Apply one instruction to the tableau, measurements are written to
+/// `clbits`
+fn apply_instruction<R: Rng>(
+    circuit: &Circuit,
+    inst: &Instruction,
+    vars: &HashMap<String, f64>,
+    state: &mut Tableau,
+    clbits: &mut [u8],
+    rng: &mut R,
+) -> Result<(), String> {
+    if let Some(condition) = &inst.condition {
+        if register_value(circuit, clbits, &condition.creg) != condition.value {
+            return Ok(());
+        }
+    }
+
+    match &inst.op {
+        Op::Gate {
+            name,
+            params,
+            qubits,
+        } => {
+            let params = params
+                .iter()
+                .map(|param| param.eval(vars))
+                .collect::<Result<Vec<f64>, String>>()?;
+            state.apply_gate(name, &params, qubits)?;
+        }
+        Op::Measure { qubit, clbit } => clbits[*clbit] = state.measure(*qubit, rng),
+        Op::Reset { qubit } => state.reset(*qubit, rng),
+        Op::Barrier { .. } => {}
+    }
+    Ok(())
+}
+
+/// Sample the shots of a circuit whose measurements are all at the end. The
+/// outcomes of the measurements of a stabilizer state are an affine function
+/// of the random outcomes: they are measured once with all random outcomes 0,
+/// and once with each random outcome 1, then every shot draws the random
+/// outcomes and adds the outcomes they flip. When there are more random
+/// outcomes than shots, each shot is measured instead.
+pub fn sample_shots(
+    circuit: &Circuit,
+    vars: &HashMap<String, f64>,
+    shots: usize,
+    seed: u64,
+) -> Result<Vec<String>, String> {
+    let mut state = Tableau::new(circuit.num_qubits());
+    let mut clbits = vec![0; circuit.num_clbits()];
+    let mut rng = shot_rng(seed, 0);
+    let mut measures = Vec::new();
+    for inst in circuit.instructions.iter() {
+        match inst.op {
+            Op::Measure { qubit, clbit } => measures.push((qubit, clbit)),
+            _ => apply_instruction(circuit, inst, vars, &mut state, &mut clbits, &mut rng)?,
+        }
+    }
+
+    // the outcomes with the random outcome `flip` 1, and the number of random
+    // outcomes
+    let outcomes = |flip: Option<usize>| {
+        let (mut state, mut clbits, mut random) = (state.clone(), clbits.clone(), 0);
+        for &(qubit, clbit) in measures.iter() {
+            clbits[clbit] = state.measure_with(qubit, || {
+                random += 1;
+                flip == Some(random - 1)
+            });
+        }
+        (clbits, random)
+    };
+    let (zero, random) = outcomes(None);
+    if random >= shots {
+        return run_shots(circuit, vars, 0..shots, seed);
+    }
+    let flips: Vec<Vec<usize>> = (0..random)
+        .map(|k| {
+            let (clbits, _) = outcomes(Some(k));
+            (0..clbits.len())
+                .filter(|&clbit| clbits[clbit] != zero[clbit])
+                .collect()
+        })
+        .collect();
+
+    Ok((0..shots)
+        .map(|shot| {
+            let mut rng = shot_rng(seed, shot);
+            let mut clbits = zero.clone();
+            for flip in flips.iter() {
+                if rng.gen::<bool>() {
+                    flip.iter().for_each(|&clbit| clbits[clbit] ^= 1);
+                }
+            }
+            circuit.format_clbits(&clbits)
+        })
+        .collect())
+}
+
+/// Simulate each shot of `shots` separately from a copy of the tableau after
+/// the gates before the first measurement, reset or condition. With terminal
+/// measurements only the measurements are repeated for each shot.
+pub fn run_shots(
+    circuit: &Circuit,
+    vars: &HashMap<String, f64>,
+    shots: std::ops::Range<usize>,
+    seed: u64,
+) -> Result<Vec<String>, String> {
+    let prefix = circuit
+        .instructions
+        .iter()
+        .position(|inst| inst.condition.is_some() || !matches!(inst.op, Op::Gate { .. }))
+        .unwrap_or(circuit.instructions.len());
+
+    let mut initial = Tableau::new(circuit.num_qubits());
+    let mut clbits = vec![0; circuit.num_clbits()];
+    let mut rng = shot_rng(seed, 0);
+    for inst in circuit.instructions[..prefix].iter() {
+        apply_instruction(circuit, inst, vars, &mut initial, &mut clbits, &mut rng)?;
+    }
+
+    shots
+        .map(|shot| {
+            let mut state = initial.clone();
+            let mut clbits = vec![0; circuit.num_clbits()];
+            let mut rng = shot_rng(seed, shot);
+            for inst in circuit.instructions[prefix..].iter() {
+                apply_instruction(circuit, inst, vars, &mut state, &mut clbits, &mut rng)?;
+            }
+            Ok(circuit.format_clbits(&clbits))
+        })
+        .collect()
+}
+
+/// The stabilizer backend, preferred for the circuits of Clifford gates
+#[derive(Debug)]
+pub struct StabilizerBackend;
+
+impl Backend for StabilizerBackend {
+    fn name(&self) -> &'static str {
+        "stabilizer"
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            name: self.name(),
+            description: "a stabilizer tableau, for Clifford circuits of thousands of qubits",
+            gates: GATES.to_vec(),
+            noise: false,
+            mid_circuit_measurements: true,
+            exact: false,
+            seeded: true,
+            vqe: false,
+            settings: vec![],
+        }
+    }
+
+    fn max_qubits(&self, memory: &MemoryBudget) -> usize {
+        widest(MAX_QUBITS, memory.budget, |n| {
+            memory_bytes(n).saturating_mul(2)
+        })
+    }
+
+    fn preferred(&self, circuit: &Circuit, info: &EmulateInfo) -> bool {
+        circuit.num_qubits() <= MAX_QUBITS && is_clifford(circuit, &info.vars)
+    }
+
+    fn prepare(&self, circuit: Arc<Circuit>, info: &EmulateInfo) -> Result<Arc<Circuit>, String> {
+        if circuit.num_qubits() > MAX_QUBITS {
+            return Err(format!(
+                "The stabilizer backend runs at most {} qubits, the circuit has {}",
+                MAX_QUBITS,
+                circuit.num_qubits()
+            ));
+        }
+        match first_non_clifford(&circuit, &info.vars) {
+            None => Ok(circuit),
+            Some(gate) => Err(format!(
+                "The stabilizer backend only runs Clifford gates, not {}",
+                gate
+            )),
+        }
+    }
+
+    fn state_bytes(&self, circuit: &Circuit, _info: &EmulateInfo) -> u64 {
+        memory_bytes(circuit.num_qubits())
+    }
+
+    /// a gate updates a column of the tableau, a measurement or a reset
+    /// updates the whole tableau
+    fn state_cost(&self, circuit: &Circuit, _info: &EmulateInfo) -> (f64, f64) {
+        let num_qubits = circuit.num_qubits() as f64;
+        (2.0 * num_qubits, num_qubits)
+    }
+
+    fn instruction_cost(
+        &self,
+        inst: &Instruction,
+        size: f64,
+        circuit: &Circuit,
+        _info: &EmulateInfo,
+    ) -> f64 {
+        match inst.op {
+            Op::Barrier { .. } => 0.0,
+            Op::Measure { .. } | Op::Reset { .. } => {
+                size * (circuit.num_qubits() as f64).max(64.0) / 64.0
+            }
+            Op::Gate { .. } => size,
+        }
+    }
+
+    fn run(&self, job: Job) -> BoxFuture<'_, Result<Output, String>> {
+        Box::pin(async move {
+            let Job {
+                pool,
+                circuit,
+                info,
+                shots,
+                seed,
+                parallel,
+                progress,
+            } = job;
+            let sequences = if circuit.measurements_are_terminal() {
+                pool.run_for(&progress, move || {
+                    sample_shots(&circuit, &info.vars, shots, seed)
+                })
+                .await
+                .and_then(|result| result)?
+            } else {
+                run_shots_parallel(pool, shots, &progress, parallel, move |range| {
+                    run_shots(&circuit, &info.vars, range, seed).map(|sequences| (sequences, ()))
+                })
+                .await?
+                .0
+            };
+            Ok(Output::new(sequences))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        circuit::qasm2,
+        simulator::{mps::gate_matrix, statevector::StateVector},
+    };
+    use num::complex::Complex64;
+
+    fn circuit(body: &str) -> Circuit {
+        qasm2::parse(&format!("OPENQASM 2.0;\ninclude \"qelib1.inc\";\n{}", body)).unwrap()
+    }
+
+    /// the matrix of the decomposition, built like `gate_matrix`
+    fn decomposed_matrix(name: &str, params: &[f64], k: usize) -> Vec<Complex64> {
+        let gates = decompose(name, params).unwrap();
+        let dim = 1 << k;
+        let mut matrix = vec![Complex64::new(0.0, 0.0); dim * dim];
+        for col in 0..dim {
+            let mut state = StateVector::new(k).unwrap();
+            state.amplitudes[0] = Complex64::new(0.0, 0.0);
+            state.amplitudes[col] = Complex64::new(1.0, 0.0);
+            for gate in gates.iter() {
+                let (name, qubits) = match *gate {
+                    H(q) => ("h", vec![q]),
+                    S(q) => ("s", vec![q]),
+                    X(q) => ("x", vec![q]),
+                    Y(q) => ("y", vec![q]),
+                    Z(q) => ("z", vec![q]),
+                    Cx(a, b) => ("cx", vec![a, b]),
+                };
+                state.apply_gate(name, &[], &qubits).unwrap();
+            }
+            for row in 0..dim {
+                matrix[row * dim + col] = state.amplitudes[row];
+            }
+        }
+        matrix
+    }
+
+    #[test]
+    fn decompositions_are_the_gates_up_to_a_phase() {
+        let turns = [0.0, FRAC_PI_2, 2.0 * FRAC_PI_2, -FRAC_PI_2];
+        for &name in GATES {
+            let (params, k) = match name {
+                "rz" | "p" | "u1" | "rx" | "ry" => (1, 1),
+                "u2" => (2, 1),
+                "u3" => (3, 1),
+                "cx" | "cz" | "cy" | "swap" => (0, 2),
+                "cp" | "cu1" | "rzz" | "rxx" => (1, 2),
+                _ => (0, 1),
+            };
+            for i in 0..turns.len().pow(params) {
+                let params: Vec<f64> = (0..params)
+                    .map(|p| turns[i / turns.len().pow(p) % turns.len()])
+                    .collect();
+                if matches!(name, "cp" | "cu1") && params[0] != 0.0 && params[0] != 2.0 * FRAC_PI_2
+                {
+                    assert!(decompose(name, &params).is_none());
+                    continue;
+                }
+                let expected = gate_matrix(name, &params, k).unwrap();
+                let actual = decomposed_matrix(name, &params, k);
+                let (j, _) = expected
+                    .iter()
+                    .enumerate()
+                    .find(|(_, x)| x.norm() > 0.1)
+                    .unwrap();
+                let phase = expected[j] / actual[j];
+                for (x, y) in expected.iter().zip(actual.iter()) {
+                    assert!((x - y * phase).norm() < 1e-9, "{}{:?}", name, params);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn non_clifford_gates() {
+        let vars = HashMap::from([("theta".to_string(), FRAC_PI_2)]);
+        assert!(is_clifford(
+            &circuit("qreg q[1];\nrz(theta) q[0];\nu2(0,pi) q[0];"),
+            &vars
+        ));
+        let c = circuit("qreg q[2];\nh q[0];\nrz(0.5) q[1];\nt q[0];");
+        assert_eq!(first_non_clifford(&c, &vars).unwrap(), "rz(0.5) q[1]");
+        assert!(!is_clifford(
+            &circuit("qreg q[1];\nrz(theta) q[0];"),
+            &HashMap::new()
+        ));
+        assert!(!is_clifford(
+            &circuit("qreg q[3];\nccx q[0],q[1],q[2];"),
+            &vars
+        ));
+    }
+
+    #[test]
+    fn measurements() {
+        let mut state = Tableau::new(2);
+        state.apply_gate("x", &[], &[1]).unwrap();
+        assert_eq!(state.measure_with(1, || panic!("random")), 1);
+        assert_eq!(state.measure_with(0, || panic!("random")), 0);
+
+        // the second qubit of a Bell pair follows the first
+        state.apply_gate("h", &[], &[0]).unwrap();
+        state.apply_gate("cx", &[], &[0, 1]).unwrap();
+        assert_eq!(state.measure_with(0, || true), 1);
+        assert_eq!(state.measure_with(1, || panic!("random")), 0);
+    }
+
+    #[test]
+    fn wide_ghz_is_sampled() {
+        let mut source = "qreg q[1000];\ncreg c[1000];\nh q[0];\n".to_string();
+        (1..1000).for_each(|i| source += &format!("cx q[{}],q[{}];\n", i - 1, i));
+        source += "measure q -> c;";
+        let sequences = sample_shots(&circuit(&source), &HashMap::new(), 40, 5).unwrap();
+        assert!(sequences
+            .iter()
+            .all(|s| s == &"0".repeat(1000) || s == &"1".repeat(1000)));
+        assert!(sequences.iter().any(|s| s.starts_with('1')));
+        assert!(sequences.iter().any(|s| s.starts_with('0')));
+    }
+
+    #[test]
+    fn sampling_matches_the_shots() {
+        let source =
+            "qreg q[3];\ncreg c[3];\nh q[0];\nh q[2];\ncx q[0],q[1];\ns q[2];\nmeasure q -> c;";
+        let c = circuit(source);
+        let sampled = sample_shots(&c, &HashMap::new(), 400, 9).unwrap();
+        let simulated = run_shots(&c, &HashMap::new(), 0..400, 9).unwrap();
+        for sequences in [&sampled, &simulated] {
+            let mut counts = HashMap::new();
+            sequences
+                .iter()
+                .for_each(|s| *counts.entry(s.as_str()).or_insert(0) += 1);
+            // q[1] follows q[0], q[2] is independent
+            assert_eq!(counts.len(), 4, "{:?}", counts);
+            assert!(counts.values().all(|&count| count > 60));
+        }
+    }
+
+    #[test]
+    fn conditions_follow_the_measurements() {
+        let source = "qreg q[2];\ncreg c[2];\nh q[0];\nmeasure q[0] -> c[0];\nif(c==1) x q[1];\nmeasure q[1] -> c[1];";
+        let sequences = run_shots(&circuit(source), &HashMap::new(), 0..40, 1).unwrap();
+        assert!(sequences.iter().all(|s| s == "00" || s == "11"));
+        assert!(sequences.iter().any(|s| s == "11"));
+    }
+}
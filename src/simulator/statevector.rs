@@ -0,0 +1,469 @@
+use std::{collections::HashMap, sync::Arc};
+
+use num::complex::Complex64;
+use rand::Rng;
+use rand_chacha::{rand_core::SeedableRng, ChaCha8Rng};
+
+use crate::{
+    circuit::{
+        gates::{controlled_1q, matrix_1q, Matrix2},
+        Circuit, Instruction, Op,
+    },
+    emulate::EmulateInfo,
+    pool::{statevector_bytes, MemoryBudget},
+};
+
+use super::{
+    backend::{all_gates, Backend, BoxFuture, Capabilities, Job, Output},
+    run_shots_parallel,
+};
+
+/// the widest circuit the backend simulates, 16 TiB of amplitudes
+pub const MAX_QUBITS: usize = 40;
+
+fn check_width(num_qubits: usize) -> Result<(), String> {
+    if num_qubits > MAX_QUBITS {
+        return Err(format!(
+            "The statevector backend simulates at most {} qubits, the circuit has {}",
+            MAX_QUBITS, num_qubits
+        ));
+    }
+    Ok(())
+}
+
+/// State vector of `num_qubits` qubits, qubit `k` is the bit `k` of the basis
+/// index. It simulates the slices of shots of a job with their own seeded
+/// random streams, which qasmsim cannot.
+#[derive(Debug, Clone)]
+pub struct StateVector {
+    pub num_qubits: usize,
+    pub amplitudes: Vec<Complex64>,
+}
+
+impl StateVector {
+    pub fn new(num_qubits: usize) -> Result<Self, String> {
+        check_width(num_qubits)?;
+        let mut amplitudes = vec![Complex64::new(0.0, 0.0); 1 << num_qubits];
+        amplitudes[0] = Complex64::new(1.0, 0.0);
+        Ok(StateVector {
+            num_qubits,
+            amplitudes,
+        })
+    }
+
+    /// apply `matrix` on `target` for the basis states where all `controls`
+    /// are 1
+    pub fn apply_controlled(&mut self, controls: &[usize], target: usize, matrix: &Matrix2) {
+        let control_mask = controls.iter().fold(0, |mask, &qubit| mask | 1 << qubit);
+        let target_bit = 1 << target;
+        for i in 0..self.amplitudes.len() {
+            if i & target_bit == 0 && i & control_mask == control_mask {
+                let j = i | target_bit;
+                let (a, b) = (self.amplitudes[i], self.amplitudes[j]);
+                self.amplitudes[i] = matrix[0][0] * a + matrix[0][1] * b;
+                self.amplitudes[j] = matrix[1][0] * a + matrix[1][1] * b;
+            }
+        }
+    }
+
+    pub fn apply_swap(&mut self, controls: &[usize], a: usize, b: usize) {
+        let control_mask = controls.iter().fold(0, |mask, &qubit| mask | 1 << qubit);
+        let (a_bit, b_bit) = (1 << a, 1 << b);
+        for i in 0..self.amplitudes.len() {
+            if i & a_bit != 0 && i & b_bit == 0 && i & control_mask == control_mask {
+                self.amplitudes.swap(i, i ^ a_bit ^ b_bit);
+            }
+        }
+    }
+
+    /// `exp(-i theta/2 Z⊗Z)`
+    pub fn apply_rzz(&mut self, theta: f64, a: usize, b: usize) {
+        let even = Complex64::from_polar(1.0, -theta / 2.0);
+        let odd = Complex64::from_polar(1.0, theta / 2.0);
+        for (i, amplitude) in self.amplitudes.iter_mut().enumerate() {
+            if ((i >> a) ^ (i >> b)) & 1 == 0 {
+                *amplitude *= even;
+            } else {
+                *amplitude *= odd;
+            }
+        }
+    }
+
+    pub fn apply_gate(
+        &mut self,
+        name: &str,
+        params: &[f64],
+        qubits: &[usize],
+    ) -> Result<(), String> {
+        if let Some(matrix) = matrix_1q(name, params) {
+            self.apply_controlled(&[], qubits[0], &matrix);
+        } else if let Some((controls, matrix)) = controlled_1q(name, params) {
+            self.apply_controlled(&qubits[..controls], qubits[controls], &matrix);
+        } else {
+            match (name, params) {
+                ("swap", []) => self.apply_swap(&[], qubits[0], qubits[1]),
+                ("cswap", []) => self.apply_swap(&qubits[..1], qubits[1], qubits[2]),
+                ("rzz", [theta]) => self.apply_rzz(*theta, qubits[0], qubits[1]),
+                ("rxx", [theta]) => {
+                    let h = matrix_1q("h", &[]).unwrap();
+                    self.apply_controlled(&[], qubits[0], &h);
+                    self.apply_controlled(&[], qubits[1], &h);
+                    self.apply_rzz(*theta, qubits[0], qubits[1]);
+                    self.apply_controlled(&[], qubits[0], &h);
+                    self.apply_controlled(&[], qubits[1], &h);
+                }
+                _ => return Err(format!("Gate {} is not supported by the simulator", name)),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn probability_one(&self, qubit: usize) -> f64 {
+        self.amplitudes
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| i >> qubit & 1 == 1)
+            .map(|(_, amplitude)| amplitude.norm_sqr())
+            .sum()
+    }
+
+    pub fn probabilities(&self) -> Vec<f64> {
+        self.amplitudes
+            .iter()
+            .map(|amplitude| amplitude.norm_sqr())
+            .collect()
+    }
+
+    /// measure a qubit and collapse the state
+    pub fn measure<R: Rng>(&mut self, qubit: usize, rng: &mut R) -> u8 {
+        let p1 = self.probability_one(qubit);
+        let outcome = u8::from(rng.gen::<f64>() < p1);
+        let norm = if outcome == 1 { p1 } else { 1.0 - p1 }.sqrt();
+        for (i, amplitude) in self.amplitudes.iter_mut().enumerate() {
+            if (i >> qubit & 1) as u8 == outcome {
+                *amplitude /= norm;
+            } else {
+                *amplitude = Complex64::new(0.0, 0.0);
+            }
+        }
+        outcome
+    }
+
+    pub fn reset<R: Rng>(&mut self, qubit: usize, rng: &mut R) {
+        if self.measure(qubit, rng) == 1 {
+            self.apply_controlled(&[], qubit, &matrix_1q("x", &[]).unwrap());
+        }
+    }
+}
+
+/// value of a classical register, bit `k` is `reg[k]`
+pub fn register_value(circuit: &Circuit, clbits: &[u8], creg: &str) -> u64 {
+    circuit.creg(creg).map_or(0, |reg| {
+        clbits[reg.offset..reg.offset + reg.size]
+            .iter()
+            .rev()
+            .fold(0, |value, &bit| value << 1 | bit as u64)
+    })
+}
+
+/// Apply one instruction to the state, measurements are written to `clbits`
+pub fn apply_instruction<R: Rng>(
+    circuit: &Circuit,
+    inst: &Instruction,
+    vars: &HashMap<String, f64>,
+    state: &mut StateVector,
+    clbits: &mut [u8],
+    rng: &mut R,
+) -> Result<(), String> {
+    if let Some(condition) = &inst.condition {
+        if register_value(circuit, clbits, &condition.creg) != condition.value {
+            return Ok(());
+        }
+    }
+
+    match &inst.op {
+        Op::Gate {
+            name,
+            params,
+            qubits,
+        } => {
+            let params = params
+                .iter()
+                .map(|param| param.eval(vars))
+                .collect::<Result<Vec<f64>, String>>()?;
+            state.apply_gate(name, &params, qubits)?;
+        }
+        Op::Measure { qubit, clbit } => clbits[*clbit] = state.measure(*qubit, rng),
+        Op::Reset { qubit } => state.reset(*qubit, rng),
+        Op::Barrier { .. } => {}
+    }
+    Ok(())
+}
+
+/// the random generator of a shot, every shot has its own stream so that the
+/// results do not depend on how the shots are split
+pub fn shot_rng(seed: u64, shot: usize) -> ChaCha8Rng {
+    let mut rng = ChaCha8Rng::seed_from_u64(seed);
+    rng.set_stream(shot as u64);
+    rng
+}
+
+/// Simulate each shot of `shots` separately, returns the bitstring of each
+/// shot
+pub fn run_shots(
+    circuit: &Circuit,
+    vars: &HashMap<String, f64>,
+    shots: std::ops::Range<usize>,
+    seed: u64,
+) -> Result<Vec<String>, String> {
+    // the gates before the first measurement, reset or condition are the
+    // same for all shots
+    let prefix = circuit
+        .instructions
+        .iter()
+        .position(|inst| inst.condition.is_some() || !matches!(inst.op, Op::Gate { .. }))
+        .unwrap_or(circuit.instructions.len());
+
+    let mut initial = StateVector::new(circuit.num_qubits())?;
+    let mut clbits = vec![0; circuit.num_clbits()];
+    let mut rng = shot_rng(seed, 0);
+    for inst in circuit.instructions[..prefix].iter() {
+        apply_instruction(circuit, inst, vars, &mut initial, &mut clbits, &mut rng)?;
+    }
+
+    shots
+        .map(|shot| {
+            let mut state = initial.clone();
+            let mut clbits = vec![0; circuit.num_clbits()];
+            let mut rng = shot_rng(seed, shot);
+            for inst in circuit.instructions[prefix..].iter() {
+                apply_instruction(circuit, inst, vars, &mut state, &mut clbits, &mut rng)?;
+            }
+            Ok(circuit.format_clbits(&clbits))
+        })
+        .collect()
+}
+
+/// Simulate the gates once and sample every shot from the final
+/// probabilities. Only valid when `circuit.measurements_are_terminal()`.
+pub fn sample_shots(
+    circuit: &Circuit,
+    vars: &HashMap<String, f64>,
+    shots: usize,
+    seed: u64,
+) -> Result<Vec<String>, String> {
+    let mut state = StateVector::new(circuit.num_qubits())?;
+    let mut measures = Vec::new();
+    for inst in circuit.instructions.iter() {
+        match &inst.op {
+            Op::Gate {
+                name,
+                params,
+                qubits,
+            } => {
+                let params = params
+                    .iter()
+                    .map(|param| param.eval(vars))
+                    .collect::<Result<Vec<f64>, String>>()?;
+                state.apply_gate(name, &params, qubits)?;
+            }
+            Op::Measure { qubit, clbit } => measures.push((*qubit, *clbit)),
+            Op::Reset { .. } => return Err("Reset is not a terminal measurement".to_string()),
+            Op::Barrier { .. } => {}
+        }
+    }
+
+    let mut cumulative = state.probabilities();
+    for i in 1..cumulative.len() {
+        cumulative[i] += cumulative[i - 1];
+    }
+    let total = *cumulative.last().unwrap();
+
+    Ok((0..shots)
+        .map(|shot| {
+            let r = shot_rng(seed, shot).gen::<f64>() * total;
+            let index = cumulative
+                .partition_point(|&p| p <= r)
+                .min(cumulative.len() - 1);
+            let mut clbits = vec![0; circuit.num_clbits()];
+            for &(qubit, clbit) in measures.iter() {
+                clbits[clbit] = (index >> qubit & 1) as u8;
+            }
+            circuit.format_clbits(&clbits)
+        })
+        .collect())
+}
+
+/// The default backend, all the amplitudes of the state
+#[derive(Debug)]
+pub struct StatevectorBackend;
+
+impl Backend for StatevectorBackend {
+    fn name(&self) -> &'static str {
+        "statevector"
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            name: self.name(),
+            description: "all the amplitudes, up to about 30 qubits",
+            gates: all_gates(),
+            noise: false,
+            mid_circuit_measurements: true,
+            exact: false,
+            seeded: true,
+            vqe: false,
+            settings: vec![],
+        }
+    }
+
+    fn max_qubits(&self, memory: &MemoryBudget) -> usize {
+        memory.max_qubits().min(MAX_QUBITS)
+    }
+
+    fn prepare(&self, circuit: Arc<Circuit>, _info: &EmulateInfo) -> Result<Arc<Circuit>, String> {
+        check_width(circuit.num_qubits())?;
+        Ok(circuit)
+    }
+
+    fn state_bytes(&self, circuit: &Circuit, _info: &EmulateInfo) -> u64 {
+        statevector_bytes(circuit.num_qubits())
+    }
+
+    fn state_cost(&self, circuit: &Circuit, _info: &EmulateInfo) -> (f64, f64) {
+        (2f64.powf(circuit.num_qubits() as f64), 1.0)
+    }
+
+    fn run(&self, job: Job) -> BoxFuture<'_, Result<Output, String>> {
+        Box::pin(async move {
+            let Job {
+                pool,
+                circuit,
+                info,
+                shots,
+                seed,
+                parallel,
+                progress,
+            } = job;
+            let sequences = if circuit.measurements_are_terminal() {
+                pool.run_for(&progress, move || {
+                    sample_shots(&circuit, &info.vars, shots, seed)
+                })
+                .await
+                .and_then(|result| result)?
+            } else {
+                run_shots_parallel(pool, shots, &progress, parallel, move |range| {
+                    run_shots(&circuit, &info.vars, range, seed).map(|sequences| (sequences, ()))
+                })
+                .await?
+                .0
+            };
+            Ok(Output::new(sequences))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::circuit::qasm2;
+
+    fn circuit(body: &str) -> Circuit {
+        qasm2::parse(&format!("OPENQASM 2.0;\ninclude \"qelib1.inc\";\n{}", body)).unwrap()
+    }
+
+    fn state_after(gates: &[(&str, &[f64], &[usize])], num_qubits: usize) -> StateVector {
+        let mut state = StateVector::new(num_qubits).unwrap();
+        for (name, params, qubits) in gates {
+            state.apply_gate(name, params, qubits).unwrap();
+        }
+        state
+    }
+
+    fn assert_probabilities(state: &StateVector, expected: &[f64]) {
+        for (p, q) in state.probabilities().iter().zip(expected) {
+            assert!((p - q).abs() < 1e-12, "{:?}", state.probabilities());
+        }
+    }
+
+    #[test]
+    fn width_is_capped() {
+        let err = StateVector::new(MAX_QUBITS + 1).unwrap_err();
+        assert!(err.contains("at most 40 qubits"), "{}", err);
+        assert_eq!(StateVector::new(3).unwrap().amplitudes.len(), 8);
+    }
+
+    #[test]
+    fn qubit_k_is_bit_k_of_the_index() {
+        let state = state_after(&[("x", &[], &[1])], 3);
+        assert_probabilities(&state, &[0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn multi_qubit_gates() {
+        // ccx flips the target only when both controls are set
+        let state = state_after(&[("x", &[], &[0]), ("ccx", &[], &[0, 1, 2])], 3);
+        assert_probabilities(&state, &[0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0]);
+        let state = state_after(
+            &[("x", &[], &[0]), ("x", &[], &[1]), ("ccx", &[], &[0, 1, 2])],
+            3,
+        );
+        assert_probabilities(&state, &[0.0; 7]);
+        assert!((state.probabilities()[7] - 1.0).abs() < 1e-12);
+
+        let state = state_after(&[("x", &[], &[0]), ("swap", &[], &[0, 2])], 3);
+        assert!((state.probabilities()[4] - 1.0).abs() < 1e-12);
+        let state = state_after(&[("x", &[], &[1]), ("cswap", &[], &[0, 1, 2])], 3);
+        assert!((state.probabilities()[2] - 1.0).abs() < 1e-12);
+
+        // rxx(pi) maps |00> to -i|11>
+        let state = state_after(&[("rxx", &[std::f64::consts::PI], &[0, 1])], 2);
+        assert!((state.amplitudes[3] - Complex64::new(0.0, -1.0)).norm() < 1e-12);
+        assert!(state_after(&[], 1).apply_gate("foo", &[], &[0]).is_err());
+    }
+
+    #[test]
+    fn sampling_follows_the_probabilities() {
+        let bell = circuit("qreg q[2];\ncreg c[2];\nh q[0];\ncx q[0],q[1];\nmeasure q -> c;\n");
+        let shots = sample_shots(&bell, &HashMap::new(), 1000, 7).unwrap();
+        assert!(shots.iter().all(|shot| shot == "00" || shot == "11"));
+        let ones = shots.iter().filter(|shot| *shot == "11").count();
+        assert!((400..600).contains(&ones), "{}", ones);
+        assert_eq!(
+            shots,
+            sample_shots(&bell, &HashMap::new(), 1000, 7).unwrap()
+        );
+    }
+
+    #[test]
+    fn shots_do_not_depend_on_the_split() {
+        let program = circuit(
+            "qreg q[2];\ncreg c[2];\nh q[0];\nmeasure q[0] -> c[0];\n\
+             if(c==1) x q[1];\nmeasure q[1] -> c[1];\n",
+        );
+        let vars = HashMap::new();
+        let all = run_shots(&program, &vars, 0..20, 3).unwrap();
+        let mut split = run_shots(&program, &vars, 0..8, 3).unwrap();
+        split.extend(run_shots(&program, &vars, 8..20, 3).unwrap());
+        assert_eq!(all, split);
+        // the condition copies the first bit
+        assert!(all.iter().all(|shot| shot == "00" || shot == "11"));
+    }
+
+    #[test]
+    fn reset_returns_to_zero() {
+        let program =
+            circuit("qreg q[1];\ncreg c[1];\nh q[0];\nreset q[0];\nmeasure q[0] -> c[0];\n");
+        let shots = run_shots(&program, &HashMap::new(), 0..50, 1).unwrap();
+        assert!(shots.iter().all(|shot| shot == "0"));
+    }
+
+    #[test]
+    fn variables_are_bound() {
+        let program = circuit("qreg q[1];\ncreg c[1];\nrx(theta) q[0];\nmeasure q[0] -> c[0];\n");
+        let vars = HashMap::from([("theta".to_string(), std::f64::consts::PI)]);
+        let shots = sample_shots(&program, &vars, 10, 0).unwrap();
+        assert!(shots.iter().all(|shot| shot == "1"));
+        assert!(sample_shots(&program, &HashMap::new(), 10, 0).is_err());
+    }
+}
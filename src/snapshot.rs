@@ -0,0 +1,170 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    jobs::{now_secs, JobStore},
+    qubits::{QMemory, QResgister},
+    ServerState,
+};
+
+/// Version of the snapshots written by this agent. Version 0 is the bare
+/// `QMemory` pickle at `MEASURE_PATH`, it can be restored as a snapshot with
+/// an idle register and no jobs.
+pub const SNAPSHOT_VERSION: u64 = 1;
+
+/// A point-in-time copy of the `ServerState`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Snapshot {
+    pub version: u64,
+    /// unix timestamp in seconds
+    pub created: u64,
+    pub qmem: QMemory,
+    pub qreg: QResgister,
+    pub jobs: JobStore,
+}
+
+impl Snapshot {
+    pub fn take(state: &ServerState) -> Self {
+        Snapshot {
+            version: SNAPSHOT_VERSION,
+            created: now_secs(),
+            qmem: state.qmem.clone(),
+            qreg: state.qreg.clone(),
+            jobs: state.jobs.clone(),
+        }
+    }
+
+    /// Replace the state with the snapshot. The retention policy of the
+    /// running agent is kept. The qubits of the jobs which were running when
+    /// the snapshot was taken are idle, the ones of the jobs running now stay
+    /// allocated and are released to the restored register when they finish.
+    pub fn restore(self, state: &mut ServerState) {
+        let policy = state.jobs.policy.clone();
+        let busy = state.qreg.busy();
+        state.qmem = self.qmem;
+        state.qreg = self.qreg;
+        state.qreg.idle = state.qreg.qubits.len().saturating_sub(busy);
+        state.jobs = self.jobs;
+        state.jobs.policy = policy;
+        state.jobs.evict();
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec(self).unwrap()
+    }
+
+    /// read a snapshot of any supported version
+    pub fn from_bytes(data: &[u8]) -> Result<Self, String> {
+        let value = match serde_json::from_slice::<Value>(data) {
+            Ok(value) => value,
+            Err(_) => {
+                let qmem = QMemory::from_pickle(data)
+                    .map_err(|err| format!("Invalid snapshot: {}", err))?;
+                return Ok(Snapshot {
+                    version: 0,
+                    created: now_secs(),
+                    qreg: QResgister::new(qmem.qubits),
+                    qmem,
+                    jobs: JobStore::default(),
+                });
+            }
+        };
+
+        match value.get("version").and_then(Value::as_u64) {
+            Some(SNAPSHOT_VERSION) => {
+                serde_json::from_value(value).map_err(|err| format!("Invalid snapshot: {}", err))
+            }
+            Some(version) => Err(format!(
+                "Snapshot version {} is not supported, the latest is {}",
+                version, SNAPSHOT_VERSION
+            )),
+            None => Err("Snapshot has no version header".to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::state;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn snapshot_has_the_version_header() {
+        let state = state(4);
+        let mut state_w = state.write().await;
+        state_w.jobs.insert("a", json!({"Result": "Success"}));
+        let bytes = Snapshot::take(&state_w).to_bytes();
+        let value: Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(value["version"], SNAPSHOT_VERSION);
+
+        let snapshot = Snapshot::from_bytes(&bytes).unwrap();
+        assert_eq!(snapshot.version, SNAPSHOT_VERSION);
+        assert!(snapshot.jobs.jobs.contains_key("a"));
+    }
+
+    #[test]
+    fn unknown_versions_are_rejected() {
+        let snapshot = |value: Value| Snapshot::from_bytes(value.to_string().as_bytes());
+        assert_eq!(
+            snapshot(json!({"version": 2})).unwrap_err(),
+            "Snapshot version 2 is not supported, the latest is 1"
+        );
+        assert_eq!(
+            snapshot(json!({"qmem": {}})).unwrap_err(),
+            "Snapshot has no version header"
+        );
+        assert!(snapshot(json!({"version": 1}))
+            .unwrap_err()
+            .starts_with("Invalid snapshot: "));
+        assert!(Snapshot::from_bytes(b"not a snapshot")
+            .unwrap_err()
+            .starts_with("Invalid snapshot: "));
+    }
+
+    #[test]
+    fn legacy_pickle_is_restored_as_version_0() {
+        // the `QMemory` dumped before the generations were tracked, the oldest
+        // row is at current_pos
+        let legacy = json!({
+            "mem": [[0, 1], [1, 0], [1, 1]],
+            "qubits": 2,
+            "capacity": 3,
+            "current_pos": 1,
+        });
+        let data =
+            serde_pickle::to_vec(&legacy, serde_pickle::SerOptions::new().proto_v2()).unwrap();
+        let snapshot = Snapshot::from_bytes(&data).unwrap();
+        assert_eq!(snapshot.version, 0);
+        assert_eq!(snapshot.qmem.mem, [[0, 1], [1, 0], [1, 1]]);
+        assert_eq!(snapshot.qmem.generations, [3, 1, 2]);
+        assert_eq!(snapshot.qreg.idle, 2);
+        assert!(snapshot.jobs.jobs.is_empty());
+    }
+
+    #[tokio::test]
+    async fn idle_qubits_are_recomputed_on_restore() {
+        let state = state(4);
+        let mut state_w = state.write().await;
+        // a job of 3 qubits runs while the snapshot is taken
+        state_w.qreg.idle = 1;
+        let snapshot = Snapshot::take(&state_w);
+        // it finished, and a job of 1 qubit runs while it is restored
+        state_w.qreg.idle = 3;
+        snapshot.restore(&mut state_w);
+        assert_eq!(state_w.qreg.idle, 3);
+
+        // the job releases its qubit to the restored register
+        state_w.qreg.release(1);
+        assert_eq!(state_w.qreg.idle, 4);
+
+        // a smaller register leaves no qubit idle while the job runs
+        state_w.qreg.idle = 1;
+        let mut snapshot = Snapshot::take(&state_w);
+        snapshot.qreg = QResgister::new(2);
+        snapshot.restore(&mut state_w);
+        assert_eq!(state_w.qreg.idle, 0);
+        state_w.qreg.release(3);
+        assert_eq!(state_w.qreg.idle, 2);
+    }
+}
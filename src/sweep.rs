@@ -0,0 +1,245 @@
+use std::collections::{BTreeMap, HashMap};
+
+use axum::{http::StatusCode, Json};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{
+    batch,
+    emulate::{EmulateMessage, EmulateMode},
+    SharedState,
+};
+
+/// the most values of a swept variable
+pub const MAX_SWEEP_VALUES: usize = 10_000;
+/// the most points of a sweep
+pub const MAX_SWEEP_POINTS: usize = 10_000;
+
+/// The values of a swept variable, a list or `num` evenly spaced values from
+/// `start` to `stop` inclusive
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum SweepValues {
+    List(Vec<f64>),
+    Linspace { start: f64, stop: f64, num: usize },
+}
+
+impl SweepValues {
+    pub fn len(&self) -> usize {
+        match self {
+            SweepValues::List(values) => values.len(),
+            SweepValues::Linspace { num, .. } => *num,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn values(&self) -> Vec<f64> {
+        match self {
+            SweepValues::List(values) => values.clone(),
+            SweepValues::Linspace { start, stop, num } => match num {
+                0 => Vec::new(),
+                1 => vec![*start],
+                _ => (0..*num)
+                    .map(|i| start + (stop - start) * i as f64 / (num - 1) as f64)
+                    .collect(),
+            },
+        }
+    }
+}
+
+/// For sweep mode, the points are the Cartesian grid of the variables, or
+/// with `zip` the values of the same index, which then need the same length.
+/// `mode` is the result mode of each point, aggregation by default.
+#[derive(Deserialize, Debug, Clone)]
+pub struct SweepSpec {
+    pub vars: BTreeMap<String, SweepValues>,
+    #[serde(default)]
+    pub zip: bool,
+    pub mode: Option<EmulateMode>,
+}
+
+impl SweepSpec {
+    /// the values of the variables in `vars` order, for each point, at most
+    /// `MAX_SWEEP_VALUES` for each variable and `MAX_SWEEP_POINTS` in all
+    pub fn points(&self) -> Result<Vec<Vec<f64>>, String> {
+        if self.vars.values().any(|values| values.is_empty()) {
+            return Err("Every swept variable needs at least one value".to_string());
+        }
+        if let Some((name, values)) = self
+            .vars
+            .iter()
+            .find(|(_, values)| values.len() > MAX_SWEEP_VALUES)
+        {
+            return Err(format!(
+                "Variable {} has {} values, a sweep takes at most {}",
+                name,
+                values.len(),
+                MAX_SWEEP_VALUES
+            ));
+        }
+        let num_points = if self.zip {
+            self.vars.values().map(|values| values.len()).max()
+        } else {
+            self.vars
+                .values()
+                .try_fold(1usize, |points, values| points.checked_mul(values.len()))
+        };
+        if num_points.unwrap_or(usize::MAX) > MAX_SWEEP_POINTS {
+            return Err(format!(
+                "The sweep has too many points, at most {} are allowed",
+                MAX_SWEEP_POINTS
+            ));
+        }
+
+        let values: Vec<Vec<f64>> = self.vars.values().map(|values| values.values()).collect();
+
+        if self.zip {
+            let len = values.first().map(|values| values.len()).unwrap_or(0);
+            if values.iter().any(|values| values.len() != len) {
+                return Err("Zipped variables need the same number of values".to_string());
+            }
+            Ok((0..len)
+                .map(|i| values.iter().map(|values| values[i]).collect())
+                .collect())
+        } else {
+            let mut points = vec![Vec::new()];
+            for values in values.iter() {
+                points = points
+                    .into_iter()
+                    .flat_map(|point| {
+                        values.iter().map(move |value| {
+                            let mut point = point.clone();
+                            point.push(*value);
+                            point
+                        })
+                    })
+                    .collect();
+            }
+            Ok(points)
+        }
+    }
+}
+
+/// Run the task once for each point of the sweep, in parallel on the compute
+/// pool with the seed of the task. The result is a table with a row for each
+/// point, its variables and the result of the point.
+pub async fn consume_sweep(
+    state: SharedState,
+    message: EmulateMessage,
+) -> (StatusCode, Json<Value>) {
+    let spec = match serde_json::from_str::<SweepSpec>(message.sweep.as_deref().unwrap_or("")) {
+        Ok(spec) => spec,
+        Err(_) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({"Error": "Invalid sweep"})),
+            )
+        }
+    };
+    let mode = spec.mode.clone().unwrap_or(EmulateMode::Aggregation);
+    if let EmulateMode::Vqe | EmulateMode::Sweep = mode {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"Error": format!("{} is not a result mode of a sweep", mode)})),
+        );
+    }
+    let points = match spec.points() {
+        Ok(points) => points,
+        Err(err) => return (StatusCode::BAD_REQUEST, Json(json!({ "Error": err }))),
+    };
+    // the swept variables extend the fixed ones
+    let vars =
+        match serde_json::from_str::<HashMap<String, f64>>(message.vars.as_deref().unwrap_or("{}"))
+        {
+            Ok(vars) => vars,
+            Err(_) => {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    Json(json!({"Error": "Invalid vars"})),
+                )
+            }
+        };
+
+    let names: Vec<String> = spec.vars.keys().cloned().collect();
+    let tasks = points
+        .iter()
+        .enumerate()
+        .map(|(index, point)| {
+            let mut vars = vars.clone();
+            vars.extend(names.iter().cloned().zip(point.iter().cloned()));
+            let mut task = message.clone();
+            task.mode = Some(mode.clone());
+            task.vars = Some(serde_json::to_string(&vars).unwrap());
+            task.sweep = None;
+            task.job_id = batch::point_job_id(&message.job_id, index);
+            Ok(task)
+        })
+        .collect();
+
+    match batch::run_tasks(state, tasks).await {
+        Ok(results) => {
+            let rows: Vec<Value> = points
+                .iter()
+                .zip(results)
+                .map(|(point, mut result)| {
+                    result["vars"] =
+                        json!(names.iter().zip(point.iter()).collect::<BTreeMap<_, _>>());
+                    result
+                })
+                .collect();
+            (
+                StatusCode::OK,
+                Json(json!({
+                    "mode": mode.to_string(),
+                    "vars": names,
+                    "seed": message.seed,
+                    "Result": rows,
+                })),
+            )
+        }
+        Err(err) => err,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(vars: Value, zip: bool) -> SweepSpec {
+        serde_json::from_value(json!({ "vars": vars, "zip": zip })).unwrap()
+    }
+
+    #[test]
+    fn grid_and_zip_points() {
+        let vars = json!({"a": [1.0, 2.0], "b": {"start": 0.0, "stop": 1.0, "num": 3}});
+        let points = spec(vars, false).points().unwrap();
+        assert_eq!(points.len(), 6);
+        assert_eq!(points[1], vec![1.0, 0.5]);
+        let vars = json!({"a": [1.0, 2.0], "b": {"start": 0.0, "stop": 1.0, "num": 2}});
+        assert_eq!(
+            spec(vars, true).points().unwrap(),
+            vec![vec![1.0, 0.0], vec![2.0, 1.0]]
+        );
+        let vars = json!({"a": [1.0, 2.0], "b": [1.0]});
+        assert!(spec(vars, true).points().is_err());
+    }
+
+    #[test]
+    fn sweep_size_is_capped() {
+        let vars = json!({"a": {"start": 0.0, "stop": 1.0, "num": MAX_SWEEP_VALUES + 1}});
+        let err = spec(vars, false).points().unwrap_err();
+        assert!(err.starts_with("Variable a has"), "{}", err);
+
+        let linspace = json!({"start": 0.0, "stop": 1.0, "num": 1000});
+        let vars = json!({"a": linspace, "b": linspace});
+        let err = spec(vars.clone(), false).points().unwrap_err();
+        assert!(err.contains("too many points"), "{}", err);
+        assert_eq!(spec(vars, true).points().unwrap().len(), 1000);
+
+        let huge = json!({"start": 0.0, "stop": 1.0, "num": usize::MAX});
+        assert!(spec(json!({"a": huge}), false).points().is_err());
+    }
+}
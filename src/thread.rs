@@ -1,48 +1,341 @@
-use crate::SharedState;
+use crate::{
+    circuit::{self, cache::CircuitCache, optimize, qasm2, qasm3, routing, transpile, Circuit},
+    pool::{self, statevector_bytes, ComputePool, MemoryReservation},
+    progress::Progress,
+    qubits::{parse_cregs, parse_qregs},
+    simulator::{
+        self,
+        backend::{Backend, BackendRegistry, Job, DEFAULT_BACKEND},
+        density, external,
+    },
+    SharedState,
+};
 
 use super::emulate::{
-    post_process_msg, post_process_msg_vqe, pre_process_msg, pre_process_msg_vqe, EmulateInfo,
-    EmulateMessage,
+    bind_vars, post_process_msg, post_process_msg_vqe, pre_process_msg, pre_process_msg_vqe,
+    EmulateInfo, EmulateMessage, EmulateResult,
 };
 use axum::{http::StatusCode, Json};
 use serde_json::{json, Value};
-use std::collections::HashMap;
+use std::{collections::HashMap, sync::Arc};
 use tokio::sync::oneshot;
 
 /// TODO: merge quantum_thread and quantum_thread_vqe
-/// quantum thread for aggregation, max, min, expectation, and sequence
+/// quantum thread for aggregation, max, min, expectation, and sequence. The
+/// cached circuit is compiled and run by the backend the registry selects,
+/// see `run_circuit`, or by qasmsim, see `fallback_to_qasmsim`.
 pub async fn quantum_thread(
+    pool: Arc<ComputePool>,
+    cache: Arc<CircuitCache>,
+    backends: Arc<BackendRegistry>,
+    progress: Progress,
     msg_rx: oneshot::Receiver<EmulateInfo>,
-    res_tx: oneshot::Sender<Result<qasmsim::Execution, String>>,
+    res_tx: oneshot::Sender<Result<EmulateResult, String>>,
 ) {
-    let msg = msg_rx.await.unwrap();
-    match qasmsim::run_mode(&msg.qasm, msg.shots, "sequence".to_string()) {
-        Ok(result) => {
-            // send the result to the classical_thread
-            res_tx.send(Ok(result)).unwrap()
-        }
+    let mut msg = msg_rx.await.unwrap();
+    let shots = msg.shots.unwrap_or(1);
+    let mut metadata = serde_json::Map::new();
+    let mut exact = serde_json::Map::new();
+
+    let result = match cache.get_or_parse(&msg.template) {
+        Ok(circuit) => match compile_circuit(circuit, &mut msg, &mut metadata) {
+            Ok(circuit) => {
+                let seed = msg.seed.unwrap_or_else(rand::random);
+                metadata.insert("seed".to_string(), json!(seed));
+                match backends.select(&msg, &circuit) {
+                    Ok(backend) => {
+                        run_circuit(
+                            pool,
+                            circuit,
+                            Arc::new(msg),
+                            shots,
+                            seed,
+                            backend.as_ref(),
+                            &progress,
+                            &mut metadata,
+                            &mut exact,
+                        )
+                        .await
+                    }
+                    Err(err) => Err(err),
+                }
+            }
+            Err(err) => Err(err),
+        },
         Err(err) => {
-            // if there are some errors, send the error message to the classical_thread
-            res_tx.send(Err(err.to_string())).unwrap()
+            match fallback_to_qasmsim(&msg, err).and_then(|_| reserve_declared(&pool, &msg.qasm)) {
+                Ok(_reservation) => match backends.get(external::QASMSIM) {
+                    Ok(backend) => {
+                        metadata.insert("seed".to_string(), Value::Null);
+                        metadata.insert("simulation_path".to_string(), json!("qasmsim"));
+                        backend
+                            .run_program(pool, msg.qasm.clone(), msg.shots, progress.clone())
+                            .await
+                            .inspect(|sequences| simulator::publish_sampled(&progress, sequences))
+                    }
+                    Err(err) => Err(err),
+                },
+                Err(err) => Err(err),
+            }
+        }
+    };
+
+    // send the result or the error message to the classical_thread
+    res_tx
+        .send(result.map(|sequences| EmulateResult {
+            sequences,
+            metadata,
+            exact,
+        }))
+        .unwrap()
+}
+
+/// Check that a program the agent cannot parse, with the parse error `err`,
+/// can run on qasmsim instead. qasmsim only runs OpenQASM 2.0 on a
+/// statevector as it is, so the program is not compiled for the device nor
+/// seeded. OpenQASM 3 and the other backends are only run by the agent, and
+/// a seed the client gave is an error, the seed of the result is null.
+pub fn fallback_to_qasmsim(msg: &EmulateInfo, err: String) -> Result<(), String> {
+    if qasm3::is_qasm3(&msg.template)
+        || msg
+            .backend
+            .as_deref()
+            .is_some_and(|backend| backend != DEFAULT_BACKEND && backend != external::QASMSIM)
+    {
+        Err(err)
+    } else if msg.optimization_level.is_some_and(|level| level > 0) {
+        Err(format!("The program cannot be optimized: {}", err))
+    } else if msg.coupling_map.is_some() {
+        Err(format!(
+            "The program cannot be routed on the coupling map of the device: {}",
+            err
+        ))
+    } else if msg.basis_gates.is_some() {
+        Err(format!(
+            "The program cannot be transpiled into the basis gates of the device: {}",
+            err
+        ))
+    } else if msg.seed.is_some() && !msg.random_seed {
+        Err(format!(
+            "seed is not supported for programs only qasmsim runs, it cannot be seeded: {}",
+            err
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// reserve the statevector of the qubits declared in a program run by qasmsim
+fn reserve_declared(pool: &ComputePool, qasm: &str) -> Result<MemoryReservation, String> {
+    let num_qubits = parse_qregs(qasm).iter().map(|qreg| qreg.size).sum();
+    pool.memory.reserve(
+        statevector_bytes(num_qubits),
+        &format!("The program of {} qubits", num_qubits),
+    )
+}
+
+/// the size of a circuit, reported before and after the optimization
+fn circuit_summary(circuit: &Circuit) -> Value {
+    json!({
+        "instructions": circuit.instructions.len(),
+        "depth": circuit.depth(),
+        "gate_counts": circuit.gate_counts(),
+    })
+}
+
+/// Compile the circuit for the device. The optimization passes of the
+/// requested level run first, then it is routed on the coupling map and
+/// transpiled into the basis gates, if the device has them. The removed
+/// instructions of each pass, the layouts and the number of swaps, the
+/// transpiled program with the variables bound, its gate counts and depth
+/// are reported in the metadata. A routed circuit is simulated on the
+/// physical qubits it uses, and the observables of the task are rewritten on
+/// them. The observables are read from the state, so the gates on the
+/// unmeasured qubits are then kept.
+pub fn compile_circuit(
+    circuit: Arc<Circuit>,
+    msg: &mut EmulateInfo,
+    metadata: &mut serde_json::Map<String, Value>,
+) -> Result<Arc<Circuit>, String> {
+    let level = msg.optimization_level.unwrap_or(0);
+    if level == 0 && msg.basis_gates.is_none() && msg.coupling_map.is_none() {
+        return Ok(circuit);
+    }
+    let mut compiled = Circuit::clone(&circuit);
+
+    if level > 0 {
+        let skip: &[&str] = match msg.observables {
+            Some(_) => &["drop_unmeasured"],
+            None => &[],
+        };
+        let removed = optimize::optimize(&mut compiled, level, skip)?;
+        metadata.insert(
+            "optimization".to_string(),
+            json!({
+                "level": level,
+                "removed": removed
+                    .into_iter()
+                    .map(|(pass, count)| (pass.to_string(), json!(count)))
+                    .collect::<serde_json::Map<_, _>>(),
+                "before": circuit_summary(&circuit),
+                "after": circuit_summary(&compiled),
+            }),
+        );
+    }
+
+    // the physical qubit of each qubit of the circuit at the end
+    let mut final_layout = None;
+    if let Some(map) = &msg.coupling_map {
+        // the gates on three qubits are expanded to be routed
+        let expanded = transpile::transpile(&compiled, &transpile::Basis::two_qubit_gates())?;
+        let routed = routing::route(&expanded, map)?;
+        metadata.insert(
+            "routing".to_string(),
+            json!({
+                "coupling_map": map.spec,
+                "initial_layout": routed.initial_layout,
+                "final_layout": routed.final_layout,
+                "swaps": routed.swaps,
+                "qasm": qasm2::to_qasm(&routed.circuit.bind(&msg.vars)),
+            }),
+        );
+        final_layout = Some(routed.final_layout);
+        compiled = routed.circuit;
+    }
+
+    if let Some(basis_gates) = &msg.basis_gates {
+        compiled = transpile::transpile(&compiled, &transpile::Basis::new(basis_gates)?)?;
+        let bound = compiled.bind(&msg.vars);
+        metadata.insert(
+            "transpiled".to_string(),
+            json!({
+                "basis_gates": basis_gates,
+                "qasm": qasm2::to_qasm(&bound),
+                "gate_counts": bound.gate_counts(),
+                "depth": bound.depth(),
+            }),
+        );
+    }
+
+    if let Some(layout) = final_layout {
+        // the observed qubits are simulated even if no gate acts on them
+        let keep = match msg.observables {
+            Some(_) => layout.clone(),
+            None => Vec::new(),
+        };
+        let (compacted, index) = routing::compact(&compiled, &keep);
+        if let Some(observables) = msg.observables.as_mut() {
+            let qubits: Vec<usize> = layout.iter().map(|&physical| index[physical]).collect();
+            *observables = observables
+                .iter()
+                .map(|spec| density::relabel_pauli(&circuit, spec, &qubits, compacted.num_qubits()))
+                .collect::<Result<Vec<String>, String>>()?;
         }
+        compiled = compacted;
     }
+    Ok(Arc::new(compiled))
 }
 
-/// quantum thread for VQE
+/// Simulate a parsed circuit on its backend. When all measurements are at
+/// the end of the circuit, it is simulated once and the shots are sampled
+/// from the final state, otherwise the shots are split across the pool, in
+/// fewer chunks at once if their memory does not fit in the budget. The
+/// shots use random streams derived from `seed`, and the memory is reserved
+/// from the budget while they run. What the backend reports is added to
+/// `metadata`, and its exact results, e.g. the probabilities of the density
+/// matrix backend, to `exact`.
+#[allow(clippy::too_many_arguments)]
+async fn run_circuit(
+    pool: Arc<ComputePool>,
+    circuit: Arc<Circuit>,
+    info: Arc<EmulateInfo>,
+    shots: usize,
+    seed: u64,
+    backend: &dyn Backend,
+    progress: &Progress,
+    metadata: &mut serde_json::Map<String, Value>,
+    exact: &mut serde_json::Map<String, Value>,
+) -> Result<Vec<String>, String> {
+    let circuit = backend.prepare(circuit, &info)?;
+    let state = backend.state_bytes(&circuit, &info);
+    let parallel =
+        simulator::parallel_chunks(state, pool.size, shots, progress.every, pool.memory.budget);
+    let _reservation = pool.memory.reserve(
+        backend.memory_bytes(&circuit, &info, parallel),
+        &format!("The circuit of {} qubits", circuit.num_qubits()),
+    )?;
+
+    let path = backend.simulation_path(&circuit);
+    metadata.insert("simulation_path".to_string(), json!(path));
+    if path == "per_shot" {
+        let chunk_size = simulator::chunk_size(pool.size, shots, progress.every);
+        metadata.insert("chunks".to_string(), json!(shots.div_ceil(chunk_size)));
+    }
+    metadata.insert("backend".to_string(), json!(backend.name()));
+
+    let output = backend
+        .run(Job {
+            pool,
+            circuit,
+            info,
+            shots,
+            seed,
+            parallel,
+            progress: progress.clone(),
+        })
+        .await?;
+    if path != "per_shot" {
+        simulator::publish_sampled(progress, &output.sequences);
+    }
+    metadata.extend(output.metadata);
+    exact.extend(output.exact);
+    Ok(output.sequences)
+}
+
+/// quantum thread for VQE, the expectation is computed exactly without
+/// sampling, so the iterations are reproducible for any seed. The program,
+/// see `vqe_program`, is run by the backend of the task, qasmsim by default.
 pub async fn quantum_thread_vqe(
+    pool: Arc<ComputePool>,
+    backends: Arc<BackendRegistry>,
+    progress: Progress,
     msg_rx: oneshot::Receiver<EmulateInfo>,
     res_tx: oneshot::Sender<Result<qasmsim::Execution, String>>,
 ) {
-    let msg = msg_rx.await.unwrap();
-    match qasmsim::run(&msg.qasm, msg.shots) {
-        Ok(result) => {
-            // send the result to the classical_thread
-            res_tx.send(Ok(result)).unwrap()
-        }
-        Err(err) => {
-            // if there are some errors, send the error message to the classical_thread
-            res_tx.send(Err(err.to_string())).unwrap()
+    let mut msg = msg_rx.await.unwrap();
+    let name = msg.backend.clone().unwrap_or(external::QASMSIM.to_string());
+    let result = match backends.get(&name).and_then(|backend| {
+        let reservation = reserve_declared(&pool, &msg.template)?;
+        Ok((backend, reservation, vqe_program(&mut msg)?))
+    }) {
+        Ok((backend, _reservation, program)) => {
+            backend.run_vqe(pool, program, msg.shots, progress).await
         }
+        Err(err) => Err(err),
+    };
+
+    // send the result or the error message to the classical_thread
+    res_tx.send(result).unwrap()
+}
+
+/// The program qasmsim runs for an iteration of VQE, OpenQASM 3 is lowered to
+/// OpenQASM 2.0. With the basis gates or the coupling map of the device the
+/// circuit is compiled for it, a program the agent cannot parse is then an
+/// error.
+fn vqe_program(msg: &mut EmulateInfo) -> Result<String, String> {
+    if msg.basis_gates.is_some() || msg.coupling_map.is_some() {
+        let circuit = circuit::parse(&msg.template)
+            .map_err(|err| format!("The program cannot be compiled for the device: {}", err))?;
+        let compiled = compile_circuit(Arc::new(circuit), msg, &mut serde_json::Map::new())?;
+        return Ok(qasm2::to_qasm(&compiled.bind(&msg.vars)));
+    }
+    if qasm3::is_qasm3(&msg.template) {
+        Ok(bind_vars(
+            &qasm2::to_qasm(&qasm3::parse(&msg.template)?),
+            &msg.vars,
+        ))
+    } else {
+        Ok(msg.qasm.clone())
     }
 }
 
@@ -51,93 +344,134 @@ pub async fn quantum_thread_vqe(
 pub async fn classical_thread(
     state: SharedState,
     msg: EmulateMessage,
+    progress: Progress,
     msg_tx: oneshot::Sender<EmulateInfo>,
-    res_rx: oneshot::Receiver<Result<qasmsim::Execution, String>>,
+    res_rx: oneshot::Receiver<Result<EmulateResult, String>>,
 ) -> (StatusCode, Json<Value>) {
-    let mode = msg.mode.clone().unwrap();
-
-    let idle_qubits = state.read().await.qreg.idle;
-    if idle_qubits < 1 || idle_qubits < msg.qubits {
-        return (
-            StatusCode::BAD_REQUEST,
-            Json(json!({"Error": "No enough qubits"})),
-        );
+    let qubits = msg.qubits;
+    {
+        let mut state_w = state.write().await;
+        if state_w.qreg.idle < 1 || state_w.qreg.idle < qubits {
+            progress.failed(&json!("No enough qubits"));
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({"Error": "No enough qubits"})),
+            );
+        }
+        state_w.qreg.idle -= qubits;
     }
 
-    let qubits = msg.qubits;
+    let result = classical_thread_allocated(state.clone(), msg, progress, msg_tx, res_rx).await;
+    state.write().await.qreg.release(qubits);
+    result
+}
 
-    state.write().await.qreg.idle -= qubits;
+/// classical thread for the jobs whose qubits are already allocated, e.g. the
+/// items of a batch. The final result or error is also published to the
+/// progress stream of the job.
+pub async fn classical_thread_allocated(
+    state: SharedState,
+    msg: EmulateMessage,
+    progress: Progress,
+    msg_tx: oneshot::Sender<EmulateInfo>,
+    res_rx: oneshot::Receiver<Result<EmulateResult, String>>,
+) -> (StatusCode, Json<Value>) {
+    let mode = msg.mode.clone().unwrap();
+    let cregs = parse_cregs(&msg.qasm);
+    let job_id = progress.job_id.clone();
+    progress.state("queued");
 
     // send the message to the quantum_thread
-    msg_tx.send(pre_process_msg(msg)).unwrap();
+    let mut info = pre_process_msg(msg);
+    {
+        let state_r = state.read().await;
+        info.basis_gates = state_r.qreg.basis_gates.clone();
+        info.coupling_map = state_r.qreg.coupling_map.clone();
+        info.noise = state_r.qreg.noise;
+    }
+    msg_tx.send(info).unwrap();
 
     // use res_rx to receive the result from the quantum_thread
-    match res_rx.await {
+    let (status, json) = match res_rx.await {
         Ok(Ok(result)) => {
-            state.write().await.qreg.idle += qubits;
             // post process message
-            match post_process_msg(state, result.sequences().clone().unwrap(), mode.to_string())
-                .await
+            match post_process_msg(
+                state,
+                result.sequences,
+                mode.to_string(),
+                &job_id,
+                &cregs,
+                result.metadata,
+                result.exact,
+            )
+            .await
             {
-                Ok(json) => return (StatusCode::OK, json),
-                Err(err) => {
-                    return (
-                        StatusCode::BAD_REQUEST,
-                        Json(json!({"Error": format!("{}", err)})),
-                    )
-                }
+                Ok(json) => (StatusCode::OK, json),
+                Err(err) => (
+                    StatusCode::BAD_REQUEST,
+                    Json(json!({"Error": format!("{}", err), "job_id": job_id})),
+                ),
             }
         }
-        Ok(Err(err)) => {
-            state.write().await.qreg.idle += qubits;
-            (
-                // quantum thread error
-                StatusCode::BAD_REQUEST,
-                Json(json!({"Error": format!("{}", err)})),
-            )
-        }
-        Err(_) => {
-            state.write().await.qreg.idle += qubits;
-            (
-                // receiver error
-                StatusCode::BAD_REQUEST,
-                Json(json!({"Error": "Internal server error"})),
-            )
-        }
+        Ok(Err(err)) => (
+            // quantum thread error
+            pool::error_status(&err),
+            Json(json!({"Error": format!("{}", err)})),
+        ),
+        Err(_) => (
+            // receiver error
+            StatusCode::BAD_REQUEST,
+            Json(json!({"Error": "Internal server error"})),
+        ),
+    };
+
+    if status == StatusCode::OK {
+        progress.done(&json);
+    } else {
+        progress.failed(&json["Error"]);
     }
+    (status, json)
 }
 
-/// classical thread for VQE
+/// classical thread for VQE, the variables and the expectation of the
+/// iteration are published to the progress stream of the job
+#[allow(clippy::too_many_arguments)]
 pub async fn classical_thread_vqe(
+    state: SharedState,
     msg: EmulateMessage,
     vars_range: HashMap<String, (f32, f32)>,
     iteration: usize,
     iterations: usize,
+    progress: Progress,
     msg_tx: oneshot::Sender<EmulateInfo>,
     res_rx: oneshot::Receiver<Result<qasmsim::Execution, String>>,
 ) -> (StatusCode, Json<Value>) {
+    let mut info = pre_process_msg_vqe(msg, vars_range, iteration, iterations);
+    {
+        let state_r = state.read().await;
+        info.basis_gates = state_r.qreg.basis_gates.clone();
+        info.coupling_map = state_r.qreg.coupling_map.clone();
+    }
+    let vars = info.vars.clone();
     // send the message to the quantum_thread
-    msg_tx
-        .send(pre_process_msg_vqe(msg, vars_range, iteration, iterations))
-        .unwrap();
+    msg_tx.send(info).unwrap();
 
     // use res_rx to receive the result from the quantum_thread
     match res_rx.await {
         Ok(Ok(result)) => {
+            progress.iteration(iteration, &vars, result.expectation());
             // post process message
             match post_process_msg_vqe(result.expectation().clone()) {
-                Ok(json) => return (StatusCode::OK, json),
-                Err(err) => {
-                    return (
-                        StatusCode::BAD_REQUEST,
-                        Json(json!({"Error": format!("{}", err)})),
-                    )
-                }
+                Ok(json) => (StatusCode::OK, json),
+                Err(err) => (
+                    StatusCode::BAD_REQUEST,
+                    Json(json!({"Error": format!("{}", err)})),
+                ),
             }
         }
         Ok(Err(err)) => (
             // quantum thread error
-            StatusCode::BAD_REQUEST,
+            pool::error_status(&err),
             Json(json!({"Error": format!("{}", err)})),
         ),
         Err(_) => (
@@ -147,3 +481,195 @@ pub async fn classical_thread_vqe(
         ),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{emulate::pre_process_msg, pool::MemoryBudget, progress::ProgressHub};
+
+    const BELL: &str = "OPENQASM 2.0;\ninclude \"qelib1.inc\";\nqreg q[2];\ncreg c[2];\n\
+                        h q[0];\ncx q[0],q[1];\nmeasure q -> c;\n";
+    /// a program only qasmsim runs
+    const OPAQUE: &str = "OPENQASM 2.0;\ninclude \"qelib1.inc\";\nqreg q[1];\nopaque g q;\n";
+
+    fn info(qasm: &str) -> EmulateInfo {
+        pre_process_msg(
+            serde_json::from_value(json!({"qasm": qasm, "qubits": 2, "shots": 10})).unwrap(),
+        )
+    }
+
+    fn basis() -> Option<Vec<String>> {
+        Some(["rz", "sx", "x", "cx"].map(String::from).to_vec())
+    }
+
+    fn pool() -> Arc<ComputePool> {
+        Arc::new(ComputePool::new(1, 1, MemoryBudget::new(1 << 30)))
+    }
+
+    fn progress() -> Progress {
+        Progress::new(Arc::new(ProgressHub::new()), "test".to_string(), None)
+    }
+
+    async fn run(info: EmulateInfo) -> Result<EmulateResult, String> {
+        run_on(info, BackendRegistry::default()).await
+    }
+
+    async fn run_on(info: EmulateInfo, backends: BackendRegistry) -> Result<EmulateResult, String> {
+        let (msg_tx, msg_rx) = oneshot::channel();
+        let (res_tx, res_rx) = oneshot::channel();
+        msg_tx.send(info).unwrap();
+        quantum_thread(
+            pool(),
+            Arc::new(CircuitCache::new(8)),
+            Arc::new(backends),
+            progress(),
+            msg_rx,
+            res_tx,
+        )
+        .await;
+        res_rx.await.unwrap()
+    }
+
+    #[test]
+    fn vqe_program_is_transpiled_into_the_basis() {
+        let mut msg = info(BELL);
+        assert_eq!(vqe_program(&mut msg).unwrap(), BELL);
+        msg.basis_gates = basis();
+        let program = vqe_program(&mut msg).unwrap();
+        assert!(!program.contains("\nh "), "{}", program);
+        assert!(program.contains("\nsx "), "{}", program);
+
+        let mut msg = info(OPAQUE);
+        msg.basis_gates = basis();
+        let err = vqe_program(&mut msg).unwrap_err();
+        assert!(err.starts_with("The program cannot be compiled"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn fallback_fails_with_basis_gates() {
+        let mut msg = info(OPAQUE);
+        msg.basis_gates = basis();
+        let err = run(msg).await.unwrap_err();
+        assert!(
+            err.starts_with("The program cannot be transpiled"),
+            "{}",
+            err
+        );
+
+        let mut msg = info(BELL);
+        msg.basis_gates = basis();
+        let result = run(msg).await.unwrap();
+        assert!(result.metadata.contains_key("transpiled"));
+    }
+
+    #[test]
+    fn vqe_program_is_routed_on_the_coupling_map() {
+        let mut msg = info(
+            "OPENQASM 2.0;\ninclude \"qelib1.inc\";\nqreg q[3];\ncreg c[3];\n\
+             cx q[0],q[1];\ncx q[1],q[2];\ncx q[0],q[2];\nmeasure q -> c;\n",
+        );
+        msg.coupling_map = Some(routing::CouplingMap::new("linear", 3).unwrap());
+        let program = vqe_program(&mut msg).unwrap();
+        assert!(program.contains("swap"), "{}", program);
+
+        let mut msg = info(OPAQUE);
+        msg.coupling_map = Some(routing::CouplingMap::new("linear", 3).unwrap());
+        assert!(vqe_program(&mut msg).is_err());
+    }
+
+    #[tokio::test]
+    async fn fallback_fails_with_a_coupling_map() {
+        let mut msg = info(OPAQUE);
+        msg.coupling_map = Some(routing::CouplingMap::new("linear", 2).unwrap());
+        let err = run(msg).await.unwrap_err();
+        assert!(err.starts_with("The program cannot be routed"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn fallback_fails_with_an_optimization_level() {
+        let mut msg = info(OPAQUE);
+        msg.optimization_level = Some(1);
+        let err = run(msg).await.unwrap_err();
+        assert!(
+            err.starts_with("The program cannot be optimized"),
+            "{}",
+            err
+        );
+    }
+
+    /// the exact expectation values of the observables on the density matrix
+    async fn expectation_values(msg: EmulateInfo, observables: &[&str]) -> Vec<f64> {
+        let mut msg = msg;
+        msg.backend = Some("density_matrix".to_string());
+        msg.observables = Some(observables.iter().map(|spec| spec.to_string()).collect());
+        let result = run(msg).await.unwrap();
+        serde_json::from_value(result.exact["expectation_values"].clone()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn observables_follow_the_routing() {
+        // q[0] ends in |0>, q[1] in |1>, q[2] in |-> and q[3] is idle
+        let source = "OPENQASM 2.0;\ninclude \"qelib1.inc\";\nqreg q[4];\n\
+                      x q[0];\ncx q[0],q[1];\ncx q[1],q[2];\ncx q[2],q[0];\nh q[2];\n";
+        let observables = ["IIIZ", "IIZI", "IZII", "IXII", "ZIII", "XIII"];
+        let expected = [1.0, -1.0, 0.0, -1.0, 1.0, 0.0];
+        let mut msg = info(source);
+        msg.coupling_map = Some(routing::CouplingMap::new("linear", 5).unwrap());
+        msg.observables = Some(observables.map(String::from).to_vec());
+        let mut metadata = serde_json::Map::new();
+        let circuit = Arc::new(circuit::parse(source).unwrap());
+        compile_circuit(circuit, &mut msg, &mut metadata).unwrap();
+        assert!(metadata["routing"]["swaps"].as_u64().unwrap() > 0);
+        assert_ne!(msg.observables.unwrap(), observables);
+
+        for coupling_map in [None, Some(routing::CouplingMap::new("linear", 5).unwrap())] {
+            let mut msg = info(source);
+            msg.coupling_map = coupling_map;
+            let values = expectation_values(msg, &observables).await;
+            for (value, expected) in values.iter().zip(expected) {
+                assert!((value - expected).abs() < 1e-9, "{:?}", values);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn observables_keep_the_unmeasured_gates() {
+        let source = "OPENQASM 2.0;\ninclude \"qelib1.inc\";\nqreg q[2];\ncreg c[1];\n\
+                      x q[1];\nh q[0];\nh q[0];\nmeasure q[0] -> c[0];\n";
+        let mut msg = info(source);
+        msg.optimization_level = Some(3);
+        msg.observables = Some(vec!["ZI".to_string()]);
+        let mut metadata = serde_json::Map::new();
+        let circuit = Arc::new(circuit::parse(source).unwrap());
+        let compiled = compile_circuit(circuit, &mut msg, &mut metadata).unwrap();
+        assert_eq!(compiled.instructions.len(), 2);
+        assert!(metadata["optimization"]["removed"]
+            .get("drop_unmeasured")
+            .is_none());
+
+        let mut msg = info(source);
+        msg.optimization_level = Some(2);
+        assert_eq!(expectation_values(msg, &["ZI", "IZ"]).await, [-1.0, 1.0]);
+    }
+
+    #[tokio::test]
+    async fn fallback_runs_on_the_qasmsim_of_the_registry() {
+        let mut backends = BackendRegistry::new();
+        backends.register(Arc::new(simulator::statevector::StatevectorBackend));
+        let err = run_on(info(OPAQUE), backends).await.unwrap_err();
+        assert!(err.starts_with("Unknown backend qasmsim"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn vqe_runs_on_the_backend_of_the_task() {
+        let mut msg = info(BELL);
+        msg.backend = Some("mps".to_string());
+        let (msg_tx, msg_rx) = oneshot::channel();
+        let (res_tx, res_rx) = oneshot::channel();
+        msg_tx.send(msg).unwrap();
+        let backends = Arc::new(BackendRegistry::default());
+        quantum_thread_vqe(pool(), backends, progress(), msg_rx, res_tx).await;
+        let err = res_rx.await.unwrap().unwrap_err();
+        assert_eq!(err, "The mps backend does not run vqe");
+    }
+}